@@ -0,0 +1,178 @@
+use std::fmt;
+
+use crate::builder::VmBuilder;
+use crate::pipeline::Pipeline;
+use crate::region::Region;
+use crate::vm::{self, Vm};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("{0} engine failed - {1}")]
+  EngineFailed(Engine, vm::Error),
+}
+
+/// Execution models a program can be scheduled on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+  /// Sequential design, one instruction per cycle.
+  Seq,
+  /// Five stage pipeline, see `Pipeline`.
+  Pipe,
+  /// Cost table, cache and fetch model configured on the builder.
+  Timing,
+}
+
+impl Engine {
+  pub fn name(self) -> &'static str {
+    match self {
+      Engine::Seq => "seq",
+      Engine::Pipe => "pipe",
+      Engine::Timing => "timing",
+    }
+  }
+}
+
+impl fmt::Display for Engine {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.pad(self.name())
+  }
+}
+
+/// How one engine scheduled the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EngineStats {
+  pub engine: Engine,
+  pub instructions: usize,
+  pub cycles: u64,
+  /// Cycles lost waiting, held stages for pipe and fetch stalls for the
+  /// timing model.
+  pub stalls: u64,
+  /// Conditional jumps that went against the prediction. Only pipe predicts
+  /// branches, the timing model charges taken ones as fetch redirects.
+  pub mispredictions: usize,
+}
+
+impl EngineStats {
+  /// Cycles per instruction.
+  pub fn cpi(&self) -> f64 {
+    match self.instructions {
+      0 => 0.0,
+      instructions => self.cycles as f64 / instructions as f64,
+    }
+  }
+
+  /// Instructions per cycle.
+  pub fn ipc(&self) -> f64 {
+    match self.cycles {
+      0 => 0.0,
+      cycles => self.instructions as f64 / cycles as f64,
+    }
+  }
+}
+
+/// Outcome of `analyze`, one entry per engine in `Engine` order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EngineComparison {
+  pub engines: Vec<EngineStats>,
+}
+
+impl EngineComparison {
+  pub fn get(&self, engine: Engine) -> Option<&EngineStats> {
+    self.engines.iter().find(|stats| stats.engine == engine)
+  }
+}
+
+impl fmt::Display for EngineComparison {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(
+      f,
+      "{:<8} {:>12} {:>10} {:>6} {:>6} {:>8} {:>14}",
+      "engine", "instructions", "cycles", "cpi", "ipc", "stalls", "mispredictions"
+    )?;
+    for stats in &self.engines {
+      writeln!(
+        f,
+        "{:<8} {:>12} {:>10} {:>6.2} {:>6.2} {:>8} {:>14}",
+        stats.engine,
+        stats.instructions,
+        stats.cycles,
+        stats.cpi(),
+        stats.ipc(),
+        stats.stalls,
+        stats.mispredictions
+      )?;
+    }
+    Ok(())
+  }
+}
+
+/// Runs `region` to completion once per engine, each on a fresh vm from
+/// `builder`, and collects how every engine scheduled it.
+pub fn analyze<R>(builder: &VmBuilder, region: &R) -> Result<EngineComparison, Error>
+where
+  R: Region,
+{
+  analyze_with(builder, region, |_| {})
+}
+
+/// Like `analyze`, calling `setup` on every vm once the program is loaded,
+/// for what the builder does not carry such as trap handlers.
+pub fn analyze_with<R, F>(
+  builder: &VmBuilder,
+  region: &R,
+  setup: F,
+) -> Result<EngineComparison, Error>
+where
+  R: Region,
+  F: Fn(&mut Vm),
+{
+  let vm = |engine| -> Result<Vm, Error> {
+    let mut vm = builder.clone().build();
+    vm.load(region)
+      .map_err(|e| Error::EngineFailed(engine, e))?;
+    setup(&mut vm);
+    Ok(vm)
+  };
+
+  let mut seq = vm(Engine::Seq)?;
+  seq
+    .run(region)
+    .map_err(|e| Error::EngineFailed(Engine::Seq, e))?;
+  let seq = EngineStats {
+    engine: Engine::Seq,
+    instructions: seq.steps(),
+    cycles: seq.steps() as u64,
+    stalls: 0,
+    mispredictions: 0,
+  };
+
+  let pipeline = Pipeline::run(&mut vm(Engine::Pipe)?, region)
+    .map_err(|e| Error::EngineFailed(Engine::Pipe, e))?;
+  let pipe = EngineStats {
+    engine: Engine::Pipe,
+    instructions: pipeline.instructions(),
+    cycles: pipeline.cycles() as u64,
+    stalls: pipeline.stalls() as u64,
+    mispredictions: pipeline.mispredictions(),
+  };
+
+  let mut timed = vm(Engine::Timing)?;
+  timed
+    .run(region)
+    .map_err(|e| Error::EngineFailed(Engine::Timing, e))?;
+  let stalls = timed
+    .timing()
+    .fetch()
+    .map_or(0, |fetch| fetch.stalls().length + fetch.stalls().redirect);
+  let timing = EngineStats {
+    engine: Engine::Timing,
+    instructions: timed.steps(),
+    cycles: timed.timing().cycles(),
+    stalls,
+    mispredictions: 0,
+  };
+
+  Ok(EngineComparison {
+    engines: vec![seq, pipe, timing],
+  })
+}
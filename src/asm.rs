@@ -0,0 +1,582 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::Block;
+use crate::disasm::{self, ColorMode, Style};
+use crate::frame::Locals;
+use crate::opcode::{Encoding, Isa, Operands};
+use crate::region::Chunk;
+use crate::register::Register;
+use crate::symbol::Symbols;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("line {0}: unknown instruction {1:?}")]
+  UnknownInstruction(usize, String),
+
+  #[error("line {0}: unknown directive {1:?}")]
+  UnknownDirective(usize, String),
+
+  #[error("line {0}: invalid operands {1:?}")]
+  InvalidOperands(usize, String),
+
+  #[error("line {0}: invalid number {1:?}")]
+  InvalidNumber(usize, String),
+
+  #[error("line {0}: label {1:?} is defined twice")]
+  DuplicateLabel(usize, String),
+
+  #[error("line {0}: undefined label {1:?}")]
+  UndefinedLabel(usize, String),
+
+  #[error("line {0}: {1} is an extension outside the strict instruction set")]
+  Extension(usize, String),
+
+  #[error("line {0}: .local {1:?} precedes every label")]
+  LocalOutsideFunction(usize, String),
+
+  #[error("line {0}: .pos {1:#x} is behind the current address {2:#x}")]
+  PositionBackwards(usize, usize, usize),
+
+  #[error("programs overlap at {0:#x}")]
+  Overlap(usize),
+
+  #[error("label {0:?} is defined by both programs")]
+  SymbolClash(String),
+
+  #[error("reassembled program differs at {0:#x}")]
+  RoundTripBytes(usize),
+
+  #[error("label {0:?} at {1:#x} reassembled to {2:?}")]
+  RoundTripSymbol(String, usize, Option<usize>),
+}
+
+/// Machine code produced by `assemble`, along with its labels.
+#[derive(Debug, Clone, Default)]
+pub struct Assembled {
+  bytes: Vec<u8>,
+  symbols: Symbols,
+  locals: Locals,
+  // address ranges holding instructions, and those holding directive data
+  code: Vec<Range<usize>>,
+  data: Vec<Range<usize>>,
+  // addresses of 8 byte fields holding the address of a label
+  relocations: Vec<usize>,
+}
+
+impl Assembled {
+  pub fn bytes(&self) -> &[u8] {
+    &self.bytes
+  }
+
+  pub fn symbols(&self) -> &Symbols {
+    &self.symbols
+  }
+
+  /// Stack slots named by `.local` directives, for `frame::backtrace`.
+  pub fn locals(&self) -> &Locals {
+    &self.locals
+  }
+
+  /// Addresses of the immediates, displacements, destinations and `.quad`
+  /// values the assembler filled in with the address of a label.
+  pub fn relocations(&self) -> &[usize] {
+    &self.relocations
+  }
+
+  /// Address ranges holding instructions or directive data, sorted and with
+  /// adjacent ranges joined. The zeros `.pos` and `.align` skip over lie
+  /// between them.
+  pub fn segments(&self) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = self
+      .code
+      .iter()
+      .chain(&self.data)
+      .filter(|range| !range.is_empty())
+      .cloned()
+      .collect();
+    ranges.sort_unstable_by_key(|range| range.start);
+    let mut segments: Vec<Range<usize>> = Vec::new();
+    for range in ranges {
+      match segments.last_mut() {
+        Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+        _ => segments.push(range),
+      }
+    }
+    segments
+  }
+
+  /// Moves the program so what was at address zero sits at `base`, adding
+  /// `base` to every relocation and label. Addresses written as numbers in
+  /// the source are left alone. The image still starts at address zero, with
+  /// the bytes below `base` zeroed, and the vm should be built with `base`
+  /// as its entry point.
+  pub fn rebase(&self, base: usize) -> Assembled {
+    let shift = |range: &Range<usize>| range.start + base..range.end + base;
+    let mut bytes = vec![0; base];
+    bytes.extend_from_slice(&self.bytes);
+    for &relocation in &self.relocations {
+      let field = &mut bytes[relocation + base..relocation + base + 8];
+      let address = Block::from_le_bytes(field.try_into().expect("8 byte field"));
+      field.copy_from_slice(&address.wrapping_add(base as Block).to_le_bytes());
+    }
+    let mut symbols = Symbols::new();
+    for (address, name) in self.symbols.iter() {
+      symbols.insert(name, address + base);
+    }
+    let mut locals = Locals::new();
+    for (function, slots) in self.locals.iter() {
+      for local in slots {
+        locals.insert(function + base, &local.name, local.offset);
+      }
+    }
+    Assembled {
+      bytes,
+      symbols,
+      locals,
+      code: self.code.iter().map(shift).collect(),
+      data: self.data.iter().map(shift).collect(),
+      relocations: self.relocations.iter().map(|r| r + base).collect(),
+    }
+  }
+
+  /// Combines `other` into this image, typically a program rebased past the
+  /// end of this one. Fails if both place instructions or data at the same
+  /// address or define the same label.
+  pub fn merge(&mut self, other: &Assembled) -> Result<(), Error> {
+    let ours = self.code.iter().chain(&self.data);
+    for range in ours {
+      let overlap = other
+        .code
+        .iter()
+        .chain(&other.data)
+        .find(|theirs| theirs.start < range.end && range.start < theirs.end);
+      if let Some(theirs) = overlap {
+        return Err(Error::Overlap(range.start.max(theirs.start)));
+      }
+    }
+    if let Some((_, name)) = other
+      .symbols
+      .iter()
+      .find(|&(_, name)| self.symbols.address_of(name).is_some())
+    {
+      return Err(Error::SymbolClash(name.to_string()));
+    }
+    if other.bytes.len() > self.bytes.len() {
+      self.bytes.resize(other.bytes.len(), 0);
+    }
+    for range in other.code.iter().chain(&other.data) {
+      self.bytes[range.clone()].copy_from_slice(&other.bytes[range.clone()]);
+    }
+    for (address, name) in other.symbols.iter() {
+      self.symbols.insert(name, address);
+    }
+    for (function, slots) in other.locals.iter() {
+      for local in slots {
+        self.locals.insert(function, &local.name, local.offset);
+      }
+    }
+    self.code.extend(other.code.iter().cloned());
+    self.data.extend(other.data.iter().cloned());
+    self.relocations.extend(&other.relocations);
+    Ok(())
+  }
+
+  /// Source that assembles back to the same bytes and labels, instructions
+  /// disassembled and everything else emitted as `.byte` data.
+  pub fn listing(&self) -> String {
+    let style = Style::new(ColorMode::Never).with_symbols(&self.symbols);
+    let mut out = String::new();
+    let mut at = 0;
+    let mut address = 0;
+    let is_code = |address: usize| self.code.iter().any(|range| range.contains(&address));
+    let is_gap = |address: usize| {
+      self.bytes[address] == 0 && !is_code(address) && self.symbols.name_of(address).is_none()
+    };
+    while address < self.bytes.len() {
+      // long zero runs are left to the gap filling of `.pos`
+      let gap = (address..self.bytes.len())
+        .take_while(|&a| is_gap(a))
+        .count();
+      if address + gap == self.bytes.len() && gap > 0 {
+        // the image ends in zeros, only the last byte is needed to keep them
+        address = self.bytes.len() - 1;
+        let _ = writeln!(out, ".pos {address:#x}\n  .byte 0x00");
+        at = self.bytes.len();
+        break;
+      }
+      if gap >= 8 {
+        address += gap;
+        continue;
+      }
+      let code = is_code(address);
+      if at != address {
+        let _ = writeln!(out, ".pos {address:#x}");
+      }
+      for name in self.symbols.names_of(address) {
+        let _ = writeln!(out, "{name}:");
+      }
+      if self.symbols.name_of(address).is_some() {
+        for local in self.locals.of(address) {
+          let _ = writeln!(out, "  .local {}, {}", local.name, local.offset);
+        }
+      }
+      let len = match code.then(|| disasm::disassemble_at(&self.bytes, address).ok()) {
+        Some(Some(instruction)) => {
+          let _ = writeln!(out, "  {}", instruction.text(&style));
+          instruction.bytes().len()
+        }
+        _ => {
+          let _ = writeln!(out, "  .byte {:#04x}", self.bytes[address]);
+          1
+        }
+      };
+      address += len;
+      at = address;
+    }
+    for (address, name) in self.symbols.iter() {
+      if address < self.bytes.len() {
+        continue;
+      }
+      if at != address {
+        let _ = writeln!(out, ".pos {address:#x}");
+        at = address;
+      }
+      let _ = writeln!(out, "{name}:");
+    }
+    out
+  }
+}
+
+impl From<Assembled> for Chunk {
+  fn from(assembled: Assembled) -> Self {
+    Chunk::from(assembled.bytes)
+  }
+}
+
+/// A number or a label standing for its address.
+#[derive(Debug, Clone)]
+enum Value {
+  Number(Block),
+  Label(String),
+}
+
+#[derive(Debug)]
+enum Item {
+  Instruction {
+    encoding: Encoding,
+    registers: Option<(u8, u8)>,
+    value: Option<Value>,
+  },
+  Quad(Value),
+  Byte(u8),
+  Pos(usize),
+  Align(usize),
+  Local(String, i64),
+}
+
+/// Assembles y86 source in the `.ys` dialect: one instruction or directive
+/// per line, optionally preceded by `label:`, with `#` starting a comment.
+/// Supported directives are `.pos ADDR`, `.align N`, `.quad VALUE` and
+/// `.byte VALUE`, and values are decimal or `0x` hex numbers or labels.
+/// `.local NAME, OFFSET` names the stack slot at `OFFSET(%rbp)` in the
+/// function of the closest label above it, for debugger frame views.
+pub fn assemble(source: &str) -> Result<Assembled, Error> {
+  assemble_isa(source, Isa::Extended)
+}
+
+/// Assembles like `assemble`, rejecting instructions `isa` lacks.
+pub fn assemble_isa(source: &str, isa: Isa) -> Result<Assembled, Error> {
+  let mut items = Vec::new();
+  let mut labels = HashMap::new();
+  let mut symbols = Symbols::new();
+  let mut locals = Locals::new();
+  let mut function = None;
+  let mut address = 0;
+  for (i, line) in source.lines().enumerate() {
+    let line_no = i + 1;
+    let mut rest = line.split('#').next().unwrap_or("").trim();
+    while let Some((label, after)) = rest.split_once(':')
+      && is_identifier(label.trim())
+    {
+      let label = label.trim().to_string();
+      if labels.insert(label.clone(), address).is_some() {
+        return Err(Error::DuplicateLabel(line_no, label));
+      }
+      symbols.insert(label, address);
+      function = Some(address);
+      rest = after.trim();
+    }
+    if rest.is_empty() {
+      continue;
+    }
+    let item = parse_item(line_no, rest)?;
+    if let Item::Instruction { encoding, .. } = &item
+      && !isa.permits(encoding)
+    {
+      return Err(Error::Extension(line_no, encoding.mnemonic.to_string()));
+    }
+    if let Item::Local(name, offset) = item {
+      let function = function.ok_or_else(|| Error::LocalOutsideFunction(line_no, name.clone()))?;
+      locals.insert(function, name, offset);
+      continue;
+    }
+    address = match &item {
+      Item::Instruction { encoding, .. } => address + encoding.size(),
+      Item::Quad(_) => address + 8,
+      Item::Byte(_) => address + 1,
+      Item::Pos(pos) if *pos < address => {
+        return Err(Error::PositionBackwards(line_no, *pos, address));
+      }
+      Item::Pos(pos) => *pos,
+      Item::Align(align) => address.next_multiple_of((*align).max(1)),
+      Item::Local(..) => unreachable!("recorded above"),
+    };
+    items.push((line_no, item));
+  }
+
+  let resolve = |line_no: usize, value: &Value| match value {
+    Value::Number(n) => Ok(*n),
+    Value::Label(label) => labels
+      .get(label)
+      .map(|&address| address as Block)
+      .ok_or_else(|| Error::UndefinedLabel(line_no, label.clone())),
+  };
+  let mut assembled = Assembled {
+    symbols,
+    locals,
+    ..Assembled::default()
+  };
+  let bytes = &mut assembled.bytes;
+  // `.pos` and `.align` only pad once something follows them, so labels past
+  // the last byte, typically `stack`, do not grow the image
+  let mut address = 0;
+  for (line_no, item) in &items {
+    match item {
+      Item::Pos(pos) => address = *pos,
+      Item::Align(align) => address = address.next_multiple_of((*align).max(1)),
+      _ => bytes.resize(address, 0),
+    }
+    let start = bytes.len();
+    let value = match item {
+      Item::Instruction {
+        encoding,
+        registers,
+        value,
+      } => {
+        bytes.push(encoding.byte);
+        if let Some((ra, rb)) = registers {
+          bytes.push(ra << 4 | rb);
+        }
+        value.as_ref()
+      }
+      Item::Quad(value) => Some(value),
+      Item::Byte(byte) => {
+        bytes.push(*byte);
+        None
+      }
+      Item::Pos(_) | Item::Align(_) | Item::Local(..) => continue,
+    };
+    if let Some(value) = value {
+      if let Value::Label(_) = value {
+        assembled.relocations.push(bytes.len());
+      }
+      bytes.extend_from_slice(&resolve(*line_no, value)?.to_le_bytes());
+    }
+    match item {
+      Item::Instruction { .. } => assembled.code.push(start..bytes.len()),
+      _ => assembled.data.push(start..bytes.len()),
+    }
+    address = bytes.len();
+  }
+  Ok(assembled)
+}
+
+/// Assembles `source`, reassembles its `Assembled::listing` and checks both
+/// produce the same bytes and labels, catching any disagreement between the
+/// assembler and the disassembler. Returns the first assembly.
+pub fn round_trip(source: &str) -> Result<Assembled, Error> {
+  let first = assemble(source)?;
+  let second = assemble(&first.listing())?;
+  let len = first.bytes.len().max(second.bytes.len());
+  if let Some(address) = (0..len).find(|&i| first.bytes.get(i) != second.bytes.get(i)) {
+    return Err(Error::RoundTripBytes(address));
+  }
+  for (address, name) in first.symbols.iter() {
+    let reassembled = second.symbols.address_of(name);
+    if reassembled != Some(address) {
+      return Err(Error::RoundTripSymbol(
+        name.to_string(),
+        address,
+        reassembled,
+      ));
+    }
+  }
+  Ok(first)
+}
+
+fn is_identifier(s: &str) -> bool {
+  let mut chars = s.chars();
+  chars
+    .next()
+    .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_item(line_no: usize, text: &str) -> Result<Item, Error> {
+  let (name, operands) = match text.split_once(char::is_whitespace) {
+    Some((name, operands)) => (name, operands.trim()),
+    None => (text, ""),
+  };
+  let invalid = || Error::InvalidOperands(line_no, operands.to_string());
+
+  if let Some(directive) = name.strip_prefix('.') {
+    let number = || parse_number(line_no, operands);
+    return match directive {
+      "pos" => Ok(Item::Pos(number()? as usize)),
+      "align" => Ok(Item::Align(number()? as usize)),
+      "quad" => Ok(Item::Quad(parse_value(line_no, operands)?)),
+      "byte" => u8::try_from(number()?)
+        .map(Item::Byte)
+        .map_err(|_| invalid()),
+      "local" => {
+        let (name, offset) = operands.split_once(',').ok_or_else(invalid)?;
+        let name = name.trim();
+        if !is_identifier(name) {
+          return Err(invalid());
+        }
+        Ok(Item::Local(
+          name.to_string(),
+          parse_number(line_no, offset.trim())?,
+        ))
+      }
+      _ => Err(Error::UnknownDirective(line_no, name.to_string())),
+    };
+  }
+
+  let encoding = Encoding::all()
+    .find(|encoding| encoding.mnemonic == name)
+    .ok_or_else(|| Error::UnknownInstruction(line_no, name.to_string()))?;
+  let fields: Vec<&str> = match operands {
+    "" => Vec::new(),
+    _ => operands.split(',').map(str::trim).collect(),
+  };
+  let register = |s: &str| {
+    s.parse::<Register>()
+      .map(|reg| reg as u8)
+      .map_err(|_| invalid())
+  };
+  // `D(%rb)` with the displacement optional
+  let memory = |s: &str| -> Result<(Value, u8), Error> {
+    let (disp, base) = s.split_once('(').ok_or_else(invalid)?;
+    let base = register(base.strip_suffix(')').ok_or_else(invalid)?.trim())?;
+    let disp = match disp.trim() {
+      "" => Value::Number(0),
+      disp => parse_value(line_no, disp)?,
+    };
+    Ok((disp, base))
+  };
+  let (registers, value) = match (encoding.operands, fields.as_slice()) {
+    (Operands::None, []) => (None, None),
+    (Operands::Registers, [ra, rb]) => (Some((register(ra)?, register(rb)?)), None),
+    (Operands::RegisterA, [ra]) => (Some((register(ra)?, 0xf)), None),
+    (Operands::Immediate, [value, rb]) => {
+      let value = value.strip_prefix('$').unwrap_or(value);
+      (
+        Some((0xf, register(rb)?)),
+        Some(parse_value(line_no, value)?),
+      )
+    }
+    // rmmovq names the source register first, mrmovq the memory operand
+    (Operands::Memory, [ra, mem]) if encoding.writes_memory => {
+      let (disp, rb) = memory(mem)?;
+      (Some((register(ra)?, rb)), Some(disp))
+    }
+    (Operands::Memory, [mem, ra]) if encoding.reads_memory => {
+      let (disp, rb) = memory(mem)?;
+      (Some((register(ra)?, rb)), Some(disp))
+    }
+    (Operands::Destination, [dest]) => (None, Some(parse_value(line_no, dest)?)),
+    _ => return Err(invalid()),
+  };
+  Ok(Item::Instruction {
+    encoding,
+    registers,
+    value,
+  })
+}
+
+fn parse_value(line_no: usize, s: &str) -> Result<Value, Error> {
+  if is_identifier(s) {
+    return Ok(Value::Label(s.to_string()));
+  }
+  parse_number(line_no, s).map(Value::Number)
+}
+
+/// Parses a decimal or `0x` hex number, either optionally negative.
+fn parse_number(line_no: usize, s: &str) -> Result<Block, Error> {
+  let invalid = || Error::InvalidNumber(line_no, s.to_string());
+  let (negative, digits) = match s.strip_prefix('-') {
+    Some(digits) => (true, digits),
+    None => (false, s),
+  };
+  // hex covers the whole unsigned range, so `0xffffffffffffffff` is -1
+  let Some(hex) = digits.strip_prefix("0x") else {
+    return s.parse().map_err(|_| invalid());
+  };
+  let value = u64::from_str_radix(hex, 16).map_err(|_| invalid())? as Block;
+  Ok(if negative {
+    value.wrapping_neg()
+  } else {
+    value
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::generator::Generator;
+
+  #[test]
+  fn generated_programs_round_trip() {
+    for seed in 0..64 {
+      let source = Generator::new(seed).generate();
+      if let Err(e) = round_trip(&source) {
+        panic!("seed {seed}: {e}\n{source}");
+      }
+    }
+  }
+
+  #[test]
+  fn aliases_and_gaps_round_trip() {
+    let source = "
+start:
+main:
+    irmovq data, %rbx
+    mrmovq 0(%rbx), %rax
+    call done
+    jmp start
+.pos 0x40
+data:
+    .quad 0x1234
+done:
+end:
+    halt
+";
+    let assembled = round_trip(source).unwrap();
+    let symbols = assembled.symbols();
+    assert_eq!(symbols.address_of("start"), Some(0));
+    assert_eq!(symbols.address_of("main"), Some(0));
+    assert_eq!(symbols.address_of("data"), Some(0x40));
+    assert_eq!(symbols.address_of("end"), symbols.address_of("done"));
+  }
+
+  #[test]
+  fn listing_keeps_every_name() {
+    let assembled = assemble("a:\nb:\n    nop\n    halt\n").unwrap();
+    let listing = assembled.listing();
+    assert!(listing.contains("a:"));
+    assert!(listing.contains("b:"));
+  }
+}
@@ -1,7 +1,837 @@
-use y86::region::Chunk;
-use y86::vm::Vm;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, bail};
+
+use y86::analysis::analyze_with;
+use y86::asm::{self, Assembled};
+use y86::builder::{CodeWrites, UninitializedReads, VmBuilder};
+use y86::compare::compare;
+use y86::debugger::{Debugger, Stop};
+use y86::device::clock::Clock;
+use y86::device::counters::PerfCounters;
+use y86::device::framebuffer::Framebuffer;
+use y86::disasm::{self, ColorMode, Style};
+use y86::event::{Event, EventFilter, EventKind};
+use y86::expr::Expr;
+use y86::generator::Generator;
+use y86::heap;
+use y86::history::WriteHistory;
+use y86::image::Image;
+use y86::memory::MEMORY_SIZE;
+use y86::minimize::{self, Minimizer};
+use y86::opcode::Isa;
+use y86::pipeline::Pipeline;
+use y86::profile::Profile;
+use y86::quota::Quotas;
+use y86::region::{Chunk, Region};
+use y86::replay::{Recording, Replay};
+use y86::runner::{BatchRunner, Outcome};
+use y86::session::Session;
+use y86::suite::Suite;
+use y86::symbol::Symbols;
+use y86::syscall::{Sandbox, Syscalls};
+use y86::trace::TraceFormat;
+use y86::vm::{self, State, Vm};
+
+const USAGE: &str = "usage: main [PROGRAM | test MANIFEST [--threads N] | compare PROGRAM OTHER]
+            [--max-steps N] [--entry ADDR]
+            [--max-instructions N] [--max-pages N] [--max-output BYTES] [--timeout MS]
+            [--dump-state PATH [--dump-memory]] [--report PATH]
+            [--watch [--delay MS]] [--display EXPR]... [--repl [--session PATH] [--write-history N]]
+            [--generate SEED] [--disassemble] [--memory-map] [--trace] [--color auto|always|never]
+            [--write-image PATH] [--symbols PATH] [--isa strict|extended] [--code-writes allow|warn|fault|self-modifying]
+            [--uninitialized allow|warn|fault]
+            [--check-targets] [--trap-overflow] [--costs PATH] [--energy PATH] [--pipeline]
+            [--analyze] [--heap-check] [--profile [--region NAME=START-END]...]
+            [--access-trace PATH [--trace-format lackey|dinero]]
+            [--framebuffer ADDR] [--clock ADDR [--virtual-time NS]] [--counters ADDR]
+            [--syscalls] [--allow PATH]... [--read-only]
+            [--record PATH | --replay PATH] [--minimize PATH]
+
+a PROGRAM that halts exits with the low 7 bits of %rax, 0 to 127, while a vm
+fault or any other error exits with 128 so it cannot pass for a halt. test,
+compare and --replay exit with 1 when something differs
+
+test runs every case of MANIFEST on --threads workers and prints which
+passed, with the differing registers, memory and output lines of the rest.
+Each case starts with `case NAME` followed by `program PATH`, `set %REG
+VALUE` or `set ADDR VALUE` lines for the initial state, `input TEXT` lines fed
+to stdin, `output TEXT` lines expected on stdout and `expect %REG VALUE` or
+`expect ADDR VALUE` lines for the final state, `input-file` and `output-file`
+taking whole files instead. Cases get --max-steps, by default 1000000
+
+--max-instructions, --max-pages, --max-output and --timeout sandbox an
+untrusted program, faulting with a quota error once it retires N instructions,
+loads from or stores to more than N distinct 4KB pages, sends more than BYTES
+to devices and syscalls or runs for longer than MS milliseconds
+
+--isa strict limits the assembler, disassembler and vm to the instructions of
+the textbook y86-64, so mulq, divq, modq and casq are rejected and fault as
+invalid opcodes, extended is the default and also accepts them
+
+--generate prints a random program that always halts, drawn from SEED with
+the default instruction mix, for benchmarking and stress testing
+
+--memory-map prints where the program, stack, guard band, rom and devices
+live instead of running the program
+
+--disassemble prints a listing of the program instead of running it and
+--trace prints every instruction as it executes, both use the `address name`
+pairs from --symbols in place of raw branch and call targets
+
+--report runs the program and writes a json summary of it to PATH: its final
+state, step and cycle counts, faults, coverage and wall-clock duration
+
+--watch prints the registers and memory changed by every step, pausing for
+--delay milliseconds between steps (default 250) or until enter is pressed
+when the delay is 0
+
+--display evaluates EXPR, such as `%rax + 8` or `mem[stack - 8]`, after every
+instruction. --trace prints the values after each instruction, --watch shows
+them with the other changes and a plain run prints every change with the step
+that caused it
+
+--repl assembles and executes one instruction per line typed at the prompt,
+printing what each changed. Instructions run one after the other whatever
+they do to the ip, a PROGRAM is loaded first so its data and labels can be
+used. `:regs` prints all registers, `:mem EXPR` four blocks from EXPR,
+`:bt` the call stack, `:frame N` the saved registers and `.local` slots of
+frame N, `:reset` starts over and `:quit` exits
+
+`:break ADDR [if EXPR]`, `:watch %REG [if EXPR]` and `:display EXPR` set
+breakpoints, watchpoints and expressions shown after every stop, `:continue`
+runs the PROGRAM from the ip until one triggers and `:history` lists what was
+typed. They are saved with the history to --session, by default PROGRAM with
+a .session extension, and restored the next time the REPL starts on it
+
+--write-history keeps the last N stores to every memory block while in the
+REPL, `:who EXPR` then lists the instructions that last wrote that address
+
+--pipeline runs the program and prints its pipe pipeline diagram, one row per
+instruction or bubble and one column per cycle, stalls in lower case
+
+--analyze runs the program on the seq, pipe and timing models and prints
+their instruction and cycle counts, cpi, ipc, stalls and mispredictions
+
+--profile prints the instructions, cycles, loads, stores and cache misses of
+every region once the program ends, the regions running from each label to
+the next unless --region names them, START and END being addresses
+
+--heap-check links the reference malloc and free into a .ys PROGRAM, which
+defines a `heap` label and calls heap_init first, and validates the heap after
+every allocator call, failing at the first call that corrupts it
+
+--access-trace writes every data load and store to PATH in valgrind lackey
+format, or dinero din format with --trace-format dinero
+
+--framebuffer attaches a 64x32 monochrome display at ADDR, one block per row
+with bit x lighting column x, and prints it once the program stops
+
+--clock attaches a clock at ADDR, reading nanoseconds since start at ADDR and
+since the unix epoch at ADDR + 8, --virtual-time advances it NS nanoseconds
+per instruction instead of following the host
+
+--counters attaches read only performance counters at ADDR, reading retired
+instructions at ADDR, cycles at ADDR + 8, cache misses at ADDR + 16 and
+mispredicted conditional jumps at ADDR + 24
+
+--syscalls enables the read, write, open and close syscalls on the standard
+streams, --allow additionally lets programs open PATH or files beneath it and
+--read-only refuses opening them for writing
+
+--record saves the effect of every step to PATH and --replay runs the program
+again against such a recording, stopping with a report at the first step
+whose register, flag or store effects differ
+
+compare steps OTHER alongside PROGRAM on a vm built with the same options,
+printing the first step whose effects differ and both final states
+
+--minimize shrinks a PROGRAM that faults to the fewest instructions that
+still fault the same way and writes it to PATH as a raw binary, deleting
+instructions or blanking them with nops. Runs get --max-steps, by default
+1000000, so shrinking cannot leave an endless loop running
+
+--costs reads `class cycles` lines overriding the timing model defaults, the
+cycle count is reported in the --dump-state output
+
+--energy reads `class energy [area]` lines, with `load`, `store` and `miss`
+for data accesses, on top of the default energy table and prints the energy
+and area the run needed, also reported by --dump-state and --report
+
+--uninitialized warns about or faults on reads of registers and memory that
+nothing wrote before, which otherwise read as zero
+
+--check-targets faults when a jump, call or return lands inside an instruction
+
+--trap-overflow faults on any arithmetic that would set the overflow flag
+
+a PROGRAM ending in .ys is assembled first, its labels standing in for
+--symbols, and a program image starts at its own entry point unless --entry
+is given
+
+--write-image writes the program to PATH as an image holding its entry point,
+--entry or 0, and the segments it occupies instead of running it";
+
+#[derive(Debug, Default)]
+struct Args {
+  program: Option<PathBuf>,
+  test: Option<PathBuf>,
+  write_image: Option<PathBuf>,
+  threads: Option<usize>,
+  max_steps: Option<usize>,
+  quotas: Quotas,
+  entry: Option<usize>,
+  dump_state: Option<PathBuf>,
+  dump_memory: bool,
+  report: Option<PathBuf>,
+  watch: bool,
+  displays: Vec<String>,
+  repl: bool,
+  session: Option<PathBuf>,
+  write_history: Option<usize>,
+  delay: Option<u64>,
+  disassemble: bool,
+  memory_map: bool,
+  generate: Option<u64>,
+  trace: bool,
+  color: ColorMode,
+  symbols: Option<PathBuf>,
+  isa: Isa,
+  code_writes: Option<CodeWrites>,
+  uninitialized_reads: Option<UninitializedReads>,
+  check_targets: bool,
+  trap_overflow: bool,
+  costs: Option<PathBuf>,
+  energy: Option<PathBuf>,
+  pipeline: bool,
+  analyze: bool,
+  heap_check: bool,
+  profile: bool,
+  regions: Vec<(String, Range<usize>)>,
+  access_trace: Option<PathBuf>,
+  trace_format: TraceFormat,
+  framebuffer: Option<usize>,
+  clock: Option<usize>,
+  virtual_time: Option<u64>,
+  counters: Option<usize>,
+  syscalls: bool,
+  allow: Vec<PathBuf>,
+  read_only: bool,
+  record: Option<PathBuf>,
+  compare: Option<PathBuf>,
+  minimize: Option<PathBuf>,
+  replay: Option<PathBuf>,
+}
+
+impl Args {
+  fn parse() -> anyhow::Result<Self> {
+    let mut args = Self::default();
+    let mut iter = env::args().skip(1);
+    while let Some(arg) = iter.next() {
+      match arg.as_str() {
+        "--max-steps" => {
+          let value = iter.next().context("--max-steps expects a value")?;
+          args.max_steps = Some(parse_number(&value)?);
+        }
+        "--max-instructions" => {
+          let value = iter.next().context("--max-instructions expects a value")?;
+          args.quotas = args.quotas.instructions(parse_number(&value)?);
+        }
+        "--max-pages" => {
+          let value = iter.next().context("--max-pages expects a value")?;
+          args.quotas = args.quotas.pages(parse_number(&value)?);
+        }
+        "--max-output" => {
+          let value = iter.next().context("--max-output expects a value")?;
+          args.quotas = args.quotas.output(parse_number(&value)?);
+        }
+        "--timeout" => {
+          let value = iter.next().context("--timeout expects a value")?;
+          args.quotas = args
+            .quotas
+            .timeout(Duration::from_millis(parse_number(&value)? as u64));
+        }
+        "--entry" => {
+          let value = iter.next().context("--entry expects a value")?;
+          args.entry = Some(parse_number(&value)?);
+        }
+        "--dump-state" => {
+          let value = iter.next().context("--dump-state expects a path")?;
+          args.dump_state = Some(PathBuf::from(value));
+        }
+        "--dump-memory" => args.dump_memory = true,
+        "--write-image" => {
+          let value = iter.next().context("--write-image expects a path")?;
+          args.write_image = Some(PathBuf::from(value));
+        }
+        "--report" => {
+          let value = iter.next().context("--report expects a path")?;
+          args.report = Some(PathBuf::from(value));
+        }
+        "--watch" => args.watch = true,
+        "--display" => {
+          let value = iter.next().context("--display expects an expression")?;
+          args.displays.push(value);
+        }
+        "--repl" => args.repl = true,
+        "--session" => {
+          let value = iter.next().context("--session expects a path")?;
+          args.session = Some(PathBuf::from(value));
+        }
+        "--write-history" => {
+          let value = iter.next().context("--write-history expects a value")?;
+          args.write_history = Some(parse_number(&value)?);
+        }
+        "--threads" => {
+          let value = iter.next().context("--threads expects a value")?;
+          args.threads = Some(parse_number(&value)?);
+        }
+        "--delay" => {
+          let value = iter.next().context("--delay expects a value")?;
+          args.delay = Some(parse_number(&value)? as u64);
+        }
+        "--check-targets" => args.check_targets = true,
+        "--trap-overflow" => args.trap_overflow = true,
+        "--pipeline" => args.pipeline = true,
+        "--analyze" => args.analyze = true,
+        "--profile" => args.profile = true,
+        "--region" => {
+          let value = iter.next().context("--region expects NAME=START-END")?;
+          let (name, range) = value
+            .split_once('=')
+            .context("--region expects NAME=START-END")?;
+          let (start, end) = range
+            .split_once('-')
+            .context("--region expects NAME=START-END")?;
+          let range = parse_number(start)?..parse_number(end)?;
+          args.regions.push((name.to_string(), range));
+        }
+        "--heap-check" => args.heap_check = true,
+        "--clock" => {
+          let value = iter.next().context("--clock expects an address")?;
+          args.clock = Some(parse_number(&value)?);
+        }
+        "--virtual-time" => {
+          let value = iter.next().context("--virtual-time expects a value")?;
+          args.virtual_time = Some(parse_number(&value)? as u64);
+        }
+        "--counters" => {
+          let value = iter.next().context("--counters expects an address")?;
+          args.counters = Some(parse_number(&value)?);
+        }
+        "--syscalls" => args.syscalls = true,
+        "--allow" => {
+          let value = iter.next().context("--allow expects a path")?;
+          args.allow.push(PathBuf::from(value));
+        }
+        "--read-only" => args.read_only = true,
+        "--record" => {
+          let value = iter.next().context("--record expects a path")?;
+          args.record = Some(PathBuf::from(value));
+        }
+        "--minimize" => {
+          let value = iter.next().context("--minimize expects a path")?;
+          args.minimize = Some(PathBuf::from(value));
+        }
+        "--replay" => {
+          let value = iter.next().context("--replay expects a path")?;
+          args.replay = Some(PathBuf::from(value));
+        }
+        "--framebuffer" => {
+          let value = iter.next().context("--framebuffer expects an address")?;
+          args.framebuffer = Some(parse_number(&value)?);
+        }
+        "--access-trace" => {
+          let value = iter.next().context("--access-trace expects a path")?;
+          args.access_trace = Some(PathBuf::from(value));
+        }
+        "--trace-format" => {
+          let value = iter.next().context("--trace-format expects a format")?;
+          args.trace_format = value.parse()?;
+        }
+        "--costs" => {
+          let value = iter.next().context("--costs expects a path")?;
+          args.costs = Some(PathBuf::from(value));
+        }
+        "--energy" => {
+          let value = iter.next().context("--energy expects a path")?;
+          args.energy = Some(PathBuf::from(value));
+        }
+        "--disassemble" => args.disassemble = true,
+        "--memory-map" => args.memory_map = true,
+        "--generate" => {
+          let value = iter.next().context("--generate expects a seed")?;
+          args.generate = Some(parse_number(&value)? as u64);
+        }
+        "--trace" => args.trace = true,
+        "--color" => {
+          let value = iter.next().context("--color expects a mode")?;
+          args.color = value.parse()?;
+        }
+        "--symbols" => {
+          let value = iter.next().context("--symbols expects a path")?;
+          args.symbols = Some(PathBuf::from(value));
+        }
+        "--isa" => {
+          let value = iter.next().context("--isa expects a mode")?;
+          args.isa = match value.as_str() {
+            "strict" => Isa::Strict,
+            "extended" => Isa::Extended,
+            _ => bail!("invalid instruction set {value}\n{USAGE}"),
+          };
+        }
+        "--code-writes" => {
+          let value = iter.next().context("--code-writes expects a mode")?;
+          args.code_writes = Some(match value.as_str() {
+            "allow" => CodeWrites::Allow,
+            "warn" => CodeWrites::Warn,
+            "fault" => CodeWrites::Fault,
+            "self-modifying" => CodeWrites::SelfModifying,
+            _ => bail!("invalid code write mode {value}\n{USAGE}"),
+          });
+        }
+        "--uninitialized" => {
+          let value = iter.next().context("--uninitialized expects a mode")?;
+          args.uninitialized_reads = Some(match value.as_str() {
+            "allow" => UninitializedReads::Allow,
+            "warn" => UninitializedReads::Warn,
+            "fault" => UninitializedReads::Fault,
+            _ => bail!("invalid uninitialized read mode {value}\n{USAGE}"),
+          });
+        }
+        "-h" | "--help" => {
+          println!("{USAGE}");
+          std::process::exit(0);
+        }
+        flag if flag.starts_with('-') => bail!("unknown option {flag}\n{USAGE}"),
+        "test" if args.program.is_none() && args.test.is_none() => {
+          let value = iter.next().context("test expects a manifest")?;
+          args.test = Some(PathBuf::from(value));
+        }
+        "compare" if args.program.is_none() && args.test.is_none() => {
+          let program = iter.next().context("compare expects two programs")?;
+          let other = iter.next().context("compare expects two programs")?;
+          args.program = Some(PathBuf::from(program));
+          args.compare = Some(PathBuf::from(other));
+        }
+        path => {
+          if args.program.is_some() {
+            bail!("unexpected argument {path}\n{USAGE}");
+          }
+          args.program = Some(PathBuf::from(path));
+        }
+      }
+    }
+    Ok(args)
+  }
+}
+
+/// Parses a decimal or `0x` prefixed hexadecimal number.
+fn parse_number(value: &str) -> anyhow::Result<usize> {
+  let parsed = match value.strip_prefix("0x") {
+    Some(hex) => usize::from_str_radix(hex, 16),
+    None => value.parse(),
+  };
+  parsed.with_context(|| format!("invalid number {value}"))
+}
+
+/// Prints the instruction at `ip`, or why it could not be decoded.
+fn current_instruction(region: &Chunk, ip: usize, style: &Style<'_>) -> String {
+  match disasm::disassemble_at(region.instructions(), ip) {
+    Ok(instruction) => instruction.line(style),
+    Err(e) => format!("{ip:#06x}: <{e}>"),
+  }
+}
+
+/// Formats the value of a watch expression, `None` when it failed.
+fn watched_value(value: Option<i64>) -> String {
+  value.map_or_else(|| "<error>".to_string(), |value| format!("{value:#x}"))
+}
+
+fn trace(vm: &mut Vm, region: &Chunk, style: &Style<'_>) -> Result<(), vm::Error> {
+  while vm.state() != State::Halted {
+    println!("{}", current_instruction(region, vm.ip(), style));
+    vm.step(region)?;
+    let values: Vec<_> = vm
+      .watched_exprs()
+      .map(|(_, expr, value)| format!("{expr} = {}", watched_value(value)))
+      .collect();
+    if !values.is_empty() {
+      println!("        {}", values.join(", "));
+    }
+  }
+  Ok(())
+}
+
+/// Single steps the vm, printing only the state each instruction changed.
+fn watch(
+  vm: &mut Vm,
+  region: &Chunk,
+  delay: Duration,
+  style: &Style<'_>,
+  color: bool,
+) -> Result<(), vm::Error> {
+  let highlight = |text: String| {
+    if color {
+      format!("\x1b[1;33m{text}\x1b[0m")
+    } else {
+      text
+    }
+  };
+
+  let nonzero_blocks = |vm: &Vm| -> HashMap<usize, i64> {
+    vm.memory_blocks()
+      .filter(|&(_, value)| value != 0)
+      .collect()
+  };
+  let watched =
+    |vm: &Vm| -> Vec<Option<i64>> { vm.watched_exprs().map(|(_, _, value)| value).collect() };
+  let mut registers: Vec<_> = vm.registers().collect();
+  let mut memory = nonzero_blocks(vm);
+
+  while vm.state() != State::Halted {
+    println!("{}", current_instruction(region, vm.ip(), style));
+    let before = watched(vm);
+    vm.step(region)?;
+
+    let mut changes = Vec::new();
+    let after: Vec<_> = vm.registers().collect();
+    for (&(reg, old), &(_, new)) in registers.iter().zip(&after) {
+      if old != new {
+        changes.push(format!(
+          "{reg} {old:#x} -> {}",
+          highlight(format!("{new:#x}"))
+        ));
+      }
+    }
+    let after_memory = nonzero_blocks(vm);
+    let mut addresses: Vec<_> = memory.keys().chain(after_memory.keys()).copied().collect();
+    addresses.sort_unstable();
+    addresses.dedup();
+    for addr in addresses {
+      let old = memory.get(&addr).copied().unwrap_or(0);
+      let new = after_memory.get(&addr).copied().unwrap_or(0);
+      if old != new {
+        changes.push(format!(
+          "mem[{addr:#x}] {old:#x} -> {}",
+          highlight(format!("{new:#x}"))
+        ));
+      }
+    }
+    for ((_, expr, new), old) in vm.watched_exprs().zip(before) {
+      if old != new {
+        changes.push(format!(
+          "{expr} {} -> {}",
+          watched_value(old),
+          highlight(watched_value(new))
+        ));
+      }
+    }
+    if !changes.is_empty() {
+      println!("        {}", changes.join(", "));
+    }
+    registers = after;
+    memory = after_memory;
+
+    if delay.is_zero() {
+      let mut line = String::new();
+      // a closed stdin just stops pausing
+      let _ = io::stdin().read_line(&mut line);
+    } else {
+      thread::sleep(delay);
+    }
+  }
+  Ok(())
+}
+
+/// Assembles a line holding exactly one instruction.
+fn assemble_instruction(line: &str) -> anyhow::Result<Vec<u8>> {
+  let bytes = asm::assemble(line)?.bytes().to_vec();
+  let instruction = disasm::disassemble_at(&bytes, 0)?;
+  if instruction.bytes().len() != bytes.len() {
+    bail!("expected a single instruction");
+  }
+  Ok(bytes)
+}
+
+/// Commands of earlier sessions kept in the session file.
+const MAX_HISTORY: usize = 1000;
+
+/// Reads instructions from stdin, assembling each into `region` after the
+/// previous one and executing it right away.
+fn repl(
+  vm: &mut Vm,
+  mut region: Chunk,
+  debugger: &mut Debugger,
+  style: &Style<'_>,
+  history: &mut Vec<String>,
+) -> anyhow::Result<()> {
+  let program = region.clone();
+  let mut next = region.instructions().len();
+  let mut line = String::new();
+  loop {
+    print!("y86> ");
+    io::stdout().flush()?;
+    line.clear();
+    if io::stdin().read_line(&mut line)? == 0 {
+      println!();
+      return Ok(());
+    }
+    let line = line.trim();
+    if !line.is_empty() {
+      history.push(line.to_string());
+    }
+    match line {
+      "" => continue,
+      ":history" => {
+        for (i, entry) in history.iter().enumerate() {
+          println!("{i:>4} {entry}");
+        }
+        continue;
+      }
+      ":continue" | ":c" => {
+        match debugger.run(vm, &region) {
+          Ok(stop) => {
+            let why = match stop {
+              Stop::Halted => "halted".to_string(),
+              Stop::Stepped => "stepped".to_string(),
+              Stop::Breakpoint(id) => format!("breakpoint {id}"),
+              Stop::Watchpoint { id, old, new, .. } => {
+                format!("watchpoint {id}, {old:#x} -> {new:#x}")
+              }
+            };
+            println!(
+              "{why}, next {}",
+              current_instruction(&region, vm.ip(), style)
+            );
+            show_displays(vm, debugger);
+          }
+          Err(e) => eprintln!("error: {e}"),
+        }
+        continue;
+      }
+      ":quit" | ":q" => return Ok(()),
+      ":regs" => {
+        for (reg, value) in vm.registers() {
+          println!("{:>5} {value:#x}", reg.to_string());
+        }
+        println!("{}", vm.flags());
+        continue;
+      }
+      ":reset" => {
+        vm.reset();
+        vm.load(&program)?;
+        region = program.clone();
+        next = region.instructions().len();
+        continue;
+      }
+      _ => {}
+    }
+    if line == ":bt" {
+      for frame in debugger.backtrace(vm) {
+        println!("{frame}");
+      }
+      continue;
+    }
+    if let Some(spec) = line.strip_prefix(":break ") {
+      match debugger.parse_breakpoint(spec) {
+        Ok(bp) => {
+          let address = bp.address();
+          println!("breakpoint {} at {address:#x}", debugger.add_breakpoint(bp));
+        }
+        Err(e) => eprintln!("error: {e}"),
+      }
+      continue;
+    }
+    if let Some(spec) = line.strip_prefix(":watch ") {
+      match debugger.parse_watchpoint(spec) {
+        Ok(wp) => println!("watchpoint {}", debugger.add_watchpoint(wp)),
+        Err(e) => eprintln!("error: {e}"),
+      }
+      continue;
+    }
+    if let Some(expr) = line.strip_prefix(":display ") {
+      match debugger.add_display(expr) {
+        Ok(_) => show_displays(vm, debugger),
+        Err(e) => eprintln!("error: {e}"),
+      }
+      continue;
+    }
+    if let Some(expr) = line.strip_prefix(":who ") {
+      match debugger.writers(vm, expr) {
+        Ok((address, writers)) if writers.is_empty() => {
+          println!("no recorded writes to {address:#x}");
+        }
+        Ok((_, writers)) => {
+          for writer in writers {
+            println!(
+              "step {:>6} wrote {:#x} to {:#x}: {}",
+              writer.step,
+              writer.value,
+              writer.address,
+              current_instruction(&region, writer.ip, style)
+            );
+          }
+        }
+        Err(e) => eprintln!("error: {e}"),
+      }
+      continue;
+    }
+    if let Some(index) = line.strip_prefix(":frame") {
+      match index.trim().parse::<usize>() {
+        Ok(index) => match debugger.frame(vm, index) {
+          Some(frame) => println!("{frame}"),
+          None => eprintln!("error: no frame {index}"),
+        },
+        Err(_) => eprintln!("error: expected a frame number"),
+      }
+      continue;
+    }
+    if let Some(expr) = line.strip_prefix(":mem") {
+      // up to four blocks, fewer near the end of memory
+      let blocks = (1..=4)
+        .rev()
+        .map(|count| debugger.examine(vm, expr, count))
+        .find(Result::is_ok)
+        .unwrap_or_else(|| debugger.examine(vm, expr, 1));
+      match blocks {
+        Ok(blocks) => {
+          for (address, value) in blocks {
+            println!("{address:#06x}: {value:#x}");
+          }
+        }
+        Err(e) => eprintln!("error: {e}"),
+      }
+      continue;
+    }
+    let bytes = match assemble_instruction(line) {
+      Ok(bytes) => bytes,
+      Err(e) => {
+        eprintln!("error: {e}");
+        continue;
+      }
+    };
+    region.write(next, &bytes);
+    vm.set_ip(next);
+    let (registers, flags) = (vm.registers().collect::<Vec<_>>(), vm.flags());
+    vm.mark_clean();
+    if let Err(e) = vm.step(&region) {
+      eprintln!("error: {e}");
+      continue;
+    }
+    println!("{}", current_instruction(&region, next, style));
+    next += bytes.len();
+
+    let mut changes = Vec::new();
+    for ((reg, old), (_, new)) in registers.into_iter().zip(vm.registers()) {
+      if old != new {
+        changes.push(format!("{reg} {old:#x} -> {new:#x}"));
+      }
+    }
+    if vm.flags() != flags {
+      changes.push(vm.flags().to_string());
+    }
+    for (address, value) in vm.dirty_blocks() {
+      changes.push(format!("mem[{address:#x}] = {value:#x}"));
+    }
+    if vm.state() == State::Halted {
+      changes.push("halted, :reset to continue".to_string());
+    }
+    if !changes.is_empty() {
+      println!("        {}", changes.join(", "));
+    }
+    show_displays(vm, debugger);
+  }
+}
+
+fn show_displays(vm: &Vm, debugger: &Debugger) {
+  for (id, expr) in debugger.displays() {
+    match expr.eval(vm) {
+      Ok(value) => println!("  {id}: {expr} = {value:#x}"),
+      Err(e) => println!("  {id}: {expr} = <{e}>"),
+    }
+  }
+}
+
+/// Reads a binary program or image, or assembles it when the name ends in
+/// `.ys`, linking the reference allocator into it when `heap` is set. Images
+/// also give their entry point.
+fn read_program(
+  path: &Path,
+  isa: Isa,
+  heap: bool,
+) -> anyhow::Result<(Vec<u8>, Option<Assembled>, Option<usize>)> {
+  if path.extension().is_some_and(|ext| ext == "ys") {
+    let mut source =
+      fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    if heap {
+      source = heap::link(&source);
+    }
+    let program = asm::assemble_isa(&source, isa)?;
+    return Ok((program.bytes().to_vec(), Some(program), None));
+  }
+  if heap {
+    bail!("--heap-check needs a .ys program to link the allocator into");
+  }
+  let bytes = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+  if Image::is_image(&bytes) {
+    let image = Image::from_bytes(&bytes)?;
+    let chunk = Chunk::from(&image);
+    return Ok((chunk.instructions().to_vec(), None, Some(image.entry())));
+  }
+  Ok((bytes, None, None))
+}
+
+/// Steps allowed to each test case or minimizer run without --max-steps.
+const DEFAULT_STEPS: usize = 1_000_000;
+
+/// Runs the cases of the manifest at `path`, failing unless all pass.
+fn run_suite(
+  path: &Path,
+  builder: VmBuilder,
+  isa: Isa,
+  threads: Option<usize>,
+) -> anyhow::Result<ExitCode> {
+  let jobs = Suite::load(path)?.jobs(isa)?;
+  let mut runner = BatchRunner::new(builder);
+  if let Some(threads) = threads {
+    runner = runner.threads(threads);
+  }
+  let report = runner.run(&jobs);
+  for job in &report.outcomes {
+    match &job.outcome {
+      Outcome::Passed => println!("pass  {} ({} steps)", job.name, job.steps),
+      Outcome::Failed(mismatches) => {
+        println!("FAIL  {}", job.name);
+        for mismatch in mismatches {
+          println!("  {mismatch}");
+        }
+      }
+      Outcome::Faulted(message) => println!("FAULT {} - {message}", job.name),
+    }
+  }
+  println!(
+    "{} passed, {} failed, {} faulted in {:.2?}",
+    report.passed(),
+    report.failed(),
+    report.faulted(),
+    report.elapsed
+  );
+  Ok(if report.passed() == report.outcomes.len() {
+    ExitCode::SUCCESS
+  } else {
+    ExitCode::FAILURE
+  })
+}
 
-#[allow(dead_code)]
 fn simple_add_program() -> Vec<u8> {
   #[rustfmt::skip]
   let program = vec![
@@ -9,12 +839,12 @@ fn simple_add_program() -> Vec<u8> {
     0x30, 0xF7, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
     // irmovq $5, %rsi (second argument)
     0x30, 0xF6, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    // call add_two (at address 0x20)
-    0x80, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // call add_two (at address 0x1e)
+    0x80, 0x1E, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
     // halt
     0x00,
     
-    // binary_add function (starts at address 0x20):
+    // binary_add function (starts at address 0x1e):
     // pushq %rbp
     0xA0, 0x5F,
     // rrmovq %rsp, %rbp
@@ -31,10 +861,350 @@ fn simple_add_program() -> Vec<u8> {
   program
 }
 
-fn main() {
-  let mut vm = Vm::new();
-  let region = Chunk::from(simple_add_program());
+/// Exit status of a vm fault or any other error, above every status a halt
+/// can produce.
+const FAULT_EXIT: u8 = 128;
+
+fn halt_exit(vm: &Vm) -> ExitCode {
+  ExitCode::from((vm.return_value() & 0x7f) as u8)
+}
+
+fn main() -> ExitCode {
+  match run() {
+    Ok(code) => code,
+    Err(e) => {
+      eprintln!("Error: {e:?}");
+      ExitCode::from(FAULT_EXIT)
+    }
+  }
+}
+
+fn run() -> anyhow::Result<ExitCode> {
+  let args = Args::parse()?;
+  if let Some(seed) = args.generate {
+    print!("{}", Generator::new(seed).generate());
+    return Ok(ExitCode::SUCCESS);
+  }
+  let (program, assembled, image_entry) = match &args.program {
+    Some(path) => read_program(path, args.isa, args.heap_check)?,
+    None => (simple_add_program(), None, None),
+  };
+  let entry = args.entry.or(image_entry);
+
+  if let Some(path) = &args.write_image {
+    let entry = entry.unwrap_or(0);
+    let image = match &assembled {
+      Some(assembled) => Image::from_assembled(assembled, entry),
+      None => Image::from_chunk(&Chunk::from(program), entry),
+    };
+    fs::write(path, image.to_bytes())
+      .with_context(|| format!("failed to write {}", path.display()))?;
+    return Ok(ExitCode::SUCCESS);
+  }
+
+  let mut builder = Vm::builder();
+  if let Some(entry) = entry {
+    builder = builder.entry(entry);
+  }
+  if let Some(max_steps) = args.max_steps {
+    builder = builder.max_steps(max_steps);
+  }
+  builder = builder.quotas(args.quotas).isa(args.isa);
+  if let Some(code_writes) = args.code_writes {
+    builder = builder.code_writes(code_writes);
+  }
+  if let Some(mode) = args.uninitialized_reads {
+    builder = builder.uninitialized_reads(mode);
+  }
+  if let Some(path) = &args.costs {
+    let costs = fs::read_to_string(path)
+      .with_context(|| format!("failed to read {}", path.display()))?
+      .parse()?;
+    builder = builder.costs(costs);
+  }
+  if let Some(path) = &args.energy {
+    let energy = fs::read_to_string(path)
+      .with_context(|| format!("failed to read {}", path.display()))?
+      .parse()?;
+    builder = builder.energy(energy);
+  }
+  let builder = builder
+    .check_targets(args.check_targets)
+    .trap_overflow(args.trap_overflow);
+  if let Some(path) = &args.test {
+    let builder = match args.max_steps {
+      Some(_) => builder,
+      None => builder.max_steps(DEFAULT_STEPS),
+    };
+    return run_suite(path, builder, args.isa, args.threads);
+  }
+  if let Some(path) = &args.minimize {
+    let builder = match args.max_steps {
+      Some(_) => builder,
+      None => builder.max_steps(DEFAULT_STEPS),
+    };
+    let fault = minimize::fault(&builder, &program).context("the program does not fault")?;
+    let fails = minimize::faults_like(builder, &program)?;
+    let minimized = Minimizer::new().minimize(&program, fails)?;
+    fs::write(path, &minimized.program)
+      .with_context(|| format!("failed to write {}", path.display()))?;
+    println!(
+      "shrank {} bytes to {} in {} runs, {} instructions removed and {} blanked, still failing with {fault}",
+      program.len(),
+      minimized.program.len(),
+      minimized.tests,
+      minimized.removed,
+      minimized.blanked
+    );
+    return Ok(ExitCode::SUCCESS);
+  }
+  let mut vm = builder.clone().build();
+  let region = Chunk::from(program);
+  vm.load(&region)?;
+  let sandbox = (args.syscalls || !args.allow.is_empty()).then(|| {
+    args
+      .allow
+      .iter()
+      .fold(Sandbox::new(), |sandbox, path| sandbox.allow(path))
+      .read_only(args.read_only)
+  });
+  if let Some(sandbox) = &sandbox {
+    vm.set_trap_handler(Syscalls::new(sandbox.clone()));
+  }
+  if let Some(base) = args.clock {
+    let clock = match args.virtual_time {
+      Some(ns) => Clock::virtual_time(ns, 0),
+      None => Clock::host(),
+    };
+    vm.attach_device(base..base + Clock::SIZE, clock)?;
+  }
+  if let Some(base) = args.counters {
+    vm.attach_device(base..base + PerfCounters::SIZE, PerfCounters::new())?;
+  }
+  let framebuffer = match args.framebuffer {
+    Some(base) => Some(vm.attach_device(base..base + Framebuffer::SIZE, Framebuffer::new())?),
+    None => None,
+  };
+  if let Some(path) = &args.access_trace {
+    let file =
+      fs::File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    vm.trace_accesses(BufWriter::new(file), args.trace_format);
+  }
+  vm.subscribe(
+    EventFilter::only(&[EventKind::CodeOverwritten, EventKind::UninitializedRead]),
+    |event: &Event| match event {
+      Event::CodeOverwritten { ip, address } => {
+        eprintln!("warning: instruction at {ip:#x} overwrote code at {address:#x}");
+      }
+      Event::UninitializedRead { ip, location } => {
+        eprintln!("warning: instruction at {ip:#x} read uninitialized {location}");
+      }
+      _ => {}
+    },
+  );
+
+  let symbols = match &args.symbols {
+    Some(path) => fs::read_to_string(path)
+      .with_context(|| format!("failed to read {}", path.display()))?
+      .parse()?,
+    None => assembled
+      .as_ref()
+      .map(|program| program.symbols().clone())
+      .unwrap_or_else(Symbols::new),
+  };
+  let style = Style::new(args.color).with_symbols(&symbols);
+  for source in &args.displays {
+    vm.watch_expr(Expr::parse(source, &symbols)?);
+  }
+  if !args.displays.is_empty() && !args.trace && !args.watch {
+    let names: Vec<String> = vm
+      .watched_exprs()
+      .map(|(_, expr, _)| expr.to_string())
+      .collect();
+    vm.subscribe(
+      EventFilter::only(&[EventKind::ExprChanged]),
+      move |event: &Event| {
+        if let Event::ExprChanged { id, step, old, new } = event {
+          println!(
+            "step {step}: {} {} -> {}",
+            names[*id],
+            watched_value(*old),
+            watched_value(*new)
+          );
+        }
+      },
+    );
+  }
+  if args.profile {
+    let profile = if args.regions.is_empty() {
+      Profile::from_symbols(&symbols, region.instructions().len())
+    } else {
+      args
+        .regions
+        .iter()
+        .fold(Profile::new(), |profile, (name, range)| {
+          profile.region(name, range.clone())
+        })
+    };
+    vm.set_profile(profile);
+  }
+
+  if args.memory_map {
+    print!("{}", vm.memory_map());
+    return Ok(ExitCode::SUCCESS);
+  }
+
+  if args.disassemble {
+    let start = entry.unwrap_or(0);
+    for instruction in disasm::disassemble_isa(region.instructions(), start, args.isa) {
+      println!("{}", instruction?.line(&style));
+    }
+    return Ok(ExitCode::SUCCESS);
+  }
+
+  if args.repl {
+    let region = match &args.program {
+      Some(_) => region,
+      None => {
+        vm.reset();
+        Chunk::from(Vec::new())
+      }
+    };
+    if let Some(depth) = args.write_history {
+      vm.set_write_history(WriteHistory::new(depth).watch(0..MEMORY_SIZE));
+    }
+    let mut debugger = Debugger::new();
+    debugger.set_symbols(symbols.clone());
+    if let Some(program) = &assembled {
+      debugger.set_locals(program.locals().clone());
+    }
+    let path = args.session.clone().or_else(|| {
+      args
+        .program
+        .as_ref()
+        .map(|path| path.with_extension("session"))
+    });
+    let mut history = Vec::new();
+    if let Some(path) = path.as_ref().filter(|path| path.exists()) {
+      let session: Session = fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?
+        .parse()?;
+      for (entry, e) in debugger.restore(&session) {
+        eprintln!("warning: dropped {entry:?} from the session - {e}");
+      }
+      history = session.history;
+    }
+    let result = repl(&mut vm, region, &mut debugger, &style, &mut history);
+    if let Some(path) = &path {
+      let mut session = debugger.session();
+      let kept = history.len().saturating_sub(MAX_HISTORY);
+      session.history = history.split_off(kept);
+      if !session.is_empty() {
+        fs::write(path, session.to_string())
+          .with_context(|| format!("failed to write {}", path.display()))?;
+      }
+    }
+    return result.map(|()| ExitCode::SUCCESS);
+  }
+
+  if args.analyze {
+    let comparison = analyze_with(&builder, &region, |vm| {
+      if let Some(sandbox) = &sandbox {
+        vm.set_trap_handler(Syscalls::new(sandbox.clone()));
+      }
+    })?;
+    print!("{comparison}");
+    return Ok(ExitCode::SUCCESS);
+  }
+
+  if args.heap_check {
+    let mut debugger = Debugger::new();
+    debugger.set_symbols(symbols.clone());
+    let checker = heap::Checker::attach(&mut debugger)?;
+    debugger.run(&mut vm, &region)?;
+    println!(
+      "heap intact after {} allocator calls, {} blocks left allocated",
+      checker.calls(),
+      checker.live().len()
+    );
+    return Ok(halt_exit(&vm));
+  }
+
+  if let Some(path) = &args.compare {
+    let other = Chunk::from(read_program(path, args.isa, args.heap_check)?.0);
+    let mut other_vm = builder.build();
+    other_vm.load(&other)?;
+    let comparison = compare(&mut vm, &region, &mut other_vm, &other);
+    print!("{comparison}");
+    return Ok(match comparison.divergence {
+      Some(_) => ExitCode::FAILURE,
+      None => ExitCode::SUCCESS,
+    });
+  }
+
+  if let Some(path) = &args.replay {
+    let bytes = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    return match Recording::from_bytes(&bytes)?.replay(&mut vm, &region)? {
+      Replay::Matched { steps } => {
+        println!("replay matched all {steps} recorded steps");
+        Ok(ExitCode::SUCCESS)
+      }
+      Replay::Diverged(divergence) => {
+        print!("{divergence}");
+        Ok(ExitCode::FAILURE)
+      }
+    };
+  }
 
-  while let Ok(()) = vm.step(&region) {}
-  dbg!(vm);
+  let result = if let Some(path) = &args.record {
+    let (recording, result) = Recording::record(&mut vm, &region);
+    fs::write(path, recording.to_bytes())
+      .with_context(|| format!("failed to write {}", path.display()))?;
+    result
+  } else if let Some(path) = &args.report {
+    let report = vm.run_report(&region);
+    fs::write(path, report.to_json())
+      .with_context(|| format!("failed to write {}", path.display()))?;
+    if let Some(fault) = report.faults.last().filter(|_| !report.halted()) {
+      bail!(
+        "vm faulted at ip {:#x} after {} steps - {}",
+        report.ip,
+        report.steps,
+        fault.message
+      );
+    }
+    Ok(())
+  } else if args.watch {
+    let delay = Duration::from_millis(args.delay.unwrap_or(250));
+    watch(&mut vm, &region, delay, &style, args.color.enabled())
+  } else if args.pipeline {
+    Pipeline::run(&mut vm, &region).map(|pipeline| print!("{}", pipeline.render(&style)))
+  } else if args.trace {
+    trace(&mut vm, &region, &style)
+  } else {
+    vm.run(&region)
+  };
+  vm.stop_access_trace()?;
+  if let Some(display) = framebuffer.and_then(|id| vm.bus().device::<Framebuffer>(id)) {
+    print!("{display}");
+  }
+  if let Some(profile) = vm.profile() {
+    print!("{profile}");
+  }
+  if let Some(energy) = vm.timing().energy() {
+    println!(
+      "energy {} ({} on memory) over {} cycles, area {}",
+      energy.total(),
+      energy.memory(),
+      vm.timing().cycles(),
+      energy.area()
+    );
+  }
+  if let Some(path) = &args.dump_state {
+    fs::write(path, vm.state_json(args.dump_memory))
+      .with_context(|| format!("failed to write {}", path.display()))?;
+  }
+  result.with_context(|| format!("vm faulted at ip {:#x} after {} steps", vm.ip(), vm.steps()))?;
+  debug_assert_eq!(vm.state(), State::Halted);
+  Ok(halt_exit(&vm))
 }
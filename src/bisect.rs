@@ -0,0 +1,383 @@
+//! Locating the first instruction two engines disagree on, such as two
+//! builds of the interpreter or a vm against a model of it.
+
+use std::fmt;
+
+use crate::Block;
+use crate::region::Region;
+use crate::register::{Flags, Register};
+use crate::replay::{self, Divergence, Effect, Mismatch, Observer};
+use crate::snapshot::Snapshot;
+use crate::vm::{State, Vm};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("the engines start from different states")]
+  DifferentStart,
+
+  #[error("{0} engine failed to rewind - {1}")]
+  RestoreFailed(&'static str, String),
+
+  #[error("no divergence within {0} steps")]
+  StepLimitExceeded(usize),
+}
+
+/// What a program can observe of a machine, registers, flags, ip, whether
+/// it halted and memory, leaving out timing and devices.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchState {
+  pub ip: usize,
+  /// In encoding order.
+  pub registers: Vec<Block>,
+  pub flags: Flags,
+  pub halted: bool,
+  pub memory: Vec<u8>,
+}
+
+/// An executable machine `Bisector` can drive, object safe so engines from
+/// other crates or crate versions can be compared through `dyn Engine`.
+/// Faults are reported as their messages for the same reason.
+pub trait Engine {
+  /// Runs one instruction, reporting what it changed.
+  fn step(&mut self) -> Result<Effect, String>;
+
+  /// Runs up to `steps` instructions, returning how many retired, fewer
+  /// once the machine halts.
+  fn advance(&mut self, steps: usize) -> Result<usize, String>;
+
+  fn arch_state(&self) -> ArchState;
+
+  /// Captures the whole machine for `restore`, in whatever form the engine
+  /// likes.
+  fn save(&self) -> Vec<u8>;
+
+  fn restore(&mut self, checkpoint: &[u8]) -> Result<(), String>;
+}
+
+/// The interpreter as an `Engine`, running `region` on `vm`.
+#[derive(Debug)]
+pub struct Interpreter<R> {
+  vm: Vm,
+  region: R,
+  observer: Observer,
+}
+
+impl<R: Region> Interpreter<R> {
+  /// Takes a vm with `region` already loaded.
+  pub fn new(mut vm: Vm, region: R) -> Self {
+    let observer = Observer::attach(&mut vm);
+    Self {
+      vm,
+      region,
+      observer,
+    }
+  }
+
+  pub fn vm(&self) -> &Vm {
+    &self.vm
+  }
+
+  pub fn into_vm(mut self) -> Vm {
+    self.observer.detach(&mut self.vm);
+    self.vm
+  }
+}
+
+impl<R: Region> Engine for Interpreter<R> {
+  fn step(&mut self) -> Result<Effect, String> {
+    self
+      .observer
+      .step(&mut self.vm, &self.region)
+      .map_err(|e| e.to_string())
+  }
+
+  fn advance(&mut self, steps: usize) -> Result<usize, String> {
+    for retired in 0..steps {
+      if self.vm.state() == State::Halted {
+        return Ok(retired);
+      }
+      self.step()?;
+    }
+    Ok(steps)
+  }
+
+  fn arch_state(&self) -> ArchState {
+    self.vm.arch_state()
+  }
+
+  fn save(&self) -> Vec<u8> {
+    self.vm.snapshot().to_bytes()
+  }
+
+  fn restore(&mut self, checkpoint: &[u8]) -> Result<(), String> {
+    let snapshot = Snapshot::from_bytes(checkpoint).map_err(|e| e.to_string())?;
+    self.vm.restore(&snapshot).map_err(|e| e.to_string())
+  }
+}
+
+/// Outcome of `Bisector::bisect`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bisection {
+  /// Steps both engines ran in agreement.
+  pub agreed: usize,
+  /// The diverging step, with the left engine as expected. `None` when both
+  /// halted, or faulted alike, in the same state.
+  pub divergence: Option<Divergence>,
+  /// The fault both engines stopped on, if they did.
+  pub fault: Option<String>,
+  pub left: ArchState,
+  pub right: ArchState,
+}
+
+impl fmt::Display for Bisection {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.divergence {
+      Some(divergence) => write!(f, "{divergence}")?,
+      None => writeln!(f, "no divergence in {} steps", self.agreed)?,
+    }
+    writeln!(f, "{:<8} {:>18} {:>18}", "", "left", "right")?;
+    let (left, right) = (&self.left, &self.right);
+    let ip = |state: &ArchState| format!("{:#x}", state.ip);
+    writeln!(f, "{:<8} {:>18} {:>18}", "ip", ip(left), ip(right))?;
+    for ((reg, l), r) in Register::iter().zip(&left.registers).zip(&right.registers) {
+      let (l, r) = (format!("{l:#x}"), format!("{r:#x}"));
+      writeln!(f, "{:<8} {:>18} {:>18}", reg.to_string(), l, r)?;
+    }
+    let (l, r) = (left.flags.to_string(), right.flags.to_string());
+    writeln!(f, "{:<8} {:>18} {:>18}", "flags", l, r)?;
+    let state = |state: &ArchState| match (&self.fault, state.halted) {
+      (Some(_), _) => "faulted",
+      (None, true) => "halted",
+      (None, false) => "running",
+    };
+    writeln!(f, "{:<8} {:>18} {:>18}", "state", state(left), state(right))
+  }
+}
+
+/// Runs two engines in lockstep, comparing their architectural state every
+/// `stride` steps, and once a comparison fails rewinds both to the last
+/// agreeing checkpoint and bisects the stride down to the single step that
+/// set them apart.
+///
+/// Differences that appear and vanish again within a stride go unnoticed,
+/// a stride of 1 compares after every step.
+#[derive(Debug, Clone)]
+pub struct Bisector {
+  stride: usize,
+  max_steps: Option<usize>,
+}
+
+impl Bisector {
+  pub fn new() -> Self {
+    Self {
+      stride: 1024,
+      max_steps: None,
+    }
+  }
+
+  pub fn stride(mut self, stride: usize) -> Self {
+    self.stride = stride.max(1);
+    self
+  }
+
+  /// Gives up with `Error::StepLimitExceeded` once both engines agreed for
+  /// `max_steps`, for programs that may never halt.
+  pub fn max_steps(mut self, max_steps: usize) -> Self {
+    self.max_steps = Some(max_steps);
+    self
+  }
+
+  /// Starts both engines from where they stand, which must already agree.
+  pub fn bisect(&self, left: &mut dyn Engine, right: &mut dyn Engine) -> Result<Bisection, Error> {
+    if left.arch_state() != right.arch_state() {
+      return Err(Error::DifferentStart);
+    }
+    let mut agreed = 0;
+    loop {
+      let mut steps = self.stride;
+      if let Some(max_steps) = self.max_steps {
+        if agreed >= max_steps {
+          return Err(Error::StepLimitExceeded(max_steps));
+        }
+        steps = steps.min(max_steps - agreed);
+      }
+      let checkpoint = (left.save(), right.save());
+      let outcome = (left.advance(steps), right.advance(steps));
+      if outcome.0 == outcome.1 && left.arch_state() == right.arch_state() {
+        let (agreed, fault) = match outcome.0 {
+          Ok(retired) if retired == steps => {
+            agreed += retired;
+            continue;
+          }
+          Ok(retired) => (agreed + retired, None),
+          // the steps before the fault are not known, `agreed` stays a lower bound
+          Err(fault) => (agreed, Some(fault)),
+        };
+        return Ok(Bisection {
+          agreed,
+          divergence: None,
+          fault,
+          left: left.arch_state(),
+          right: right.arch_state(),
+        });
+      }
+      let mut divergence = narrow(left, right, checkpoint, steps)?;
+      divergence.step += agreed;
+      return Ok(Bisection {
+        agreed: divergence.step,
+        divergence: Some(divergence),
+        fault: None,
+        left: left.arch_state(),
+        right: right.arch_state(),
+      });
+    }
+  }
+}
+
+impl Default for Bisector {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Binary searches the `steps` after `checkpoint`, which agreed, down to the
+/// first step after which the engines differ, counting steps from the
+/// checkpoint, and compares the effects of that step.
+fn narrow(
+  left: &mut dyn Engine,
+  right: &mut dyn Engine,
+  mut checkpoint: (Vec<u8>, Vec<u8>),
+  steps: usize,
+) -> Result<Divergence, Error> {
+  // the engines agree after `good` steps and differ after `bad`
+  let (mut good, mut bad) = (0, steps);
+  while bad - good > 1 {
+    rewind(left, right, &checkpoint)?;
+    let mid = good + (bad - good) / 2;
+    let outcome = (left.advance(mid - good), right.advance(mid - good));
+    if outcome.0 == outcome.1 && left.arch_state() == right.arch_state() {
+      good = mid;
+      checkpoint = (left.save(), right.save());
+    } else {
+      bad = mid;
+    }
+  }
+  rewind(left, right, &checkpoint)?;
+  let address = left.arch_state().ip;
+  let effects = (left.step(), right.step());
+  let mismatches = match &effects {
+    (Ok(expected), Ok(actual)) => replay::mismatches(expected, actual),
+    (Err(e), Ok(_)) | (Ok(_), Err(e)) => vec![Mismatch::Fault(e.clone())],
+    (Err(l), Err(r)) if l != r => vec![Mismatch::Fault(format!("{l} vs {r}"))],
+    (Err(_), Err(_)) => Vec::new(),
+  };
+  Ok(Divergence {
+    step: good,
+    address,
+    expected: effects.0.ok(),
+    actual: effects.1.ok(),
+    mismatches,
+  })
+}
+
+fn rewind(
+  left: &mut dyn Engine,
+  right: &mut dyn Engine,
+  checkpoint: &(Vec<u8>, Vec<u8>),
+) -> Result<(), Error> {
+  left
+    .restore(&checkpoint.0)
+    .map_err(|e| Error::RestoreFailed("left", e))?;
+  right
+    .restore(&checkpoint.1)
+    .map_err(|e| Error::RestoreFailed("right", e))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm;
+  use crate::builder::VmBuilder;
+  use crate::region::Chunk;
+
+  // adds 1 to a %rax 100 below the maximum 200 times, overflowing on the
+  // 101st add at step 3 + 3 * 100
+  const OVERFLOW: &str = "
+    irmovq $0x7fffffffffffff9b, %rax
+    irmovq $1, %rcx
+    irmovq $200, %rdx
+loop:
+    addq %rcx, %rax
+    subq %rcx, %rdx
+    jne loop
+    halt
+";
+
+  fn engine(builder: VmBuilder, source: &str) -> Interpreter<Chunk> {
+    let region = Chunk::from(asm::assemble(source).unwrap().bytes().to_vec());
+    let mut vm = builder.build();
+    vm.load(&region).unwrap();
+    Interpreter::new(vm, region)
+  }
+
+  #[test]
+  fn finds_the_first_diverging_step() {
+    for stride in [1, 16, 1024] {
+      let mut left = engine(VmBuilder::new(), OVERFLOW);
+      let mut right = engine(VmBuilder::new().trap_overflow(true), OVERFLOW);
+      let bisection = Bisector::new()
+        .stride(stride)
+        .bisect(&mut left, &mut right)
+        .unwrap();
+      let divergence = bisection.divergence.unwrap();
+      assert_eq!((divergence.step, divergence.address), (303, 0x1e));
+      assert!(matches!(divergence.mismatches[..], [Mismatch::Fault(_)]));
+      assert!(divergence.expected.is_some() && divergence.actual.is_none());
+      assert_eq!(bisection.agreed, 303);
+      // the engines are left after the diverging step, the right one on the
+      // add it faulted on
+      assert_eq!((bisection.left.ip, bisection.right.ip), (0x20, 0x1e));
+      assert_eq!(left.vm().steps(), 304);
+    }
+  }
+
+  #[test]
+  fn agreeing_engines_run_to_the_end() {
+    let mut left = engine(VmBuilder::new(), OVERFLOW);
+    let mut right = engine(VmBuilder::new(), OVERFLOW);
+    let bisection = Bisector::new()
+      .stride(64)
+      .bisect(&mut left, &mut right)
+      .unwrap();
+    assert_eq!(bisection.divergence, None);
+    assert_eq!(bisection.fault, None);
+    assert_eq!(bisection.agreed, 3 + 3 * 200 + 1);
+    assert!(bisection.left.halted);
+    assert!(
+      bisection
+        .to_string()
+        .starts_with("no divergence in 604 steps")
+    );
+  }
+
+  #[test]
+  fn rejects_different_starts_and_endless_agreement() {
+    let mut left = engine(VmBuilder::new(), OVERFLOW);
+    let mut right = engine(VmBuilder::new(), "nop\nhalt");
+    assert!(matches!(
+      Bisector::new().bisect(&mut left, &mut right),
+      Err(Error::DifferentStart)
+    ));
+
+    let mut left = engine(VmBuilder::new(), "loop: jmp loop");
+    let mut right = engine(VmBuilder::new(), "loop: jmp loop");
+    assert!(matches!(
+      Bisector::new()
+        .stride(7)
+        .max_steps(100)
+        .bisect(&mut left, &mut right),
+      Err(Error::StepLimitExceeded(100))
+    ));
+    assert_eq!(left.vm().steps(), 100);
+  }
+}
@@ -0,0 +1,358 @@
+use std::ops::Range;
+
+use crate::Block;
+use crate::memory::MainMemory;
+use crate::opcode::Isa;
+use crate::quota::Quotas;
+use crate::timing::{Cache, CostTable, EnergyTable, Fetch};
+use crate::vm::Vm;
+
+/// What happens when the program stores over the code placed by `Vm::load`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CodeWrites {
+  /// Let the store through silently.
+  Allow,
+  /// Let the store through and emit `Event::CodeOverwritten`.
+  #[default]
+  Warn,
+  /// Reject the store with `Error::CodeOverwrite`.
+  Fault,
+  /// Let the store through and fetch instructions from memory, so modified
+  /// code is what executes.
+  SelfModifying,
+}
+
+/// What `divq` and `modq` do when dividing the most negative value by -1,
+/// the one quotient that does not fit in a register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DivisionOverflow {
+  /// Wrap like two's complement hardware, leaving the dividend in place for
+  /// `divq` and zero for `modq`, and set OF.
+  #[default]
+  Wrap,
+  /// Fail with `Error::ArithmeticOverflow`.
+  Trap,
+}
+
+/// What happens when an instruction reads a register or memory block that
+/// was never written, which otherwise just reads as zero. The program loaded
+/// by `Vm::load`, host writes and `%rsp` count as written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UninitializedReads {
+  /// Read the zero silently.
+  #[default]
+  Allow,
+  /// Read the zero and emit `Event::UninitializedRead`.
+  Warn,
+  /// Fail with `Error::UninitializedRead`.
+  Fault,
+}
+
+/// Read only startup code, mapped at `base` whenever the vm is reset.
+#[derive(Debug, Clone)]
+pub(crate) struct Rom {
+  pub(crate) base: usize,
+  pub(crate) bytes: Vec<u8>,
+}
+
+impl Rom {
+  pub(crate) fn range(&self) -> Range<usize> {
+    self.base..self.base + self.bytes.len()
+  }
+
+  pub(crate) fn byte(&self, address: usize) -> Option<u8> {
+    self.bytes.get(address.checked_sub(self.base)?).copied()
+  }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+  pub(crate) entry: usize,
+  pub(crate) max_steps: Option<usize>,
+  pub(crate) code_writes: CodeWrites,
+  pub(crate) uninitialized_reads: UninitializedReads,
+  pub(crate) stack_top: usize,
+  pub(crate) stack_size: usize,
+  pub(crate) stack_guard: usize,
+  pub(crate) check_targets: bool,
+  pub(crate) division_overflow: DivisionOverflow,
+  pub(crate) trap_overflow: bool,
+  pub(crate) costs: CostTable,
+  pub(crate) cache: Option<Cache>,
+  pub(crate) energy: Option<EnergyTable>,
+  pub(crate) fetch: Option<Fetch>,
+  pub(crate) rom: Option<Rom>,
+  pub(crate) quotas: Quotas,
+  pub(crate) isa: Isa,
+  pub(crate) seed: u64,
+}
+
+impl Config {
+  pub(crate) const DEFAULT_STACK_SIZE: usize = 0x2000; // 8KB
+
+  /// Addresses the stack may occupy, growing down from `stack_top`.
+  pub(crate) fn stack(&self) -> Range<usize> {
+    self.stack_top.saturating_sub(self.stack_size)..self.stack_top
+  }
+
+  /// Where execution starts after a reset, the boot rom if there is one.
+  pub(crate) fn reset_ip(&self) -> usize {
+    self.rom.as_ref().map_or(self.entry, |rom| rom.base)
+  }
+
+  /// Initial `%rsp`, the highest block inside the stack.
+  pub(crate) fn stack_pointer(&self) -> Block {
+    self.stack_top as Block - 8
+  }
+
+  /// Unmapped band directly below the stack, empty when disabled.
+  pub(crate) fn stack_guard(&self) -> Range<usize> {
+    let base = self.stack().start;
+    base.saturating_sub(self.stack_guard)..base
+  }
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      entry: 0,
+      max_steps: None,
+      code_writes: CodeWrites::default(),
+      uninitialized_reads: UninitializedReads::default(),
+      stack_top: MainMemory::MEMORY_SIZE,
+      stack_size: Self::DEFAULT_STACK_SIZE,
+      stack_guard: 0,
+      check_targets: false,
+      division_overflow: DivisionOverflow::default(),
+      trap_overflow: false,
+      costs: CostTable::default(),
+      cache: None,
+      energy: None,
+      fetch: None,
+      rom: None,
+      quotas: Quotas::default(),
+      isa: Isa::default(),
+      seed: 0,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VmBuilder {
+  config: Config,
+}
+
+impl VmBuilder {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Address of the first instruction to execute.
+  pub fn entry(mut self, entry: usize) -> Self {
+    self.config.entry = entry;
+    self
+  }
+
+  /// Instruction set the vm executes, defaults to `Isa::Extended`. Under
+  /// `Isa::Strict` the extensions are invalid opcodes, failing the step or
+  /// reaching the trap handler like any other.
+  pub fn isa(mut self, isa: Isa) -> Self {
+    self.config.isa = isa;
+    self
+  }
+
+  /// Upper bound on the number of instructions the vm will execute before
+  /// failing with `Error::StepLimitExceeded`.
+  pub fn max_steps(mut self, max_steps: usize) -> Self {
+    self.config.max_steps = Some(max_steps);
+    self
+  }
+
+  /// How stores over loaded code are treated, defaults to `CodeWrites::Warn`.
+  pub fn code_writes(mut self, code_writes: CodeWrites) -> Self {
+    self.config.code_writes = code_writes;
+    self
+  }
+
+  /// How reads of never written registers and memory are treated, defaults
+  /// to `UninitializedReads::Allow`.
+  pub fn uninitialized_reads(mut self, mode: UninitializedReads) -> Self {
+    self.config.uninitialized_reads = mode;
+    self
+  }
+
+  /// Places the stack so it grows down from `top`, which defaults to the end
+  /// of memory. `%rsp` starts at `top - 8`.
+  pub fn stack_top(mut self, top: usize) -> Self {
+    self.config.stack_top = top;
+    self
+  }
+
+  /// Room set aside for the stack below its top, defaults to 8KB. This is
+  /// layout only, placing the stack in `Vm::memory_map` and the guard band
+  /// directly beneath it, nothing stops `%rsp` leaving it unless
+  /// `stack_guard` is set.
+  pub fn stack_size(mut self, bytes: usize) -> Self {
+    self.config.stack_size = bytes;
+    self
+  }
+
+  /// Reserves `bytes` of unmapped memory below the stack, so a stack overrun
+  /// fails with `Error::StackGuardHit` instead of clobbering whatever lives
+  /// below it. Disabled by default.
+  pub fn stack_guard(mut self, bytes: usize) -> Self {
+    self.config.stack_guard = bytes;
+    self
+  }
+
+  /// Faults with `Error::MisalignedTarget` when a jump, call or return lands
+  /// inside an instruction rather than at its first byte. Instruction starts
+  /// come from a linear sweep of the program in `Vm::load`. Disabled by
+  /// default.
+  pub fn check_targets(mut self, check: bool) -> Self {
+    self.config.check_targets = check;
+    self
+  }
+
+  /// How the quotient overflow of `divq` and `modq` is handled, defaults to
+  /// `DivisionOverflow::Wrap`.
+  pub fn division_overflow(mut self, mode: DivisionOverflow) -> Self {
+    self.config.division_overflow = mode;
+    self
+  }
+
+  /// Fails with `Error::ArithmeticOverflow` instead of setting OF whenever an
+  /// `opq` overflows, leaving the destination and flags untouched. Disabled
+  /// by default, as plain y86 arithmetic wraps.
+  pub fn trap_overflow(mut self, trap: bool) -> Self {
+    self.config.trap_overflow = trap;
+    self
+  }
+
+  /// Cycle costs used by the timing model, defaults to `CostTable::seq`.
+  pub fn costs(mut self, costs: CostTable) -> Self {
+    self.config.costs = costs;
+    self
+  }
+
+  /// Data cache consulted by the timing model to charge hits and misses.
+  /// Without one every access is charged as a hit.
+  pub fn cache(mut self, cache: Cache) -> Self {
+    self.config.cache = Some(cache);
+    self
+  }
+
+  /// Accumulates energy alongside cycles, see `Timing::energy`. Disabled by
+  /// default.
+  pub fn energy(mut self, energy: EnergyTable) -> Self {
+    self.config.energy = Some(energy);
+    self
+  }
+
+  /// Models limited fetch bandwidth in the timing model, see `Fetch`.
+  pub fn fetch(mut self, fetch: Fetch) -> Self {
+    self.config.fetch = Some(fetch);
+    self
+  }
+
+  /// Maps `bytes` read only at `base` and starts execution there rather than
+  /// at the entry point, both when built and after `Vm::reset`. The rom is
+  /// responsible for handing control to the loaded program, for instance
+  /// with `call` to the entry point followed by `halt`, and can hold shared
+  /// routines programs call into. Stores into it fail with `Error::RomWrite`.
+  pub fn boot_rom(mut self, base: usize, bytes: impl Into<Vec<u8>>) -> Self {
+    self.config.rom = Some(Rom {
+      base,
+      bytes: bytes.into(),
+    });
+    self
+  }
+
+  /// Limits enforced on every run, each failing the step that goes over it
+  /// with `Error::QuotaExceeded`. A run starts when the vm is built or reset.
+  pub fn quotas(mut self, quotas: Quotas) -> Self {
+    self.config.quotas = quotas;
+    self
+  }
+
+  /// Seed of the entropy every source of randomness in the vm draws from,
+  /// like fault injection, defaults to 0. Runs with the same seed repeat
+  /// exactly, `Vm::nondeterminism` lists what could still differ.
+  pub fn seed(mut self, seed: u64) -> Self {
+    self.config.seed = seed;
+    self
+  }
+
+  pub fn build(self) -> Vm {
+    Vm::with_config(self.config)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm;
+  use crate::region::Chunk;
+  use crate::register::Register;
+  use crate::vm::Error;
+
+  fn program(source: &str) -> Chunk {
+    Chunk::from(asm::assemble(source).unwrap().bytes().to_vec())
+  }
+
+  #[test]
+  fn stack_overruns_hit_the_guard() {
+    // stack at 0xf00..0x1000, guard band at 0xec0..0xf00
+    let builder = VmBuilder::new()
+      .stack_top(0x1000)
+      .stack_size(0x100)
+      .stack_guard(0x40);
+    let region = program("loop: pushq %rax\njmp loop");
+    let mut vm = builder.clone().build();
+    vm.load(&region).unwrap();
+    assert!(matches!(vm.run(&region), Err(Error::StackGuardHit(0xef8))));
+    assert_eq!(vm.register(Register::Rsp), 0xf00);
+
+    // only the band itself is unmapped
+    let region = program("rmmovq %rax, 0xeb8(%rbx)\nrmmovq %rax, 0xec0(%rbx)\nhalt");
+    let mut vm = builder.build();
+    vm.load(&region).unwrap();
+    assert!(matches!(vm.run(&region), Err(Error::StackGuardHit(0xec0))));
+  }
+
+  // hands control to the program at 0 and marks that it returned
+  const BOOT: &str = "call 0\nirmovq $1, %rdi\nhalt";
+
+  #[test]
+  fn boot_rom_runs_first_and_survives_reset() {
+    let builder = VmBuilder::new().boot_rom(0x800, asm::assemble(BOOT).unwrap().bytes());
+    let region = program("irmovq $7, %rax\nmrmovq 0x800(%rbx), %rcx\nret");
+    let mut vm = builder.build();
+    assert_eq!(vm.ip(), 0x800);
+    vm.load(&region).unwrap();
+    vm.run(&region).unwrap();
+    assert_eq!(vm.register(Register::Rax), 7);
+    assert_eq!(vm.register(Register::Rdi), 1);
+    // programs can read the rom
+    assert_eq!(vm.register(Register::Rcx) & 0xff, 0x80);
+
+    vm.reset();
+    assert_eq!(vm.ip(), 0x800);
+    vm.load(&region).unwrap();
+    vm.run(&region).unwrap();
+    assert_eq!(vm.register(Register::Rdi), 1);
+  }
+
+  #[test]
+  fn boot_rom_rejects_stores_and_overlapping_loads() {
+    let rom = asm::assemble(BOOT).unwrap().bytes().to_vec();
+    let region = program("rmmovq %rax, 0x808(%rbx)\nret");
+    let mut vm = VmBuilder::new().boot_rom(0x800, rom.clone()).build();
+    vm.load(&region).unwrap();
+    assert!(matches!(vm.run(&region), Err(Error::RomWrite(0x808))));
+    assert_eq!(vm.read_bytes(0x800, rom.len()).unwrap(), rom);
+
+    let mut vm = VmBuilder::new().boot_rom(0, rom).build();
+    assert!(matches!(vm.load(&region), Err(Error::RomWrite(0))));
+  }
+}
@@ -0,0 +1,345 @@
+use std::any::Any;
+use std::fmt;
+use std::ops::Range;
+
+use crate::Block;
+use crate::snapshot;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("device range {range:#x?} overlaps {other:#x?} at the same priority")]
+  Overlap {
+    range: Range<usize>,
+    other: Range<usize>,
+  },
+
+  #[error("device range {0:#x?} is empty")]
+  EmptyRange(Range<usize>),
+}
+
+/// A memory mapped peripheral. Accesses are block sized and `offset` is
+/// relative to the start of the range the device was attached at.
+pub trait Device: Any + Send {
+  fn read(&mut self, offset: usize) -> Block;
+
+  fn write(&mut self, offset: usize, value: Block);
+
+  /// Called after every retired instruction.
+  fn tick(&mut self) {}
+
+  /// Called after `tick` with the vm statistics as they stand, for devices
+  /// exposing them to the guest.
+  fn sample(&mut self, counters: &Counters) {
+    let _ = counters;
+  }
+
+  /// False for devices reading host state, like a host clock, so their
+  /// data differs between runs whatever the seed.
+  fn deterministic(&self) -> bool {
+    true
+  }
+
+  /// Called by `Vm::reset`, devices keep their state by default.
+  fn reset(&mut self) {}
+
+  /// Internal state to include in `Vm::snapshot`, stateless devices save
+  /// nothing. `snapshot::Encoder` helps lay it out.
+  fn save(&self) -> Vec<u8> {
+    Vec::new()
+  }
+
+  /// Restores what `save` produced.
+  fn load(&mut self, state: &[u8]) -> Result<(), snapshot::Error> {
+    let _ = state;
+    Ok(())
+  }
+}
+
+/// Vm statistics handed to `Device::sample`, counted since the vm was built
+/// or reset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counters {
+  pub instructions: u64,
+  pub cycles: u64,
+  /// Always zero without a cache model.
+  pub cache_misses: u64,
+  pub mispredictions: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DeviceId(pub(crate) usize);
+
+struct Mapping {
+  id: DeviceId,
+  range: Range<usize>,
+  priority: i32,
+  device: Box<dyn Device>,
+}
+
+/// Routes accesses to the devices claiming their address, memory gets
+/// whatever no device claims.
+///
+/// Ranges may only overlap at different priorities, the device with the
+/// highest priority then answers for the shared addresses.
+#[derive(Default)]
+pub struct Bus {
+  // sorted by descending priority, so the first match wins
+  mappings: Vec<Mapping>,
+  next_id: usize,
+}
+
+impl Bus {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Attaches `device` at `range` with priority 0.
+  pub fn attach(
+    &mut self,
+    range: Range<usize>,
+    device: impl Device + 'static,
+  ) -> Result<DeviceId, Error> {
+    self.attach_with_priority(range, 0, device)
+  }
+
+  pub fn attach_with_priority(
+    &mut self,
+    range: Range<usize>,
+    priority: i32,
+    device: impl Device + 'static,
+  ) -> Result<DeviceId, Error> {
+    if range.is_empty() {
+      return Err(Error::EmptyRange(range));
+    }
+    if let Some(other) = self
+      .mappings
+      .iter()
+      .find(|m| m.priority == priority && m.range.start < range.end && range.start < m.range.end)
+    {
+      return Err(Error::Overlap {
+        range,
+        other: other.range.clone(),
+      });
+    }
+    let id = DeviceId(self.next_id);
+    self.next_id += 1;
+    let at = self.mappings.partition_point(|m| m.priority >= priority);
+    self.mappings.insert(
+      at,
+      Mapping {
+        id,
+        range,
+        priority,
+        device: Box::new(device),
+      },
+    );
+    Ok(id)
+  }
+
+  pub fn detach(&mut self, id: DeviceId) -> Option<Box<dyn Device>> {
+    let at = self.mappings.iter().position(|m| m.id == id)?;
+    Some(self.mappings.remove(at).device)
+  }
+
+  /// Every attached device with its range and priority, highest priority
+  /// first.
+  pub fn devices(&self) -> impl Iterator<Item = (DeviceId, Range<usize>, i32)> + '_ {
+    self
+      .mappings
+      .iter()
+      .map(|m| (m.id, m.range.clone(), m.priority))
+  }
+
+  /// Address range `id` is attached at.
+  pub fn range(&self, id: DeviceId) -> Option<Range<usize>> {
+    self.mapping(id).map(|m| m.range.clone())
+  }
+
+  /// The device attached as `id`, if it is a `T`.
+  pub fn device<T: Device>(&self, id: DeviceId) -> Option<&T> {
+    let device: &dyn Any = self.mapping(id)?.device.as_ref();
+    device.downcast_ref()
+  }
+
+  pub fn device_mut<T: Device>(&mut self, id: DeviceId) -> Option<&mut T> {
+    let mapping = self.mappings.iter_mut().find(|m| m.id == id)?;
+    let device: &mut dyn Any = mapping.device.as_mut();
+    device.downcast_mut()
+  }
+
+  /// Whether some device answers for `address`.
+  pub fn claims(&self, address: usize) -> bool {
+    self.find(address).is_some()
+  }
+
+  fn mapping(&self, id: DeviceId) -> Option<&Mapping> {
+    self.mappings.iter().find(|m| m.id == id)
+  }
+
+  fn find(&self, address: usize) -> Option<usize> {
+    self
+      .mappings
+      .iter()
+      .position(|m| m.range.contains(&address))
+  }
+
+  /// Reads from the device claiming `address`, `None` when it is memory.
+  pub(crate) fn read(&mut self, address: usize) -> Option<Block> {
+    let at = self.find(address)?;
+    let mapping = &mut self.mappings[at];
+    Some(mapping.device.read(address - mapping.range.start))
+  }
+
+  /// Writes to the device claiming `address`, false when it is memory.
+  pub(crate) fn write(&mut self, address: usize, value: Block) -> bool {
+    let Some(at) = self.find(address) else {
+      return false;
+    };
+    let mapping = &mut self.mappings[at];
+    mapping.device.write(address - mapping.range.start, value);
+    true
+  }
+
+  pub(crate) fn tick(&mut self) {
+    for mapping in &mut self.mappings {
+      mapping.device.tick();
+    }
+  }
+
+  pub(crate) fn sample(&mut self, counters: &Counters) {
+    for mapping in &mut self.mappings {
+      mapping.device.sample(counters);
+    }
+  }
+
+  pub(crate) fn save(&self) -> Vec<(DeviceId, Vec<u8>)> {
+    self
+      .mappings
+      .iter()
+      .map(|m| (m.id, m.device.save()))
+      .collect()
+  }
+
+  pub(crate) fn load(&mut self, states: &[(DeviceId, Vec<u8>)]) -> Result<(), snapshot::Error> {
+    for (id, state) in states {
+      let mapping = self
+        .mappings
+        .iter_mut()
+        .find(|m| m.id == *id)
+        .ok_or(snapshot::Error::MissingDevice(*id))?;
+      mapping.device.load(state)?;
+    }
+    Ok(())
+  }
+
+  /// Devices that are not `Device::deterministic`.
+  pub(crate) fn nondeterministic(&self) -> impl Iterator<Item = DeviceId> + '_ {
+    self
+      .mappings
+      .iter()
+      .filter(|m| !m.device.deterministic())
+      .map(|m| m.id)
+  }
+
+  pub(crate) fn reset(&mut self) {
+    for mapping in &mut self.mappings {
+      mapping.device.reset();
+    }
+  }
+}
+
+impl fmt::Debug for Bus {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_list()
+      .entries(self.mappings.iter().map(|m| (m.id, &m.range, m.priority)))
+      .finish()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm;
+  use crate::builder::VmBuilder;
+  use crate::region::Chunk;
+  use crate::register::Register;
+
+  /// Answers every read with `value` and remembers the last write.
+  #[derive(Debug, Default)]
+  struct Latch {
+    value: Block,
+    written: Option<(usize, Block)>,
+  }
+
+  impl Latch {
+    fn new(value: Block) -> Self {
+      Self {
+        value,
+        written: None,
+      }
+    }
+  }
+
+  impl Device for Latch {
+    fn read(&mut self, _offset: usize) -> Block {
+      self.value
+    }
+
+    fn write(&mut self, offset: usize, value: Block) {
+      self.written = Some((offset, value));
+    }
+  }
+
+  #[test]
+  fn higher_priorities_win_shared_addresses() {
+    let mut bus = Bus::new();
+    let low = bus.attach(0x100..0x200, Latch::new(1)).unwrap();
+    let high = bus
+      .attach_with_priority(0x180..0x188, 1, Latch::new(2))
+      .unwrap();
+    assert_eq!(bus.read(0x100), Some(1));
+    assert_eq!(bus.read(0x180), Some(2));
+    assert_eq!(bus.read(0x200), None);
+    assert!(bus.write(0x1f8, 9));
+    assert_eq!(bus.device::<Latch>(low).unwrap().written, Some((0xf8, 9)));
+
+    let order: Vec<_> = bus.devices().map(|(id, _, _)| id).collect();
+    assert_eq!(order, [high, low]);
+    assert!(bus.detach(high).is_some());
+    assert_eq!(bus.read(0x180), Some(1));
+    assert!(bus.range(high).is_none());
+  }
+
+  #[test]
+  fn rejects_empty_and_overlapping_ranges() {
+    let mut bus = Bus::new();
+    bus.attach(0x100..0x200, Latch::default()).unwrap();
+    assert!(matches!(
+      bus.attach(0x1f8..0x208, Latch::default()),
+      Err(Error::Overlap { other, .. }) if other == (0x100..0x200)
+    ));
+    assert!(matches!(
+      bus.attach(0x300..0x300, Latch::default()),
+      Err(Error::EmptyRange(_))
+    ));
+    assert!(bus.attach(0x200..0x208, Latch::default()).is_ok());
+  }
+
+  #[test]
+  fn program_accesses_reach_devices() {
+    let region = Chunk::from(
+      asm::assemble("mrmovq 0x1000(%rbx), %rax\nrmmovq %rax, 0x1008(%rbx)\nhalt")
+        .unwrap()
+        .bytes()
+        .to_vec(),
+    );
+    let mut vm = VmBuilder::new().build();
+    vm.load(&region).unwrap();
+    let id = vm.attach_device(0x1000..0x1010, Latch::new(42)).unwrap();
+    vm.run(&region).unwrap();
+    assert_eq!(vm.register(Register::Rax), 42);
+    assert_eq!(vm.bus().device::<Latch>(id).unwrap().written, Some((8, 42)));
+    // memory behind the device is untouched
+    assert_eq!(vm.read_bytes(0x1008, 8).unwrap(), [0; 8]);
+  }
+}
@@ -0,0 +1,120 @@
+use std::fmt;
+
+use crate::region::Region;
+use crate::replay::{self, Divergence, Mismatch, Observer};
+use crate::vm::{State, Vm};
+
+/// Where one side of a comparison ended up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Summary {
+  pub steps: usize,
+  pub cycles: u64,
+  pub ip: usize,
+  pub halted: bool,
+  /// Why the side stopped early, if it did.
+  pub fault: Option<String>,
+}
+
+impl Summary {
+  fn of(vm: &Vm, fault: Option<String>) -> Self {
+    Self {
+      steps: vm.steps(),
+      cycles: vm.timing().cycles(),
+      ip: vm.ip(),
+      halted: vm.state() == State::Halted,
+      fault,
+    }
+  }
+}
+
+/// Outcome of `compare`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comparison {
+  /// First step whose effects differ, with the left side as expected.
+  pub divergence: Option<Divergence>,
+  pub left: Summary,
+  pub right: Summary,
+}
+
+impl fmt::Display for Comparison {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.divergence {
+      Some(divergence) => write!(f, "{divergence}")?,
+      None => writeln!(f, "no divergence")?,
+    }
+    writeln!(f, "{:<8} {:>18} {:>18}", "", "left", "right")?;
+    let (left, right) = (&self.left, &self.right);
+    writeln!(f, "{:<8} {:>18} {:>18}", "steps", left.steps, right.steps)?;
+    writeln!(
+      f,
+      "{:<8} {:>18} {:>18}",
+      "cycles", left.cycles, right.cycles
+    )?;
+    let ip = |summary: &Summary| format!("{:#x}", summary.ip);
+    writeln!(f, "{:<8} {:>18} {:>18}", "ip", ip(left), ip(right))?;
+    let state = |summary: &Summary| match (&summary.fault, summary.halted) {
+      (Some(_), _) => "faulted",
+      (None, true) => "halted",
+      (None, false) => "running",
+    };
+    writeln!(f, "{:<8} {:>18} {:>18}", "state", state(left), state(right))?;
+    for (side, summary) in [("left", left), ("right", right)] {
+      if let Some(fault) = &summary.fault {
+        writeln!(f, "{side} fault: {fault}")?;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Steps two machines in lockstep, each on its own program and
+/// configuration, until both halt, either faults, or a step's register,
+/// flag, store or control flow effects differ. Both are bounded by their
+/// `VmBuilder::max_steps`. Timing is not compared, so the same program under
+/// two cost models only differs in the summaries.
+pub fn compare<A, B>(left: &mut Vm, left_region: &A, right: &mut Vm, right_region: &B) -> Comparison
+where
+  A: Region,
+  B: Region,
+{
+  let mut observers = (Observer::attach(left), Observer::attach(right));
+  let mut divergence = None;
+  let mut faults = (None, None);
+  while left.state() != State::Halted || right.state() != State::Halted {
+    let (step, address) = (left.steps(), left.ip());
+    let effects = (
+      observers.0.step(left, left_region),
+      observers.1.step(right, right_region),
+    );
+    let mismatches = match &effects {
+      (Ok(expected), Ok(actual)) => replay::mismatches(expected, actual),
+      (Err(e), Ok(_)) | (Ok(_), Err(e)) => vec![Mismatch::Fault(e.to_string())],
+      (Err(_), Err(_)) => Vec::new(),
+    };
+    let (expected, actual) = effects;
+    let stop = expected.is_err() || actual.is_err();
+    if !mismatches.is_empty() {
+      divergence = Some(Divergence {
+        step,
+        address,
+        expected: expected.as_ref().ok().cloned(),
+        actual: actual.as_ref().ok().cloned(),
+        mismatches,
+      });
+    }
+    faults = (
+      expected.err().map(|e| e.to_string()),
+      actual.err().map(|e| e.to_string()),
+    );
+    if stop || divergence.is_some() {
+      break;
+    }
+  }
+  observers.0.detach(left);
+  observers.1.detach(right);
+  Comparison {
+    divergence,
+    left: Summary::of(left, faults.0),
+    right: Summary::of(right, faults.1),
+  }
+}
@@ -0,0 +1,274 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+use crate::region::Region;
+use crate::vm::{self, State, Vm};
+
+const RUNNING: u8 = 0;
+const PAUSED: u8 = 1;
+const STOPPING: u8 = 2;
+
+type Query = Box<dyn FnOnce(&Vm) + Send>;
+
+enum Command {
+  Step,
+  Query(Query),
+}
+
+#[derive(Default)]
+struct Inner {
+  commands: VecDeque<Command>,
+  finished: bool,
+}
+
+struct Shared {
+  mode: AtomicU8,
+  // set whenever the runner has to leave its fast path, so the common case
+  // costs one relaxed load per instruction
+  attention: AtomicBool,
+  inner: Mutex<Inner>,
+  wake: Condvar,
+}
+
+impl Shared {
+  fn lock(&self) -> MutexGuard<'_, Inner> {
+    self.inner.lock().expect("control lock poisoned")
+  }
+
+  fn notify(&self) {
+    self.attention.store(true, Ordering::Release);
+    // taking the lock orders this wakeup after any in progress attention
+    // check, so a runner about to wait cannot miss it
+    let _inner = self.lock();
+    self.wake.notify_all();
+  }
+}
+
+/// How a controlled run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+  Halted,
+  /// A controller asked the run to stop early.
+  Stopped,
+}
+
+/// Thread safe handle driving a vm that runs on another thread.
+#[derive(Clone)]
+pub struct Controller {
+  shared: Arc<Shared>,
+}
+
+/// The vm side of a controller, checked between instructions.
+pub struct Runner {
+  shared: Arc<Shared>,
+}
+
+/// Creates a connected controller and runner pair.
+pub fn channel() -> (Controller, Runner) {
+  let shared = Arc::new(Shared {
+    mode: AtomicU8::new(RUNNING),
+    attention: AtomicBool::new(false),
+    inner: Mutex::new(Inner::default()),
+    wake: Condvar::new(),
+  });
+  let controller = Controller {
+    shared: Arc::clone(&shared),
+  };
+  (controller, Runner { shared })
+}
+
+/// Join handle of a spawned run, yielding the vm back along with how it ended.
+pub type RunHandle = JoinHandle<(Vm, Result<Exit, vm::Error>)>;
+
+/// Moves `vm` onto a new thread and runs it under the returned controller.
+pub fn spawn<R>(mut vm: Vm, region: R) -> (Controller, RunHandle)
+where
+  R: Region + Send + 'static,
+{
+  let (controller, runner) = channel();
+  let handle = thread::spawn(move || {
+    let result = runner.run(&mut vm, &region);
+    (vm, result)
+  });
+  (controller, handle)
+}
+
+impl Controller {
+  /// Pauses the vm before its next instruction.
+  pub fn pause(&self) {
+    self.shared.mode.store(PAUSED, Ordering::Release);
+    self.shared.notify();
+  }
+
+  pub fn resume(&self) {
+    self.shared.mode.store(RUNNING, Ordering::Release);
+    self.shared.notify();
+  }
+
+  /// Executes a single instruction while paused, ignored while running.
+  pub fn step(&self) {
+    self.shared.lock().commands.push_back(Command::Step);
+    self.shared.notify();
+  }
+
+  /// Ends the run before the next instruction, paused or not.
+  pub fn stop(&self) {
+    self.shared.mode.store(STOPPING, Ordering::Release);
+    self.shared.notify();
+  }
+
+  pub fn is_paused(&self) -> bool {
+    self.shared.mode.load(Ordering::Acquire) == PAUSED
+  }
+
+  pub fn is_finished(&self) -> bool {
+    self.shared.lock().finished
+  }
+
+  /// Runs `f` against the vm between two instructions and returns its result,
+  /// or `None` once the run has ended. Queries and steps are serviced in the
+  /// order they were issued.
+  pub fn query<T, F>(&self, f: F) -> Option<T>
+  where
+    T: Send + 'static,
+    F: FnOnce(&Vm) -> T + Send + 'static,
+  {
+    let (tx, rx) = mpsc::channel();
+    {
+      let mut inner = self.shared.lock();
+      if inner.finished {
+        return None;
+      }
+      inner.commands.push_back(Command::Query(Box::new(move |vm| {
+        let _ = tx.send(f(vm));
+      })));
+    }
+    self.shared.notify();
+    // the runner drops pending queries when it finishes, closing the channel
+    rx.recv().ok()
+  }
+}
+
+impl Runner {
+  /// Steps `vm` until it halts, faults or a controller stops it.
+  pub fn run<R>(&self, vm: &mut Vm, region: &R) -> Result<Exit, vm::Error>
+  where
+    R: Region,
+  {
+    let result = self.run_inner(vm, region);
+    let mut inner = self.shared.lock();
+    inner.finished = true;
+    inner.commands.clear();
+    result
+  }
+
+  fn run_inner<R>(&self, vm: &mut Vm, region: &R) -> Result<Exit, vm::Error>
+  where
+    R: Region,
+  {
+    loop {
+      if self.shared.attention.load(Ordering::Acquire)
+        && let Some(exit) = self.service(vm, region)?
+      {
+        return Ok(exit);
+      }
+      if vm.state() == State::Halted {
+        return Ok(Exit::Halted);
+      }
+      vm.step(region)?;
+    }
+  }
+
+  /// Handles queued commands and blocks while paused.
+  fn service<R>(&self, vm: &mut Vm, region: &R) -> Result<Option<Exit>, vm::Error>
+  where
+    R: Region,
+  {
+    let mut inner = self.shared.lock();
+    loop {
+      self.shared.attention.store(false, Ordering::Release);
+      let paused = self.shared.mode.load(Ordering::Acquire) == PAUSED;
+      while let Some(command) = inner.commands.pop_front() {
+        match command {
+          Command::Query(query) => query(vm),
+          Command::Step if paused && vm.state() != State::Halted => {
+            vm.step(region)?;
+          }
+          Command::Step => {}
+        }
+      }
+      match self.shared.mode.load(Ordering::Acquire) {
+        STOPPING => return Ok(Some(Exit::Stopped)),
+        PAUSED if vm.state() != State::Halted => {
+          if !self.shared.attention.load(Ordering::Acquire) {
+            inner = self.shared.wake.wait(inner).expect("control lock poisoned");
+          }
+        }
+        _ => return Ok(None),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm;
+  use crate::builder::VmBuilder;
+  use crate::region::Chunk;
+  use crate::register::Register;
+
+  fn start(source: &str) -> (Controller, RunHandle) {
+    let region = Chunk::from(asm::assemble(source).unwrap().bytes().to_vec());
+    let mut vm = VmBuilder::new().build();
+    vm.load(&region).unwrap();
+    spawn(vm, region)
+  }
+
+  const SPIN: &str = "
+    irmovq $1, %rcx
+loop:
+    addq %rcx, %rax
+    jmp loop
+";
+
+  #[test]
+  fn pause_holds_the_vm_until_stepped() {
+    let (controller, handle) = start(SPIN);
+    controller.pause();
+    assert!(controller.is_paused());
+    let paused = controller.query(Vm::steps).unwrap();
+    assert_eq!(controller.query(Vm::steps), Some(paused));
+    controller.step();
+    assert_eq!(controller.query(Vm::steps), Some(paused + 1));
+    controller.resume();
+    controller.stop();
+    let (vm, result) = handle.join().unwrap();
+    assert_eq!(result.unwrap(), Exit::Stopped);
+    assert!(vm.steps() > paused);
+    assert!(controller.is_finished());
+  }
+
+  #[test]
+  fn stop_ends_a_paused_run() {
+    let (controller, handle) = start(SPIN);
+    controller.pause();
+    controller.query(|_| ()).unwrap();
+    controller.stop();
+    let (_, result) = handle.join().unwrap();
+    assert_eq!(result.unwrap(), Exit::Stopped);
+  }
+
+  #[test]
+  fn queries_end_with_the_run() {
+    let (controller, handle) = start("irmovq $5, %rax\nhalt");
+    let (vm, result) = handle.join().unwrap();
+    assert_eq!(result.unwrap(), Exit::Halted);
+    assert_eq!(vm.register(Register::Rax), 5);
+    assert!(controller.is_finished());
+    assert_eq!(controller.query(Vm::steps), None);
+  }
+}
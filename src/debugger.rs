@@ -0,0 +1,779 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::disasm::{Disassembled, Instruction};
+use crate::expr::{self, Expr};
+use crate::frame::{self, Frame, Locals};
+use crate::history::Writer;
+use crate::region::Region;
+use crate::register::Register;
+use crate::session::Session;
+use crate::symbol::Symbols;
+use crate::vm::{self, ExecutedInstruction, Vm};
+use crate::{BLOCK_SIZE, Block};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("invalid breakpoint {0:?}, expected `ADDR [if EXPR]`")]
+  InvalidBreakpoint(String),
+
+  #[error("invalid watchpoint {0:?}, expected `%REG [if EXPR]`")]
+  InvalidWatchpoint(String),
+
+  #[error("no breakpoint with id {0}")]
+  UnknownBreakpoint(usize),
+
+  #[error("no watchpoint with id {0}")]
+  UnknownWatchpoint(usize),
+
+  #[error("no display with id {0}")]
+  UnknownDisplay(usize),
+
+  #[error("writes are not being recorded")]
+  NoWriteHistory,
+
+  #[error("condition of breakpoint {0} failed - {1}")]
+  ConditionFailed(usize, expr::Error),
+
+  #[error("condition of watchpoint {0} failed - {1}")]
+  WatchConditionFailed(usize, expr::Error),
+
+  #[error("hook of breakpoint {0} failed - {1}")]
+  HookFailed(usize, Box<dyn std::error::Error + Send + Sync>),
+
+  #[error("expression error - {0}")]
+  ExprError(#[from] expr::Error),
+
+  #[error("vm error - {0}")]
+  VmError(#[from] vm::Error),
+}
+
+/// Stops execution before the instruction at `address` runs, provided the
+/// optional condition evaluates to true at that point.
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+  address: usize,
+  condition: Option<Expr>,
+  hits: usize,
+}
+
+impl Breakpoint {
+  pub fn new(address: usize) -> Self {
+    Self {
+      address,
+      condition: None,
+      hits: 0,
+    }
+  }
+
+  pub fn with_condition(mut self, condition: Expr) -> Self {
+    self.condition = Some(condition);
+    self
+  }
+
+  pub fn address(&self) -> usize {
+    self.address
+  }
+
+  pub fn condition(&self) -> Option<&Expr> {
+    self.condition.as_ref()
+  }
+
+  /// Number of times execution stopped at this breakpoint.
+  pub fn hits(&self) -> usize {
+    self.hits
+  }
+}
+
+impl Breakpoint {
+  /// Parses like `from_str`, resolving labels from `symbols` in both the
+  /// address and the condition, as in `sort_loop` or `buffer + 16 if %rax`.
+  /// The address may only use numbers and labels.
+  pub fn parse(s: &str, symbols: &Symbols) -> Result<Self, Error> {
+    let (address, condition) = split_condition(s);
+    if address.is_empty() {
+      return Err(Error::InvalidBreakpoint(s.to_string()));
+    }
+    let address = Expr::parse(address, symbols)?.eval_const()?;
+    let address = usize::try_from(address).map_err(|_| Error::InvalidBreakpoint(s.to_string()))?;
+    let mut breakpoint = Breakpoint::new(address);
+    breakpoint.condition = condition
+      .map(|condition| Expr::parse(condition, symbols))
+      .transpose()?;
+    Ok(breakpoint)
+  }
+}
+
+/// Parses `0x40` or `0x40 if %rdi == 0 && mem[0x1000] > 5`.
+impl FromStr for Breakpoint {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Self::parse(s, &Symbols::new())
+  }
+}
+
+/// Splits `TARGET [if EXPR]` at the first standalone `if`.
+fn split_condition(s: &str) -> (&str, Option<&str>) {
+  let s = s.trim();
+  let standalone = |at: usize| {
+    let before = s[..at].chars().next_back();
+    let after = s[at + 2..].chars().next();
+    before.is_some_and(char::is_whitespace)
+      && !after.is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+  };
+  match s.match_indices("if").find(|&(at, _)| standalone(at)) {
+    Some((at, _)) => (s[..at].trim_end(), Some(&s[at + 2..])),
+    None => (s, None),
+  }
+}
+
+impl fmt::Display for Breakpoint {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{:#x}", self.address)?;
+    if let Some(condition) = &self.condition {
+      write!(f, " if {condition}")?;
+    }
+    Ok(())
+  }
+}
+
+/// Stops execution after an instruction changes `register`, provided the
+/// optional condition evaluates to true once the instruction has run.
+#[derive(Debug, Clone)]
+pub struct Watchpoint {
+  register: Register,
+  condition: Option<Expr>,
+  hits: usize,
+}
+
+impl Watchpoint {
+  pub fn new(register: Register) -> Self {
+    Self {
+      register,
+      condition: None,
+      hits: 0,
+    }
+  }
+
+  pub fn with_condition(mut self, condition: Expr) -> Self {
+    self.condition = Some(condition);
+    self
+  }
+
+  pub fn register(&self) -> Register {
+    self.register
+  }
+
+  pub fn condition(&self) -> Option<&Expr> {
+    self.condition.as_ref()
+  }
+
+  /// Number of times execution stopped at this watchpoint.
+  pub fn hits(&self) -> usize {
+    self.hits
+  }
+}
+
+impl Watchpoint {
+  /// Parses like `from_str`, resolving labels from `symbols` in the
+  /// condition, as in `%rax if %rax == limit`.
+  pub fn parse(s: &str, symbols: &Symbols) -> Result<Self, Error> {
+    let (register, condition) = split_condition(s);
+    let register = register
+      .parse()
+      .map_err(|_| Error::InvalidWatchpoint(s.to_string()))?;
+    let mut watchpoint = Watchpoint::new(register);
+    watchpoint.condition = condition
+      .map(|condition| Expr::parse(condition, symbols))
+      .transpose()?;
+    Ok(watchpoint)
+  }
+}
+
+/// Parses `%rbp` or `%rax if %rax < 0`.
+impl FromStr for Watchpoint {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Self::parse(s, &Symbols::new())
+  }
+}
+
+impl fmt::Display for Watchpoint {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.register)?;
+    if let Some(condition) = &self.condition {
+      write!(f, " if {condition}")?;
+    }
+    Ok(())
+  }
+}
+
+impl fmt::Debug for Debugger {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Debugger")
+      .field("breakpoints", &self.breakpoints)
+      .field("watchpoints", &self.watchpoints)
+      .field("displays", &self.displays)
+      .field("hooks", &self.hooks.keys().collect::<Vec<_>>())
+      .field("symbols", &self.symbols)
+      .finish()
+  }
+}
+
+/// Why `Debugger::run` handed control back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+  Halted,
+  /// Stopped at the breakpoint with the given id.
+  Breakpoint(usize),
+  /// `Debugger::step_over` or `Debugger::step_out` completed.
+  Stepped,
+  /// Stopped after the instruction at `address` changed the register of the
+  /// watchpoint with the given id from `old` to `new`.
+  Watchpoint {
+    id: usize,
+    address: usize,
+    old: Block,
+    new: Block,
+  },
+}
+
+/// What a hook wants the debugger to do after it ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+  /// Resume execution as if the breakpoint had not triggered.
+  Continue,
+  /// Stop and report the breakpoint to the caller.
+  Stop,
+}
+
+/// Code run whenever its breakpoint triggers, free to inspect and modify the
+/// machine before deciding whether execution stops.
+pub trait Hook {
+  fn call(&mut self, vm: &mut Vm) -> Result<Action, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+impl<F> Hook for F
+where
+  F: FnMut(&mut Vm) -> Result<Action, Box<dyn std::error::Error + Send + Sync>>,
+{
+  fn call(&mut self, vm: &mut Vm) -> Result<Action, Box<dyn std::error::Error + Send + Sync>> {
+    self(vm)
+  }
+}
+
+#[derive(Default)]
+pub struct Debugger {
+  breakpoints: Vec<Option<Breakpoint>>,
+  watchpoints: Vec<Option<Watchpoint>>,
+  displays: Vec<Option<Expr>>,
+  hooks: HashMap<usize, Box<dyn Hook>>,
+  symbols: Symbols,
+  locals: Locals,
+  // step count of the vm when we last stopped it, so resuming does not
+  // immediately stop at the same breakpoint again
+  stopped_at: Option<usize>,
+}
+
+impl Debugger {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Labels of the debugged program, resolved by `parse_breakpoint`,
+  /// `parse_watchpoint`, `address` and `examine`.
+  pub fn set_symbols(&mut self, symbols: Symbols) {
+    self.symbols = symbols;
+  }
+
+  pub fn symbols(&self) -> &Symbols {
+    &self.symbols
+  }
+
+  /// Named stack slots shown by `backtrace` and `frame`, usually
+  /// `Assembled::locals`.
+  pub fn set_locals(&mut self, locals: Locals) {
+    self.locals = locals;
+  }
+
+  /// The call stack of `vm`, innermost frame first, see `frame::backtrace`.
+  pub fn backtrace(&self, vm: &Vm) -> Vec<Frame> {
+    frame::backtrace(vm, &self.symbols, &self.locals)
+  }
+
+  /// Frame `index` of the call stack, 0 being the one executing.
+  pub fn frame(&self, vm: &Vm, index: usize) -> Option<Frame> {
+    self.backtrace(vm).into_iter().nth(index)
+  }
+
+  /// Parses a breakpoint against the program's labels, see
+  /// `Breakpoint::parse`.
+  pub fn parse_breakpoint(&self, s: &str) -> Result<Breakpoint, Error> {
+    Breakpoint::parse(s, &self.symbols)
+  }
+
+  pub fn parse_watchpoint(&self, s: &str) -> Result<Watchpoint, Error> {
+    Watchpoint::parse(s, &self.symbols)
+  }
+
+  /// Evaluates an address expression such as `buffer + 16` or `%rsp + 8`
+  /// against the program's labels and the current state of `vm`.
+  pub fn address(&self, vm: &Vm, expr: &str) -> Result<usize, Error> {
+    let value = Expr::parse(expr, &self.symbols)?.eval(vm)?;
+    // addresses past the end of memory fail when read anyway
+    Ok(value as usize)
+  }
+
+  /// Reads `count` blocks starting at the address `expr` evaluates to, which
+  /// need not be aligned, returning each along with its address.
+  pub fn examine(&self, vm: &Vm, expr: &str, count: usize) -> Result<Vec<(usize, Block)>, Error> {
+    let address = self.address(vm, expr)?;
+    let bytes = vm.read_bytes(address, count * BLOCK_SIZE)?;
+    let blocks = bytes
+      .chunks_exact(BLOCK_SIZE)
+      .enumerate()
+      .map(|(i, chunk)| {
+        let block = Block::from_le_bytes(chunk.try_into().expect("chunk is a block"));
+        (address + i * BLOCK_SIZE, block)
+      })
+      .collect();
+    Ok(blocks)
+  }
+
+  /// The kept stores covering the address `expr` evaluates to, newest first,
+  /// along with that address. Empty when none was kept, either because the
+  /// address was never written or it lies outside the watched ranges.
+  pub fn writers(&self, vm: &Vm, expr: &str) -> Result<(usize, Vec<Writer>), Error> {
+    let history = vm.write_history().ok_or(Error::NoWriteHistory)?;
+    let address = self.address(vm, expr)?;
+    Ok((address, history.writers(address).copied().collect()))
+  }
+
+  /// Registers a breakpoint, returning the id used to refer to it later.
+  pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+    self.breakpoints.push(Some(breakpoint));
+    self.breakpoints.len() - 1
+  }
+
+  pub fn remove_breakpoint(&mut self, id: usize) -> Result<Breakpoint, Error> {
+    self.hooks.remove(&id);
+    self
+      .breakpoints
+      .get_mut(id)
+      .and_then(Option::take)
+      .ok_or(Error::UnknownBreakpoint(id))
+  }
+
+  /// Attaches a hook to a breakpoint, replacing any previous one.
+  pub fn attach_hook(&mut self, id: usize, hook: impl Hook + 'static) -> Result<(), Error> {
+    if self.breakpoint(id).is_none() {
+      return Err(Error::UnknownBreakpoint(id));
+    }
+    self.hooks.insert(id, Box::new(hook));
+    Ok(())
+  }
+
+  pub fn detach_hook(&mut self, id: usize) {
+    self.hooks.remove(&id);
+  }
+
+  pub fn breakpoint(&self, id: usize) -> Option<&Breakpoint> {
+    self.breakpoints.get(id).and_then(Option::as_ref)
+  }
+
+  /// Registers a watchpoint, returning the id used to refer to it later.
+  /// Watchpoint and breakpoint ids are numbered independently.
+  pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+    self.watchpoints.push(Some(watchpoint));
+    self.watchpoints.len() - 1
+  }
+
+  pub fn remove_watchpoint(&mut self, id: usize) -> Result<Watchpoint, Error> {
+    self
+      .watchpoints
+      .get_mut(id)
+      .and_then(Option::take)
+      .ok_or(Error::UnknownWatchpoint(id))
+  }
+
+  pub fn watchpoint(&self, id: usize) -> Option<&Watchpoint> {
+    self.watchpoints.get(id).and_then(Option::as_ref)
+  }
+
+  /// Live watchpoints along with their ids.
+  pub fn watchpoints(&self) -> impl Iterator<Item = (usize, &Watchpoint)> + '_ {
+    self
+      .watchpoints
+      .iter()
+      .enumerate()
+      .filter_map(|(id, wp)| wp.as_ref().map(|wp| (id, wp)))
+  }
+
+  /// Adds an expression to show whenever execution stops, returning its id.
+  pub fn add_display(&mut self, expr: &str) -> Result<usize, Error> {
+    self.displays.push(Some(Expr::parse(expr, &self.symbols)?));
+    Ok(self.displays.len() - 1)
+  }
+
+  pub fn remove_display(&mut self, id: usize) -> Result<Expr, Error> {
+    self
+      .displays
+      .get_mut(id)
+      .and_then(Option::take)
+      .ok_or(Error::UnknownDisplay(id))
+  }
+
+  /// Live display expressions along with their ids.
+  pub fn displays(&self) -> impl Iterator<Item = (usize, &Expr)> + '_ {
+    self
+      .displays
+      .iter()
+      .enumerate()
+      .filter_map(|(id, expr)| expr.as_ref().map(|expr| (id, expr)))
+  }
+
+  /// Breakpoints without hooks, watchpoints and displays as a session to
+  /// save, breakpoint addresses written as labels where one matches.
+  /// History is left for the caller to fill in.
+  pub fn session(&self) -> Session {
+    let breakpoints = self
+      .breakpoints()
+      .filter(|(id, _)| !self.hooks.contains_key(id))
+      .map(|(_, bp)| {
+        let mut text = match self.symbols.name_of(bp.address) {
+          Some(label) => label.to_string(),
+          None => format!("{:#x}", bp.address),
+        };
+        if let Some(condition) = &bp.condition {
+          text = format!("{text} if {condition}");
+        }
+        text
+      })
+      .collect();
+    Session {
+      breakpoints,
+      watchpoints: self.watchpoints().map(|(_, wp)| wp.to_string()).collect(),
+      displays: self.displays().map(|(_, expr)| expr.to_string()).collect(),
+      history: Vec::new(),
+    }
+  }
+
+  /// Adds the breakpoints, watchpoints and displays of `session`, parsed
+  /// against the current symbols. Entries that no longer parse, usually
+  /// over a label removed since, are skipped and returned with the reason.
+  pub fn restore(&mut self, session: &Session) -> Vec<(String, Error)> {
+    let mut skipped = Vec::new();
+    for text in &session.breakpoints {
+      match self.parse_breakpoint(text) {
+        Ok(bp) => {
+          self.add_breakpoint(bp);
+        }
+        Err(e) => skipped.push((text.clone(), e)),
+      }
+    }
+    for text in &session.watchpoints {
+      match self.parse_watchpoint(text) {
+        Ok(wp) => {
+          self.add_watchpoint(wp);
+        }
+        Err(e) => skipped.push((text.clone(), e)),
+      }
+    }
+    for text in &session.displays {
+      if let Err(e) = self.add_display(text) {
+        skipped.push((text.clone(), e));
+      }
+    }
+    skipped
+  }
+
+  /// Live breakpoints along with their ids.
+  pub fn breakpoints(&self) -> impl Iterator<Item = (usize, &Breakpoint)> + '_ {
+    self
+      .breakpoints
+      .iter()
+      .enumerate()
+      .filter_map(|(id, bp)| bp.as_ref().map(|bp| (id, bp)))
+  }
+
+  /// Returns the id of the first breakpoint at the current ip whose condition
+  /// holds.
+  fn triggered(&mut self, vm: &Vm) -> Result<Option<usize>, Error> {
+    let ip = vm.ip();
+    for (id, slot) in self.breakpoints.iter_mut().enumerate() {
+      let Some(bp) = slot.as_mut().filter(|bp| bp.address == ip) else {
+        continue;
+      };
+      let hit = match &bp.condition {
+        Some(condition) => condition
+          .is_true(vm)
+          .map_err(|e| Error::ConditionFailed(id, e))?,
+        None => true,
+      };
+      if hit {
+        bp.hits += 1;
+        return Ok(Some(id));
+      }
+    }
+    Ok(None)
+  }
+
+  /// Returns the first watchpoint whose register the last instruction changed
+  /// and whose condition holds, along with the old and new values.
+  fn watched(&mut self, vm: &Vm, before: &[Block]) -> Result<Option<(usize, Block, Block)>, Error> {
+    for (id, slot) in self.watchpoints.iter_mut().enumerate() {
+      let Some(wp) = slot.as_mut() else {
+        continue;
+      };
+      let (old, new) = (before[wp.register as usize], vm.register(wp.register));
+      if old == new {
+        continue;
+      }
+      let hit = match &wp.condition {
+        Some(condition) => condition
+          .is_true(vm)
+          .map_err(|e| Error::WatchConditionFailed(id, e))?,
+        None => true,
+      };
+      if hit {
+        wp.hits += 1;
+        return Ok(Some((id, old, new)));
+      }
+    }
+    Ok(None)
+  }
+
+  /// Executes the next instruction unless a breakpoint at the current ip
+  /// stops first, then checks watchpoints. `resuming` skips the breakpoint
+  /// check, for continuing from where execution last stopped.
+  fn advance<R>(&mut self, vm: &mut Vm, region: &R, resuming: bool) -> Result<Flow, Error>
+  where
+    R: Region,
+  {
+    if !resuming && let Some(id) = self.triggered(vm)? {
+      let action = match self.hooks.get_mut(&id) {
+        Some(hook) => hook.call(vm).map_err(|e| Error::HookFailed(id, e))?,
+        None => Action::Stop,
+      };
+      if action == Action::Stop {
+        self.stopped_at = Some(vm.steps());
+        return Ok(Flow::Stop(Stop::Breakpoint(id)));
+      }
+    }
+    let watching = self.watchpoints.iter().any(Option::is_some);
+    let before: Vec<Block> = if watching {
+      vm.registers().map(|(_, value)| value).collect()
+    } else {
+      Vec::new()
+    };
+    let Some(executed) = vm.iter(region).next().transpose()? else {
+      return Ok(Flow::Stop(Stop::Halted));
+    };
+    if watching && let Some((id, old, new)) = self.watched(vm, &before)? {
+      return Ok(Flow::Stop(Stop::Watchpoint {
+        id,
+        address: executed.address(),
+        old,
+        new,
+      }));
+    }
+    Ok(Flow::Executed(executed))
+  }
+
+  /// Runs until the machine halts, a breakpoint triggers or a watched
+  /// register changes. Calling `run` again after a breakpoint stop continues
+  /// past it.
+  pub fn run<R>(&mut self, vm: &mut Vm, region: &R) -> Result<Stop, Error>
+  where
+    R: Region,
+  {
+    let mut resuming = self.stopped_at == Some(vm.steps());
+    loop {
+      if let Flow::Stop(stop) = self.advance(vm, region, resuming)? {
+        return Ok(stop);
+      }
+      resuming = false;
+    }
+  }
+
+  /// Executes one instruction, treating a `call` and everything up to its
+  /// matching `ret` as part of it. Returns `Stop::Stepped` once back at the
+  /// instruction after the call, unless a breakpoint or watchpoint inside the
+  /// callee stops execution first.
+  pub fn step_over<R>(&mut self, vm: &mut Vm, region: &R) -> Result<Stop, Error>
+  where
+    R: Region,
+  {
+    self.run_to_depth(vm, region, 0)
+  }
+
+  /// Runs until the current function returns, stopping at the instruction
+  /// after the `call` that entered it. Nested calls are run through, so a
+  /// recursive function only returns when its own `ret` executes.
+  pub fn step_out<R>(&mut self, vm: &mut Vm, region: &R) -> Result<Stop, Error>
+  where
+    R: Region,
+  {
+    self.run_to_depth(vm, region, -1)
+  }
+
+  /// Runs until the call depth relative to the current function drops to
+  /// `target`, counting every executed `call` and `ret`.
+  fn run_to_depth<R>(&mut self, vm: &mut Vm, region: &R, target: isize) -> Result<Stop, Error>
+  where
+    R: Region,
+  {
+    // stepping always leaves the current instruction, breakpoint or not
+    let mut resuming = true;
+    let mut depth = 0;
+    loop {
+      let executed = match self.advance(vm, region, resuming)? {
+        Flow::Stop(stop) => return Ok(stop),
+        Flow::Executed(executed) => executed,
+      };
+      resuming = false;
+      match executed.instruction().map(Disassembled::instruction) {
+        Some(Instruction::Call(_)) => depth += 1,
+        Some(Instruction::Ret) => depth -= 1,
+        _ => {}
+      }
+      if depth <= target {
+        self.stopped_at = Some(vm.steps());
+        return Ok(Stop::Stepped);
+      }
+    }
+  }
+}
+
+enum Flow {
+  Stop(Stop),
+  Executed(ExecutedInstruction),
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm;
+  use crate::builder::VmBuilder;
+  use crate::region::Chunk;
+
+  const NESTED: &str = "
+    call f
+after_f:
+    irmovq $1, %rdi
+    halt
+f:
+    call g
+after_g:
+    irmovq $2, %rsi
+    ret
+g:
+    irmovq $3, %rdx
+    ret
+";
+
+  fn start() -> (Vm, Chunk, Symbols) {
+    let assembled = asm::assemble(NESTED).unwrap();
+    let region = Chunk::from(assembled.bytes().to_vec());
+    let mut vm = VmBuilder::new().build();
+    vm.load(&region).unwrap();
+    (vm, region, assembled.symbols().clone())
+  }
+
+  #[test]
+  fn step_over_runs_calls_to_completion() {
+    let (mut vm, region, symbols) = start();
+    let mut debugger = Debugger::new();
+    assert_eq!(debugger.step_over(&mut vm, &region).unwrap(), Stop::Stepped);
+    assert_eq!(Some(vm.ip()), symbols.address_of("after_f"));
+    assert_eq!(vm.register(Register::Rdx), 3);
+    assert_eq!(vm.register(Register::Rsi), 2);
+    // anything else is a single step
+    assert_eq!(debugger.step_over(&mut vm, &region).unwrap(), Stop::Stepped);
+    assert_eq!(vm.register(Register::Rdi), 1);
+    assert_eq!(debugger.step_over(&mut vm, &region).unwrap(), Stop::Stepped);
+    assert_eq!(debugger.step_over(&mut vm, &region).unwrap(), Stop::Halted);
+  }
+
+  #[test]
+  fn step_out_returns_one_frame_at_a_time() {
+    let (mut vm, region, symbols) = start();
+    let mut debugger = Debugger::new();
+    let g = symbols.address_of("g").unwrap();
+    let id = debugger.add_breakpoint(Breakpoint::new(g));
+    assert_eq!(
+      debugger.run(&mut vm, &region).unwrap(),
+      Stop::Breakpoint(id)
+    );
+    assert_eq!(debugger.step_out(&mut vm, &region).unwrap(), Stop::Stepped);
+    assert_eq!(Some(vm.ip()), symbols.address_of("after_g"));
+    assert_eq!(debugger.step_out(&mut vm, &region).unwrap(), Stop::Stepped);
+    assert_eq!(Some(vm.ip()), symbols.address_of("after_f"));
+    assert_eq!(vm.register(Register::Rsi), 2);
+  }
+
+  #[test]
+  fn breakpoints_inside_callees_interrupt_steps() {
+    let (mut vm, region, symbols) = start();
+    let mut debugger = Debugger::new();
+    let g = symbols.address_of("g").unwrap();
+    let id = debugger.add_breakpoint(Breakpoint::new(g));
+    assert_eq!(
+      debugger.step_over(&mut vm, &region).unwrap(),
+      Stop::Breakpoint(id)
+    );
+    assert_eq!(vm.ip(), g);
+    // stepping again leaves the breakpoint behind
+    assert_eq!(debugger.step_over(&mut vm, &region).unwrap(), Stop::Stepped);
+    assert_eq!(vm.register(Register::Rdx), 3);
+  }
+
+  #[test]
+  fn resolves_labels_in_breakpoints_and_memory_reads() {
+    let assembled = asm::assemble(
+      "
+    irmovq $3, %rcx
+sort_loop:
+    irmovq $1, %rdx
+    subq %rdx, %rcx
+    jne sort_loop
+    halt
+    .align 8
+buffer:
+    .quad 0x11
+    .quad 0x22
+",
+    )
+    .unwrap();
+    let region = Chunk::from(assembled.bytes().to_vec());
+    let mut vm = VmBuilder::new().build();
+    vm.load(&region).unwrap();
+    let mut debugger = Debugger::new();
+    debugger.set_symbols(assembled.symbols().clone());
+    let sort_loop = assembled.symbols().address_of("sort_loop").unwrap();
+    let buffer = assembled.symbols().address_of("buffer").unwrap();
+
+    let breakpoint = debugger.parse_breakpoint("sort_loop if %rcx == 1").unwrap();
+    assert_eq!(breakpoint.address(), sort_loop);
+    let id = debugger.add_breakpoint(breakpoint);
+    assert_eq!(
+      debugger.run(&mut vm, &region).unwrap(),
+      Stop::Breakpoint(id)
+    );
+    assert_eq!(vm.register(Register::Rcx), 1);
+
+    assert_eq!(debugger.address(&vm, "buffer + 8").unwrap(), buffer + 8);
+    assert_eq!(
+      debugger.examine(&vm, "buffer + 8", 1).unwrap(),
+      [(buffer + 8, 0x22)]
+    );
+    let watchpoint = debugger
+      .parse_watchpoint("%rcx if %rcx < sort_loop")
+      .unwrap();
+    assert!(watchpoint.condition().is_some());
+    assert!(debugger.parse_breakpoint("nowhere").is_err());
+  }
+}
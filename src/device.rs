@@ -0,0 +1,6 @@
+//! Peripherals to attach to the vm bus with `Vm::attach_device`.
+
+pub mod block;
+pub mod clock;
+pub mod counters;
+pub mod framebuffer;
@@ -0,0 +1,301 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::bus::Device;
+use crate::snapshot::{self, Decoder, Encoder};
+use crate::{BLOCK_SIZE, Block};
+
+/// Values written to the command register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+  /// Copy the selected sector into the buffer.
+  Read = 1,
+  /// Copy the buffer into the selected sector.
+  Write = 2,
+}
+
+/// Values read from the status register, describing the last command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+  Ok = 0,
+  BadSector = 1,
+  IoError = 2,
+  BadCommand = 3,
+}
+
+#[derive(Debug)]
+enum Backing {
+  Memory(Vec<u8>),
+  File(File),
+}
+
+/// A disk of 512 byte sectors driven through memory mapped registers.
+///
+/// Programs store a sector number to `SECTOR`, then a `Command` to
+/// `COMMAND`, which completes immediately and sets `STATUS`. Sector data
+/// moves through the `BUFFER` window one block at a time, `SECTORS` reads as
+/// the capacity.
+#[derive(Debug)]
+pub struct BlockDevice {
+  backing: Backing,
+  sectors: usize,
+  sector: Block,
+  status: Status,
+  buffer: [u8; Self::SECTOR_SIZE],
+}
+
+impl BlockDevice {
+  pub const SECTOR_SIZE: usize = 512;
+
+  /// Register offsets.
+  pub const COMMAND: usize = 0x00;
+  pub const STATUS: usize = 0x08;
+  pub const SECTOR: usize = 0x10;
+  pub const SECTORS: usize = 0x18;
+  pub const BUFFER: usize = 0x20;
+  /// Bytes of address space to attach the device at.
+  pub const SIZE: usize = Self::BUFFER + Self::SECTOR_SIZE;
+
+  /// A zeroed in memory disk of `sectors` sectors.
+  pub fn in_memory(sectors: usize) -> Self {
+    Self::from_bytes(vec![0; sectors * Self::SECTOR_SIZE])
+  }
+
+  /// An in memory disk holding `bytes`, zero padded to a whole sector.
+  pub fn from_bytes(mut bytes: Vec<u8>) -> Self {
+    bytes.resize(bytes.len().next_multiple_of(Self::SECTOR_SIZE), 0);
+    let sectors = bytes.len() / Self::SECTOR_SIZE;
+    Self::new(Backing::Memory(bytes), sectors)
+  }
+
+  /// A disk backed by the file at `path`, opened for reading and writing.
+  /// A trailing partial sector is not addressable.
+  pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+    let file = File::options().read(true).write(true).open(path)?;
+    let sectors = file.metadata()?.len() as usize / Self::SECTOR_SIZE;
+    Ok(Self::new(Backing::File(file), sectors))
+  }
+
+  fn new(backing: Backing, sectors: usize) -> Self {
+    Self {
+      backing,
+      sectors,
+      sector: 0,
+      status: Status::Ok,
+      buffer: [0; Self::SECTOR_SIZE],
+    }
+  }
+
+  pub fn sectors(&self) -> usize {
+    self.sectors
+  }
+
+  pub fn status(&self) -> Status {
+    self.status
+  }
+
+  /// Contents of an in memory disk, `None` when backed by a file.
+  pub fn bytes(&self) -> Option<&[u8]> {
+    match &self.backing {
+      Backing::Memory(bytes) => Some(bytes),
+      Backing::File(_) => None,
+    }
+  }
+
+  fn execute(&mut self, command: Block) -> Status {
+    let command = match command {
+      1 => Command::Read,
+      2 => Command::Write,
+      _ => return Status::BadCommand,
+    };
+    let Some(sector) = usize::try_from(self.sector)
+      .ok()
+      .filter(|&sector| sector < self.sectors)
+    else {
+      return Status::BadSector;
+    };
+    let start = sector * Self::SECTOR_SIZE;
+    let result = match (&mut self.backing, command) {
+      (Backing::Memory(bytes), Command::Read) => {
+        self
+          .buffer
+          .copy_from_slice(&bytes[start..start + Self::SECTOR_SIZE]);
+        Ok(())
+      }
+      (Backing::Memory(bytes), Command::Write) => {
+        bytes[start..start + Self::SECTOR_SIZE].copy_from_slice(&self.buffer);
+        Ok(())
+      }
+      (Backing::File(file), Command::Read) => file
+        .seek(SeekFrom::Start(start as u64))
+        .and_then(|_| file.read_exact(&mut self.buffer)),
+      (Backing::File(file), Command::Write) => file
+        .seek(SeekFrom::Start(start as u64))
+        .and_then(|_| file.write_all(&self.buffer)),
+    };
+    match result {
+      Ok(()) => Status::Ok,
+      Err(_) => Status::IoError,
+    }
+  }
+}
+
+impl Status {
+  fn from_code(code: u64) -> Option<Self> {
+    let status = match code {
+      0 => Status::Ok,
+      1 => Status::BadSector,
+      2 => Status::IoError,
+      3 => Status::BadCommand,
+      _ => return None,
+    };
+    Some(status)
+  }
+}
+
+impl Device for BlockDevice {
+  /// Saves the registers and buffer, plus the contents of an in memory disk.
+  /// A file backed disk is the file, its contents are not saved.
+  fn save(&self) -> Vec<u8> {
+    let mut e = Encoder::new();
+    e.i64(self.sector)
+      .u64(self.status as u64)
+      .bytes(&self.buffer);
+    if let Backing::Memory(bytes) = &self.backing {
+      e.bytes(bytes);
+    }
+    e.finish()
+  }
+
+  fn load(&mut self, state: &[u8]) -> Result<(), snapshot::Error> {
+    let invalid = |what: &str| snapshot::Error::InvalidDeviceState(format!("block device {what}"));
+    let mut d = Decoder::new(state);
+    let sector = d.i64()?;
+    let status = Status::from_code(d.u64()?).ok_or_else(|| invalid("status"))?;
+    let buffer = d.bytes()?;
+    if buffer.len() != Self::SECTOR_SIZE {
+      return Err(invalid("buffer"));
+    }
+    if let Backing::Memory(bytes) = &mut self.backing {
+      let saved = d.bytes()?;
+      if saved.len() != bytes.len() {
+        return Err(invalid("capacity"));
+      }
+      bytes.copy_from_slice(saved);
+    }
+    self.sector = sector;
+    self.status = status;
+    self.buffer.copy_from_slice(buffer);
+    Ok(())
+  }
+
+  fn read(&mut self, offset: usize) -> Block {
+    match offset {
+      Self::STATUS => self.status as Block,
+      Self::SECTOR => self.sector,
+      Self::SECTORS => self.sectors as Block,
+      Self::BUFFER..Self::SIZE => {
+        let at = (offset - Self::BUFFER) / BLOCK_SIZE * BLOCK_SIZE;
+        let mut bytes = [0; BLOCK_SIZE];
+        bytes.copy_from_slice(&self.buffer[at..at + BLOCK_SIZE]);
+        Block::from_le_bytes(bytes)
+      }
+      _ => 0,
+    }
+  }
+
+  fn write(&mut self, offset: usize, value: Block) {
+    match offset {
+      Self::COMMAND => self.status = self.execute(value),
+      Self::SECTOR => self.sector = value,
+      Self::BUFFER..Self::SIZE => {
+        let at = (offset - Self::BUFFER) / BLOCK_SIZE * BLOCK_SIZE;
+        self.buffer[at..at + BLOCK_SIZE].copy_from_slice(&value.to_le_bytes());
+      }
+      _ => {}
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm;
+  use crate::builder::VmBuilder;
+  use crate::region::Chunk;
+  use crate::register::Register;
+
+  #[test]
+  fn programs_copy_sectors() {
+    let mut bytes = vec![0; 2 * BlockDevice::SECTOR_SIZE];
+    bytes[BlockDevice::SECTOR_SIZE..][..8].copy_from_slice(&41i64.to_le_bytes());
+    // device at 0x1000, reads sector 1, bumps its first block, writes sector 0
+    let region = Chunk::from(
+      asm::assemble(
+        "
+    irmovq $1, %rax
+    rmmovq %rax, 0x1010(%rbx)
+    rmmovq %rax, 0x1000(%rbx)
+    mrmovq 0x1020(%rbx), %rcx
+    addq %rax, %rcx
+    rmmovq %rcx, 0x1020(%rbx)
+    rmmovq %rbx, 0x1010(%rbx)
+    irmovq $2, %rax
+    rmmovq %rax, 0x1000(%rbx)
+    mrmovq 0x1008(%rbx), %rdx
+    mrmovq 0x1018(%rbx), %rsi
+    halt
+",
+      )
+      .unwrap()
+      .bytes()
+      .to_vec(),
+    );
+    let mut vm = VmBuilder::new().build();
+    vm.load(&region).unwrap();
+    let id = vm
+      .attach_device(
+        0x1000..0x1000 + BlockDevice::SIZE,
+        BlockDevice::from_bytes(bytes),
+      )
+      .unwrap();
+    vm.run(&region).unwrap();
+    assert_eq!(vm.register(Register::Rdx), Status::Ok as Block);
+    assert_eq!(vm.register(Register::Rsi), 2);
+    let disk = vm.bus().device::<BlockDevice>(id).unwrap().bytes().unwrap();
+    assert_eq!(disk[..8], 42i64.to_le_bytes());
+    assert_eq!(disk[BlockDevice::SECTOR_SIZE..][..8], 41i64.to_le_bytes());
+  }
+
+  #[test]
+  fn reports_bad_sectors_and_commands() {
+    let mut disk = BlockDevice::in_memory(1);
+    disk.write(BlockDevice::SECTOR, 1);
+    disk.write(BlockDevice::COMMAND, Command::Read as Block);
+    assert_eq!(disk.status(), Status::BadSector);
+    disk.write(BlockDevice::SECTOR, -1);
+    disk.write(BlockDevice::COMMAND, Command::Write as Block);
+    assert_eq!(disk.status(), Status::BadSector);
+    disk.write(BlockDevice::SECTOR, 0);
+    disk.write(BlockDevice::COMMAND, 7);
+    assert_eq!(disk.read(BlockDevice::STATUS), Status::BadCommand as Block);
+  }
+
+  #[test]
+  fn file_backed_disks_write_through() {
+    let path = std::env::temp_dir().join(format!("y86-block-{}", std::process::id()));
+    std::fs::write(&path, vec![0; BlockDevice::SECTOR_SIZE + 100]).unwrap();
+    let mut disk = BlockDevice::open(&path).unwrap();
+    assert_eq!(disk.sectors(), 1);
+    assert!(disk.bytes().is_none());
+    disk.write(BlockDevice::BUFFER + 8, 0x0102);
+    disk.write(BlockDevice::COMMAND, Command::Write as Block);
+    assert_eq!(disk.status(), Status::Ok);
+    drop(disk);
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(bytes[8..10], [0x02, 0x01]);
+    assert_eq!(bytes.len(), BlockDevice::SECTOR_SIZE + 100);
+  }
+}
@@ -0,0 +1,167 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::Block;
+use crate::bus::Device;
+use crate::snapshot::{self, Decoder, Encoder};
+
+#[derive(Debug, Clone, Copy)]
+enum Source {
+  Host(Instant),
+  Virtual { ns_per_instruction: u64, epoch: u64 },
+}
+
+/// Exposes time to guest programs as two read only registers, nanoseconds
+/// since the clock was attached or reset at `MONOTONIC`, and nanoseconds
+/// since the unix epoch at `WALL`.
+///
+/// A virtual clock advances a fixed amount per retired instruction instead
+/// of following the host, so runs are reproducible.
+#[derive(Debug, Clone)]
+pub struct Clock {
+  source: Source,
+  // nanoseconds elapsed on a virtual clock
+  elapsed: u64,
+}
+
+impl Clock {
+  pub const MONOTONIC: usize = 0x00;
+  pub const WALL: usize = 0x08;
+  /// Bytes of address space to attach the clock at.
+  pub const SIZE: usize = 0x10;
+
+  /// Follows the host clocks.
+  pub fn host() -> Self {
+    Self {
+      source: Source::Host(Instant::now()),
+      elapsed: 0,
+    }
+  }
+
+  /// Advances by `ns_per_instruction` for every retired instruction, with
+  /// wall clock time starting at `epoch` nanoseconds past the unix epoch.
+  pub fn virtual_time(ns_per_instruction: u64, epoch: u64) -> Self {
+    Self {
+      source: Source::Virtual {
+        ns_per_instruction,
+        epoch,
+      },
+      elapsed: 0,
+    }
+  }
+
+  pub fn is_virtual(&self) -> bool {
+    matches!(self.source, Source::Virtual { .. })
+  }
+
+  /// Nanoseconds since the clock was attached or reset.
+  pub fn monotonic(&self) -> u64 {
+    match self.source {
+      Source::Host(start) => start.elapsed().as_nanos() as u64,
+      Source::Virtual { .. } => self.elapsed,
+    }
+  }
+
+  /// Nanoseconds since the unix epoch.
+  pub fn wall(&self) -> u64 {
+    match self.source {
+      Source::Host(_) => SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64),
+      Source::Virtual { epoch, .. } => epoch + self.elapsed,
+    }
+  }
+}
+
+impl Device for Clock {
+  fn read(&mut self, offset: usize) -> Block {
+    match offset {
+      Self::MONOTONIC => self.monotonic() as Block,
+      Self::WALL => self.wall() as Block,
+      _ => 0,
+    }
+  }
+
+  fn write(&mut self, _offset: usize, _value: Block) {}
+
+  fn tick(&mut self) {
+    if let Source::Virtual {
+      ns_per_instruction, ..
+    } = self.source
+    {
+      self.elapsed += ns_per_instruction;
+    }
+  }
+
+  /// Only virtual time is saved, a host clock keeps following the host.
+  fn save(&self) -> Vec<u8> {
+    Encoder::new().u64(self.elapsed).finish()
+  }
+
+  fn load(&mut self, state: &[u8]) -> Result<(), snapshot::Error> {
+    self.elapsed = Decoder::new(state).u64()?;
+    Ok(())
+  }
+
+  fn deterministic(&self) -> bool {
+    self.is_virtual()
+  }
+
+  fn reset(&mut self) {
+    self.elapsed = 0;
+    if let Source::Host(start) = &mut self.source {
+      *start = Instant::now();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm;
+  use crate::builder::VmBuilder;
+  use crate::region::Chunk;
+  use crate::register::Register;
+  use crate::rng;
+
+  const READ_TWICE: &str = "
+    nop
+    nop
+    mrmovq 0x1000(%rbx), %rax
+    mrmovq 0x1008(%rbx), %rcx
+    halt
+";
+
+  #[test]
+  fn virtual_time_follows_retired_instructions() {
+    let region = Chunk::from(asm::assemble(READ_TWICE).unwrap().bytes().to_vec());
+    let mut vm = VmBuilder::new().build();
+    vm.load(&region).unwrap();
+    let id = vm
+      .attach_device(0x1000..0x1000 + Clock::SIZE, Clock::virtual_time(10, 1000))
+      .unwrap();
+    vm.run(&region).unwrap();
+    assert_eq!(vm.register(Register::Rax), 20);
+    assert_eq!(vm.register(Register::Rcx), 1030);
+    assert!(vm.nondeterminism().is_empty());
+
+    let clock = vm.bus().device::<Clock>(id).unwrap().clone();
+    let mut restored = Clock::virtual_time(10, 1000);
+    restored.load(&clock.save()).unwrap();
+    assert_eq!(restored.monotonic(), clock.monotonic());
+    vm.reset();
+    assert_eq!(vm.bus().device::<Clock>(id).unwrap().monotonic(), 0);
+  }
+
+  #[test]
+  fn host_clocks_are_nondeterministic() {
+    let mut vm = VmBuilder::new().build();
+    let id = vm
+      .attach_device(0x1000..0x1000 + Clock::SIZE, Clock::host())
+      .unwrap();
+    assert!(matches!(vm.nondeterminism()[..], [rng::Source::Host(host)] if host == id));
+    let mut clock = Clock::host();
+    let wall = clock.read(Clock::WALL);
+    assert!(wall > 0 && clock.read(Clock::WALL) >= wall);
+    assert_eq!(clock.read(Clock::SIZE), 0);
+  }
+}
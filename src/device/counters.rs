@@ -0,0 +1,156 @@
+use crate::Block;
+use crate::bus::{Counters, Device};
+use crate::snapshot::{self, Decoder, Encoder};
+
+/// Exposes vm statistics to guest programs as read only registers, retired
+/// instructions at `INSTRUCTIONS`, cycles at `CYCLES`, data cache misses at
+/// `CACHE_MISSES` and mispredicted conditional jumps at `MISPREDICTIONS`.
+///
+/// Counts are sampled after every retired instruction, so a read sees the
+/// statistics as they stood before the reading instruction ran.
+#[derive(Debug, Clone, Default)]
+pub struct PerfCounters {
+  counters: Counters,
+}
+
+impl PerfCounters {
+  pub const INSTRUCTIONS: usize = 0x00;
+  pub const CYCLES: usize = 0x08;
+  pub const CACHE_MISSES: usize = 0x10;
+  pub const MISPREDICTIONS: usize = 0x18;
+  /// Bytes of address space to attach the counters at.
+  pub const SIZE: usize = 0x20;
+
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// The last sample taken.
+  pub fn counters(&self) -> &Counters {
+    &self.counters
+  }
+}
+
+impl Device for PerfCounters {
+  fn read(&mut self, offset: usize) -> Block {
+    let counters = &self.counters;
+    match offset {
+      Self::INSTRUCTIONS => counters.instructions as Block,
+      Self::CYCLES => counters.cycles as Block,
+      Self::CACHE_MISSES => counters.cache_misses as Block,
+      Self::MISPREDICTIONS => counters.mispredictions as Block,
+      _ => 0,
+    }
+  }
+
+  fn write(&mut self, _offset: usize, _value: Block) {}
+
+  fn sample(&mut self, counters: &Counters) {
+    self.counters = *counters;
+  }
+
+  fn save(&self) -> Vec<u8> {
+    let counters = &self.counters;
+    Encoder::new()
+      .u64(counters.instructions)
+      .u64(counters.cycles)
+      .u64(counters.cache_misses)
+      .u64(counters.mispredictions)
+      .finish()
+  }
+
+  fn load(&mut self, state: &[u8]) -> Result<(), snapshot::Error> {
+    let mut d = Decoder::new(state);
+    self.counters = Counters {
+      instructions: d.u64()?,
+      cycles: d.u64()?,
+      cache_misses: d.u64()?,
+      mispredictions: d.u64()?,
+    };
+    Ok(())
+  }
+
+  /// The vm statistics start over on reset, and so do the counters.
+  fn reset(&mut self) {
+    self.counters = Counters::default();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm;
+  use crate::builder::VmBuilder;
+  use crate::region::Chunk;
+  use crate::register::Register;
+
+  #[test]
+  fn programs_read_counts_from_before_the_read() {
+    let region = Chunk::from(
+      asm::assemble(
+        "
+    nop
+    nop
+    mrmovq 0x1000(%rbx), %rax
+    mrmovq 0x1008(%rbx), %rcx
+    mrmovq 0x1008(%rbx), %rdx
+    mrmovq 0x1010(%rbx), %rsi
+    halt
+",
+      )
+      .unwrap()
+      .bytes()
+      .to_vec(),
+    );
+    let mut vm = VmBuilder::new().build();
+    vm.load(&region).unwrap();
+    let id = vm
+      .attach_device(0x1000..0x1000 + PerfCounters::SIZE, PerfCounters::new())
+      .unwrap();
+    vm.run(&region).unwrap();
+    assert_eq!(vm.register(Register::Rax), 2);
+    assert!(vm.register(Register::Rdx) > vm.register(Register::Rcx));
+    // no cache model, so nothing misses
+    assert_eq!(vm.register(Register::Rsi), 0);
+
+    let counters = *vm.bus().device::<PerfCounters>(id).unwrap().counters();
+    assert_eq!(counters.instructions, 7);
+    assert_eq!(counters.cycles, vm.timing().cycles());
+    let mut restored = PerfCounters::new();
+    restored.load(&PerfCounters { counters }.save()).unwrap();
+    assert_eq!(*restored.counters(), counters);
+    vm.reset();
+    assert_eq!(
+      *vm.bus().device::<PerfCounters>(id).unwrap().counters(),
+      Counters::default()
+    );
+  }
+
+  #[test]
+  fn counts_fall_through_branches_as_mispredicted() {
+    let region = Chunk::from(
+      asm::assemble(
+        "
+    irmovq $1, %rax
+    andq %rax, %rax
+    je skip
+    jne skip
+    halt
+skip:
+    mrmovq 0x1018(%rbx), %rcx
+    halt
+",
+      )
+      .unwrap()
+      .bytes()
+      .to_vec(),
+    );
+    let mut vm = VmBuilder::new().build();
+    vm.load(&region).unwrap();
+    vm.attach_device(0x1000..0x1000 + PerfCounters::SIZE, PerfCounters::new())
+      .unwrap();
+    vm.run(&region).unwrap();
+    assert_eq!(vm.register(Register::Rcx), 1);
+    assert_eq!(vm.timing().mispredictions(), 1);
+  }
+}
@@ -0,0 +1,165 @@
+use std::fmt;
+
+use crate::bus::Device;
+use crate::snapshot::{self, Decoder, Encoder};
+use crate::{BLOCK_SIZE, Block};
+
+/// A 64 by 32 monochrome display. Each row is one block, bit `x` of the
+/// block at offset `y * 8` is the pixel in column `x` of row `y`. Reads
+/// return the current row so programs can update pixels with `andq` and
+/// `xorq`. Offsets past the last row read as zero and ignore writes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+  rows: [u64; Self::HEIGHT],
+}
+
+impl Framebuffer {
+  pub const WIDTH: usize = 64;
+  pub const HEIGHT: usize = 32;
+  /// Bytes of address space to attach the framebuffer at.
+  pub const SIZE: usize = Self::HEIGHT * BLOCK_SIZE;
+
+  pub fn new() -> Self {
+    Self {
+      rows: [0; Self::HEIGHT],
+    }
+  }
+
+  pub fn pixel(&self, x: usize, y: usize) -> bool {
+    x < Self::WIDTH && y < Self::HEIGHT && (self.rows[y] >> x) & 1 == 1
+  }
+
+  pub fn set_pixel(&mut self, x: usize, y: usize, on: bool) {
+    if x < Self::WIDTH && y < Self::HEIGHT {
+      let mask = 1 << x;
+      if on {
+        self.rows[y] |= mask;
+      } else {
+        self.rows[y] &= !mask;
+      }
+    }
+  }
+
+  /// Raw rows, top to bottom, for front ends doing their own rendering.
+  pub fn rows(&self) -> &[u64; Self::HEIGHT] {
+    &self.rows
+  }
+
+  pub fn clear(&mut self) {
+    self.rows = [0; Self::HEIGHT];
+  }
+
+  /// Draws the display with `on` and `off` characters, one line per row.
+  pub fn render(&self, on: char, off: char) -> String {
+    let mut out = String::with_capacity((Self::WIDTH + 1) * Self::HEIGHT);
+    for y in 0..Self::HEIGHT {
+      out.extend((0..Self::WIDTH).map(|x| if self.pixel(x, y) { on } else { off }));
+      out.push('\n');
+    }
+    out
+  }
+}
+
+impl Default for Framebuffer {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Device for Framebuffer {
+  fn read(&mut self, offset: usize) -> Block {
+    self
+      .rows
+      .get(offset / BLOCK_SIZE)
+      .map_or(0, |&row| row as Block)
+  }
+
+  fn write(&mut self, offset: usize, value: Block) {
+    if let Some(row) = self.rows.get_mut(offset / BLOCK_SIZE) {
+      *row = value as u64;
+    }
+  }
+
+  fn reset(&mut self) {
+    self.clear();
+  }
+
+  fn save(&self) -> Vec<u8> {
+    let mut e = Encoder::new();
+    for &row in &self.rows {
+      e.u64(row);
+    }
+    e.finish()
+  }
+
+  fn load(&mut self, state: &[u8]) -> Result<(), snapshot::Error> {
+    let mut d = Decoder::new(state);
+    for row in &mut self.rows {
+      *row = d.u64()?;
+    }
+    Ok(())
+  }
+}
+
+/// Renders lit pixels as `#` and dark ones as `.`.
+impl fmt::Display for Framebuffer {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.render('#', '.'))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm;
+  use crate::builder::VmBuilder;
+  use crate::region::Chunk;
+
+  #[test]
+  fn programs_draw_rows() {
+    let region = Chunk::from(
+      asm::assemble(
+        "
+    irmovq $5, %rax
+    rmmovq %rax, 0x1008(%rbx)
+    mrmovq 0x1008(%rbx), %rcx
+    irmovq $4, %rdx
+    xorq %rdx, %rcx
+    rmmovq %rcx, 0x1010(%rbx)
+    halt
+",
+      )
+      .unwrap()
+      .bytes()
+      .to_vec(),
+    );
+    let mut vm = VmBuilder::new().build();
+    vm.load(&region).unwrap();
+    let id = vm
+      .attach_device(0x1000..0x1000 + Framebuffer::SIZE, Framebuffer::new())
+      .unwrap();
+    vm.run(&region).unwrap();
+    let display = vm.bus().device::<Framebuffer>(id).unwrap();
+    assert_eq!(display.rows()[..3], [0, 5, 1]);
+    assert!(display.pixel(0, 1) && !display.pixel(1, 1) && display.pixel(2, 1));
+    let lines: Vec<_> = display.to_string().lines().map(str::to_owned).collect();
+    assert_eq!(lines.len(), Framebuffer::HEIGHT);
+    assert!(lines[1].starts_with("#.#."));
+  }
+
+  #[test]
+  fn ignores_pixels_off_screen() {
+    let mut display = Framebuffer::new();
+    display.set_pixel(Framebuffer::WIDTH, 0, true);
+    display.set_pixel(0, Framebuffer::HEIGHT, true);
+    assert_eq!(display, Framebuffer::new());
+    assert_eq!(display.read(Framebuffer::SIZE), 0);
+
+    display.set_pixel(63, 31, true);
+    let mut restored = Framebuffer::new();
+    restored.load(&display.save()).unwrap();
+    assert!(restored.pixel(63, 31));
+    restored.reset();
+    assert_eq!(restored, Framebuffer::new());
+  }
+}
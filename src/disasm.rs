@@ -0,0 +1,436 @@
+use std::fmt::{self, Write};
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+
+use crate::Block;
+use crate::opcode::{self, Condition, Encoding, Isa, MAX_INSTRUCTION_LEN, OpFun, Opcode, Operands};
+use crate::register::{self, Register};
+use crate::symbol::Symbols;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("truncated instruction at address {0:#x}")]
+  Truncated(usize),
+
+  #[error("opcode error - {0}")]
+  OpcodeError(#[from] opcode::Error),
+
+  #[error("register error - {0}")]
+  RegisterError(#[from] register::Error),
+
+  #[error("invalid color mode {0:?}, expected auto, always or never")]
+  InvalidColorMode(String),
+}
+
+/// A fully decoded instruction, operands included.
+#[derive(Debug)]
+pub(crate) enum Instruction {
+  Halt,
+  Nop,
+  Rrmovq(Register, Register),
+  Cmovxx(Condition, Register, Register),
+  Irmovq(Register, Block),
+  Rmmovq(Register, Register, Block),
+  Mrmovq(Register, Register, Block),
+  Opq(OpFun, Register, Register),
+  Jxx(Condition, usize),
+  Call(usize),
+  Ret,
+  Pushq(Register),
+  Popq(Register),
+  Casq(Register, Register, Block),
+}
+
+impl Instruction {
+  /// The table entry the instruction was decoded from.
+  pub(crate) fn opcode(&self) -> Opcode {
+    match *self {
+      Instruction::Halt => Opcode::Halt,
+      Instruction::Nop => Opcode::Nop,
+      // an unconditional move is encoded as `rrmovq`
+      Instruction::Rrmovq(..) | Instruction::Cmovxx(Condition::Always, ..) => Opcode::Rrmovq,
+      Instruction::Cmovxx(cond, ..) => Opcode::Cmovxx(cond),
+      Instruction::Irmovq(..) => Opcode::Irmovq,
+      Instruction::Rmmovq(..) => Opcode::Rmmovq,
+      Instruction::Mrmovq(..) => Opcode::Mrmovq,
+      Instruction::Opq(fun, ..) => Opcode::Opq(fun),
+      Instruction::Jxx(cond, _) => Opcode::Jxx(cond),
+      Instruction::Call(_) => Opcode::Call,
+      Instruction::Ret => Opcode::Ret,
+      Instruction::Pushq(_) => Opcode::Pushq,
+      Instruction::Popq(_) => Opcode::Popq,
+      Instruction::Casq(..) => Opcode::Casq,
+    }
+  }
+
+  /// Registers read in decode, the source operands of the pipe design plus
+  /// the `%rax` casq compares against.
+  pub(crate) fn reads(&self) -> [Option<Register>; 3] {
+    match *self {
+      Instruction::Halt | Instruction::Nop | Instruction::Irmovq(..) | Instruction::Jxx(..) => {
+        [None, None, None]
+      }
+      Instruction::Rrmovq(ra, _) | Instruction::Cmovxx(_, ra, _) => [Some(ra), None, None],
+      Instruction::Rmmovq(ra, rb, _) | Instruction::Opq(_, ra, rb) => [Some(ra), Some(rb), None],
+      Instruction::Mrmovq(_, rb, _) => [Some(rb), None, None],
+      Instruction::Call(_) | Instruction::Ret | Instruction::Popq(_) => {
+        [Some(Register::Rsp), None, None]
+      }
+      Instruction::Pushq(ra) => [Some(ra), Some(Register::Rsp), None],
+      Instruction::Casq(ra, rb, _) => [Some(ra), Some(rb), Some(Register::Rax)],
+    }
+  }
+
+  /// Registers written back, conditional moves count even when not taken.
+  pub(crate) fn writes(&self) -> [Option<Register>; 2] {
+    match *self {
+      Instruction::Halt | Instruction::Nop | Instruction::Rmmovq(..) | Instruction::Jxx(..) => {
+        [None, None]
+      }
+      Instruction::Rrmovq(_, rb)
+      | Instruction::Cmovxx(_, _, rb)
+      | Instruction::Irmovq(rb, _)
+      | Instruction::Opq(_, _, rb) => [Some(rb), None],
+      Instruction::Mrmovq(ra, ..) => [Some(ra), None],
+      Instruction::Call(_) | Instruction::Ret | Instruction::Pushq(_) => {
+        [Some(Register::Rsp), None]
+      }
+      Instruction::Popq(ra) => [Some(ra), Some(Register::Rsp)],
+      Instruction::Casq(..) => [Some(Register::Rax), None],
+    }
+  }
+
+  /// Register written with a value read from memory, if any.
+  pub(crate) fn loads(&self) -> Option<Register> {
+    match *self {
+      Instruction::Mrmovq(ra, ..) | Instruction::Popq(ra) => Some(ra),
+      Instruction::Casq(..) => Some(Register::Rax),
+      _ => None,
+    }
+  }
+}
+
+struct Decoder<'b> {
+  bytes: &'b [u8],
+  start: usize,
+  pos: usize,
+}
+
+impl Decoder<'_> {
+  fn eat(&mut self) -> Result<u8, Error> {
+    let byte = *self
+      .bytes
+      .get(self.pos)
+      .ok_or(Error::Truncated(self.start))?;
+    self.pos += 1;
+    Ok(byte)
+  }
+
+  fn eat_registers(&mut self) -> Result<(u8, u8), Error> {
+    let byte = self.eat()?;
+    Ok((byte >> 4, byte & 0xf))
+  }
+
+  fn eat_immediate(&mut self) -> Result<Block, Error> {
+    let mut bytes = [0u8; 8];
+    for byte in &mut bytes {
+      *byte = self.eat()?;
+    }
+    Ok(Block::from_le_bytes(bytes))
+  }
+}
+
+/// Decodes the instruction starting at `address`, returning it along with its
+/// encoded length.
+pub(crate) fn decode(bytes: &[u8], address: usize) -> Result<(Instruction, usize), Error> {
+  decode_isa(bytes, address, Isa::Extended)
+}
+
+fn decode_isa(bytes: &[u8], address: usize, isa: Isa) -> Result<(Instruction, usize), Error> {
+  let mut d = Decoder {
+    bytes,
+    start: address,
+    pos: address,
+  };
+  let byte = d.eat()?;
+  // operand fields are read from the encoding, then given their meaning
+  let operands = Encoding::of_isa(byte, isa)?.operands;
+  let (ra, rb) = match operands {
+    Operands::None | Operands::Destination => (0xf, 0xf),
+    _ => d.eat_registers()?,
+  };
+  let value = match operands {
+    Operands::Immediate | Operands::Memory | Operands::Destination => d.eat_immediate()?,
+    _ => 0,
+  };
+  let reg = Register::try_from;
+  let instruction = match Opcode::try_from(byte)? {
+    Opcode::Halt => Instruction::Halt,
+    Opcode::Nop => Instruction::Nop,
+    Opcode::Rrmovq => Instruction::Rrmovq(reg(ra)?, reg(rb)?),
+    Opcode::Cmovxx(cond) => Instruction::Cmovxx(cond, reg(ra)?, reg(rb)?),
+    Opcode::Irmovq => Instruction::Irmovq(reg(rb)?, value),
+    Opcode::Rmmovq => Instruction::Rmmovq(reg(ra)?, reg(rb)?, value),
+    Opcode::Mrmovq => Instruction::Mrmovq(reg(ra)?, reg(rb)?, value),
+    Opcode::Opq(fun) => Instruction::Opq(fun, reg(ra)?, reg(rb)?),
+    Opcode::Jxx(cond) => Instruction::Jxx(cond, value as usize),
+    Opcode::Call => Instruction::Call(value as usize),
+    Opcode::Ret => Instruction::Ret,
+    Opcode::Pushq => Instruction::Pushq(reg(ra)?),
+    Opcode::Popq => Instruction::Popq(reg(ra)?),
+    Opcode::Casq => Instruction::Casq(reg(ra)?, reg(rb)?, value),
+  };
+  Ok((instruction, d.pos - address))
+}
+
+/// Controls whether formatted output contains ansi color escapes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorMode {
+  /// Color only when stdout is a terminal.
+  #[default]
+  Auto,
+  Always,
+  Never,
+}
+
+impl ColorMode {
+  pub fn enabled(self) -> bool {
+    match self {
+      ColorMode::Auto => io::stdout().is_terminal(),
+      ColorMode::Always => true,
+      ColorMode::Never => false,
+    }
+  }
+}
+
+impl FromStr for ColorMode {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "auto" => Ok(ColorMode::Auto),
+      "always" => Ok(ColorMode::Always),
+      "never" => Ok(ColorMode::Never),
+      _ => Err(Error::InvalidColorMode(s.to_string())),
+    }
+  }
+}
+
+const MNEMONIC: &str = "\x1b[1;34m";
+const REGISTER: &str = "\x1b[32m";
+const IMMEDIATE: &str = "\x1b[33m";
+const SYMBOL: &str = "\x1b[35m";
+const ADDRESS: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+/// How disassembled instructions are rendered.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Style<'s> {
+  color: bool,
+  symbols: Option<&'s Symbols>,
+}
+
+impl<'s> Style<'s> {
+  pub fn new(mode: ColorMode) -> Self {
+    Self {
+      color: mode.enabled(),
+      symbols: None,
+    }
+  }
+
+  /// Substitutes known symbol names for branch and call targets.
+  pub fn with_symbols(mut self, symbols: &'s Symbols) -> Self {
+    self.symbols = Some(symbols);
+    self
+  }
+
+  fn paint(&self, out: &mut String, color: &str, text: fmt::Arguments<'_>) {
+    if self.color {
+      let _ = write!(out, "{color}{text}{RESET}");
+    } else {
+      let _ = out.write_fmt(text);
+    }
+  }
+
+  fn register(&self, out: &mut String, reg: Register) {
+    self.paint(out, REGISTER, format_args!("{reg}"));
+  }
+
+  fn target(&self, out: &mut String, address: usize) {
+    match self.symbols.and_then(|symbols| symbols.name_of(address)) {
+      Some(name) => self.paint(out, SYMBOL, format_args!("{name}")),
+      None => self.paint(out, IMMEDIATE, format_args!("{address:#x}")),
+    }
+  }
+
+  pub(crate) fn instruction(&self, instruction: &Instruction) -> String {
+    let mut out = String::new();
+    let mnemonic = instruction.opcode().mnemonic();
+    self.paint(&mut out, MNEMONIC, format_args!("{mnemonic}"));
+    match *instruction {
+      Instruction::Halt | Instruction::Nop | Instruction::Ret => {}
+      Instruction::Rrmovq(ra, rb)
+      | Instruction::Cmovxx(_, ra, rb)
+      | Instruction::Opq(_, ra, rb) => {
+        out.push(' ');
+        self.register(&mut out, ra);
+        out.push_str(", ");
+        self.register(&mut out, rb);
+      }
+      Instruction::Irmovq(rb, imm) => {
+        out.push(' ');
+        self.paint(&mut out, IMMEDIATE, format_args!("${imm}"));
+        out.push_str(", ");
+        self.register(&mut out, rb);
+      }
+      Instruction::Rmmovq(ra, rb, disp) | Instruction::Casq(ra, rb, disp) => {
+        out.push(' ');
+        self.register(&mut out, ra);
+        out.push_str(", ");
+        self.paint(&mut out, IMMEDIATE, format_args!("{disp}"));
+        out.push('(');
+        self.register(&mut out, rb);
+        out.push(')');
+      }
+      Instruction::Mrmovq(ra, rb, disp) => {
+        out.push(' ');
+        self.paint(&mut out, IMMEDIATE, format_args!("{disp}"));
+        out.push('(');
+        self.register(&mut out, rb);
+        out.push_str("), ");
+        self.register(&mut out, ra);
+      }
+      Instruction::Jxx(_, dest) | Instruction::Call(dest) => {
+        out.push(' ');
+        self.target(&mut out, dest);
+      }
+      Instruction::Pushq(ra) | Instruction::Popq(ra) => {
+        out.push(' ');
+        self.register(&mut out, ra);
+      }
+    }
+    out
+  }
+}
+
+/// One decoded instruction along with where it came from.
+#[derive(Debug)]
+pub struct Disassembled {
+  address: usize,
+  bytes: [u8; MAX_INSTRUCTION_LEN],
+  len: usize,
+  instruction: Instruction,
+}
+
+impl Disassembled {
+  fn new(address: usize, bytes: &[u8], instruction: Instruction) -> Self {
+    let mut buffer = [0; MAX_INSTRUCTION_LEN];
+    buffer[..bytes.len()].copy_from_slice(bytes);
+    Self {
+      address,
+      bytes: buffer,
+      len: bytes.len(),
+      instruction,
+    }
+  }
+
+  pub fn address(&self) -> usize {
+    self.address
+  }
+
+  pub(crate) fn instruction(&self) -> &Instruction {
+    &self.instruction
+  }
+
+  /// Length, operand and access metadata shared by the instruction's opcode.
+  pub fn encoding(&self) -> Encoding {
+    Encoding::of(self.bytes[0]).expect("disassembled instruction has a valid opcode")
+  }
+
+  /// Raw encoding of the instruction.
+  pub fn bytes(&self) -> &[u8] {
+    &self.bytes[..self.len]
+  }
+
+  /// Renders the instruction in assembly syntax, without address or encoding.
+  pub fn text(&self, style: &Style<'_>) -> String {
+    style.instruction(&self.instruction)
+  }
+
+  /// Renders an objdump style line with address, encoding, symbol label and
+  /// assembly text.
+  pub fn line(&self, style: &Style<'_>) -> String {
+    let mut out = String::new();
+    if let Some(name) = style
+      .symbols
+      .and_then(|symbols| symbols.name_of(self.address))
+    {
+      style.paint(&mut out, SYMBOL, format_args!("{name}:"));
+      out.push('\n');
+    }
+    let encoding = self
+      .bytes
+      .iter()
+      .map(|b| format!("{b:02x}"))
+      .collect::<Vec<_>>()
+      .join(" ");
+    style.paint(
+      &mut out,
+      ADDRESS,
+      format_args!("{:#06x}: {encoding:<29}", self.address),
+    );
+    out.push(' ');
+    out.push_str(&self.text(style));
+    out
+  }
+}
+
+/// Decodes the single instruction at `address`.
+pub fn disassemble_at(bytes: &[u8], address: usize) -> Result<Disassembled, Error> {
+  disassemble_at_isa(bytes, address, Isa::Extended)
+}
+
+/// Decodes like `disassemble_at`, failing on instructions `isa` lacks.
+pub fn disassemble_at_isa(bytes: &[u8], address: usize, isa: Isa) -> Result<Disassembled, Error> {
+  let (instruction, len) = decode_isa(bytes, address, isa)?;
+  Ok(Disassembled::new(
+    address,
+    &bytes[address..address + len],
+    instruction,
+  ))
+}
+
+/// Decodes `bytes`, the whole encoding of one instruction fetched from
+/// `address`, for reporting what executed whatever memory holds by now.
+pub(crate) fn disassemble_fetched(bytes: &[u8], address: usize) -> Result<Disassembled, Error> {
+  let (instruction, _) = decode(bytes, 0)?;
+  Ok(Disassembled::new(address, bytes, instruction))
+}
+
+/// Linear sweep from `start` to the end of `bytes`, stopping after the first
+/// byte sequence that fails to decode.
+pub fn disassemble(
+  bytes: &[u8],
+  start: usize,
+) -> impl Iterator<Item = Result<Disassembled, Error>> + '_ {
+  disassemble_isa(bytes, start, Isa::Extended)
+}
+
+/// Linear sweep like `disassemble`, also stopping at instructions `isa`
+/// lacks.
+pub fn disassemble_isa(
+  bytes: &[u8],
+  start: usize,
+  isa: Isa,
+) -> impl Iterator<Item = Result<Disassembled, Error>> + '_ {
+  let mut address = Some(start);
+  std::iter::from_fn(move || {
+    let at = address.filter(|&at| at < bytes.len())?;
+    let result = disassemble_at_isa(bytes, at, isa);
+    address = match &result {
+      Ok(d) => Some(at + d.bytes.len()),
+      Err(_) => None,
+    };
+    Some(result)
+  })
+}
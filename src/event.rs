@@ -0,0 +1,214 @@
+use std::fmt;
+use std::sync::mpsc;
+
+use crate::Block;
+use crate::register::Register;
+
+/// Something observable that happened while the vm executed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+  /// An instruction completed, `step` is its zero based index.
+  InstructionRetired {
+    step: usize,
+    address: usize,
+  },
+  MemoryWritten {
+    address: usize,
+    value: Block,
+  },
+  /// A conditional jump whose condition held.
+  BranchTaken {
+    address: usize,
+    target: usize,
+  },
+  /// The instruction at `address` failed.
+  FaultRaised {
+    address: usize,
+    message: String,
+  },
+  /// The instruction at `ip` stored over code placed by `Vm::load`.
+  CodeOverwritten {
+    ip: usize,
+    address: usize,
+  },
+  /// A memory mapped device was accessed.
+  DeviceIo {
+    address: usize,
+    value: Block,
+    write: bool,
+  },
+  /// The instruction at `ip` read a register or memory block nothing had
+  /// written yet, see `VmBuilder::uninitialized_reads`.
+  UninitializedRead {
+    ip: usize,
+    location: Location,
+  },
+  /// The value of the watch expression `id` changed with the instruction
+  /// `step`, see `Vm::watch_expr`. `None` stands for an evaluation error,
+  /// such as reading past the end of memory.
+  ExprChanged {
+    id: usize,
+    step: usize,
+    old: Option<Block>,
+    new: Option<Block>,
+  },
+}
+
+/// Register or aligned memory block read by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+  Register(Register),
+  Memory(usize),
+}
+
+impl fmt::Display for Location {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Location::Register(reg) => write!(f, "{reg}"),
+      Location::Memory(address) => write!(f, "mem[{address:#x}]"),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+  InstructionRetired,
+  MemoryWritten,
+  BranchTaken,
+  FaultRaised,
+  CodeOverwritten,
+  DeviceIo,
+  UninitializedRead,
+  ExprChanged,
+}
+
+impl Event {
+  pub fn kind(&self) -> EventKind {
+    match self {
+      Event::InstructionRetired { .. } => EventKind::InstructionRetired,
+      Event::MemoryWritten { .. } => EventKind::MemoryWritten,
+      Event::BranchTaken { .. } => EventKind::BranchTaken,
+      Event::FaultRaised { .. } => EventKind::FaultRaised,
+      Event::CodeOverwritten { .. } => EventKind::CodeOverwritten,
+      Event::DeviceIo { .. } => EventKind::DeviceIo,
+      Event::UninitializedRead { .. } => EventKind::UninitializedRead,
+      Event::ExprChanged { .. } => EventKind::ExprChanged,
+    }
+  }
+}
+
+/// Set of event kinds a subscriber is interested in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EventFilter(u32);
+
+impl EventFilter {
+  pub fn all() -> Self {
+    Self(u32::MAX)
+  }
+
+  pub fn none() -> Self {
+    Self(0)
+  }
+
+  pub fn only(kinds: &[EventKind]) -> Self {
+    kinds
+      .iter()
+      .fold(Self::none(), |filter, &kind| filter.with(kind))
+  }
+
+  pub fn with(self, kind: EventKind) -> Self {
+    Self(self.0 | 1 << kind as u32)
+  }
+
+  pub fn without(self, kind: EventKind) -> Self {
+    Self(self.0 & !(1 << kind as u32))
+  }
+
+  pub fn contains(self, kind: EventKind) -> bool {
+    self.0 & 1 << kind as u32 != 0
+  }
+}
+
+/// Receives events synchronously on the thread running the vm.
+pub trait Subscriber: Send {
+  fn notify(&mut self, event: &Event);
+}
+
+impl Subscriber for mpsc::Sender<Event> {
+  fn notify(&mut self, event: &Event) {
+    // a dropped receiver just stops listening
+    let _ = self.send(event.clone());
+  }
+}
+
+impl<F> Subscriber for F
+where
+  F: FnMut(&Event) + Send,
+{
+  fn notify(&mut self, event: &Event) {
+    self(event)
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(usize);
+
+#[derive(Default)]
+pub(crate) struct EventBus {
+  subscribers: Vec<(SubscriptionId, EventFilter, Box<dyn Subscriber>)>,
+  next_id: usize,
+  // union of every subscriber filter, lets emitters skip building events
+  interest: EventFilter,
+}
+
+impl EventBus {
+  pub(crate) fn subscribe(
+    &mut self,
+    filter: EventFilter,
+    subscriber: Box<dyn Subscriber>,
+  ) -> SubscriptionId {
+    let id = SubscriptionId(self.next_id);
+    self.next_id += 1;
+    self.subscribers.push((id, filter, subscriber));
+    self.interest = EventFilter(self.interest.0 | filter.0);
+    id
+  }
+
+  pub(crate) fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+    let before = self.subscribers.len();
+    self.subscribers.retain(|(sub, _, _)| *sub != id);
+    self.interest = self
+      .subscribers
+      .iter()
+      .fold(EventFilter::none(), |acc, (_, filter, _)| {
+        EventFilter(acc.0 | filter.0)
+      });
+    self.subscribers.len() != before
+  }
+
+  pub(crate) fn wants(&self, kind: EventKind) -> bool {
+    self.interest.contains(kind)
+  }
+
+  /// Delivers the event built by `event` if anyone listens for `kind`.
+  pub(crate) fn emit(&mut self, kind: EventKind, event: impl FnOnce() -> Event) {
+    if !self.wants(kind) {
+      return;
+    }
+    let event = event();
+    for (_, filter, subscriber) in &mut self.subscribers {
+      if filter.contains(kind) {
+        subscriber.notify(&event);
+      }
+    }
+  }
+}
+
+impl fmt::Debug for EventBus {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("EventBus")
+      .field("subscribers", &self.subscribers.len())
+      .field("interest", &self.interest)
+      .finish()
+  }
+}
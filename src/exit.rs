@@ -0,0 +1,44 @@
+use std::fmt;
+
+use crate::quota::Usage;
+use crate::vm::{self, Vm};
+
+/// How a run ended.
+#[derive(Debug, Clone, Copy)]
+pub enum Exit<'e> {
+  Halted,
+  /// The step that failed, ip is left at the faulting instruction.
+  Faulted(&'e vm::Error),
+}
+
+/// What the vm consumed since it was built or reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+  pub steps: usize,
+  pub cycles: u64,
+  /// Energy consumed, when the vm has an energy table.
+  pub energy: Option<u64>,
+  pub usage: Usage,
+}
+
+/// Called once a run halts or faults, before `Vm::run` or the other run
+/// methods return, with the final state readable through `vm`. Runs that
+/// stop early, like `Vm::run_until` reaching its address, do not exit.
+pub trait ExitHook: Send {
+  fn exited(&mut self, vm: &Vm, exit: Exit<'_>, stats: &Stats);
+}
+
+impl<F> ExitHook for F
+where
+  F: FnMut(&Vm, Exit<'_>, &Stats) + Send,
+{
+  fn exited(&mut self, vm: &Vm, exit: Exit<'_>, stats: &Stats) {
+    self(vm, exit, stats)
+  }
+}
+
+impl fmt::Debug for dyn ExitHook {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("ExitHook")
+  }
+}
@@ -0,0 +1,461 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::Block;
+use crate::memory;
+use crate::opcode::Condition;
+use crate::register::{Flag, Register};
+use crate::symbol::Symbols;
+use crate::vm::Vm;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("unexpected {0:?} at offset {1}")]
+  UnexpectedToken(String, usize),
+
+  #[error("unexpected end of expression")]
+  UnexpectedEnd,
+
+  #[error("invalid number {0:?}")]
+  InvalidNumber(String),
+
+  #[error("unknown identifier {0:?}")]
+  UnknownIdentifier(String),
+
+  #[error("{0} depends on machine state, expected a constant address")]
+  NotConstant(String),
+
+  #[error("expression nests deeper than {0} levels")]
+  TooDeep(usize),
+
+  #[error("division by zero")]
+  DivisionByZero,
+
+  #[error("memory error - {0}")]
+  MemoryError(#[from] memory::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UnOp {
+  Neg,
+  Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinOp {
+  Or,
+  And,
+  Eq,
+  Ne,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+  Add,
+  Sub,
+  Mul,
+  Div,
+  Mod,
+}
+
+impl BinOp {
+  fn symbol(self) -> &'static str {
+    match self {
+      BinOp::Or => "||",
+      BinOp::And => "&&",
+      BinOp::Eq => "==",
+      BinOp::Ne => "!=",
+      BinOp::Lt => "<",
+      BinOp::Le => "<=",
+      BinOp::Gt => ">",
+      BinOp::Ge => ">=",
+      BinOp::Add => "+",
+      BinOp::Sub => "-",
+      BinOp::Mul => "*",
+      BinOp::Div => "/",
+      BinOp::Mod => "%",
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+  Number(Block),
+  Register(Register),
+  Flag(Flag),
+  Condition(Condition),
+  Ip,
+  Memory(Box<Node>),
+  Unary(UnOp, Box<Node>),
+  Binary(BinOp, Box<Node>, Box<Node>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+  Number(Block),
+  Register(String),
+  Ident(String),
+  Punct(&'static str),
+}
+
+impl fmt::Display for Token {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Token::Number(n) => write!(f, "{n}"),
+      Token::Register(name) => write!(f, "%{name}"),
+      Token::Ident(name) => write!(f, "{name}"),
+      Token::Punct(p) => write!(f, "{p}"),
+    }
+  }
+}
+
+// longest first so `<=` wins over `<`
+const PUNCTUATION: [&str; 18] = [
+  "||", "&&", "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "%", "!", "(", ")", "[", "]",
+];
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, Error> {
+  let bytes = source.as_bytes();
+  let mut tokens = Vec::new();
+  let mut pos = 0;
+  while pos < bytes.len() {
+    let c = bytes[pos];
+    if c.is_ascii_whitespace() {
+      pos += 1;
+      continue;
+    }
+    let start = pos;
+    let word_end = |from: usize| {
+      from
+        + bytes[from..]
+          .iter()
+          .take_while(|b| b.is_ascii_alphanumeric() || **b == b'_')
+          .count()
+    };
+    if c.is_ascii_digit() {
+      pos = word_end(pos);
+      let text = &source[start..pos];
+      let number = match text.strip_prefix("0x") {
+        Some(hex) => Block::from_str_radix(hex, 16),
+        None => text.parse(),
+      };
+      let number = number.map_err(|_| Error::InvalidNumber(text.to_string()))?;
+      tokens.push((Token::Number(number), start));
+    } else if c == b'%' && bytes.get(pos + 1).is_some_and(u8::is_ascii_alphabetic) {
+      pos = word_end(pos + 1);
+      tokens.push((Token::Register(source[start + 1..pos].to_string()), start));
+    } else if c.is_ascii_alphabetic() || c == b'_' {
+      pos = word_end(pos);
+      tokens.push((Token::Ident(source[start..pos].to_string()), start));
+    } else {
+      let punct = PUNCTUATION
+        .iter()
+        .find(|p| source[pos..].starts_with(**p))
+        .ok_or_else(|| Error::UnexpectedToken(source[pos..].chars().take(1).collect(), pos))?;
+      pos += punct.len();
+      tokens.push((Token::Punct(punct), start));
+    }
+  }
+  Ok(tokens)
+}
+
+/// Nesting of parentheses, brackets and unary operators `Expr::parse`
+/// accepts, deeper input is rejected before it can overflow the stack.
+const MAX_DEPTH: usize = 256;
+
+struct Parser<'s> {
+  tokens: Vec<(Token, usize)>,
+  pos: usize,
+  symbols: &'s Symbols,
+  depth: usize,
+}
+
+impl Parser<'_> {
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.pos).map(|(token, _)| token)
+  }
+
+  fn next(&mut self) -> Result<(Token, usize), Error> {
+    let token = self
+      .tokens
+      .get(self.pos)
+      .cloned()
+      .ok_or(Error::UnexpectedEnd)?;
+    self.pos += 1;
+    Ok(token)
+  }
+
+  fn expect(&mut self, punct: &'static str) -> Result<(), Error> {
+    match self.next()? {
+      (Token::Punct(p), _) if p == punct => Ok(()),
+      (token, offset) => Err(Error::UnexpectedToken(token.to_string(), offset)),
+    }
+  }
+
+  fn eat_binop(&mut self, ops: &[BinOp]) -> Option<BinOp> {
+    let Some(Token::Punct(p)) = self.peek() else {
+      return None;
+    };
+    let op = ops.iter().copied().find(|op| op.symbol() == *p)?;
+    self.pos += 1;
+    Some(op)
+  }
+
+  /// Parses one precedence level of left associative binary operators.
+  fn binary(&mut self, level: usize) -> Result<Node, Error> {
+    const LEVELS: [&[BinOp]; 5] = [
+      &[BinOp::Or],
+      &[BinOp::And],
+      &[
+        BinOp::Eq,
+        BinOp::Ne,
+        BinOp::Le,
+        BinOp::Ge,
+        BinOp::Lt,
+        BinOp::Gt,
+      ],
+      &[BinOp::Add, BinOp::Sub],
+      &[BinOp::Mul, BinOp::Div, BinOp::Mod],
+    ];
+    let Some(ops) = LEVELS.get(level) else {
+      return self.unary();
+    };
+    let mut lhs = self.binary(level + 1)?;
+    while let Some(op) = self.eat_binop(ops) {
+      let rhs = self.binary(level + 1)?;
+      lhs = Node::Binary(op, Box::new(lhs), Box::new(rhs));
+    }
+    Ok(lhs)
+  }
+
+  fn unary(&mut self) -> Result<Node, Error> {
+    // every nested operand passes through here
+    if self.depth == MAX_DEPTH {
+      return Err(Error::TooDeep(MAX_DEPTH));
+    }
+    self.depth += 1;
+    let node = self.operand();
+    self.depth -= 1;
+    node
+  }
+
+  fn operand(&mut self) -> Result<Node, Error> {
+    match self.peek() {
+      Some(Token::Punct("-")) => {
+        self.pos += 1;
+        Ok(Node::Unary(UnOp::Neg, Box::new(self.unary()?)))
+      }
+      Some(Token::Punct("!")) => {
+        self.pos += 1;
+        Ok(Node::Unary(UnOp::Not, Box::new(self.unary()?)))
+      }
+      _ => self.primary(),
+    }
+  }
+
+  fn primary(&mut self) -> Result<Node, Error> {
+    let (token, offset) = self.next()?;
+    let node = match token {
+      Token::Number(n) => Node::Number(n),
+      Token::Register(name) => {
+        let reg = name
+          .parse::<Register>()
+          .map_err(|_| Error::UnknownIdentifier(format!("%{name}")))?;
+        Node::Register(reg)
+      }
+      Token::Ident(name) => match name.as_str() {
+        "zf" => Node::Flag(Flag::ZF),
+        "sf" => Node::Flag(Flag::SF),
+        "of" => Node::Flag(Flag::OF),
+        "ip" => Node::Ip,
+        "cc" => {
+          self.expect("[")?;
+          let (token, offset) = self.next()?;
+          let Token::Ident(suffix) = token else {
+            return Err(Error::UnexpectedToken(token.to_string(), offset));
+          };
+          let cond = suffix
+            .parse::<Condition>()
+            .map_err(|_| Error::UnknownIdentifier(suffix))?;
+          self.expect("]")?;
+          Node::Condition(cond)
+        }
+        "mem" => {
+          self.expect("[")?;
+          let address = self.binary(0)?;
+          self.expect("]")?;
+          Node::Memory(Box::new(address))
+        }
+        _ => match self.symbols.address_of(&name) {
+          Some(address) => Node::Number(address as Block),
+          None => return Err(Error::UnknownIdentifier(name)),
+        },
+      },
+      Token::Punct("(") => {
+        let inner = self.binary(0)?;
+        self.expect(")")?;
+        inner
+      }
+      token => return Err(Error::UnexpectedToken(token.to_string(), offset)),
+    };
+    Ok(node)
+  }
+}
+
+/// An integer expression over machine state, used for breakpoint conditions.
+///
+/// Operands are numbers (decimal or `0x` hex), registers (`%rax`), flags
+/// (`zf`, `sf`, `of`), conditions (`cc[le]`, 1 when a `jle` would be taken),
+/// the instruction pointer (`ip`) and aligned memory blocks (`mem[%rsp + 8]`),
+/// plus labels when parsed with `Expr::parse`.
+/// Operators follow c precedence: `||`, `&&`, comparisons, `+ -`, `* / %`,
+/// then unary `-` and `!`. Comparisons and logic evaluate to 0 or 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+  source: String,
+  root: Node,
+}
+
+impl Expr {
+  /// Parses `source`, replacing names from `symbols` by their addresses.
+  /// Flags, `ip`, `cc` and `mem` take precedence over labels of the same name.
+  pub fn parse(source: &str, symbols: &Symbols) -> Result<Self, Error> {
+    let mut parser = Parser {
+      tokens: tokenize(source)?,
+      pos: 0,
+      symbols,
+      depth: 0,
+    };
+    let root = parser.binary(0)?;
+    if let Some((token, offset)) = parser.tokens.get(parser.pos) {
+      return Err(Error::UnexpectedToken(token.to_string(), *offset));
+    }
+    Ok(Self {
+      source: source.trim().to_string(),
+      root,
+    })
+  }
+
+  pub fn eval(&self, vm: &Vm) -> Result<Block, Error> {
+    eval(&self.root, Some(vm))
+  }
+
+  /// Evaluates an expression made only of numbers and labels, such as the
+  /// address of a breakpoint.
+  pub fn eval_const(&self) -> Result<Block, Error> {
+    eval(&self.root, None).map_err(|e| match e {
+      Error::NotConstant(_) => Error::NotConstant(self.source.clone()),
+      e => e,
+    })
+  }
+
+  /// Evaluates the expression and treats any nonzero result as true.
+  pub fn is_true(&self, vm: &Vm) -> Result<bool, Error> {
+    Ok(self.eval(vm)? != 0)
+  }
+}
+
+fn eval(node: &Node, vm: Option<&Vm>) -> Result<Block, Error> {
+  let machine = || vm.ok_or_else(|| Error::NotConstant(String::new()));
+  let value = match node {
+    Node::Number(n) => *n,
+    Node::Register(reg) => machine()?.register(*reg),
+    Node::Flag(flag) => machine()?.flag(*flag) as Block,
+    Node::Condition(cond) => cond.eval(machine()?.flags()) as Block,
+    Node::Ip => machine()?.ip() as Block,
+    Node::Memory(address) => {
+      let address = eval(address, vm)? as usize;
+      machine()?.memory().read(address)?
+    }
+    Node::Unary(UnOp::Neg, inner) => eval(inner, vm)?.wrapping_neg(),
+    Node::Unary(UnOp::Not, inner) => (eval(inner, vm)? == 0) as Block,
+    // short circuit so `%rsp != 0 && mem[%rsp] == 1` never faults
+    Node::Binary(BinOp::And, lhs, rhs) => (eval(lhs, vm)? != 0 && eval(rhs, vm)? != 0) as Block,
+    Node::Binary(BinOp::Or, lhs, rhs) => (eval(lhs, vm)? != 0 || eval(rhs, vm)? != 0) as Block,
+    Node::Binary(op, lhs, rhs) => {
+      let (a, b) = (eval(lhs, vm)?, eval(rhs, vm)?);
+      match op {
+        BinOp::Eq => (a == b) as Block,
+        BinOp::Ne => (a != b) as Block,
+        BinOp::Lt => (a < b) as Block,
+        BinOp::Le => (a <= b) as Block,
+        BinOp::Gt => (a > b) as Block,
+        BinOp::Ge => (a >= b) as Block,
+        BinOp::Add => a.wrapping_add(b),
+        BinOp::Sub => a.wrapping_sub(b),
+        BinOp::Mul => a.wrapping_mul(b),
+        BinOp::Div | BinOp::Mod if b == 0 => return Err(Error::DivisionByZero),
+        BinOp::Div => a.wrapping_div(b),
+        BinOp::Mod => a.wrapping_rem(b),
+        BinOp::And | BinOp::Or => unreachable!("handled above"),
+      }
+    }
+  };
+  Ok(value)
+}
+
+impl FromStr for Expr {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Self::parse(s, &Symbols::new())
+  }
+}
+
+impl fmt::Display for Expr {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.source)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::builder::VmBuilder;
+
+  fn constant(source: &str) -> Result<Block, Error> {
+    let mut symbols = Symbols::new();
+    symbols.insert("data", 0x40);
+    Expr::parse(source, &symbols)?.eval_const()
+  }
+
+  #[test]
+  fn evaluates_constants_with_precedence() {
+    assert_eq!(constant("1 + 2 * 3").unwrap(), 7);
+    assert_eq!(constant("(1 + 2) * 3").unwrap(), 9);
+    assert_eq!(constant("data + 0x8").unwrap(), 0x48);
+    assert_eq!(constant("-4 % 3").unwrap(), -1);
+    assert_eq!(constant("1 < 2 && 2 != 2").unwrap(), 0);
+    assert!(matches!(constant("1 / 0"), Err(Error::DivisionByZero)));
+  }
+
+  #[test]
+  fn reports_parse_errors() {
+    assert!(matches!(constant("1 +"), Err(Error::UnexpectedEnd)));
+    assert!(matches!(constant("1 2"), Err(Error::UnexpectedToken(_, 2))));
+    assert!(matches!(constant("nope"), Err(Error::UnknownIdentifier(_))));
+    assert!(matches!(constant("%rax"), Err(Error::NotConstant(_))));
+  }
+
+  #[test]
+  fn rejects_deep_nesting() {
+    let nested = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+    assert_eq!(constant(&nested(200)).unwrap(), 1);
+    assert!(matches!(
+      nested(200_000).parse::<Expr>(),
+      Err(Error::TooDeep(MAX_DEPTH))
+    ));
+    assert!(matches!(
+      constant(&"-".repeat(100_000)),
+      Err(Error::TooDeep(MAX_DEPTH))
+    ));
+  }
+
+  #[test]
+  fn evaluates_against_a_vm() {
+    let mut vm = VmBuilder::new().build();
+    vm.set_register(Register::Rax, 5);
+    let expr = Expr::parse("%rax * 2 + 1", &Symbols::new()).unwrap();
+    assert_eq!(expr.eval(&vm).unwrap(), 11);
+  }
+}
@@ -0,0 +1,315 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::register::Register;
+use crate::symbol::Symbols;
+use crate::vm::Vm;
+use crate::{BLOCK_SIZE, Block};
+
+// `pushq %rbp` then `rrmovq %rsp, %rbp`
+const PROLOGUE: [u8; 4] = [0xa0, 0x5f, 0x20, 0x45];
+
+// frames walked before giving up on a corrupted chain
+const MAX_DEPTH: usize = 256;
+
+/// A named stack slot of a function, at `offset` from its frame pointer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Local {
+  pub name: String,
+  pub offset: i64,
+}
+
+/// Locals of every annotated function, keyed by the function's address.
+/// The assembler collects them from `.local NAME, OFFSET` directives.
+#[derive(Debug, Clone, Default)]
+pub struct Locals {
+  by_function: BTreeMap<usize, Vec<Local>>,
+}
+
+impl Locals {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn insert(&mut self, function: usize, name: impl Into<String>, offset: i64) {
+    let name = name.into();
+    let locals = self.by_function.entry(function).or_default();
+    locals.retain(|local| local.name != name);
+    locals.push(Local { name, offset });
+  }
+
+  pub fn of(&self, function: usize) -> &[Local] {
+    self.by_function.get(&function).map_or(&[], Vec::as_slice)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.by_function.is_empty()
+  }
+
+  /// Functions in ascending address order, each with its locals.
+  pub fn iter(&self) -> impl Iterator<Item = (usize, &[Local])> + '_ {
+    self
+      .by_function
+      .iter()
+      .map(|(&function, locals)| (function, locals.as_slice()))
+  }
+}
+
+/// One activation on the call stack, as reconstructed by `backtrace`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+  /// Zero for the innermost frame.
+  pub index: usize,
+  /// Where the frame is executing, the return address for callers.
+  pub ip: usize,
+  /// Entry point and name of the function holding `ip`, if a label covers it.
+  pub function: Option<(usize, String)>,
+  /// `%rbp` of the frame, `None` while its prologue has not set it up.
+  pub frame_pointer: Option<usize>,
+  pub return_address: Option<usize>,
+  /// Registers the function pushed right after its prologue, with the stack
+  /// address and value of each.
+  pub saved: Vec<(Register, usize, Block)>,
+  /// Annotated locals with their stack address and value.
+  pub locals: Vec<(Local, usize, Block)>,
+}
+
+impl fmt::Display for Frame {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "#{} {:#06x}", self.index, self.ip)?;
+    if let Some((entry, name)) = &self.function {
+      write!(f, " in {name}")?;
+      if self.ip != *entry {
+        write!(f, "+{:#x}", self.ip - entry)?;
+      }
+    }
+    if let Some(fp) = self.frame_pointer {
+      write!(f, " (%rbp {fp:#x})")?;
+    }
+    for (reg, address, value) in &self.saved {
+      write!(f, "\n    saved {reg} at {address:#x} = {value:#x}")?;
+    }
+    for (local, address, value) in &self.locals {
+      write!(f, "\n    {} at {address:#x} = {value}", local.name)?;
+    }
+    Ok(())
+  }
+}
+
+/// Walks the frame pointer chain of `vm`, innermost frame first. Functions
+/// are expected to start with `pushq %rbp; rrmovq %rsp, %rbp`, leaving the
+/// caller's `%rbp` at `0(%rbp)` and the return address at `8(%rbp)`. The
+/// innermost frame is also understood while stopped at the entry, halfway
+/// through the prologue or at the `ret`. The walk ends at a zero or
+/// unreadable frame pointer, or one that does not move up the stack, with a
+/// last frame for the code that made the outermost call.
+pub fn backtrace(vm: &Vm, symbols: &Symbols, locals: &Locals) -> Vec<Frame> {
+  let read = |address: usize| -> Option<Block> {
+    let bytes = vm.read_bytes(address, BLOCK_SIZE).ok()?;
+    Some(Block::from_le_bytes(bytes.try_into().ok()?))
+  };
+  let mut frames = Vec::new();
+  let mut ip = vm.ip();
+  let mut fp = Some(vm.register(Register::Rbp) as usize);
+  let rsp = vm.register(Register::Rsp) as usize;
+  let function = function_of(vm, symbols, ip);
+
+  // without a frame of its own yet, the return address sits on top
+  let at_ret = vm.read_bytes(ip, 1).is_ok_and(|byte| byte == [0x90]);
+  let partial = match function {
+    Some((entry, _)) if has_prologue(vm, entry) && ip == entry => Some(rsp),
+    // an `%rsp` at the very top leaves the return address unreadable
+    Some((entry, _)) if has_prologue(vm, entry) && entry.checked_add(2) == Some(ip) => {
+      rsp.checked_add(BLOCK_SIZE)
+    }
+    _ if at_ret => Some(rsp),
+    _ => None,
+  };
+  if let Some(slot) = partial {
+    let return_address = read(slot).map(|address| address as usize);
+    frames.push(Frame {
+      index: 0,
+      ip,
+      function: function.map(|(entry, name)| (entry, name.to_string())),
+      frame_pointer: None,
+      return_address,
+      saved: Vec::new(),
+      locals: Vec::new(),
+    });
+    if slot != rsp {
+      // halfway through the prologue, the caller's `%rbp` was just pushed
+      fp = read(rsp).map(|fp| fp as usize);
+    }
+    match return_address {
+      Some(address) => ip = address,
+      None => return frames,
+    }
+  }
+
+  let mut last = None;
+  while let Some(frame_pointer) = fp.filter(|&fp| fp != 0 && last.is_none_or(|last| fp > last)) {
+    if frames.len() >= MAX_DEPTH {
+      break;
+    }
+    let (Some(caller_fp), Some(return_address)) = (
+      read(frame_pointer),
+      frame_pointer.checked_add(BLOCK_SIZE).and_then(read),
+    ) else {
+      break;
+    };
+    let function = function_of(vm, symbols, ip);
+    let mut frame = Frame {
+      index: frames.len(),
+      ip,
+      function: function.map(|(entry, name)| (entry, name.to_string())),
+      frame_pointer: Some(frame_pointer),
+      return_address: Some(return_address as usize),
+      saved: Vec::new(),
+      locals: Vec::new(),
+    };
+    if let Some((entry, _)) = function {
+      for (i, reg) in saved_registers(vm, entry).into_iter().enumerate() {
+        let address = frame_pointer.wrapping_sub((i + 1) * BLOCK_SIZE);
+        if let Some(value) = read(address) {
+          frame.saved.push((reg, address, value));
+        }
+      }
+      for local in locals.of(entry) {
+        let address = frame_pointer.wrapping_add_signed(local.offset as isize);
+        if let Some(value) = read(address) {
+          frame.locals.push((local.clone(), address, value));
+        }
+      }
+    }
+    frames.push(frame);
+    last = Some(frame_pointer);
+    fp = Some(caller_fp as usize);
+    ip = return_address as usize;
+  }
+  // the outermost code, which usually never set up a frame of its own
+  if frames.len() < MAX_DEPTH {
+    frames.push(Frame {
+      index: frames.len(),
+      ip,
+      function: function_of(vm, symbols, ip).map(|(entry, name)| (entry, name.to_string())),
+      frame_pointer: None,
+      return_address: None,
+      saved: Vec::new(),
+      locals: Vec::new(),
+    });
+  }
+  frames
+}
+
+/// The function holding `address`: the closest label at or below it that
+/// starts with the frame pointer prologue, falling back on the closest label
+/// when none does, as loop and branch labels also live inside functions.
+fn function_of<'s>(vm: &Vm, symbols: &'s Symbols, address: usize) -> Option<(usize, &'s str)> {
+  let below = || symbols.iter().take_while(|&(at, _)| at <= address);
+  below()
+    .filter(|&(at, _)| has_prologue(vm, at))
+    .last()
+    .or_else(|| below().last())
+}
+
+fn has_prologue(vm: &Vm, address: usize) -> bool {
+  vm.read_bytes(address, PROLOGUE.len())
+    .is_ok_and(|bytes| bytes == PROLOGUE)
+}
+
+/// Registers pushed by the `pushq` run directly after the prologue at `entry`.
+fn saved_registers(vm: &Vm, entry: usize) -> Vec<Register> {
+  let mut saved = Vec::new();
+  let mut address = entry + PROLOGUE.len();
+  while let Ok(bytes) = vm.read_bytes(address, 2)
+    && bytes[0] == 0xa0
+    && let Ok(reg) = Register::try_from(bytes[1] >> 4)
+  {
+    saved.push(reg);
+    address += 2;
+  }
+  saved
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm::{self, Assembled};
+  use crate::builder::VmBuilder;
+  use crate::region::Chunk;
+
+  const SQUARE: &str = "
+main:
+    irmovq $5, %rdi
+    irmovq $9, %rbx
+    call square
+    halt
+square:
+    .local x, -16
+    pushq %rbp
+    rrmovq %rsp, %rbp
+    pushq %rbx
+    pushq %rdi
+    rrmovq %rdi, %rax
+inside:
+    mulq %rdi, %rax
+    popq %rdi
+    popq %rbx
+    popq %rbp
+    ret
+";
+
+  // where `call square` returns to
+  const AFTER: usize = 0x1d;
+
+  // runs the program up to the label `stop`
+  fn stop_at(stop: &str) -> (Vm, Assembled) {
+    let assembled = asm::assemble(SQUARE).unwrap();
+    let region = Chunk::from(assembled.bytes().to_vec());
+    let mut vm = VmBuilder::new().build();
+    vm.load(&region).unwrap();
+    let stop = assembled.symbols().address_of(stop).unwrap();
+    while vm.ip() != stop {
+      vm.step(&region).unwrap();
+    }
+    (vm, assembled)
+  }
+
+  #[test]
+  fn walks_frames_with_saved_registers_and_locals() {
+    let (vm, assembled) = stop_at("inside");
+    let symbols = assembled.symbols();
+    let frames = backtrace(&vm, symbols, assembled.locals());
+    assert_eq!(frames.len(), 2);
+    let square = symbols.address_of("square").unwrap();
+    let fp = vm.register(Register::Rbp) as usize;
+    assert_eq!(frames[0].function, Some((square, "square".to_string())));
+    assert_eq!(frames[0].frame_pointer, Some(fp));
+    assert_eq!(frames[0].return_address, Some(AFTER));
+    assert_eq!(
+      frames[0].saved,
+      [(Register::Rbx, fp - 8, 9), (Register::Rdi, fp - 16, 5)]
+    );
+    assert_eq!(frames[0].locals.len(), 1);
+    assert_eq!(
+      (frames[0].locals[0].0.name.as_str(), frames[0].locals[0].2),
+      ("x", 5)
+    );
+    assert_eq!(frames[1].ip, AFTER);
+    assert_eq!(frames[1].function.as_ref().unwrap().1, "main");
+    let text = frames[0].to_string();
+    assert!(text.starts_with(&format!("#0 {:#06x} in square+", frames[0].ip)));
+    assert!(text.contains("saved %rbx") && text.contains("x at"));
+  }
+
+  #[test]
+  fn understands_frames_before_the_prologue() {
+    let (vm, assembled) = stop_at("square");
+    let symbols = assembled.symbols();
+    let frames = backtrace(&vm, symbols, assembled.locals());
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].frame_pointer, None);
+    assert_eq!(frames[0].return_address, Some(AFTER));
+    assert_eq!(frames[1].ip, AFTER);
+  }
+}
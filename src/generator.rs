@@ -0,0 +1,351 @@
+use std::fmt::Write;
+
+use crate::register::Register;
+use crate::rng::Entropy;
+
+/// Kinds of instruction the generator draws from, in proportion to their
+/// weights.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Op {
+  /// `rrmovq` and `irmovq`.
+  Move,
+  /// `addq`, `subq`, `andq`, `xorq` and `mulq`, division could fault.
+  Arithmetic,
+  ConditionalMove,
+  /// `mrmovq` from the data area.
+  Load,
+  /// `rmmovq` into the data area.
+  Store,
+  /// A `pushq` immediately balanced by a `popq`.
+  Stack,
+  /// A conditional jump forward over a few instructions.
+  Branch,
+  /// A `call` to a short leaf function.
+  Call,
+}
+
+// registers random instructions may use, the rest hold loop state
+const FREE: [Register; 9] = [
+  Register::Rax,
+  Register::Rcx,
+  Register::Rdx,
+  Register::Rbx,
+  Register::Rsi,
+  Register::Rdi,
+  Register::Rbp,
+  Register::R8,
+  Register::R9,
+];
+// one counter per nesting level
+const COUNTERS: [Register; 3] = [Register::R12, Register::R11, Register::R10];
+// holds 1 for decrementing counters
+const ONE: Register = Register::R13;
+// holds the address of the data area
+const BASE: Register = Register::R14;
+
+const LEAVES: usize = 4;
+
+/// Component name the generator draws from its entropy under.
+pub const ENTROPY: &str = "generator";
+
+/// Builds random but valid programs with a chosen instruction mix, loop
+/// structure and memory footprint, for benchmarking and stress testing
+/// engines. Every program halts: the only backward jumps close loops with a
+/// fixed trip count, and every register is initialized before use. The same
+/// seed and settings always produce the same program.
+#[derive(Debug, Clone)]
+pub struct Generator {
+  seed: u64,
+  weights: Vec<(Op, u32)>,
+  instructions: usize,
+  loops: usize,
+  nesting: usize,
+  iterations: usize,
+  footprint: usize,
+}
+
+impl Generator {
+  pub fn new(seed: u64) -> Self {
+    Self {
+      seed,
+      weights: vec![
+        (Op::Move, 2),
+        (Op::Arithmetic, 4),
+        (Op::ConditionalMove, 1),
+        (Op::Load, 2),
+        (Op::Store, 1),
+        (Op::Stack, 1),
+        (Op::Branch, 1),
+        (Op::Call, 1),
+      ],
+      instructions: 100,
+      loops: 4,
+      nesting: 1,
+      iterations: 10,
+      footprint: 256,
+    }
+  }
+
+  /// Relative frequency of `op`, 0 leaves it out.
+  pub fn weight(mut self, op: Op, weight: u32) -> Self {
+    if let Some((_, w)) = self.weights.iter_mut().find(|(o, _)| *o == op) {
+      *w = weight;
+    }
+    self
+  }
+
+  /// Random instructions in the program text, defaults to 100.
+  pub fn instructions(mut self, instructions: usize) -> Self {
+    self.instructions = instructions;
+    self
+  }
+
+  /// Loops the instructions are spread over, defaults to 4. Zero gives
+  /// straight line code.
+  pub fn loops(mut self, loops: usize) -> Self {
+    self.loops = loops;
+    self
+  }
+
+  /// Depth of each loop nest, from 1 to 3, defaults to 1.
+  pub fn nesting(mut self, nesting: usize) -> Self {
+    self.nesting = nesting.clamp(1, COUNTERS.len());
+    self
+  }
+
+  /// Trip count of every loop, defaults to 10.
+  pub fn iterations(mut self, iterations: usize) -> Self {
+    self.iterations = iterations.max(1);
+    self
+  }
+
+  /// Bytes of data loads and stores range over, rounded up to a block,
+  /// defaults to 256. The data area follows the program and must fit below
+  /// the stack.
+  pub fn footprint(mut self, bytes: usize) -> Self {
+    self.footprint = bytes.max(8).next_multiple_of(8);
+    self
+  }
+
+  /// The program as `.ys` source, for `asm::assemble`.
+  pub fn generate(&self) -> String {
+    self.generate_from(&mut Entropy::new(self.seed))
+  }
+
+  /// Like `generate`, drawing from `entropy` in place of the seed given to
+  /// `new`, so a vm's entropy can also decide its program.
+  pub fn generate_from(&self, entropy: &mut Entropy) -> String {
+    let mut g = Emitter {
+      entropy,
+      out: String::new(),
+      labels: 0,
+      weights: self
+        .weights
+        .iter()
+        .copied()
+        .filter(|&(_, w)| w > 0)
+        .collect(),
+      footprint: self.footprint,
+    };
+    let _ = writeln!(g.out, "# generated with seed {}", g.entropy.seed());
+    let _ = writeln!(g.out, "main:");
+    g.line(format_args!("irmovq data, {BASE}"));
+    g.line(format_args!("irmovq $1, {ONE}"));
+    // store every block of the data area once, so it is not part of the
+    // image the vm treats as code and loads never read uninitialized memory
+    let [pointer, count, step] = COUNTERS;
+    g.line(format_args!("rrmovq {BASE}, {pointer}"));
+    g.line(format_args!("irmovq ${}, {count}", self.footprint / 8));
+    g.line(format_args!("irmovq $8, {step}"));
+    let _ = writeln!(g.out, "init:");
+    g.line(format_args!("rmmovq {pointer}, ({pointer})"));
+    g.line(format_args!("addq {step}, {pointer}"));
+    g.line(format_args!("subq {ONE}, {count}"));
+    g.line(format_args!("jne init"));
+    for reg in FREE {
+      let value = g.value();
+      g.line(format_args!("irmovq ${value}, {reg}"));
+    }
+    let parts = self.loops.max(1);
+    for part in 0..parts {
+      // spread the remainder over the first parts
+      let count = self.instructions / parts + usize::from(part < self.instructions % parts);
+      if self.loops == 0 {
+        g.body(count);
+      } else {
+        g.nest(self.nesting, self.iterations, count);
+      }
+    }
+    g.line(format_args!("halt"));
+    for leaf in 0..LEAVES {
+      let _ = writeln!(g.out, "leaf{leaf}:");
+      for _ in 0..3 {
+        g.simple();
+      }
+      g.line(format_args!("ret"));
+    }
+    let _ = writeln!(g.out, ".align 8\ndata:");
+    g.out
+  }
+}
+
+impl Default for Generator {
+  fn default() -> Self {
+    Self::new(0)
+  }
+}
+
+struct Emitter<'e> {
+  entropy: &'e mut Entropy,
+  out: String,
+  labels: usize,
+  weights: Vec<(Op, u32)>,
+  footprint: usize,
+}
+
+impl Emitter<'_> {
+  fn below(&mut self, bound: usize) -> usize {
+    self.entropy.below(ENTROPY, bound)
+  }
+
+  fn line(&mut self, text: std::fmt::Arguments<'_>) {
+    let _ = writeln!(self.out, "  {text}");
+  }
+
+  fn label(&mut self, prefix: &str) -> String {
+    self.labels += 1;
+    format!("{prefix}{}", self.labels)
+  }
+
+  fn value(&mut self) -> i64 {
+    self.below(1 << 16) as i64 - (1 << 15)
+  }
+
+  fn register(&mut self) -> Register {
+    FREE[self.below(FREE.len())]
+  }
+
+  fn offset(&mut self) -> usize {
+    self.below(self.footprint / 8) * 8
+  }
+
+  /// `depth` loops inside each other, the innermost holding `count`
+  /// instructions.
+  fn nest(&mut self, depth: usize, iterations: usize, count: usize) {
+    let counter = COUNTERS[depth - 1];
+    let head = self.label("loop");
+    self.line(format_args!("irmovq ${iterations}, {counter}"));
+    let _ = writeln!(self.out, "{head}:");
+    if depth > 1 {
+      self.nest(depth - 1, iterations, count);
+    } else {
+      self.body(count);
+    }
+    self.line(format_args!("subq {ONE}, {counter}"));
+    self.line(format_args!("jne {head}"));
+  }
+
+  fn body(&mut self, count: usize) {
+    let mut emitted = 0;
+    while emitted < count {
+      emitted += self.op(count - emitted);
+    }
+  }
+
+  fn pick(&mut self) -> Op {
+    let total: u32 = self.weights.iter().map(|&(_, w)| w).sum();
+    if total == 0 {
+      return Op::Arithmetic;
+    }
+    let mut roll = self.below(total as usize) as u32;
+    for &(op, weight) in &self.weights {
+      if roll < weight {
+        return op;
+      }
+      roll -= weight;
+    }
+    unreachable!("roll is below the total weight")
+  }
+
+  /// Emits one drawn op using at most `budget` instructions, returning how
+  /// many it used.
+  fn op(&mut self, budget: usize) -> usize {
+    match self.pick() {
+      Op::Stack if budget >= 2 => {
+        let (ra, rb) = (self.register(), self.register());
+        self.line(format_args!("pushq {ra}"));
+        self.line(format_args!("popq {rb}"));
+        2
+      }
+      Op::Branch if budget >= 2 => {
+        const CONDITIONS: [&str; 6] = ["jle", "jl", "je", "jne", "jge", "jg"];
+        let jump = CONDITIONS[self.below(CONDITIONS.len())];
+        let skip = self.label("skip");
+        self.line(format_args!("{jump} {skip}"));
+        let over = 1 + self.below(3.min(budget - 1));
+        for _ in 0..over {
+          self.simple();
+        }
+        let _ = writeln!(self.out, "{skip}:");
+        1 + over
+      }
+      Op::Call => {
+        let leaf = self.below(LEAVES);
+        self.line(format_args!("call leaf{leaf}"));
+        1
+      }
+      Op::Load => {
+        let (offset, ra) = (self.offset(), self.register());
+        self.line(format_args!("mrmovq {offset}({BASE}), {ra}"));
+        1
+      }
+      Op::Store => {
+        let (ra, offset) = (self.register(), self.offset());
+        self.line(format_args!("rmmovq {ra}, {offset}({BASE})"));
+        1
+      }
+      Op::ConditionalMove => {
+        const MOVES: [&str; 6] = ["cmovle", "cmovl", "cmove", "cmovne", "cmovge", "cmovg"];
+        let mov = MOVES[self.below(MOVES.len())];
+        let (ra, rb) = (self.register(), self.register());
+        self.line(format_args!("{mov} {ra}, {rb}"));
+        1
+      }
+      Op::Move => {
+        self.mov();
+        1
+      }
+      // also the two instruction ops without room left
+      _ => {
+        self.arithmetic();
+        1
+      }
+    }
+  }
+
+  /// A move or arithmetic instruction, which can go anywhere.
+  fn simple(&mut self) {
+    if self.below(2) == 0 {
+      self.mov();
+    } else {
+      self.arithmetic();
+    }
+  }
+
+  fn mov(&mut self) {
+    let (ra, rb) = (self.register(), self.register());
+    if self.below(2) == 0 {
+      let value = self.value();
+      self.line(format_args!("irmovq ${value}, {rb}"));
+    } else {
+      self.line(format_args!("rrmovq {ra}, {rb}"));
+    }
+  }
+
+  fn arithmetic(&mut self) {
+    const ARITHMETIC: [&str; 5] = ["addq", "subq", "andq", "xorq", "mulq"];
+    let op = ARITHMETIC[self.below(ARITHMETIC.len())];
+    let (ra, rb) = (self.register(), self.register());
+    self.line(format_args!("{op} {ra}, {rb}"));
+  }
+}
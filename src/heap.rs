@@ -0,0 +1,288 @@
+//! Reference `malloc` and `free` written in Y86, and a host side checker of
+//! the heap they manage, for malloc lab style exercises.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::debugger::{self, Action, Breakpoint, Debugger};
+use crate::register::Register;
+use crate::vm::Vm;
+use crate::{BLOCK_SIZE, Block};
+
+/// Source of the allocator, see the comments at its top for the block
+/// layout and calling convention.
+pub const SOURCE: &str = include_str!("heap/malloc.ys");
+
+// prologue header and footer, then the first block
+const FIRST: usize = 3 * BLOCK_SIZE;
+const PROLOGUE: Block = 16 | ALLOCATED;
+const EPILOGUE: Block = ALLOCATED;
+const ALLOCATED: Block = 1;
+const MIN_BLOCK: usize = 32;
+const ALIGNMENT: usize = 16;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("program has no {0} label, link it with heap::link")]
+  MissingLabel(&'static str),
+
+  #[error("debugger error - {0}")]
+  DebuggerError(#[from] debugger::Error),
+}
+
+/// Adds the allocator to `program`, which still has to define `heap`. It
+/// goes in front of the `.pos` directives and bare labels ending the
+/// program, which usually place `heap` and `stack` past everything else.
+pub fn link(program: &str) -> String {
+  let lines: Vec<&str> = program.lines().collect();
+  let placement = |line: &&&str| {
+    let line = line.split('#').next().unwrap_or_default().trim();
+    line.is_empty()
+      || line.starts_with(".pos")
+      || line.starts_with(".align")
+      || line
+        .strip_suffix(':')
+        .is_some_and(|label| !label.contains(char::is_whitespace))
+  };
+  let tail = lines.iter().rev().take_while(placement).count();
+  let (code, placed) = lines.split_at(lines.len() - tail);
+  format!("{}\n{SOURCE}\n{}\n", code.join("\n"), placed.join("\n"))
+}
+
+/// An allocator call, with the argument it was made with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Call {
+  Init { size: usize },
+  Malloc { size: usize },
+  Free { pointer: usize },
+}
+
+impl fmt::Display for Call {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Call::Init { size } => write!(f, "heap_init({size})"),
+      Call::Malloc { size } => write!(f, "malloc({size})"),
+      Call::Free { pointer } => write!(f, "free({pointer:#x})"),
+    }
+  }
+}
+
+/// Heap invariant broken by an allocator call, block addresses are those of
+/// headers.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+  #[error("heap memory at {0:#x} is unreadable")]
+  Unreadable(usize),
+
+  #[error("prologue was overwritten")]
+  Prologue,
+
+  #[error("no epilogue at the heap end {0:#x}")]
+  Epilogue(usize),
+
+  #[error("block at {0:#x} has invalid header {1:#x}")]
+  Header(usize, Block),
+
+  #[error("block at {0:#x} runs past the heap end")]
+  Overrun(usize),
+
+  #[error("footer of block at {0:#x} differs from its header")]
+  Footer(usize),
+
+  #[error("free blocks at {0:#x} and {1:#x} were not coalesced")]
+  Uncoalesced(usize, usize),
+
+  #[error("returned {0:#x}, not the payload of a big enough allocated block")]
+  BadAllocation(usize),
+
+  #[error("returned {0:#x}, which is still allocated")]
+  Reused(usize),
+
+  #[error("{0:#x} was not returned by malloc or is already free")]
+  InvalidFree(usize),
+
+  #[error("{0:#x} is allocated but no longer a block")]
+  Lost(usize),
+
+  #[error("block at {0:#x} is allocated but malloc never returned it")]
+  Unknown(usize),
+}
+
+/// A call that left the heap broken, the error of the hook that found it.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("heap corrupted by {call} - {violation}")]
+pub struct Corruption {
+  pub call: Call,
+  pub violation: Violation,
+}
+
+#[derive(Debug)]
+struct State {
+  heap: usize,
+  pending: Option<Call>,
+  // payloads malloc returned and free has not taken back, with the size
+  // asked for
+  live: BTreeMap<usize, usize>,
+  calls: usize,
+}
+
+/// Validates the heap after every call of the reference allocator, through
+/// debugger hooks on its entry points and on its returns. The walk checks
+/// the prologue and epilogue, that headers match footers and blocks tile the
+/// heap, that no two free blocks are adjacent and that the allocated blocks
+/// are exactly the payloads malloc returned and free has not taken back.
+/// A broken invariant stops the debugger with a `debugger::Error::HookFailed`
+/// holding a `Corruption`.
+#[derive(Debug, Clone)]
+pub struct Checker {
+  state: Rc<RefCell<State>>,
+}
+
+impl Checker {
+  /// Sets the hooks on `debugger`, whose symbols must be those of a program
+  /// linked with the allocator.
+  pub fn attach(debugger: &mut Debugger) -> Result<Self, Error> {
+    let symbols = debugger.symbols().clone();
+    let label = |name: &'static str| symbols.address_of(name).ok_or(Error::MissingLabel(name));
+    let state = Rc::new(RefCell::new(State {
+      heap: label("heap")?,
+      pending: None,
+      live: BTreeMap::new(),
+      calls: 0,
+    }));
+    for name in ["heap_init", "malloc", "free"] {
+      let entry = debugger.add_breakpoint(Breakpoint::new(label(name)?));
+      let shared = Rc::clone(&state);
+      debugger.attach_hook(entry, move |vm: &mut Vm| {
+        let argument = vm.register(Register::Rdi) as usize;
+        shared.borrow_mut().pending = Some(match name {
+          "heap_init" => Call::Init { size: argument },
+          "malloc" => Call::Malloc { size: argument },
+          _ => Call::Free { pointer: argument },
+        });
+        Ok(Action::Continue)
+      })?;
+    }
+    for name in ["heap_init_ret", "malloc_ret", "free_ret"] {
+      let exit = debugger.add_breakpoint(Breakpoint::new(label(name)?));
+      let shared = Rc::clone(&state);
+      debugger.attach_hook(exit, move |vm: &mut Vm| {
+        shared.borrow_mut().returned(vm)?;
+        Ok(Action::Continue)
+      })?;
+    }
+    Ok(Self { state })
+  }
+
+  /// Allocator calls checked so far.
+  pub fn calls(&self) -> usize {
+    self.state.borrow().calls
+  }
+
+  /// Payloads currently allocated, with the size asked for each.
+  pub fn live(&self) -> Vec<(usize, usize)> {
+    let state = self.state.borrow();
+    state.live.iter().map(|(&at, &size)| (at, size)).collect()
+  }
+}
+
+impl State {
+  fn returned(&mut self, vm: &Vm) -> Result<(), Corruption> {
+    let Some(call) = self.pending.take() else {
+      // jumped into the allocator without calling an entry point
+      return Ok(());
+    };
+    self.calls += 1;
+    let result = vm.register(Register::Rax) as usize;
+    self
+      .check(vm, call, result)
+      .map_err(|violation| Corruption { call, violation })
+  }
+
+  fn check(&mut self, vm: &Vm, call: Call, result: usize) -> Result<(), Violation> {
+    let blocks = walk(vm, self.heap)?;
+    let allocated = |payload: usize| {
+      blocks
+        .iter()
+        .any(|&(at, _, used)| used && at + BLOCK_SIZE == payload)
+    };
+    match call {
+      Call::Init { .. } => self.live.clear(),
+      Call::Malloc { size } if result != 0 => {
+        if self.live.contains_key(&result) {
+          return Err(Violation::Reused(result));
+        }
+        let fits = blocks.iter().any(|&(at, block, used)| {
+          used && at + BLOCK_SIZE == result && block - 2 * BLOCK_SIZE >= size
+        });
+        if !fits || !result.is_multiple_of(ALIGNMENT) {
+          return Err(Violation::BadAllocation(result));
+        }
+        self.live.insert(result, size);
+      }
+      Call::Malloc { .. } => {}
+      Call::Free { pointer: 0 } => {}
+      Call::Free { pointer } => {
+        if self.live.remove(&pointer).is_none() {
+          return Err(Violation::InvalidFree(pointer));
+        }
+      }
+    }
+    if let Some(&lost) = self.live.keys().find(|&&payload| !allocated(payload)) {
+      return Err(Violation::Lost(lost));
+    }
+    match blocks
+      .iter()
+      .find(|&&(at, _, used)| used && !self.live.contains_key(&(at + BLOCK_SIZE)))
+    {
+      Some(&(at, _, _)) => Err(Violation::Unknown(at)),
+      None => Ok(()),
+    }
+  }
+}
+
+/// Every block of the heap at `heap` as its header address, size and
+/// whether it is allocated, after checking the layout.
+fn walk(vm: &Vm, heap: usize) -> Result<Vec<(usize, usize, bool)>, Violation> {
+  let read = |address: usize| -> Result<Block, Violation> {
+    let bytes = vm
+      .read_bytes(address, BLOCK_SIZE)
+      .map_err(|_| Violation::Unreadable(address))?;
+    Ok(Block::from_le_bytes(
+      bytes.try_into().expect("read a whole block"),
+    ))
+  };
+  let end = read(heap)? as usize;
+  if read(heap + BLOCK_SIZE)? != PROLOGUE || read(heap + 2 * BLOCK_SIZE)? != PROLOGUE {
+    return Err(Violation::Prologue);
+  }
+  if end < heap + FIRST || read(end)? != EPILOGUE {
+    return Err(Violation::Epilogue(end));
+  }
+  let mut blocks: Vec<(usize, usize, bool)> = Vec::new();
+  let mut at = heap + FIRST;
+  while at < end {
+    let header = read(at)?;
+    let size = (header & !0xf) as usize;
+    if header & 0xe != 0 || header < 0 || size < MIN_BLOCK {
+      return Err(Violation::Header(at, header));
+    }
+    if at + size > end {
+      return Err(Violation::Overrun(at));
+    }
+    if read(at + size - BLOCK_SIZE)? != header {
+      return Err(Violation::Footer(at));
+    }
+    let used = header & ALLOCATED != 0;
+    if let Some(&(previous, _, false)) = blocks.last()
+      && !used
+    {
+      return Err(Violation::Uncoalesced(previous, at));
+    }
+    blocks.push((at, size, used));
+    at += size;
+  }
+  Ok(blocks)
+}
@@ -0,0 +1,102 @@
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+
+use crate::{BLOCK_SIZE, Block};
+
+/// A store kept by `WriteHistory`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Writer {
+  /// Zero based index of the storing instruction, as counted by `Vm::steps`.
+  pub step: usize,
+  pub ip: usize,
+  /// First byte written, the store covers the block from there.
+  pub address: usize,
+  pub value: Block,
+}
+
+impl Writer {
+  pub fn covers(&self, address: usize) -> bool {
+    (self.address..self.address + BLOCK_SIZE).contains(&address)
+  }
+}
+
+/// The last `depth` stores to every aligned block inside the watched
+/// ranges, see `Vm::set_write_history`. A store straddling two blocks is
+/// kept for both.
+#[derive(Debug, Clone)]
+pub struct WriteHistory {
+  depth: usize,
+  ranges: Vec<Range<usize>>,
+  // newest last, keyed by aligned block address
+  blocks: HashMap<usize, VecDeque<Writer>>,
+}
+
+impl WriteHistory {
+  pub fn new(depth: usize) -> Self {
+    Self {
+      depth: depth.max(1),
+      ranges: Vec::new(),
+      blocks: HashMap::new(),
+    }
+  }
+
+  /// Records stores overlapping `range` as well.
+  pub fn watch(mut self, range: Range<usize>) -> Self {
+    self.ranges.push(range);
+    self
+  }
+
+  pub fn depth(&self) -> usize {
+    self.depth
+  }
+
+  pub fn ranges(&self) -> &[Range<usize>] {
+    &self.ranges
+  }
+
+  /// Kept stores covering `address`, newest first.
+  pub fn writers(&self, address: usize) -> impl Iterator<Item = &Writer> + '_ {
+    let block = address - address % BLOCK_SIZE;
+    self
+      .blocks
+      .get(&block)
+      .into_iter()
+      .flat_map(|writers| writers.iter().rev())
+      .filter(move |writer| writer.covers(address))
+  }
+
+  /// The store that gave `address` its current value, if it was kept.
+  pub fn last_writer(&self, address: usize) -> Option<&Writer> {
+    self.writers(address).next()
+  }
+
+  /// Forgets every store, keeping the watched ranges.
+  pub fn clear(&mut self) {
+    self.blocks.clear();
+  }
+
+  pub(crate) fn record(&mut self, step: usize, ip: usize, address: usize, value: Block) {
+    let end = address + BLOCK_SIZE;
+    if !self
+      .ranges
+      .iter()
+      .any(|range| range.start < end && address < range.end)
+    {
+      return;
+    }
+    let writer = Writer {
+      step,
+      ip,
+      address,
+      value,
+    };
+    let first = address - address % BLOCK_SIZE;
+    for block in (first..end).step_by(BLOCK_SIZE) {
+      let writers = self.blocks.entry(block).or_default();
+      if writers.len() == self.depth {
+        writers.pop_front();
+      }
+      writers.push_back(writer);
+    }
+  }
+}
@@ -0,0 +1,213 @@
+use std::ops::Range;
+
+use crate::asm::Assembled;
+use crate::memory::MEMORY_SIZE;
+use crate::region::{Chunk, Region};
+use crate::snapshot::{self, Decoder, Encoder};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("not a program image")]
+  BadMagic,
+
+  #[error("unsupported program image version {0}")]
+  UnsupportedVersion(u64),
+
+  #[error("segments overlap at {0:#x}")]
+  Overlap(usize),
+
+  #[error("segment at {0:#x} runs past the end of memory")]
+  OutOfMemory(usize),
+
+  #[error("entry point {0:#x} is past the end of memory")]
+  InvalidEntry(usize),
+
+  #[error("decode error - {0}")]
+  DecodeError(#[from] snapshot::Error),
+}
+
+/// Bytes placed at `address` when the image is loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+  pub address: usize,
+  pub bytes: Vec<u8>,
+}
+
+impl Segment {
+  /// Ends at `usize::MAX` for a segment running off the address space.
+  pub fn range(&self) -> Range<usize> {
+    self.address..self.address.saturating_add(self.bytes.len())
+  }
+}
+
+/// A program distributed as a single file: its entry point and the segments
+/// making up its memory layout, everything else starting out zeroed.
+///
+/// Encoded little endian as the magic `y86prog\0`, the version, the entry
+/// point and the number of segments, then every segment as its address,
+/// length and bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Image {
+  entry: usize,
+  segments: Vec<Segment>,
+}
+
+impl Image {
+  const MAGIC: &'static [u8; 8] = b"y86prog\0";
+  const VERSION: u64 = 1;
+
+  pub fn new(entry: usize) -> Self {
+    Self {
+      entry,
+      segments: Vec::new(),
+    }
+  }
+
+  /// Adds a segment, where segments overlap the later one wins.
+  pub fn segment(mut self, address: usize, bytes: impl Into<Vec<u8>>) -> Self {
+    self.segments.push(Segment {
+      address,
+      bytes: bytes.into(),
+    });
+    self
+  }
+
+  /// One segment per run of instructions and data the assembler placed, so
+  /// the gaps left by `.pos` take no space.
+  pub fn from_assembled(assembled: &Assembled, entry: usize) -> Self {
+    let bytes = assembled.bytes();
+    assembled
+      .segments()
+      .into_iter()
+      .fold(Self::new(entry), |image, range| {
+        image.segment(range.start, &bytes[range.clone()])
+      })
+  }
+
+  /// The whole of `chunk` as a single segment at address zero.
+  pub fn from_chunk(chunk: &Chunk, entry: usize) -> Self {
+    Self::new(entry).segment(0, chunk.instructions())
+  }
+
+  pub fn entry(&self) -> usize {
+    self.entry
+  }
+
+  pub fn segments(&self) -> &[Segment] {
+    &self.segments
+  }
+
+  /// Whether `bytes` start like an encoded image, to tell images from raw
+  /// programs.
+  pub fn is_image(bytes: &[u8]) -> bool {
+    bytes.starts_with(Self::MAGIC)
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut e = Encoder::new();
+    e.u64(u64::from_le_bytes(*Self::MAGIC))
+      .u64(Self::VERSION)
+      .u64(self.entry as u64)
+      .u64(self.segments.len() as u64);
+    for segment in &self.segments {
+      e.u64(segment.address as u64).bytes(&segment.bytes);
+    }
+    e.finish()
+  }
+
+  /// Decodes an image, rejecting overlapping segments and any reaching past
+  /// the end of memory.
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+    if !Self::is_image(bytes) {
+      return Err(Error::BadMagic);
+    }
+    let mut d = Decoder::new(&bytes[Self::MAGIC.len()..]);
+    let version = d.u64()?;
+    if version != Self::VERSION {
+      return Err(Error::UnsupportedVersion(version));
+    }
+    let entry = d.u64()? as usize;
+    if entry >= MEMORY_SIZE {
+      return Err(Error::InvalidEntry(entry));
+    }
+    let mut image = Self::new(entry);
+    for _ in 0..d.u64()? {
+      let address = d.u64()? as usize;
+      let segment = Segment {
+        address,
+        bytes: d.bytes()?.to_vec(),
+      };
+      // the lengths come from the file, so the end may overflow
+      let range = match address.checked_add(segment.bytes.len()) {
+        Some(end) if end <= MEMORY_SIZE => address..end,
+        _ => return Err(Error::OutOfMemory(address)),
+      };
+      if let Some(other) = image
+        .segments
+        .iter()
+        .find(|other| other.address < range.end && range.start < other.range().end)
+      {
+        return Err(Error::Overlap(address.max(other.address)));
+      }
+      image.segments.push(segment);
+    }
+    Ok(image)
+  }
+}
+
+/// Lays the segments out from address zero, zero filling the gaps, which is
+/// the layout `Vm::load` expects.
+impl From<&Image> for Chunk {
+  fn from(image: &Image) -> Self {
+    let mut chunk = Chunk::from(Vec::new());
+    for segment in &image.segments {
+      chunk.write(segment.address, &segment.bytes);
+    }
+    chunk
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn round_trips_through_bytes() {
+    let image = Image::new(0x10)
+      .segment(0, vec![0x10, 0x00])
+      .segment(0x100, vec![1, 2, 3]);
+    assert_eq!(Image::from_bytes(&image.to_bytes()).unwrap(), image);
+  }
+
+  #[test]
+  fn rejects_segments_past_memory() {
+    let image = Image::new(0).segment(MEMORY_SIZE - 1, vec![0; 2]);
+    assert!(matches!(
+      Image::from_bytes(&image.to_bytes()),
+      Err(Error::OutOfMemory(_))
+    ));
+    let image = Image::new(0).segment(usize::MAX - 7, vec![0; 16]);
+    assert!(matches!(
+      Image::from_bytes(&image.to_bytes()),
+      Err(Error::OutOfMemory(_))
+    ));
+  }
+
+  #[test]
+  fn rejects_overlaps_and_bad_entries() {
+    let image = Image::new(0).segment(0, vec![0; 8]).segment(4, vec![0; 8]);
+    assert!(matches!(
+      Image::from_bytes(&image.to_bytes()),
+      Err(Error::Overlap(_))
+    ));
+    let image = Image::new(MEMORY_SIZE);
+    assert!(matches!(
+      Image::from_bytes(&image.to_bytes()),
+      Err(Error::InvalidEntry(_))
+    ));
+    assert!(matches!(
+      Image::from_bytes(b"\x30\xf0"),
+      Err(Error::BadMagic)
+    ));
+  }
+}
@@ -0,0 +1,261 @@
+use std::ops::Range;
+
+use crate::disasm;
+use crate::region::Region;
+use crate::register::Register;
+use crate::vm::{self, Executed, State, Vm};
+use crate::{BLOCK_SIZE, Block};
+
+/// A fault the injector applied, and the step it applied before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Injected {
+  pub step: usize,
+  pub fault: Fault,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+  /// `bit` of `register` was inverted.
+  RegisterBit { register: Register, bit: u32 },
+  /// `bit` of the block at `address` was inverted.
+  MemoryBit { address: usize, bit: u32 },
+  /// The instruction read the value `register` held before the previous
+  /// instruction wrote it, as if forwarding had failed.
+  DroppedForward { register: Register, stale: Block },
+}
+
+#[derive(Debug, Clone)]
+enum Rule {
+  RegisterBit,
+  MemoryBit(Range<usize>),
+  DroppedForward,
+}
+
+/// Component name the injector draws from `Vm::entropy_mut` under.
+pub const ENTROPY: &str = "inject";
+
+/// Steps a vm while deliberately corrupting it according to a policy,
+/// drawing from the vm's entropy so the same seed and program always see the
+/// same faults.
+///
+/// Each rule fires before every `every`th instruction, with the register,
+/// address and bit picked at random. Faults are applied to architectural
+/// state directly and leave no trace besides `Injector::log`.
+#[derive(Debug, Clone, Default)]
+pub struct Injector {
+  rules: Vec<(usize, Rule)>,
+  log: Vec<Injected>,
+  // register values before the previous instruction ran
+  previous: Option<Vec<Block>>,
+}
+
+impl Injector {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Flips a random bit of a random register every `every` instructions.
+  pub fn flip_register_bit(mut self, every: usize) -> Self {
+    self.rules.push((every.max(1), Rule::RegisterBit));
+    self
+  }
+
+  /// Flips a random bit of a random block inside `range` every `every`
+  /// instructions.
+  pub fn corrupt_memory(mut self, every: usize, range: Range<usize>) -> Self {
+    self.rules.push((every.max(1), Rule::MemoryBit(range)));
+    self
+  }
+
+  /// Every `every` instructions, hands the instruction the stale value of a
+  /// source register the previous instruction wrote. The register file is
+  /// repaired afterwards unless the instruction overwrote the register, so
+  /// only the one consumer is affected. Nothing happens when the instruction
+  /// does not depend on its predecessor.
+  pub fn drop_forward(mut self, every: usize) -> Self {
+    self.rules.push((every.max(1), Rule::DroppedForward));
+    self
+  }
+
+  /// Faults applied so far, oldest first.
+  pub fn log(&self) -> &[Injected] {
+    &self.log
+  }
+
+  /// Applies any faults due, then executes one instruction.
+  pub fn step<R>(&mut self, vm: &mut Vm, region: &R) -> Result<Executed, vm::Error>
+  where
+    R: Region,
+  {
+    let step = vm.steps();
+    let before: Vec<Block> = vm.registers().map(|(_, value)| value).collect();
+    let mut repair = None;
+    for i in 0..self.rules.len() {
+      let (every, rule) = &self.rules[i];
+      if !(step + 1).is_multiple_of(*every) {
+        continue;
+      }
+      let fault = match rule.clone() {
+        Rule::RegisterBit => Some(self.flip_register(vm)),
+        Rule::MemoryBit(range) => self.flip_memory(vm, range)?,
+        Rule::DroppedForward => {
+          let fault = self.drop(vm, region, &before);
+          if let Some(Fault::DroppedForward { register, stale }) = fault {
+            repair = Some((register, before[register as usize], stale));
+          }
+          fault
+        }
+      };
+      if let Some(fault) = fault {
+        self.log.push(Injected { step, fault });
+      }
+    }
+    self.previous = Some(before);
+    let result = vm.step(region);
+    if let Some((register, value, stale)) = repair
+      && vm.register(register) == stale
+    {
+      vm.set_register(register, value);
+    }
+    result
+  }
+
+  /// Steps until the vm halts or faults.
+  pub fn run<R>(&mut self, vm: &mut Vm, region: &R) -> Result<(), vm::Error>
+  where
+    R: Region,
+  {
+    while vm.state() != State::Halted {
+      self.step(vm, region)?;
+    }
+    Ok(())
+  }
+
+  fn flip_register(&mut self, vm: &mut Vm) -> Fault {
+    let register = Register::iter()
+      .nth(vm.entropy_mut().below(ENTROPY, Register::iter().count()))
+      .expect("index below register count");
+    let bit = vm.entropy_mut().below(ENTROPY, Block::BITS as usize) as u32;
+    vm.set_register(register, vm.register(register) ^ (1 << bit));
+    Fault::RegisterBit { register, bit }
+  }
+
+  fn flip_memory(&mut self, vm: &mut Vm, range: Range<usize>) -> Result<Option<Fault>, vm::Error> {
+    let first = range.start.next_multiple_of(BLOCK_SIZE);
+    let blocks = range.end.saturating_sub(first) / BLOCK_SIZE;
+    if blocks == 0 {
+      return Ok(None);
+    }
+    let address = first + vm.entropy_mut().below(ENTROPY, blocks) * BLOCK_SIZE;
+    let bit = vm.entropy_mut().below(ENTROPY, Block::BITS as usize) as u32;
+    let mut bytes = vm.read_bytes(address, BLOCK_SIZE)?;
+    bytes[bit as usize / 8] ^= 1 << (bit % 8);
+    vm.write_bytes(address, &bytes)?;
+    Ok(Some(Fault::MemoryBit { address, bit }))
+  }
+
+  fn drop<R>(&mut self, vm: &mut Vm, region: &R, before: &[Block]) -> Option<Fault>
+  where
+    R: Region,
+  {
+    let previous = self.previous.as_ref()?;
+    let (instruction, _) = disasm::decode(region.instructions(), vm.ip()).ok()?;
+    let candidates: Vec<Register> = instruction
+      .reads()
+      .into_iter()
+      .flatten()
+      .filter(|&reg| previous[reg as usize] != before[reg as usize])
+      .collect();
+    if candidates.is_empty() {
+      return None;
+    }
+    let register = candidates[vm.entropy_mut().below(ENTROPY, candidates.len())];
+    let stale = previous[register as usize];
+    vm.set_register(register, stale);
+    Some(Fault::DroppedForward { register, stale })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm;
+  use crate::builder::VmBuilder;
+  use crate::region::Chunk;
+
+  fn start(seed: u64, source: &str) -> (Vm, Chunk) {
+    let region = Chunk::from(asm::assemble(source).unwrap().bytes().to_vec());
+    let mut vm = VmBuilder::new().seed(seed).build();
+    vm.load(&region).unwrap();
+    (vm, region)
+  }
+
+  const COUNT: &str = "
+    irmovq $1, %rcx
+    irmovq $8, %rdx
+loop:
+    addq %rcx, %rax
+    subq %rcx, %rdx
+    jne loop
+    halt
+";
+
+  #[test]
+  fn same_seed_injects_the_same_faults() {
+    let run = |seed| {
+      let (mut vm, region) = start(seed, COUNT);
+      let mut injector = Injector::new().flip_register_bit(4);
+      // a flipped counter may never reach zero
+      for _ in 0..100 {
+        if vm.state() == State::Halted || injector.step(&mut vm, &region).is_err() {
+          break;
+        }
+      }
+      (injector.log().to_vec(), vm.arch_state())
+    };
+    let (log, state) = run(7);
+    assert!(!log.is_empty());
+    assert!(log.iter().all(|injected| (injected.step + 1) % 4 == 0
+      && matches!(injected.fault, Fault::RegisterBit { .. })));
+    assert_eq!(run(7), (log, state));
+  }
+
+  #[test]
+  fn corrupts_one_bit_of_memory_in_range() {
+    let (mut vm, region) = start(1, "nop\nhalt");
+    vm.write_bytes(0x100, &[0xff; 8]).unwrap();
+    let mut injector = Injector::new().corrupt_memory(1, 0x0fc..0x108);
+    injector.step(&mut vm, &region).unwrap();
+    let [
+      Injected {
+        step: 0,
+        fault: Fault::MemoryBit { address, bit },
+      },
+    ] = injector.log()[..]
+    else {
+      panic!("unexpected faults {:?}", injector.log());
+    };
+    assert_eq!(address, 0x100);
+    let value = Block::from_le_bytes(vm.read_bytes(0x100, 8).unwrap().try_into().unwrap());
+    assert_eq!(value, !(1 << bit));
+  }
+
+  #[test]
+  fn dropped_forwards_reach_only_the_consumer() {
+    let (mut vm, region) = start(3, "irmovq $5, %rax\naddq %rax, %rcx\nhalt");
+    let mut injector = Injector::new().drop_forward(1);
+    injector.run(&mut vm, &region).unwrap();
+    assert_eq!(
+      injector.log(),
+      [Injected {
+        step: 1,
+        fault: Fault::DroppedForward {
+          register: Register::Rax,
+          stale: 0,
+        },
+      }]
+    );
+    assert_eq!(vm.register(Register::Rcx), 0);
+    assert_eq!(vm.register(Register::Rax), 5);
+  }
+}
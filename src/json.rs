@@ -0,0 +1,169 @@
+use std::fmt::{self, Write};
+
+/// Minimal json value used for machine readable output, serde is not a
+/// dependency of this crate.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Json {
+  Bool(bool),
+  Int(i64),
+  Str(String),
+  Array(Vec<Json>),
+  Object(Vec<(String, Json)>),
+}
+
+impl Json {
+  pub(crate) fn object<K, I>(fields: I) -> Self
+  where
+    K: Into<String>,
+    I: IntoIterator<Item = (K, Json)>,
+  {
+    Json::Object(fields.into_iter().map(|(k, v)| (k.into(), v)).collect())
+  }
+
+  fn fmt_pretty(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+    let indent = |f: &mut fmt::Formatter<'_>, depth: usize| {
+      for _ in 0..depth {
+        f.write_str("  ")?;
+      }
+      Ok(())
+    };
+    match self {
+      Json::Array(items) if !items.is_empty() => {
+        f.write_str("[\n")?;
+        for (i, item) in items.iter().enumerate() {
+          indent(f, depth + 1)?;
+          item.fmt_pretty(f, depth + 1)?;
+          f.write_str(if i + 1 < items.len() { ",\n" } else { "\n" })?;
+        }
+        indent(f, depth)?;
+        f.write_char(']')
+      }
+      Json::Object(fields) if !fields.is_empty() => {
+        f.write_str("{\n")?;
+        for (i, (key, value)) in fields.iter().enumerate() {
+          indent(f, depth + 1)?;
+          write_str(f, key)?;
+          f.write_str(": ")?;
+          value.fmt_pretty(f, depth + 1)?;
+          f.write_str(if i + 1 < fields.len() { ",\n" } else { "\n" })?;
+        }
+        indent(f, depth)?;
+        f.write_char('}')
+      }
+      other => write!(f, "{other}"),
+    }
+  }
+}
+
+fn write_str(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+  f.write_char('"')?;
+  for c in s.chars() {
+    match c {
+      '"' => f.write_str("\\\"")?,
+      '\\' => f.write_str("\\\\")?,
+      '\n' => f.write_str("\\n")?,
+      '\r' => f.write_str("\\r")?,
+      '\t' => f.write_str("\\t")?,
+      c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+      c => f.write_char(c)?,
+    }
+  }
+  f.write_char('"')
+}
+
+/// Compact by default, `{:#}` pretty prints with two space indentation.
+impl fmt::Display for Json {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if f.alternate() {
+      return self.fmt_pretty(f, 0);
+    }
+    match self {
+      Json::Bool(b) => write!(f, "{b}"),
+      Json::Int(n) => write!(f, "{n}"),
+      Json::Str(s) => write_str(f, s),
+      Json::Array(items) => {
+        f.write_char('[')?;
+        for (i, item) in items.iter().enumerate() {
+          if i > 0 {
+            f.write_char(',')?;
+          }
+          write!(f, "{item}")?;
+        }
+        f.write_char(']')
+      }
+      Json::Object(fields) => {
+        f.write_char('{')?;
+        for (i, (key, value)) in fields.iter().enumerate() {
+          if i > 0 {
+            f.write_char(',')?;
+          }
+          write_str(f, key)?;
+          write!(f, ":{value}")?;
+        }
+        f.write_char('}')
+      }
+    }
+  }
+}
+
+impl From<bool> for Json {
+  fn from(b: bool) -> Self {
+    Json::Bool(b)
+  }
+}
+
+impl From<i64> for Json {
+  fn from(n: i64) -> Self {
+    Json::Int(n)
+  }
+}
+
+impl From<usize> for Json {
+  fn from(n: usize) -> Self {
+    Json::Int(n as i64)
+  }
+}
+
+impl From<&str> for Json {
+  fn from(s: &str) -> Self {
+    Json::Str(s.to_string())
+  }
+}
+
+impl From<String> for Json {
+  fn from(s: String) -> Self {
+    Json::Str(s)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn encodes_compact_and_pretty() {
+    let value = Json::object([
+      ("ok", Json::from(true)),
+      ("steps", Json::from(3usize)),
+      (
+        "items",
+        Json::Array(vec![Json::from(-1i64), Json::from("x")]),
+      ),
+      ("empty", Json::Array(Vec::new())),
+    ]);
+    assert_eq!(
+      value.to_string(),
+      r#"{"ok":true,"steps":3,"items":[-1,"x"],"empty":[]}"#
+    );
+    assert_eq!(
+      format!("{value:#}"),
+      "{\n  \"ok\": true,\n  \"steps\": 3,\n  \"items\": [\n    -1,\n    \"x\"\n  ],\n  \"empty\": []\n}"
+    );
+  }
+
+  #[test]
+  fn escapes_strings() {
+    let value = Json::from("a\"b\\c\n\t\u{1}");
+    assert_eq!(value.to_string(), r#""a\"b\\c\n\t\u0001""#);
+  }
+}
@@ -0,0 +1,114 @@
+use std::fmt;
+use std::ops::Range;
+
+use crate::bus::DeviceId;
+
+/// What occupies an area of the address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+  /// The read only boot rom.
+  Rom,
+  /// The program placed by `Vm::load`.
+  Code,
+  Stack,
+  /// The unmapped band below the stack.
+  Guard,
+  /// A memory mapped device, shadowing whatever else is listed there.
+  Device {
+    id: DeviceId,
+    priority: i32,
+  },
+}
+
+impl fmt::Display for Kind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Kind::Rom => f.pad("rom"),
+      Kind::Code => f.pad("code"),
+      Kind::Stack => f.pad("stack"),
+      Kind::Guard => f.pad("guard"),
+      Kind::Device { id, priority: 0 } => f.pad(&format!("device {}", id.0)),
+      Kind::Device { id, priority } => f.pad(&format!("device {} (priority {priority})", id.0)),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Area {
+  pub kind: Kind,
+  pub range: Range<usize>,
+}
+
+/// Layout of the address space, see `Vm::memory_map`. Areas are sorted by
+/// start address and may overlap, as a device can sit on top of memory that
+/// also belongs to the stack or the program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryMap {
+  /// Bytes of main memory, the rom and devices may extend past it.
+  pub size: usize,
+  pub areas: Vec<Area>,
+}
+
+impl MemoryMap {
+  pub(crate) fn new(size: usize, mut areas: Vec<Area>) -> Self {
+    areas.retain(|area| !area.range.is_empty());
+    areas.sort_by_key(|area| (area.range.start, area.range.end));
+    Self { size, areas }
+  }
+
+  /// Every area containing `address`, devices first as they answer for it.
+  pub fn at(&self, address: usize) -> Vec<&Area> {
+    let mut areas: Vec<_> = self
+      .areas
+      .iter()
+      .filter(|area| area.range.contains(&address))
+      .collect();
+    areas.sort_by_key(|area| !matches!(area.kind, Kind::Device { .. }));
+    areas
+  }
+
+  /// Memory not claimed by any area.
+  pub fn free(&self) -> Vec<Range<usize>> {
+    let mut free = Vec::new();
+    let mut at = 0;
+    for area in &self.areas {
+      if area.range.start > at {
+        free.push(at..area.range.start.min(self.size));
+      }
+      at = at.max(area.range.end);
+    }
+    if at < self.size {
+      free.push(at..self.size);
+    }
+    free.retain(|range| !range.is_empty());
+    free
+  }
+}
+
+/// One line per area with free memory in between, e.g.
+/// `0x0000-0x009b  code   155 bytes`.
+impl fmt::Display for MemoryMap {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let free = self.free();
+    let mut lines: Vec<(Range<usize>, String)> = self
+      .areas
+      .iter()
+      .map(|area| (area.range.clone(), area.kind.to_string()))
+      .chain(free.into_iter().map(|range| (range, "free".to_string())))
+      .collect();
+    lines.sort_by_key(|(range, _)| (range.start, range.end));
+    let width = lines.iter().map(|(_, kind)| kind.len()).max().unwrap_or(0);
+    let end = lines.iter().map(|(range, _)| range.end).max().unwrap_or(0);
+    let digits = format!("{end:#06x}").len();
+    for (range, kind) in lines {
+      let span = format!("{:#06x}-{:#06x}", range.start, range.end);
+      writeln!(
+        f,
+        "{span:<span_width$}  {kind:<width$}  {} bytes",
+        range.len(),
+        span_width = 2 * digits + 1
+      )?;
+    }
+    Ok(())
+  }
+}
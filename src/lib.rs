@@ -1,9 +1,50 @@
 use std::mem;
 
+pub mod analysis;
+pub mod asm;
+pub mod bisect;
+pub mod builder;
+pub mod bus;
+pub mod compare;
+pub mod control;
+pub mod debugger;
+pub mod device;
+pub mod disasm;
+pub mod event;
+pub mod exit;
+pub mod expr;
+pub mod frame;
+pub mod generator;
+pub mod heap;
+pub mod history;
+pub mod image;
+pub mod inject;
+mod json;
+pub mod layout;
 pub mod memory;
+pub mod minimize;
+pub mod multicore;
 pub mod opcode;
+pub mod pipeline;
+pub mod profile;
+pub mod quota;
 pub mod region;
 pub mod register;
+pub mod replay;
+pub mod report;
+pub mod rng;
+pub mod runner;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod session;
+pub mod snapshot;
+pub mod suite;
+pub mod superscalar;
+pub mod symbol;
+pub mod syscall;
+pub mod timing;
+pub mod trace;
+pub mod trap;
 pub mod vm;
 
 pub(crate) type Word = i64;
@@ -1,4 +1,7 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::ops::Range;
 
 use crate::{BLOCK_SIZE, Block};
 
@@ -11,58 +14,296 @@ pub enum Error {
   UnalignedAccess(usize),
 }
 
-pub(crate) struct MainMemory {
+/// Bytes of address space every vm has.
+pub const MEMORY_SIZE: usize = 1 << 16; // 64KB of memory
+
+/// Storage behind the address space of a vm, swapped in with
+/// `Vm::set_memory`. Accesses are bounds checked before they reach the
+/// backend, so every byte it is asked about lies below `MEMORY_SIZE`, and
+/// bytes never written read as zero.
+pub trait MemoryBackend: fmt::Debug + Send {
+  /// Fills `buf` with the bytes starting at `addr`.
+  fn read(&self, addr: usize, buf: &mut [u8]);
+
+  fn write(&mut self, addr: usize, bytes: &[u8]);
+
+  /// Zeroes every byte.
+  fn clear(&mut self);
+
+  /// The whole address space as one slice, for backends that store it that
+  /// way. Lets the vm fetch from and snapshot memory without copying it.
+  fn as_slice(&self) -> Option<&[u8]> {
+    None
+  }
+}
+
+/// One flat allocation covering the address space, the default backend.
+#[derive(Clone)]
+pub struct Dense {
   bytes: Vec<u8>,
 }
 
+impl Dense {
+  pub fn new() -> Self {
+    Self::default()
+  }
+}
+
+impl Default for Dense {
+  fn default() -> Self {
+    Self {
+      bytes: vec![0; MEMORY_SIZE],
+    }
+  }
+}
+
+impl MemoryBackend for Dense {
+  fn read(&self, addr: usize, buf: &mut [u8]) {
+    buf.copy_from_slice(&self.bytes[addr..addr + buf.len()]);
+  }
+
+  fn write(&mut self, addr: usize, bytes: &[u8]) {
+    self.bytes[addr..addr + bytes.len()].copy_from_slice(bytes);
+  }
+
+  fn clear(&mut self) {
+    self.bytes.fill(0);
+  }
+
+  fn as_slice(&self) -> Option<&[u8]> {
+    Some(&self.bytes)
+  }
+}
+
+impl fmt::Debug for Dense {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Dense {{ size: {} bytes }}", self.bytes.len())
+  }
+}
+
+/// Allocates fixed size pages on first write, so mostly untouched memory
+/// costs little to keep or clear.
+#[derive(Clone, Default)]
+pub struct Sparse {
+  pages: BTreeMap<usize, Box<[u8; Sparse::PAGE_SIZE]>>,
+}
+
+impl Sparse {
+  pub const PAGE_SIZE: usize = 4096;
+
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Number of pages allocated so far.
+  pub fn pages(&self) -> usize {
+    self.pages.len()
+  }
+
+  // splits `addr..addr + len` at page boundaries into (page, offset, span)
+  fn spans(addr: usize, len: usize) -> impl Iterator<Item = (usize, usize, Range<usize>)> {
+    let mut at = addr;
+    std::iter::from_fn(move || {
+      if at >= addr + len {
+        return None;
+      }
+      let (page, offset) = (at / Self::PAGE_SIZE, at % Self::PAGE_SIZE);
+      let n = (Self::PAGE_SIZE - offset).min(addr + len - at);
+      let span = at - addr..at - addr + n;
+      at += n;
+      Some((page, offset, span))
+    })
+  }
+}
+
+impl MemoryBackend for Sparse {
+  fn read(&self, addr: usize, buf: &mut [u8]) {
+    for (page, offset, span) in Self::spans(addr, buf.len()) {
+      let out = &mut buf[span];
+      match self.pages.get(&page) {
+        Some(bytes) => out.copy_from_slice(&bytes[offset..offset + out.len()]),
+        None => out.fill(0),
+      }
+    }
+  }
+
+  fn write(&mut self, addr: usize, bytes: &[u8]) {
+    for (page, offset, span) in Self::spans(addr, bytes.len()) {
+      let data = &bytes[span];
+      // writing zeros to a missing page changes nothing
+      if !self.pages.contains_key(&page) && data.iter().all(|&b| b == 0) {
+        continue;
+      }
+      let stored = self
+        .pages
+        .entry(page)
+        .or_insert_with(|| Box::new([0; Self::PAGE_SIZE]));
+      stored[offset..offset + data.len()].copy_from_slice(data);
+    }
+  }
+
+  fn clear(&mut self) {
+    self.pages.clear();
+  }
+}
+
+impl fmt::Debug for Sparse {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Sparse {{ pages: {} }}", self.pages.len())
+  }
+}
+
+pub(crate) struct MainMemory {
+  backend: Box<dyn MemoryBackend>,
+  // one bit per block written since the last `mark_clean`
+  dirty: Vec<u64>,
+  // one bit per block ever written, only reset by `clear`
+  written: Vec<u64>,
+}
+
 impl MainMemory {
-  pub(crate) const MEMORY_SIZE: usize = 1 << 16; // 64KB of memory
+  pub(crate) const MEMORY_SIZE: usize = MEMORY_SIZE;
 
   pub(crate) fn read(&self, addr: usize) -> Result<Block, Error> {
-    if addr % BLOCK_SIZE != 0 {
+    if !addr.is_multiple_of(BLOCK_SIZE) {
       return Err(Error::UnalignedAccess(addr));
     }
-    if addr + BLOCK_SIZE > self.bytes.len() {
-      return Err(Error::InvalidAddress(addr));
-    }
-    // safety:
-    // - we verified 8 byte alignment (can use read)
-    // - we made sure we are reading within valid bytes
-    let value = unsafe {
-      let block = self.bytes.as_ptr().add(addr) as *const Block;
-      block.read()
-    };
-    Ok(value)
+    self.check(addr, BLOCK_SIZE)?;
+    let mut bytes = [0u8; BLOCK_SIZE];
+    self.backend.read(addr, &mut bytes);
+    Ok(Block::from_ne_bytes(bytes))
   }
 
   pub(crate) fn write(&mut self, addr: usize, value: Block) -> Result<(), Error> {
-    if addr % BLOCK_SIZE != 0 {
+    if !addr.is_multiple_of(BLOCK_SIZE) {
       return Err(Error::UnalignedAccess(addr));
     }
-    if addr + BLOCK_SIZE > self.bytes.len() {
+    self.check(addr, BLOCK_SIZE)?;
+    self.backend.write(addr, &value.to_ne_bytes());
+    self.mark_dirty(addr, BLOCK_SIZE);
+    Ok(())
+  }
+
+  fn check(&self, addr: usize, len: usize) -> Result<(), Error> {
+    if addr.checked_add(len).is_none_or(|end| end > MEMORY_SIZE) {
       return Err(Error::InvalidAddress(addr));
     }
-    // safety:
-    // - we verified 8 byte alignment (can use write)
-    // - we made sure we are writing within valid bytes
-    unsafe {
-      let block = self.bytes.as_ptr().add(addr) as *mut Block;
-      block.write(value);
+    Ok(())
+  }
+
+  /// Moves the contents into `backend` and keeps using it from now on.
+  pub(crate) fn set_backend(&mut self, mut backend: Box<dyn MemoryBackend>) {
+    backend.clear();
+    backend.write(0, &self.bytes());
+    self.backend = backend;
+  }
+
+  fn mark_dirty(&mut self, addr: usize, len: usize) {
+    if len == 0 {
+      return;
+    }
+    for block in addr / BLOCK_SIZE..=(addr + len - 1) / BLOCK_SIZE {
+      self.dirty[block / 64] |= 1 << (block % 64);
+      self.written[block / 64] |= 1 << (block % 64);
     }
+  }
+
+  /// Whether any byte of the block holding `addr` was ever written.
+  pub(crate) fn is_written(&self, addr: usize) -> bool {
+    let block = addr / BLOCK_SIZE;
+    self
+      .written
+      .get(block / 64)
+      .is_some_and(|word| word & (1 << (block % 64)) != 0)
+  }
+
+  fn is_dirty(&self, block: usize) -> bool {
+    self.dirty[block / 64] & (1 << (block % 64)) != 0
+  }
+
+  /// Forgets every write so far, later writes are reported as dirty again.
+  pub(crate) fn mark_clean(&mut self) {
+    self.dirty.fill(0);
+  }
+
+  /// Block aligned address ranges written since the last `mark_clean`,
+  /// adjacent dirty blocks coalesced into one range.
+  pub(crate) fn dirty_regions(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+    let blocks = MEMORY_SIZE / BLOCK_SIZE;
+    let mut block = 0;
+    std::iter::from_fn(move || {
+      while block < blocks && !self.is_dirty(block) {
+        // skip clean words wholesale
+        if block % 64 == 0 && self.dirty[block / 64] == 0 {
+          block += 64;
+        } else {
+          block += 1;
+        }
+      }
+      if block >= blocks {
+        return None;
+      }
+      let start = block;
+      while block < blocks && self.is_dirty(block) {
+        block += 1;
+      }
+      Some(start * BLOCK_SIZE..block * BLOCK_SIZE)
+    })
+  }
+
+  /// Byte granular read, no alignment required. Borrowed when the backend
+  /// stores memory contiguously.
+  pub(crate) fn read_bytes(&self, addr: usize, len: usize) -> Result<Cow<'_, [u8]>, Error> {
+    self.check(addr, len)?;
+    if let Some(bytes) = self.backend.as_slice() {
+      return Ok(Cow::Borrowed(&bytes[addr..addr + len]));
+    }
+    let mut bytes = vec![0; len];
+    self.backend.read(addr, &mut bytes);
+    Ok(Cow::Owned(bytes))
+  }
+
+  /// Byte granular write, no alignment required.
+  pub(crate) fn write_bytes(&mut self, addr: usize, bytes: &[u8]) -> Result<(), Error> {
+    self.check(addr, bytes.len())?;
+    self.backend.write(addr, bytes);
+    self.mark_dirty(addr, bytes.len());
     Ok(())
   }
+
+  /// All of memory, for snapshots and disassembly.
+  pub(crate) fn bytes(&self) -> Cow<'_, [u8]> {
+    self
+      .read_bytes(0, MEMORY_SIZE)
+      .expect("whole address space in bounds")
+  }
+
+  pub(crate) fn clear(&mut self) {
+    self.backend.clear();
+    self.mark_clean();
+    self.written.fill(0);
+  }
+
+  /// Iterates over every aligned block along with its address.
+  pub(crate) fn blocks(&self) -> impl Iterator<Item = (usize, Block)> + '_ {
+    (0..MEMORY_SIZE)
+      .step_by(BLOCK_SIZE)
+      .map(|addr| (addr, self.read(addr).expect("aligned block in bounds")))
+  }
 }
 
 impl Default for MainMemory {
   fn default() -> Self {
     Self {
-      bytes: vec![0; Self::MEMORY_SIZE],
+      backend: Box::new(Dense::new()),
+      dirty: vec![0; (MEMORY_SIZE / BLOCK_SIZE).div_ceil(64)],
+      written: vec![0; (MEMORY_SIZE / BLOCK_SIZE).div_ceil(64)],
     }
   }
 }
 
 impl fmt::Debug for MainMemory {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    write!(f, "MainMemory {{ size: {} bytes }}", self.bytes.len())
+    write!(f, "MainMemory {{ backend: {:?} }}", self.backend)
   }
 }
@@ -0,0 +1,157 @@
+//! Shrinking of programs that fail, for fuzzing findings and bug reports.
+
+use std::ops::Range;
+
+use crate::builder::VmBuilder;
+use crate::disasm;
+use crate::region::Chunk;
+use crate::vm;
+
+const NOP: u8 = 0x10;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("the program does not fail to begin with")]
+  NotFailing,
+}
+
+/// Outcome of `Minimizer::minimize`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Minimized {
+  pub program: Vec<u8>,
+  /// Instructions and undecodable bytes deleted, moving what followed.
+  pub removed: usize,
+  /// Instructions overwritten with `nop`s where deleting them would have
+  /// moved code the failure depends on.
+  pub blanked: usize,
+  /// Times the failure check ran.
+  pub tests: usize,
+}
+
+/// Shrinks a failing program one instruction boundary at a time: runs of
+/// instructions are deleted, or blanked with `nop`s when deleting them loses
+/// the failure, halving the run length until no single instruction can go.
+/// Bytes that do not decode, typically data, are units of their own.
+#[derive(Debug, Clone)]
+pub struct Minimizer {
+  max_tests: usize,
+}
+
+impl Minimizer {
+  pub fn new() -> Self {
+    Self { max_tests: 10_000 }
+  }
+
+  /// Stops shrinking after `max_tests` checks, returning the smallest
+  /// failing program found so far.
+  pub fn max_tests(mut self, max_tests: usize) -> Self {
+    self.max_tests = max_tests;
+    self
+  }
+
+  /// Shrinks `program` while `fails` holds for it, see `faults_like` for
+  /// the usual check.
+  pub fn minimize(
+    &self,
+    program: &[u8],
+    mut fails: impl FnMut(&[u8]) -> bool,
+  ) -> Result<Minimized, Error> {
+    if !fails(program) {
+      return Err(Error::NotFailing);
+    }
+    let mut best = Minimized {
+      program: program.to_vec(),
+      removed: 0,
+      blanked: 0,
+      tests: 1,
+    };
+    let mut progress = true;
+    while progress && best.tests < self.max_tests {
+      progress = false;
+      let mut size = units(&best.program).len().div_ceil(2).max(1);
+      loop {
+        let mut i = 0;
+        while i < units(&best.program).len() && best.tests < self.max_tests {
+          let units = units(&best.program);
+          let run = &units[i..(i + size).min(units.len())];
+          let bytes = run[0].start..run[run.len() - 1].end;
+          let mut deleted = best.program.clone();
+          deleted.drain(bytes.clone());
+          best.tests += 1;
+          if fails(&deleted) {
+            best.program = deleted;
+            best.removed += run.len();
+            progress = true;
+            continue;
+          }
+          if best.program[bytes.clone()].iter().any(|&byte| byte != NOP) {
+            let mut blanked = best.program.clone();
+            blanked[bytes].fill(NOP);
+            best.tests += 1;
+            if fails(&blanked) {
+              best.program = blanked;
+              best.blanked += run.len();
+              progress = true;
+            }
+          }
+          i += size;
+        }
+        if size == 1 {
+          break;
+        }
+        size = size.div_ceil(2);
+      }
+    }
+    Ok(best)
+  }
+}
+
+impl Default for Minimizer {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Byte ranges of the instructions of `program` found by decoding from
+/// address zero, undecodable bytes each taking a range of their own.
+pub fn units(program: &[u8]) -> Vec<Range<usize>> {
+  let mut units = Vec::new();
+  let mut at = 0;
+  while at < program.len() {
+    let len =
+      disasm::disassemble_at(program, at).map_or(1, |instruction| instruction.bytes().len());
+    units.push(at..at + len);
+    at += len;
+  }
+  units
+}
+
+/// The error a vm built by `builder` stops with when running `program`, or
+/// `None` if it halts. Give the builder a `max_steps` so programs that loop
+/// forever fail with `Error::StepLimitExceeded` rather than hang.
+pub fn fault(builder: &VmBuilder, program: &[u8]) -> Option<vm::Error> {
+  let chunk = Chunk::from(program.to_vec());
+  let mut vm = builder.clone().build();
+  vm.load(&chunk).and_then(|()| vm.run(&chunk)).err()
+}
+
+/// A check for `Minimizer::minimize` holding for programs that fault like
+/// `program` does, with the same kind of error whatever the addresses and
+/// values in it. Fails if `program` does not fault at all. As with `fault`,
+/// the builder needs a `max_steps`, shrinking easily turns a loop endless.
+pub fn faults_like(builder: VmBuilder, program: &[u8]) -> Result<impl FnMut(&[u8]) -> bool, Error> {
+  let original = fault(&builder, program).ok_or(Error::NotFailing)?;
+  let expected = kind(&original);
+  Ok(move |candidate: &[u8]| fault(&builder, candidate).is_some_and(|e| kind(&e) == expected))
+}
+
+/// The variant of `error`, nested ones included so an invalid opcode stays
+/// apart from other opcode errors.
+fn kind(error: &vm::Error) -> String {
+  // variants nest as `Outer(Inner(..))` in the debug form, keep the names
+  format!("{error:?}")
+    .split(|c: char| !c.is_alphanumeric() && c != '_')
+    .take_while(|part| part.starts_with(char::is_uppercase))
+    .collect::<Vec<_>>()
+    .join("::")
+}
@@ -0,0 +1,227 @@
+use crate::Block;
+use crate::region::Region;
+use crate::register::Register;
+use crate::vm::{self, Context, State, Vm};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("no core with index {0}")]
+  InvalidCore(usize),
+
+  #[error("core {0} faulted - {1}")]
+  CoreFaulted(usize, vm::Error),
+}
+
+/// Several cores executing the same region against one shared memory.
+///
+/// Cores are stepped one instruction at a time on the calling thread, so every
+/// instruction is atomic with respect to the other cores and no locking is
+/// involved. `casq` compares and swaps a block in one instruction, the
+/// building block for locks and lock free structures between cores.
+///
+/// Core `i` starts at the vm entry point with `i` in `%rdi` and its own
+/// stack, `stack_size` bytes below the stack of core `i - 1`. A stack that
+/// would start below address zero wraps, faulting on the first push.
+#[derive(Debug)]
+pub struct Multicore {
+  vm: Vm,
+  cores: Vec<Context>,
+}
+
+impl Multicore {
+  pub fn new(vm: Vm, cores: usize, stack_size: usize) -> Self {
+    let template = vm.context();
+    let cores = (0..cores)
+      .map(|i| {
+        let mut context = template.clone();
+        let below = (i as Block).wrapping_mul(stack_size as Block);
+        context.reg_file[Register::Rsp] = context.reg_file[Register::Rsp].wrapping_sub(below);
+        context.reg_file[Register::Rdi] = i as Block;
+        context
+      })
+      .collect();
+    Self { vm, cores }
+  }
+
+  pub fn cores(&self) -> usize {
+    self.cores.len()
+  }
+
+  pub fn is_halted(&self) -> bool {
+    self.cores.iter().all(|core| core.state == State::Halted)
+  }
+
+  pub fn core_state(&self, core: usize) -> Result<State, Error> {
+    Ok(self.cores.get(core).ok_or(Error::InvalidCore(core))?.state)
+  }
+
+  /// Runs `f` with the vm viewed from `core`, registers and ip included.
+  pub fn with_core<T>(&mut self, core: usize, f: impl FnOnce(&mut Vm) -> T) -> Result<T, Error> {
+    let context = self.cores.get_mut(core).ok_or(Error::InvalidCore(core))?;
+    self.vm.swap_context(context);
+    let result = f(&mut self.vm);
+    self.vm.swap_context(context);
+    Ok(result)
+  }
+
+  /// Executes one instruction on `core`, halted cores are left alone.
+  pub fn step_core<R>(&mut self, core: usize, region: &R) -> Result<(), Error>
+  where
+    R: Region,
+  {
+    if self.core_state(core)? == State::Halted {
+      return Ok(());
+    }
+    self
+      .with_core(core, |vm| vm.step(region))?
+      .map(drop)
+      .map_err(|e| Error::CoreFaulted(core, e))
+  }
+
+  /// Round robin over the cores, `quantum` instructions at a time, until
+  /// every core has halted.
+  pub fn run<R>(&mut self, region: &R, quantum: usize) -> Result<(), Error>
+  where
+    R: Region,
+  {
+    let quantum = quantum.max(1);
+    while !self.is_halted() {
+      for core in 0..self.cores.len() {
+        for _ in 0..quantum {
+          if self.cores[core].state == State::Halted {
+            break;
+          }
+          self.step_core(core, region)?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// The shared vm, its registers belong to no core.
+  pub fn vm(&self) -> &Vm {
+    &self.vm
+  }
+
+  pub fn into_vm(self) -> Vm {
+    self.vm
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm;
+  use crate::builder::VmBuilder;
+  use crate::disasm;
+  use crate::region::Chunk;
+
+  // every core adds 1 to count 100 times under a spin lock
+  const LOCKED: &str = "
+    irmovq $100, %rsi
+    irmovq $1, %rdx
+loop:
+acquire:
+    irmovq $0, %rax
+    casq %rdx, lock(%r8)
+    jne acquire
+    mrmovq count(%r8), %rcx
+    addq %rdx, %rcx
+    rmmovq %rcx, count(%r8)
+    irmovq $0, %rcx
+    rmmovq %rcx, lock(%r8)
+    subq %rdx, %rsi
+    jne loop
+    halt
+    .align 8
+lock:
+    .quad 0
+count:
+    .quad 0
+";
+
+  fn load(source: &str) -> (Vm, Chunk, usize) {
+    let assembled = asm::assemble(source).unwrap();
+    let count = assembled.symbols().address_of("count").unwrap_or(0);
+    let region = Chunk::from(assembled.bytes().to_vec());
+    let mut vm = VmBuilder::new().build();
+    vm.load(&region).unwrap();
+    (vm, region, count)
+  }
+
+  fn block(vm: &Vm, address: usize) -> Block {
+    let bytes = vm.read_bytes(address, 8).unwrap();
+    Block::from_le_bytes(bytes.try_into().unwrap())
+  }
+
+  #[test]
+  fn casq_serializes_cores() {
+    let (vm, region, count) = load(LOCKED);
+    let mut cores = Multicore::new(vm, 4, 0x400);
+    // a quantum of 3 interleaves cores inside the critical section
+    cores.run(&region, 3).unwrap();
+    assert!(cores.is_halted());
+    assert_eq!(block(cores.vm(), count), 400);
+  }
+
+  #[test]
+  fn cores_get_their_index_and_stack() {
+    let (vm, region, _) = load("halt\n");
+    let rsp = vm.register(Register::Rsp);
+    let mut cores = Multicore::new(vm, 3, 0x100);
+    cores.run(&region, 1).unwrap();
+    for core in 0..3 {
+      let (rdi, sp) = cores
+        .with_core(core, |vm| {
+          (vm.register(Register::Rdi), vm.register(Register::Rsp))
+        })
+        .unwrap();
+      assert_eq!(rdi, core as Block);
+      assert_eq!(sp, rsp - 0x100 * core as Block);
+    }
+    assert!(matches!(cores.core_state(3), Err(Error::InvalidCore(3))));
+  }
+
+  #[test]
+  fn casq_swaps_or_loads() {
+    let source = "
+    irmovq $5, %rax
+    irmovq $9, %rbx
+    casq %rbx, cell(%r8)
+    casq %rbx, cell(%r8)
+    halt
+    .align 8
+cell:
+    .quad 5
+";
+    let (mut vm, region, _) = load(source);
+    let cell = asm::assemble(source)
+      .unwrap()
+      .symbols()
+      .address_of("cell")
+      .unwrap();
+    for _ in 0..3 {
+      vm.step(&region).unwrap();
+    }
+    assert_eq!(block(&vm, cell), 9);
+    assert!(vm.flags().zf);
+    vm.step(&region).unwrap();
+    assert_eq!(vm.register(Register::Rax), 9);
+    assert!(!vm.flags().zf);
+  }
+
+  #[test]
+  fn casq_reads_rax() {
+    let bytes = asm::assemble("casq %rbx, 0(%rcx)\n").unwrap();
+    let (instruction, _) = disasm::decode(bytes.bytes(), 0).unwrap();
+    assert_eq!(
+      instruction.reads(),
+      [
+        Some(Register::Rbx),
+        Some(Register::Rcx),
+        Some(Register::Rax)
+      ]
+    );
+    assert_eq!(instruction.loads(), Some(Register::Rax));
+  }
+}
@@ -1,10 +1,18 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::register::Flags;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
   #[error("invalid opcode {0}")]
   InvalidOpcode(u8),
+
+  #[error("unknown condition {0:?}, expected always, le, l, e, ne, ge or g")]
+  UnknownCondition(String),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum OpFun {
   Add,
   Sub,
@@ -15,91 +23,331 @@ pub(crate) enum OpFun {
   Mod,
 }
 
-impl TryFrom<u8> for OpFun {
-  type Error = Error;
+/// Condition codes tested by the `jxx` and `cmovxx` families, numbered by
+/// their function code. Comparisons are signed, against the result of the
+/// last `opq`, so `subq %rax, %rbx` followed by `jl` jumps if `%rbx < %rax`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Condition {
+  /// Unconditional, `jmp` and `rrmovq`.
+  Always = 0,
+  /// `le`, taken when `(SF ^ OF) | ZF`.
+  LessEqual = 1,
+  /// `l`, taken when `SF ^ OF`.
+  Less = 2,
+  /// `e`, taken when `ZF`.
+  Equal = 3,
+  /// `ne`, taken when `!ZF`.
+  NotEqual = 4,
+  /// `ge`, taken when `!(SF ^ OF)`.
+  GreaterEqual = 5,
+  /// `g`, taken when `!(SF ^ OF) & !ZF`.
+  Greater = 6,
+}
 
-  fn try_from(byte: u8) -> Result<Self, Self::Error> {
-    let op = match byte {
-      0x0 => OpFun::Add,
-      0x1 => OpFun::Sub,
-      0x2 => OpFun::And,
-      0x3 => OpFun::Xor,
-      0x4 => OpFun::Mul,
-      0x5 => OpFun::Div,
-      0x6 => OpFun::Mod,
-      _ => return Err(Error::InvalidOpcode(byte)),
-    };
-    Ok(op)
-  }
-}
-
-#[derive(Debug)]
-pub(crate) enum JCmovFun {
-  LessEqual,    // le (ifun = 1)
-  Less,         // l (ifun = 2)
-  Equal,        // e (ifun = 3)
-  NotEqual,     // ne (ifun = 4)
-  GreaterEqual, // ge (ifun = 5)
-  Greater,      // g (ifun = 6)
-}
-
-impl TryFrom<u8> for JCmovFun {
-  type Error = Error;
+impl Condition {
+  const ALL: [Condition; 7] = [
+    Condition::Always,
+    Condition::LessEqual,
+    Condition::Less,
+    Condition::Equal,
+    Condition::NotEqual,
+    Condition::GreaterEqual,
+    Condition::Greater,
+  ];
 
-  fn try_from(byte: u8) -> Result<Self, Self::Error> {
-    let op = match byte {
-      0x1 => JCmovFun::LessEqual,
-      0x2 => JCmovFun::Less,
-      0x3 => JCmovFun::Equal,
-      0x4 => JCmovFun::NotEqual,
-      0x5 => JCmovFun::GreaterEqual,
-      0x6 => JCmovFun::Greater,
-      _ => return Err(Error::InvalidOpcode(byte)),
-    };
-    Ok(op)
+  /// Every condition in function code order.
+  pub fn iter() -> impl Iterator<Item = Condition> {
+    Self::ALL.into_iter()
+  }
+
+  /// The function code, the low nibble of the opcode byte.
+  pub fn ifun(self) -> u8 {
+    self as u8
+  }
+
+  /// Mnemonic suffix, e.g. `le` for `jle` and `cmovle`, empty for `Always`.
+  pub fn suffix(self) -> &'static str {
+    match self {
+      Condition::Always => "",
+      Condition::LessEqual => "le",
+      Condition::Less => "l",
+      Condition::Equal => "e",
+      Condition::NotEqual => "ne",
+      Condition::GreaterEqual => "ge",
+      Condition::Greater => "g",
+    }
+  }
+
+  /// Whether a jump or conditional move with this condition is taken.
+  pub fn eval(self, flags: Flags) -> bool {
+    let (zf, sf, of) = (flags.zf, flags.sf, flags.of);
+    match self {
+      Condition::Always => true,
+      Condition::LessEqual => (sf ^ of) | zf,
+      Condition::Less => sf ^ of,
+      Condition::Equal => zf,
+      Condition::NotEqual => !zf,
+      Condition::GreaterEqual => !(sf ^ of),
+      Condition::Greater => !(sf ^ of) & !zf,
+    }
   }
 }
 
-#[derive(Debug)]
+/// Formats as the mnemonic suffix, `always` for `Always`.
+impl fmt::Display for Condition {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Condition::Always => f.pad("always"),
+      cond => f.pad(cond.suffix()),
+    }
+  }
+}
+
+/// Accepts a mnemonic suffix such as `le` or `ne`, or `always`.
+impl FromStr for Condition {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "always" => Ok(Condition::Always),
+      "" => Err(Error::UnknownCondition(s.to_string())),
+      _ => Condition::iter()
+        .find(|cond| cond.suffix() == s)
+        .ok_or_else(|| Error::UnknownCondition(s.to_string())),
+    }
+  }
+}
+
+/// Which instructions are accepted. The crate's `mulq`, `divq` and `modq`
+/// reuse `opq` function codes 4 to 6, which CS:APP leaves invalid, and its
+/// `casq` takes the unused opcode 0xd.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Isa {
+  /// Only the CS:APP instruction set, extensions decode as invalid opcodes.
+  Strict,
+  /// The CS:APP instruction set plus the crate's extensions.
+  #[default]
+  Extended,
+}
+
+impl Isa {
+  pub fn permits(self, encoding: &Encoding) -> bool {
+    self == Isa::Extended || !encoding.extension
+  }
+}
+
+/// Length of the longest encoding, `irmovq`, `rmmovq` and `mrmovq`.
+pub const MAX_INSTRUCTION_LEN: usize = 10;
+
+/// Operand fields encoded after the opcode byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operands {
+  /// Nothing follows, `halt`, `nop` and `ret`.
+  None,
+  /// A register byte naming `rA` and `rB`.
+  Registers,
+  /// A register byte naming only `rA`, with `rB` set to 0xf.
+  RegisterA,
+  /// A register byte naming only `rB` followed by an 8 byte immediate.
+  Immediate,
+  /// A register byte naming `rA` and `rB` followed by an 8 byte displacement.
+  Memory,
+  /// An 8 byte absolute address.
+  Destination,
+}
+
+impl Operands {
+  /// Bytes the operands occupy after the opcode byte.
+  pub fn size(self) -> usize {
+    match self {
+      Operands::None => 0,
+      Operands::Registers | Operands::RegisterA => 1,
+      Operands::Destination => 8,
+      Operands::Immediate | Operands::Memory => 9,
+    }
+  }
+}
+
+/// What is known about an instruction from its first byte alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Encoding {
+  pub byte: u8,
+  pub mnemonic: &'static str,
+  pub operands: Operands,
+  pub reads_memory: bool,
+  pub writes_memory: bool,
+  /// Reads the condition codes, every `jxx` and `cmovxx`.
+  pub reads_flags: bool,
+  pub writes_flags: bool,
+  /// Not part of CS:APP, rejected under `Isa::Strict`.
+  pub extension: bool,
+}
+
+impl Encoding {
+  /// Looks up the instruction whose first byte is `byte`.
+  pub fn of(byte: u8) -> Result<Self, Error> {
+    Ok(row(byte)?.encoding())
+  }
+
+  /// Looks up `byte` like `of`, treating instructions `isa` lacks as
+  /// invalid.
+  pub fn of_isa(byte: u8, isa: Isa) -> Result<Self, Error> {
+    Some(Self::of(byte)?)
+      .filter(|encoding| isa.permits(encoding))
+      .ok_or(Error::InvalidOpcode(byte))
+  }
+
+  /// Every valid first byte, in ascending order.
+  pub fn all() -> impl Iterator<Item = Self> {
+    TABLE.iter().map(Row::encoding)
+  }
+
+  /// Total encoded length in bytes.
+  pub fn size(&self) -> usize {
+    1 + self.operands.size()
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum Opcode {
   Halt,
   Nop,
   Rrmovq,
-  Cmovxx(JCmovFun),
+  Cmovxx(Condition),
   Irmovq,
   Rmmovq,
   Mrmovq,
   Opq(OpFun),
-  Jxx(JCmovFun),
+  Jxx(Condition),
   Call,
   Ret,
   Pushq,
   Popq,
+  Casq,
+}
+
+impl Opcode {
+  pub(crate) fn mnemonic(self) -> &'static str {
+    TABLE
+      .iter()
+      .find(|row| row.opcode == self)
+      .expect("every opcode has a row")
+      .mnemonic
+  }
+
+  /// Decodes `byte` as `try_from` does, but only into instructions of `isa`.
+  pub(crate) fn decode(byte: u8, isa: Isa) -> Result<Self, Error> {
+    let row = row(byte)?;
+    if !isa.permits(&row.encoding()) {
+      return Err(Error::InvalidOpcode(byte));
+    }
+    Ok(row.opcode)
+  }
 }
 
 impl TryFrom<u8> for Opcode {
   type Error = Error;
 
   fn try_from(byte: u8) -> Result<Self, Self::Error> {
-    let (high, low) = (byte >> 4, byte & 0xf);
-    let op = match high {
-      0x0 => Opcode::Halt,
-      0x1 => Opcode::Nop,
-      0x2 => match low {
-        0x0 => Opcode::Rrmovq,
-        _ => Opcode::Cmovxx(JCmovFun::try_from(low)?),
-      },
-      0x3 => Opcode::Irmovq,
-      0x4 => Opcode::Rmmovq,
-      0x5 => Opcode::Mrmovq,
-      0x6 => Opcode::Opq(OpFun::try_from(low)?),
-      0x7 => Opcode::Jxx(JCmovFun::try_from(low)?),
-      0x8 => Opcode::Call,
-      0x9 => Opcode::Ret,
-      0xA => Opcode::Pushq,
-      0xB => Opcode::Popq,
-      _ => return Err(Error::InvalidOpcode(byte)),
-    };
-    Ok(op)
+    Ok(row(byte)?.opcode)
+  }
+}
+
+// bits of `Row::effects`
+const READS_MEMORY: u8 = 1 << 0;
+const WRITES_MEMORY: u8 = 1 << 1;
+const READS_FLAGS: u8 = 1 << 2;
+const WRITES_FLAGS: u8 = 1 << 3;
+const EXTENSION: u8 = 1 << 4;
+
+/// One valid first byte and everything derived from it.
+struct Row {
+  byte: u8,
+  opcode: Opcode,
+  mnemonic: &'static str,
+  operands: Operands,
+  effects: u8,
+}
+
+impl Row {
+  fn encoding(&self) -> Encoding {
+    Encoding {
+      byte: self.byte,
+      mnemonic: self.mnemonic,
+      operands: self.operands,
+      reads_memory: self.effects & READS_MEMORY != 0,
+      writes_memory: self.effects & WRITES_MEMORY != 0,
+      reads_flags: self.effects & READS_FLAGS != 0,
+      writes_flags: self.effects & WRITES_FLAGS != 0,
+      extension: self.effects & EXTENSION != 0,
+    }
   }
 }
+
+macro_rules! table {
+  ($($byte:literal $opcode:expr, $mnemonic:literal, $operands:ident, $effects:expr;)*) => {
+    [$(Row {
+      byte: $byte,
+      opcode: $opcode,
+      mnemonic: $mnemonic,
+      operands: Operands::$operands,
+      effects: $effects,
+    }),*]
+  };
+}
+
+/// The instruction set, one row per valid first byte in ascending order.
+/// Decoding, lengths, mnemonics for the assembler and disassembler and the
+/// `Encoding` properties all come from here, so a new instruction starts
+/// with a row, then its execution in `vm` and its `Instruction` form.
+#[rustfmt::skip]
+const TABLE: [Row; 31] = table! {
+  0x00 Opcode::Halt, "halt", None, 0;
+  0x10 Opcode::Nop, "nop", None, 0;
+  0x20 Opcode::Rrmovq, "rrmovq", Registers, 0;
+  0x21 Opcode::Cmovxx(Condition::LessEqual), "cmovle", Registers, READS_FLAGS;
+  0x22 Opcode::Cmovxx(Condition::Less), "cmovl", Registers, READS_FLAGS;
+  0x23 Opcode::Cmovxx(Condition::Equal), "cmove", Registers, READS_FLAGS;
+  0x24 Opcode::Cmovxx(Condition::NotEqual), "cmovne", Registers, READS_FLAGS;
+  0x25 Opcode::Cmovxx(Condition::GreaterEqual), "cmovge", Registers, READS_FLAGS;
+  0x26 Opcode::Cmovxx(Condition::Greater), "cmovg", Registers, READS_FLAGS;
+  0x30 Opcode::Irmovq, "irmovq", Immediate, 0;
+  0x40 Opcode::Rmmovq, "rmmovq", Memory, WRITES_MEMORY;
+  0x50 Opcode::Mrmovq, "mrmovq", Memory, READS_MEMORY;
+  0x60 Opcode::Opq(OpFun::Add), "addq", Registers, WRITES_FLAGS;
+  0x61 Opcode::Opq(OpFun::Sub), "subq", Registers, WRITES_FLAGS;
+  0x62 Opcode::Opq(OpFun::And), "andq", Registers, WRITES_FLAGS;
+  0x63 Opcode::Opq(OpFun::Xor), "xorq", Registers, WRITES_FLAGS;
+  0x64 Opcode::Opq(OpFun::Mul), "mulq", Registers, WRITES_FLAGS | EXTENSION;
+  0x65 Opcode::Opq(OpFun::Div), "divq", Registers, WRITES_FLAGS | EXTENSION;
+  0x66 Opcode::Opq(OpFun::Mod), "modq", Registers, WRITES_FLAGS | EXTENSION;
+  0x70 Opcode::Jxx(Condition::Always), "jmp", Destination, 0;
+  0x71 Opcode::Jxx(Condition::LessEqual), "jle", Destination, READS_FLAGS;
+  0x72 Opcode::Jxx(Condition::Less), "jl", Destination, READS_FLAGS;
+  0x73 Opcode::Jxx(Condition::Equal), "je", Destination, READS_FLAGS;
+  0x74 Opcode::Jxx(Condition::NotEqual), "jne", Destination, READS_FLAGS;
+  0x75 Opcode::Jxx(Condition::GreaterEqual), "jge", Destination, READS_FLAGS;
+  0x76 Opcode::Jxx(Condition::Greater), "jg", Destination, READS_FLAGS;
+  0x80 Opcode::Call, "call", Destination, WRITES_MEMORY;
+  0x90 Opcode::Ret, "ret", None, READS_MEMORY;
+  0xa0 Opcode::Pushq, "pushq", RegisterA, WRITES_MEMORY;
+  0xb0 Opcode::Popq, "popq", RegisterA, READS_MEMORY;
+  0xd0 Opcode::Casq, "casq", Memory, READS_MEMORY | WRITES_MEMORY | WRITES_FLAGS | EXTENSION;
+};
+
+// position in `TABLE` of every first byte, `u8::MAX` if invalid
+const INDEX: [u8; 256] = {
+  let mut index = [u8::MAX; 256];
+  let mut i = 0;
+  while i < TABLE.len() {
+    index[TABLE[i].byte as usize] = i as u8;
+    i += 1;
+  }
+  index
+};
+
+fn row(byte: u8) -> Result<&'static Row, Error> {
+  TABLE
+    .get(INDEX[byte as usize] as usize)
+    .ok_or(Error::InvalidOpcode(byte))
+}
@@ -0,0 +1,349 @@
+use std::fmt;
+
+use crate::disasm::{Disassembled, Instruction, Style};
+use crate::opcode::Condition;
+use crate::region::Region;
+use crate::register::Register;
+use crate::vm::{self, Executed, ExecutedInstruction, Vm};
+
+/// The five stages of the pipe design.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+  Fetch,
+  Decode,
+  Execute,
+  Memory,
+  WriteBack,
+}
+
+impl Stage {
+  pub fn letter(self) -> char {
+    match self {
+      Stage::Fetch => 'F',
+      Stage::Decode => 'D',
+      Stage::Execute => 'E',
+      Stage::Memory => 'M',
+      Stage::WriteBack => 'W',
+    }
+  }
+}
+
+/// What one row of the diagram holds during one cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+  pub stage: Stage,
+  /// Held in `stage` by a stall rather than advancing into it.
+  pub stalled: bool,
+}
+
+impl Cell {
+  fn new(stage: Stage) -> Self {
+    Self {
+      stage,
+      stalled: false,
+    }
+  }
+
+  fn stall(stage: Stage) -> Self {
+    Self {
+      stage,
+      stalled: true,
+    }
+  }
+}
+
+/// One instruction, or a bubble, and the stages it occupies cycle by cycle.
+#[derive(Debug)]
+pub struct Row {
+  executed: Option<Executed>,
+  start: usize,
+  cells: Vec<Cell>,
+}
+
+impl Row {
+  /// What retired, `None` for a bubble.
+  pub fn executed(&self) -> Option<&Executed> {
+    self.executed.as_ref()
+  }
+
+  /// The retired instruction, `None` for a bubble or a trap.
+  pub fn instruction(&self) -> Option<&Disassembled> {
+    self.executed.as_ref()?.instruction()
+  }
+
+  pub fn is_bubble(&self) -> bool {
+    self.executed.is_none()
+  }
+
+  /// First cycle of the row, cycles are numbered from 1.
+  pub fn start(&self) -> usize {
+    self.start
+  }
+
+  /// Cells for consecutive cycles beginning at `start`.
+  pub fn cells(&self) -> &[Cell] {
+    &self.cells
+  }
+
+  pub fn at(&self, cycle: usize) -> Option<Cell> {
+    self.cells.get(cycle.checked_sub(self.start)?).copied()
+  }
+
+  fn end(&self) -> usize {
+    self.start + self.cells.len()
+  }
+}
+
+/// Schedules retired instructions through a pipe style five stage pipeline
+/// and records the resulting instruction by cycle diagram.
+///
+/// Data hazards are forwarded, except a value loaded by `mrmovq` or `popq`
+/// and used by the next instruction, which stalls it in decode for a cycle.
+/// Conditional jumps are predicted taken, so a jump that falls through
+/// cancels two fetched instructions, and `ret` stalls fetch until its return
+/// address is written back. Cancelled and stalled slots show up as bubbles
+/// in execute. A trap handler's work is scheduled like a `nop`.
+#[derive(Debug, Default)]
+pub struct Pipeline {
+  rows: Vec<Row>,
+  // cycle the previous instruction entered execute
+  last_execute: Option<usize>,
+  // destination of a load retired just before, for load/use hazards
+  loaded: Option<Register>,
+  // cycle the next instruction is fetched in
+  next_fetch: usize,
+  mispredictions: usize,
+}
+
+impl Pipeline {
+  pub fn new() -> Self {
+    Self {
+      next_fetch: 1,
+      ..Self::default()
+    }
+  }
+
+  /// Runs `vm` until it halts or faults, scheduling every retired
+  /// instruction.
+  pub fn run<R>(vm: &mut Vm, region: &R) -> Result<Self, vm::Error>
+  where
+    R: Region,
+  {
+    let mut pipeline = Self::new();
+    for executed in vm.iter(region) {
+      pipeline.push(executed?);
+    }
+    Ok(pipeline)
+  }
+
+  /// Schedules the next retired instruction in program order.
+  pub fn push(&mut self, executed: ExecutedInstruction) {
+    let next_ip = executed.next_ip();
+    let executed = executed.into_executed();
+    let disassembled = executed.instruction();
+    let instruction = disassembled.map(Disassembled::instruction);
+    let fall_through = disassembled.map(|d| d.address() + d.bytes().len());
+
+    let fetch = self.next_fetch;
+    let decode = match self.last_execute {
+      Some(last) => (fetch + 1).max(last),
+      None => fetch + 1,
+    };
+    let load_use = self
+      .loaded
+      .zip(instruction)
+      .is_some_and(|(reg, instruction)| instruction.reads().contains(&Some(reg)));
+    let mut execute = decode + 1 + load_use as usize;
+    if let Some(last) = self.last_execute {
+      execute = execute.max(last + 1);
+      for cycle in last + 1..execute {
+        self.rows.push(Row {
+          executed: None,
+          start: cycle,
+          cells: vec![
+            Cell::new(Stage::Execute),
+            Cell::new(Stage::Memory),
+            Cell::new(Stage::WriteBack),
+          ],
+        });
+      }
+    }
+
+    let mut cells = vec![Cell::new(Stage::Fetch)];
+    cells.extend((fetch + 1..decode).map(|_| Cell::stall(Stage::Fetch)));
+    cells.push(Cell::new(Stage::Decode));
+    cells.extend((decode + 1..execute).map(|_| Cell::stall(Stage::Decode)));
+    cells.extend([Stage::Execute, Stage::Memory, Stage::WriteBack].map(Cell::new));
+
+    self.next_fetch = match instruction {
+      // mispredicted, the right path is fetched once the jump leaves execute
+      Some(&Instruction::Jxx(cond, _))
+        if cond != Condition::Always && Some(next_ip) == fall_through =>
+      {
+        self.mispredictions += 1;
+        execute + 1
+      }
+      Some(Instruction::Ret) => execute + 2,
+      _ => fetch + 1,
+    };
+    self.loaded = instruction.and_then(Instruction::loads);
+    self.last_execute = Some(execute);
+    self.rows.push(Row {
+      executed: Some(executed),
+      start: fetch,
+      cells,
+    });
+  }
+
+  pub fn rows(&self) -> &[Row] {
+    &self.rows
+  }
+
+  /// Cycles until the last instruction leaves write back.
+  pub fn cycles(&self) -> usize {
+    self.rows.iter().map(|row| row.end() - 1).max().unwrap_or(0)
+  }
+
+  /// Retired instructions, bubbles excluded.
+  pub fn instructions(&self) -> usize {
+    self.rows.iter().filter(|row| !row.is_bubble()).count()
+  }
+
+  /// Instructions retired per cycle.
+  pub fn ipc(&self) -> f64 {
+    match self.cycles() {
+      0 => 0.0,
+      cycles => self.instructions() as f64 / cycles as f64,
+    }
+  }
+
+  pub fn bubbles(&self) -> usize {
+    self.rows.iter().filter(|row| row.is_bubble()).count()
+  }
+
+  /// Conditional jumps that fell through against the prediction.
+  pub fn mispredictions(&self) -> usize {
+    self.mispredictions
+  }
+
+  /// Cycles instructions spent held in fetch or decode.
+  pub fn stalls(&self) -> usize {
+    self
+      .rows
+      .iter()
+      .flat_map(|row| &row.cells)
+      .filter(|cell| cell.stalled)
+      .count()
+  }
+
+  /// Renders the diagram as text, one row per instruction or bubble and one
+  /// column per cycle. Stages are upper case letters, stalls lower case.
+  pub fn render(&self, style: &Style<'_>) -> String {
+    let labels: Vec<String> = self
+      .rows
+      .iter()
+      .map(|row| match &row.executed {
+        Some(executed) => format!(
+          "{:#06x}: {}",
+          executed.address(),
+          executed.text(&Style::default())
+        ),
+        None => "bubble".to_string(),
+      })
+      .collect();
+    let width = labels.iter().map(String::len).max().unwrap_or(0);
+    let cycles = self.cycles();
+
+    let mut out = format!("{:width$}", "");
+    for cycle in 1..=cycles {
+      out.push_str(&format!("{cycle:>4}"));
+    }
+    out.push('\n');
+    for (row, label) in self.rows.iter().zip(&labels) {
+      // pad by the plain label so colors do not skew the columns
+      let text = match &row.executed {
+        Some(executed) => format!("{:#06x}: {}", executed.address(), executed.text(style)),
+        None => label.clone(),
+      };
+      out.push_str(&text);
+      out.push_str(&" ".repeat(width - label.len()));
+      for cycle in 1..row.end() {
+        let letter = match row.at(cycle) {
+          Some(cell) if cell.stalled => cell.stage.letter().to_ascii_lowercase(),
+          Some(cell) => cell.stage.letter(),
+          None => ' ',
+        };
+        out.push_str(&format!("{letter:>4}"));
+      }
+      out.push('\n');
+    }
+    out
+  }
+}
+
+impl fmt::Display for Pipeline {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.render(&Style::default()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm;
+  use crate::builder::VmBuilder;
+  use crate::region::Chunk;
+
+  fn schedule(source: &str) -> Pipeline {
+    let region = Chunk::from(asm::assemble(source).unwrap().bytes().to_vec());
+    let mut vm = VmBuilder::new().build();
+    vm.load(&region).unwrap();
+    Pipeline::run(&mut vm, &region).unwrap()
+  }
+
+  #[test]
+  fn independent_instructions_overlap() {
+    let pipeline = schedule("irmovq $1, %rax\nirmovq $2, %rcx\nhalt");
+    assert_eq!(pipeline.instructions(), 3);
+    assert_eq!(pipeline.cycles(), 7);
+    assert_eq!((pipeline.bubbles(), pipeline.stalls()), (0, 0));
+    let text = pipeline.to_string();
+    let lines: Vec<_> = text.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].trim_start().starts_with("1   2   3"));
+    assert!(lines[2].ends_with("   F   D   E   M   W"));
+  }
+
+  #[test]
+  fn load_use_stalls_in_decode() {
+    let pipeline = schedule("mrmovq 0x100(%rbx), %rax\naddq %rax, %rcx\nhalt");
+    let rows = pipeline.rows();
+    assert!(rows[1].is_bubble());
+    assert_eq!(rows[1].start(), 4);
+    assert_eq!(
+      rows[2].at(4),
+      Some(Cell {
+        stage: Stage::Decode,
+        stalled: true
+      })
+    );
+    assert_eq!(rows[2].at(5).unwrap().stage, Stage::Execute);
+    assert_eq!(pipeline.bubbles(), 1);
+
+    // forwarded without a load in between
+    let pipeline = schedule("irmovq $1, %rax\naddq %rax, %rcx\nhalt");
+    assert_eq!(pipeline.bubbles(), 0);
+  }
+
+  #[test]
+  fn fall_through_jumps_and_returns_cost_bubbles() {
+    let pipeline = schedule("irmovq $1, %rax\nandq %rax, %rax\nje skip\nhalt\nskip: halt");
+    assert_eq!(pipeline.mispredictions(), 1);
+    assert_eq!(pipeline.bubbles(), 2);
+
+    let pipeline = schedule("call f\nhalt\nf: ret");
+    assert_eq!(pipeline.mispredictions(), 0);
+    assert_eq!(pipeline.bubbles(), 3);
+    assert!(pipeline.ipc() < 1.0);
+  }
+}
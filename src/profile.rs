@@ -0,0 +1,165 @@
+use std::fmt;
+use std::ops::Range;
+
+use crate::symbol::Symbols;
+
+/// What the instructions inside a region cost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RegionStats {
+  pub instructions: usize,
+  pub cycles: u64,
+  pub loads: usize,
+  pub stores: usize,
+  /// Data accesses that missed the cache, zero without one.
+  pub misses: usize,
+}
+
+impl RegionStats {
+  fn add(&mut self, other: &RegionStats) {
+    self.instructions += other.instructions;
+    self.cycles += other.cycles;
+    self.loads += other.loads;
+    self.stores += other.stores;
+    self.misses += other.misses;
+  }
+}
+
+/// Named address ranges and what the instructions fetched from each cost,
+/// see `Vm::set_profile`. An instruction counts towards every region holding
+/// its address, so a loop registered inside a function shows up in both,
+/// and towards `Profile::unattributed` when none does.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+  regions: Vec<(String, Range<usize>)>,
+  stats: Vec<RegionStats>,
+  unattributed: RegionStats,
+  // data accesses of the instruction being executed
+  loads: usize,
+  stores: usize,
+}
+
+impl Profile {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Adds a region, ranges may overlap.
+  pub fn region(mut self, name: impl Into<String>, range: Range<usize>) -> Self {
+    self.regions.push((name.into(), range));
+    self.stats.push(RegionStats::default());
+    self
+  }
+
+  /// One region per label, running up to the next label or `end`. Labels
+  /// inside functions split them, leave those out of `symbols` or add the
+  /// whole function with `region` for inclusive numbers.
+  pub fn from_symbols(symbols: &Symbols, end: usize) -> Self {
+    let labels: Vec<(usize, &str)> = symbols.iter().filter(|&(at, _)| at < end).collect();
+    let mut profile = Self::new();
+    for (i, &(start, name)) in labels.iter().enumerate() {
+      let next = labels.get(i + 1).map_or(end, |&(at, _)| at);
+      if start < next {
+        profile = profile.region(name, start..next);
+      }
+    }
+    profile
+  }
+
+  /// Every region with what it cost, in the order they were added.
+  pub fn stats(&self) -> impl Iterator<Item = (&str, &Range<usize>, &RegionStats)> + '_ {
+    self
+      .regions
+      .iter()
+      .zip(&self.stats)
+      .map(|((name, range), stats)| (name.as_str(), range, stats))
+  }
+
+  /// The region named `name`, the first one if several are.
+  pub fn get(&self, name: &str) -> Option<&RegionStats> {
+    let index = self.regions.iter().position(|(n, _)| n == name)?;
+    Some(&self.stats[index])
+  }
+
+  /// Instructions outside every region.
+  pub fn unattributed(&self) -> &RegionStats {
+    &self.unattributed
+  }
+
+  /// Forgets what was counted, keeping the regions.
+  pub fn clear(&mut self) {
+    self.stats.fill(RegionStats::default());
+    self.unattributed = RegionStats::default();
+    self.loads = 0;
+    self.stores = 0;
+  }
+
+  pub(crate) fn access(&mut self, write: bool) {
+    if write {
+      self.stores += 1;
+    } else {
+      self.loads += 1;
+    }
+  }
+
+  /// Charges the instruction at `address` that just retired, taking
+  /// `cycles` and missing the cache `misses` times.
+  pub(crate) fn retire(&mut self, address: usize, cycles: u64, misses: usize) {
+    let cost = RegionStats {
+      instructions: 1,
+      cycles,
+      loads: self.loads,
+      stores: self.stores,
+      misses,
+    };
+    self.loads = 0;
+    self.stores = 0;
+    let mut attributed = false;
+    for ((_, range), stats) in self.regions.iter().zip(&mut self.stats) {
+      if range.contains(&address) {
+        stats.add(&cost);
+        attributed = true;
+      }
+    }
+    if !attributed {
+      self.unattributed.add(&cost);
+    }
+  }
+}
+
+/// One row per region with its range, instructions, cycles, loads, stores
+/// and misses, then the unattributed instructions if there were any.
+impl fmt::Display for Profile {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let width = self
+      .regions
+      .iter()
+      .map(|(name, _)| name.len())
+      .max()
+      .unwrap_or(0)
+      .max("(other)".len());
+    writeln!(
+      f,
+      "{:<width$}  {:<13}  {:>12} {:>12} {:>10} {:>10} {:>8}",
+      "region", "range", "instructions", "cycles", "loads", "stores", "misses"
+    )?;
+    let row = |f: &mut fmt::Formatter<'_>, name: &str, range: String, stats: &RegionStats| {
+      writeln!(
+        f,
+        "{name:<width$}  {range:<13}  {:>12} {:>12} {:>10} {:>10} {:>8}",
+        stats.instructions, stats.cycles, stats.loads, stats.stores, stats.misses
+      )
+    };
+    for (name, range, stats) in self.stats() {
+      row(
+        f,
+        name,
+        format!("{:#06x}-{:#06x}", range.start, range.end),
+        stats,
+      )?;
+    }
+    if self.unattributed.instructions > 0 {
+      row(f, "(other)", String::new(), &self.unattributed)?;
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,242 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::memory::{MEMORY_SIZE, Sparse};
+
+/// Hard limits on what one run may consume, for programs that cannot be
+/// trusted to halt or to behave. Every limit is off until set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Quotas {
+  instructions: Option<usize>,
+  pages: Option<usize>,
+  output: Option<usize>,
+  timeout: Option<Duration>,
+}
+
+impl Quotas {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Instructions the run may retire.
+  pub fn instructions(mut self, instructions: usize) -> Self {
+    self.instructions = Some(instructions);
+    self
+  }
+
+  /// Distinct 4KB memory pages the program may load from or store to.
+  pub fn pages(mut self, pages: usize) -> Self {
+    self.pages = Some(pages);
+    self
+  }
+
+  /// Bytes the program may send to devices and through the `write` syscall.
+  pub fn output(mut self, bytes: usize) -> Self {
+    self.output = Some(bytes);
+    self
+  }
+
+  /// Wall clock time the run may take, counted from its first instruction.
+  pub fn timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = Some(timeout);
+    self
+  }
+}
+
+/// The limit a run hit, carried by `Error::QuotaExceeded`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quota {
+  Instructions(usize),
+  Pages(usize),
+  Output(usize),
+  Timeout(Duration),
+}
+
+impl fmt::Display for Quota {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Quota::Instructions(n) => write!(f, "instruction quota of {n}"),
+      Quota::Pages(n) => write!(f, "memory quota of {n} pages"),
+      Quota::Output(n) => write!(f, "output quota of {n} bytes"),
+      Quota::Timeout(timeout) => write!(f, "time quota of {timeout:?}"),
+    }
+  }
+}
+
+/// What the current run consumed so far, see `Vm::usage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Usage {
+  pub instructions: usize,
+  pub pages: usize,
+  pub output: usize,
+  pub elapsed: Duration,
+}
+
+const PAGES: usize = MEMORY_SIZE.div_ceil(Sparse::PAGE_SIZE);
+
+/// Consumption of the current run measured against its quotas.
+#[derive(Debug, Clone)]
+pub(crate) struct Meter {
+  quotas: Quotas,
+  instructions: usize,
+  // one bit per page loaded from or stored to
+  touched: Vec<u64>,
+  pages: usize,
+  output: usize,
+  started: Option<Instant>,
+}
+
+impl Meter {
+  pub(crate) fn new(quotas: Quotas) -> Self {
+    Self {
+      quotas,
+      instructions: 0,
+      touched: vec![0; PAGES.div_ceil(64)],
+      pages: 0,
+      output: 0,
+      started: None,
+    }
+  }
+
+  /// Starts a new run, forgetting everything consumed so far.
+  pub(crate) fn reset(&mut self) {
+    *self = Self::new(self.quotas);
+  }
+
+  pub(crate) fn usage(&self) -> Usage {
+    Usage {
+      instructions: self.instructions,
+      pages: self.pages,
+      output: self.output,
+      elapsed: self
+        .started
+        .map_or(Duration::ZERO, |started| started.elapsed()),
+    }
+  }
+
+  /// Checks the quotas that allow another instruction to start.
+  pub(crate) fn begin(&mut self) -> Result<(), Quota> {
+    if let Some(max) = self.quotas.instructions
+      && self.instructions >= max
+    {
+      return Err(Quota::Instructions(max));
+    }
+    let started = *self.started.get_or_insert_with(Instant::now);
+    if let Some(timeout) = self.quotas.timeout
+      && started.elapsed() > timeout
+    {
+      return Err(Quota::Timeout(timeout));
+    }
+    Ok(())
+  }
+
+  /// Counts a retired instruction, failing if output earlier in it went
+  /// over quota.
+  pub(crate) fn retire(&mut self) -> Result<(), Quota> {
+    if let Some(max) = self.quotas.output
+      && self.output > max
+    {
+      return Err(Quota::Output(max));
+    }
+    self.instructions += 1;
+    Ok(())
+  }
+
+  /// Records an access to the page holding `address`.
+  pub(crate) fn touch(&mut self, address: usize) -> Result<(), Quota> {
+    let page = address / Sparse::PAGE_SIZE;
+    let (word, bit) = (page / 64, 1 << (page % 64));
+    if self.touched.get(word).is_none_or(|&w| w & bit != 0) {
+      return Ok(());
+    }
+    if let Some(max) = self.quotas.pages
+      && self.pages >= max
+    {
+      return Err(Quota::Pages(max));
+    }
+    self.touched[word] |= bit;
+    self.pages += 1;
+    Ok(())
+  }
+
+  /// Adds `bytes` of output, failing once they no longer fit the quota.
+  pub(crate) fn output(&mut self, bytes: usize) -> Result<(), Quota> {
+    self.output += bytes;
+    match self.quotas.output {
+      Some(max) if self.output > max => Err(Quota::Output(max)),
+      _ => Ok(()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm;
+  use crate::builder::VmBuilder;
+  use crate::region::Chunk;
+  use crate::syscall::{Console, Sandbox, Syscalls};
+  use crate::vm::{Error, Vm};
+
+  fn start(quotas: Quotas, source: &str) -> (Vm, Chunk) {
+    let region = Chunk::from(asm::assemble(source).unwrap().bytes().to_vec());
+    let mut vm = VmBuilder::new().quotas(quotas).build();
+    vm.load(&region).unwrap();
+    (vm, region)
+  }
+
+  #[test]
+  fn instruction_quota_stops_the_run() {
+    let (mut vm, region) = start(Quotas::new().instructions(5), "loop: jmp loop");
+    let err = vm.run(&region).unwrap_err();
+    assert!(matches!(err, Error::QuotaExceeded(Quota::Instructions(5))));
+    assert_eq!(vm.steps(), 5);
+    assert_eq!(vm.usage().instructions, 5);
+
+    // a reset starts a new run with a fresh allowance
+    vm.reset();
+    assert_eq!(vm.usage(), Usage::default());
+    assert!(vm.run_for(&region, 5).is_ok());
+  }
+
+  #[test]
+  fn page_quota_counts_distinct_pages() {
+    let (mut vm, region) = start(
+      Quotas::new().pages(2),
+      "
+    rmmovq %rax, 0x1000(%rbx)
+    rmmovq %rax, 0x1008(%rbx)
+    mrmovq 0x2000(%rbx), %rcx
+    rmmovq %rax, 0x3000(%rbx)
+    halt
+",
+    );
+    let err = vm.run(&region).unwrap_err();
+    assert!(matches!(err, Error::QuotaExceeded(Quota::Pages(2))));
+    assert_eq!(vm.steps(), 3);
+    assert_eq!(vm.usage().pages, 2);
+  }
+
+  #[test]
+  fn output_quota_covers_syscall_writes() {
+    let (mut vm, region) = start(
+      Quotas::new().output(2),
+      "
+    irmovq $1, %rax
+    irmovq $1, %rdi
+    irmovq message, %rsi
+    irmovq $3, %rdx
+    .byte 0xc0
+    halt
+message:
+    .byte 0x68
+    .byte 0x69
+    .byte 0x0a
+",
+    );
+    vm.set_trap_handler(Syscalls::new(Sandbox::new()).with_console(Console::new("")));
+    let err = vm.run(&region).unwrap_err();
+    assert!(matches!(err, Error::QuotaExceeded(Quota::Output(2))));
+    assert_eq!(vm.usage().output, 3);
+  }
+}
@@ -1,11 +1,120 @@
+use crate::Block;
+use crate::disasm;
+use crate::opcode::Operands;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("replacement of {0} bytes is longer than the {1} bytes it replaces at {2:#x}")]
+  TooLong(usize, usize, usize),
+
+  #[error("replacement at {0:#x} is not a single valid instruction")]
+  InvalidReplacement(usize),
+
+  #[error("instruction at {0:#x} has no immediate operand")]
+  NoImmediate(usize),
+
+  #[error("disassembly error - {0}")]
+  DisasmError(#[from] disasm::Error),
+}
+
 pub trait Region {
   fn instructions(&self) -> &[u8];
 }
 
+/// Program bytes the vm fetches instructions from. Patches made between
+/// steps take effect at once, though the copy `Vm::load` placed in memory
+/// keeps the original bytes until the program is loaded again.
+#[derive(Debug, Clone)]
 pub struct Chunk {
   instructions: Vec<u8>,
 }
 
+impl Chunk {
+  const NOP: u8 = 0x10;
+  const JMP: u8 = 0x70;
+  const JUMP_LEN: usize = 9;
+
+  /// Replaces the instruction at `address` with the encoded instruction
+  /// `bytes`, filling any bytes left over with `nop`s.
+  pub fn replace_instruction(&mut self, address: usize, bytes: &[u8]) -> Result<(), Error> {
+    let len = bytes.len();
+    let decoded = disasm::disassemble_at(bytes, 0).map(|replacement| replacement.bytes().len());
+    if decoded.ok() != Some(len) {
+      return Err(Error::InvalidReplacement(address));
+    }
+    let old = disasm::disassemble_at(&self.instructions, address)?
+      .bytes()
+      .len();
+    if len > old {
+      return Err(Error::TooLong(len, old, address));
+    }
+    self.instructions[address..address + len].copy_from_slice(bytes);
+    self.instructions[address + len..address + old].fill(Self::NOP);
+    Ok(())
+  }
+
+  /// Overwrites the immediate, displacement or destination of the
+  /// instruction at `address`.
+  pub fn set_immediate(&mut self, address: usize, value: Block) -> Result<(), Error> {
+    let instruction = disasm::disassemble_at(&self.instructions, address)?;
+    let offset = match instruction.encoding().operands {
+      Operands::Immediate | Operands::Memory => 2,
+      Operands::Destination => 1,
+      Operands::None | Operands::Registers | Operands::RegisterA => {
+        return Err(Error::NoImmediate(address));
+      }
+    };
+    let at = address + offset;
+    self.instructions[at..at + 8].copy_from_slice(&value.to_le_bytes());
+    Ok(())
+  }
+
+  /// Redirects execution at `address` to `target`, overwriting as many whole
+  /// instructions as the jump needs and padding the last with `nop`s.
+  /// Returns the displaced instructions, which use absolute addresses and can
+  /// be placed at the target unchanged, typically followed by a jump back to
+  /// `address + displaced.len()`.
+  pub fn insert_jump(&mut self, address: usize, target: usize) -> Result<Vec<u8>, Error> {
+    let mut end = address;
+    while end < address + Self::JUMP_LEN {
+      end += disasm::disassemble_at(&self.instructions, end)?
+        .bytes()
+        .len();
+    }
+    let displaced = self.instructions[address..end].to_vec();
+    self.instructions[address..address + Self::JUMP_LEN].copy_from_slice(&Self::jump(target));
+    self.instructions[address + Self::JUMP_LEN..end].fill(Self::NOP);
+    Ok(displaced)
+  }
+
+  /// Encoding of an unconditional jump to `target`, what `insert_jump`
+  /// writes. It clobbers no state, flags included.
+  pub fn jump(target: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(Self::JUMP_LEN);
+    bytes.push(Self::JMP);
+    bytes.extend_from_slice(&(target as u64).to_le_bytes());
+    bytes
+  }
+
+  /// Writes `bytes` over whatever is at `address`, growing the chunk if they
+  /// run past its end. Nothing is decoded, see `replace_instruction` for a
+  /// checked edit.
+  pub fn write(&mut self, address: usize, bytes: &[u8]) {
+    let end = address + bytes.len();
+    if end > self.instructions.len() {
+      self.instructions.resize(end, 0);
+    }
+    self.instructions[address..end].copy_from_slice(bytes);
+  }
+
+  /// Appends `bytes`, for instance a trampoline, returning their address.
+  pub fn append(&mut self, bytes: &[u8]) -> usize {
+    let address = self.instructions.len();
+    self.instructions.extend_from_slice(bytes);
+    address
+  }
+}
+
 impl Region for Chunk {
   fn instructions(&self) -> &[u8] {
     &self.instructions
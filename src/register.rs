@@ -1,25 +1,28 @@
+use std::fmt;
 use std::ops::{Deref, DerefMut, Index, IndexMut};
+use std::str::FromStr;
 
 use crate::Word;
-use crate::memory::MainMemory;
-use crate::opcode::JCmovFun;
+use crate::opcode::Condition;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
   #[error("invalid register {0:#x}")]
   InvalidRegister(u8),
+
+  #[error("unknown register {0:?}")]
+  UnknownRegister(String),
 }
 
 type RegisterSlot = Word;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Registers([RegisterSlot; 15]);
 
 impl Registers {
-  fn new() -> Self {
+  fn new(stack_pointer: Word) -> Self {
     let mut regs = [0; 15];
-    // Initialize stack pointer to top of memory (grows down)
-    regs[Register::Rsp as usize] = MainMemory::MEMORY_SIZE as i64 - 8;
+    regs[Register::Rsp as usize] = stack_pointer;
     Self(regs)
   }
 }
@@ -38,63 +41,71 @@ impl DerefMut for Registers {
   }
 }
 
-#[derive(Debug)]
-struct Flags {
-  zf: bool,
-  sf: bool,
-  of: bool,
+/// Snapshot of the condition codes set by the last `opq`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Flags {
+  /// Zero flag, the result was zero.
+  pub zf: bool,
+  /// Sign flag, the result was negative.
+  pub sf: bool,
+  /// Overflow flag, the operation overflowed in two's complement.
+  pub of: bool,
 }
 
 impl Flags {
-  fn new() -> Self {
-    Self {
-      zf: false,
-      sf: false,
-      of: false,
-    }
+  /// Whether a jump or conditional move with `cond` would be taken, see
+  /// `Condition::eval`.
+  pub fn eval_condition(&self, cond: Condition) -> bool {
+    cond.eval(*self)
   }
+}
 
-  fn eval_condition(&self, cond: &JCmovFun) -> bool {
-    match cond {
-      // SF^OF | ZF
-      JCmovFun::LessEqual => (self.sf ^ self.of) | self.zf,
-      // SF^OF
-      JCmovFun::Less => self.sf ^ self.of,
-      // ZF
-      JCmovFun::Equal => self.zf,
-      // !ZF
-      JCmovFun::NotEqual => !self.zf,
-      // !(SF^OF)
-      JCmovFun::GreaterEqual => !(self.sf ^ self.of),
-      // !(SF^OF) & !ZF
-      JCmovFun::Greater => !(self.sf ^ self.of) & !self.zf,
-    }
+impl fmt::Display for Flags {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "zf={} sf={} of={}",
+      self.zf as u8, self.sf as u8, self.of as u8
+    )
   }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct RegisterFile {
   registers: Registers,
   flags: Flags,
+  // one bit per register assigned since construction, `%rsp` from the start
+  written: u16,
 }
 
 impl RegisterFile {
-  pub(crate) fn eval_condition(&self, cond: &JCmovFun) -> bool {
+  /// Zeroed registers and flags, except `%rsp` which starts at `stack_pointer`.
+  pub(crate) fn new(stack_pointer: Word) -> Self {
+    Self {
+      registers: Registers::new(stack_pointer),
+      flags: Flags::default(),
+      written: 1 << Register::Rsp as u16,
+    }
+  }
+
+  /// Whether `reg` was ever assigned, reading one that was not yields the
+  /// zero it started with.
+  pub(crate) fn is_written(&self, reg: Register) -> bool {
+    self.written & 1 << reg as u16 != 0
+  }
+
+  pub(crate) fn eval_condition(&self, cond: Condition) -> bool {
     self.flags.eval_condition(cond)
   }
-}
 
-impl Default for RegisterFile {
-  fn default() -> Self {
-    Self {
-      registers: Registers::new(),
-      flags: Flags::new(),
-    }
+  pub(crate) fn flags(&self) -> Flags {
+    self.flags
   }
 }
 
-#[derive(Clone, Copy)]
-pub(crate) enum Register {
+/// One of the fifteen general purpose registers, numbered by their encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Register {
   Rax = 0,
   Rcx = 1,
   Rdx = 2,
@@ -112,6 +123,71 @@ pub(crate) enum Register {
   R14 = 14,
 }
 
+impl Register {
+  const ALL: [Register; 15] = [
+    Register::Rax,
+    Register::Rcx,
+    Register::Rdx,
+    Register::Rbx,
+    Register::Rsp,
+    Register::Rbp,
+    Register::Rsi,
+    Register::Rdi,
+    Register::R8,
+    Register::R9,
+    Register::R10,
+    Register::R11,
+    Register::R12,
+    Register::R13,
+    Register::R14,
+  ];
+
+  /// Every register in encoding order.
+  pub fn iter() -> impl Iterator<Item = Register> {
+    Self::ALL.into_iter()
+  }
+
+  /// Name without the `%` sigil, e.g. `rax`.
+  pub fn name(self) -> &'static str {
+    match self {
+      Register::Rax => "rax",
+      Register::Rcx => "rcx",
+      Register::Rdx => "rdx",
+      Register::Rbx => "rbx",
+      Register::Rsp => "rsp",
+      Register::Rbp => "rbp",
+      Register::Rsi => "rsi",
+      Register::Rdi => "rdi",
+      Register::R8 => "r8",
+      Register::R9 => "r9",
+      Register::R10 => "r10",
+      Register::R11 => "r11",
+      Register::R12 => "r12",
+      Register::R13 => "r13",
+      Register::R14 => "r14",
+    }
+  }
+}
+
+/// Accepts names with or without the `%` sigil, `%rax` and `rax` alike.
+impl FromStr for Register {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let name = s.strip_prefix('%').unwrap_or(s);
+    Register::iter()
+      .find(|reg| reg.name() == name)
+      .ok_or_else(|| Error::UnknownRegister(s.to_string()))
+  }
+}
+
+/// Formats as assembly syntax, e.g. `%rax`.
+impl fmt::Display for Register {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "%{}", self.name())
+  }
+}
+
 impl TryFrom<u8> for Register {
   type Error = Error;
 
@@ -148,10 +224,12 @@ impl Index<Register> for RegisterFile {
 
 impl IndexMut<Register> for RegisterFile {
   fn index_mut(&mut self, index: Register) -> &mut Self::Output {
+    self.written |= 1 << index as u16;
     &mut self.registers[index as usize]
   }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum Flag {
   ZF, // zero flag
   SF, // sign flag
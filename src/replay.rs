@@ -0,0 +1,445 @@
+use std::fmt;
+use std::sync::mpsc;
+
+use crate::Block;
+use crate::event::{Event, EventFilter, EventKind, SubscriptionId};
+use crate::region::Region;
+use crate::register::{Flags, Register};
+use crate::snapshot::{self, Decoder, Encoder, Snapshot};
+use crate::vm::{self, State, Vm};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("vm error - {0}")]
+  VmError(#[from] vm::Error),
+
+  #[error("snapshot error - {0}")]
+  SnapshotError(#[from] snapshot::Error),
+}
+
+/// Architectural changes made by one instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Effect {
+  /// Address of the instruction.
+  pub address: usize,
+  pub next_ip: usize,
+  /// Registers the instruction changed, with their new values.
+  pub registers: Vec<(Register, Block)>,
+  pub flags: Flags,
+  /// Stores to memory and devices, in program order.
+  pub writes: Vec<(usize, Block)>,
+}
+
+/// How a replayed step differs from the recorded one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+  /// The instruction executed from a different address.
+  Address {
+    expected: usize,
+    actual: usize,
+  },
+  /// A register ended up with a different value, `None` if the step left it
+  /// unchanged.
+  Register {
+    register: Register,
+    expected: Option<Block>,
+    actual: Option<Block>,
+  },
+  Flags {
+    expected: Flags,
+    actual: Flags,
+  },
+  /// The n-th store differs, `None` if the step made fewer stores.
+  Write {
+    index: usize,
+    expected: Option<(usize, Block)>,
+    actual: Option<(usize, Block)>,
+  },
+  NextIp {
+    expected: usize,
+    actual: usize,
+  },
+  /// The recorded step completed but replaying it failed.
+  Fault(String),
+}
+
+/// First point where a replay departed from its recording.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+  /// Zero based index of the step that differed.
+  pub step: usize,
+  pub address: usize,
+  pub expected: Option<Effect>,
+  pub actual: Option<Effect>,
+  /// Every difference found in the step, in the order listed by `Mismatch`.
+  pub mismatches: Vec<Mismatch>,
+}
+
+impl fmt::Display for Divergence {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "diverged at step {} ({:#x})", self.step, self.address)?;
+    let block = |value: Option<Block>| value.map_or("unchanged".to_string(), |v| format!("{v:#x}"));
+    let write = |value: Option<(usize, Block)>| {
+      value.map_or("none".to_string(), |(address, v)| {
+        format!("{v:#x} to {address:#x}")
+      })
+    };
+    for mismatch in &self.mismatches {
+      match mismatch {
+        Mismatch::Address { expected, actual } => {
+          writeln!(f, "  address: expected {expected:#x}, got {actual:#x}")?
+        }
+        Mismatch::Register {
+          register,
+          expected,
+          actual,
+        } => writeln!(
+          f,
+          "  {register}: expected {}, got {}",
+          block(*expected),
+          block(*actual)
+        )?,
+        Mismatch::Flags { expected, actual } => {
+          writeln!(f, "  flags: expected {}, got {}", expected, actual)?
+        }
+        Mismatch::Write {
+          index,
+          expected,
+          actual,
+        } => writeln!(
+          f,
+          "  store {index}: expected {}, got {}",
+          write(*expected),
+          write(*actual)
+        )?,
+        Mismatch::NextIp { expected, actual } => {
+          writeln!(f, "  next ip: expected {expected:#x}, got {actual:#x}")?
+        }
+        Mismatch::Fault(message) => writeln!(f, "  fault: {message}")?,
+      }
+    }
+    Ok(())
+  }
+}
+
+/// How a replay ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Replay {
+  /// Every recorded step was reproduced exactly.
+  Matched {
+    steps: usize,
+  },
+  Diverged(Divergence),
+}
+
+/// Starting state plus the effect of every step of a recorded run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recording {
+  start: Snapshot,
+  effects: Vec<Effect>,
+}
+
+impl Recording {
+  const MAGIC: &'static [u8; 8] = b"y86rec\0\0";
+
+  /// Snapshots `vm` and records steps until it halts. A failing step ends
+  /// the recording without being part of it, the error is returned alongside.
+  pub fn record<R>(vm: &mut Vm, region: &R) -> (Self, Result<(), vm::Error>)
+  where
+    R: Region,
+  {
+    let mut recording = Self {
+      start: vm.snapshot(),
+      effects: Vec::new(),
+    };
+    let mut observer = Observer::attach(vm);
+    let mut result = Ok(());
+    while vm.state() != State::Halted {
+      match observer.step(vm, region) {
+        Ok(effect) => recording.effects.push(effect),
+        Err(e) => {
+          result = Err(e);
+          break;
+        }
+      }
+    }
+    observer.detach(vm);
+    (recording, result)
+  }
+
+  pub fn start(&self) -> &Snapshot {
+    &self.start
+  }
+
+  pub fn effects(&self) -> &[Effect] {
+    &self.effects
+  }
+
+  /// Restores `vm` to the recorded start and steps it alongside the
+  /// recording, stopping at the first step whose effects differ. Devices and
+  /// hooks attached to `vm` are used as is, which is what makes this useful
+  /// for hunting nondeterminism in them.
+  pub fn replay<R>(&self, vm: &mut Vm, region: &R) -> Result<Replay, Error>
+  where
+    R: Region,
+  {
+    vm.restore(&self.start)?;
+    let mut observer = Observer::attach(vm);
+    let mut outcome = Replay::Matched {
+      steps: self.effects.len(),
+    };
+    for (i, expected) in self.effects.iter().enumerate() {
+      let address = vm.ip();
+      let (actual, mismatches) = match observer.step(vm, region) {
+        Ok(actual) => {
+          let mismatches = mismatches(expected, &actual);
+          (Some(actual), mismatches)
+        }
+        Err(e) => (None, vec![Mismatch::Fault(e.to_string())]),
+      };
+      if !mismatches.is_empty() {
+        outcome = Replay::Diverged(Divergence {
+          step: self.start.steps() + i,
+          address,
+          expected: Some(expected.clone()),
+          actual,
+          mismatches,
+        });
+        break;
+      }
+    }
+    observer.detach(vm);
+    Ok(outcome)
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut e = Encoder::new();
+    e.bytes(Self::MAGIC)
+      .bytes(&self.start.to_bytes())
+      .u64(self.effects.len() as u64);
+    for effect in &self.effects {
+      e.u64(effect.address as u64)
+        .u64(effect.next_ip as u64)
+        .u64(effect.registers.len() as u64);
+      for &(register, value) in &effect.registers {
+        e.u64(register as u64).i64(value);
+      }
+      e.bool(effect.flags.zf)
+        .bool(effect.flags.sf)
+        .bool(effect.flags.of)
+        .u64(effect.writes.len() as u64);
+      for &(address, value) in &effect.writes {
+        e.u64(address as u64).i64(value);
+      }
+    }
+    e.finish()
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self, snapshot::Error> {
+    let mut d = Decoder::new(bytes);
+    if d.bytes()? != Self::MAGIC.as_slice() {
+      return Err(snapshot::Error::BadMagic);
+    }
+    let start = Snapshot::from_bytes(d.bytes()?)?;
+    let effects = (0..d.u64()?)
+      .map(|_| {
+        let address = d.u64()? as usize;
+        let next_ip = d.u64()? as usize;
+        let registers = (0..d.u64()?)
+          .map(|_| {
+            let code = d.u64()?;
+            let register = u8::try_from(code)
+              .ok()
+              .and_then(|code| Register::try_from(code).ok())
+              .ok_or(snapshot::Error::InvalidRegister(code))?;
+            Ok((register, d.i64()?))
+          })
+          .collect::<Result<_, snapshot::Error>>()?;
+        let flags = Flags {
+          zf: d.bool()?,
+          sf: d.bool()?,
+          of: d.bool()?,
+        };
+        let writes = (0..d.u64()?)
+          .map(|_| Ok((d.u64()? as usize, d.i64()?)))
+          .collect::<Result<_, snapshot::Error>>()?;
+        Ok(Effect {
+          address,
+          next_ip,
+          registers,
+          flags,
+          writes,
+        })
+      })
+      .collect::<Result<_, snapshot::Error>>()?;
+    Ok(Self { start, effects })
+  }
+}
+
+pub(crate) fn mismatches(expected: &Effect, actual: &Effect) -> Vec<Mismatch> {
+  let mut mismatches = Vec::new();
+  if expected.address != actual.address {
+    mismatches.push(Mismatch::Address {
+      expected: expected.address,
+      actual: actual.address,
+    });
+  }
+  let changed = |effect: &Effect, register| {
+    effect
+      .registers
+      .iter()
+      .find(|&&(r, _)| r == register)
+      .map(|&(_, value)| value)
+  };
+  for register in Register::iter() {
+    let (expected, actual) = (changed(expected, register), changed(actual, register));
+    if expected != actual {
+      mismatches.push(Mismatch::Register {
+        register,
+        expected,
+        actual,
+      });
+    }
+  }
+  if expected.flags != actual.flags {
+    mismatches.push(Mismatch::Flags {
+      expected: expected.flags,
+      actual: actual.flags,
+    });
+  }
+  for index in 0..expected.writes.len().max(actual.writes.len()) {
+    let (expected, actual) = (
+      expected.writes.get(index).copied(),
+      actual.writes.get(index).copied(),
+    );
+    if expected != actual {
+      mismatches.push(Mismatch::Write {
+        index,
+        expected,
+        actual,
+      });
+    }
+  }
+  if expected.next_ip != actual.next_ip {
+    mismatches.push(Mismatch::NextIp {
+      expected: expected.next_ip,
+      actual: actual.next_ip,
+    });
+  }
+  mismatches
+}
+
+/// Captures the stores made by each step through the event bus.
+#[derive(Debug)]
+pub(crate) struct Observer {
+  id: SubscriptionId,
+  events: mpsc::Receiver<Event>,
+}
+
+impl Observer {
+  pub(crate) fn attach(vm: &mut Vm) -> Self {
+    let filter = EventFilter::only(&[EventKind::MemoryWritten, EventKind::DeviceIo]);
+    let (id, events) = vm.subscribe_channel(filter);
+    Self { id, events }
+  }
+
+  pub(crate) fn step<R>(&mut self, vm: &mut Vm, region: &R) -> Result<Effect, vm::Error>
+  where
+    R: Region,
+  {
+    let address = vm.ip();
+    let before: Vec<Block> = vm.registers().map(|(_, value)| value).collect();
+    let result = vm.step(region);
+    let writes = self
+      .events
+      .try_iter()
+      .filter_map(|event| match event {
+        Event::MemoryWritten { address, value } => Some((address, value)),
+        Event::DeviceIo {
+          address,
+          value,
+          write: true,
+        } => Some((address, value)),
+        _ => None,
+      })
+      .collect();
+    result?;
+    let registers = vm
+      .registers()
+      .zip(before)
+      .filter(|&((_, after), before)| after != before)
+      .map(|(changed, _)| changed)
+      .collect();
+    Ok(Effect {
+      address,
+      next_ip: vm.ip(),
+      registers,
+      flags: vm.flags(),
+      writes,
+    })
+  }
+
+  pub(crate) fn detach(self, vm: &mut Vm) {
+    vm.unsubscribe(self.id);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm;
+  use crate::builder::VmBuilder;
+  use crate::region::Chunk;
+
+  const SOURCE: &str = "
+    irmovq $3, %rcx
+    irmovq $1, %rdx
+loop:
+    subq %rdx, %rcx
+    rmmovq %rcx, 0x200(%rbx)
+    jne loop
+    halt
+";
+
+  fn recorded() -> (Recording, Chunk) {
+    let region = Chunk::from(asm::assemble(SOURCE).unwrap().bytes().to_vec());
+    let mut vm = VmBuilder::new().build();
+    vm.load(&region).unwrap();
+    let (recording, result) = Recording::record(&mut vm, &region);
+    result.unwrap();
+    (recording, region)
+  }
+
+  #[test]
+  fn round_trips_through_bytes() {
+    let (recording, _) = recorded();
+    assert_eq!(recording.effects().len(), 12);
+    assert_eq!(recording.effects()[3].writes, [(0x200, 2)]);
+    let decoded = Recording::from_bytes(&recording.to_bytes()).unwrap();
+    assert_eq!(decoded, recording);
+  }
+
+  #[test]
+  fn replays_recorded_runs() {
+    let (recording, region) = recorded();
+    let mut vm = VmBuilder::new().build();
+    let replay = recording.replay(&mut vm, &region).unwrap();
+    assert_eq!(replay, Replay::Matched { steps: 12 });
+  }
+
+  #[test]
+  fn reports_the_first_differing_step() {
+    let (recording, _) = recorded();
+    let patched = SOURCE.replace("0x200", "0x300");
+    let region = Chunk::from(asm::assemble(&patched).unwrap().bytes().to_vec());
+    let mut vm = VmBuilder::new().build();
+    let Replay::Diverged(divergence) = recording.replay(&mut vm, &region).unwrap() else {
+      panic!("replay matched a different program");
+    };
+    assert_eq!(divergence.step, 3);
+    assert!(matches!(
+      divergence.mismatches[..],
+      [Mismatch::Write { index: 0, .. }]
+    ));
+  }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use crate::Block;
+use crate::disasm;
+use crate::event::{Event, EventFilter, EventKind};
+use crate::json::Json;
+use crate::region::Region;
+use crate::register::{Flags, Register};
+use crate::vm::{State, Vm};
+
+/// An instruction that failed during a run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fault {
+  pub address: usize,
+  pub message: String,
+}
+
+/// How much of the loaded program a run executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Coverage {
+  /// Distinct instruction addresses executed.
+  pub executed: usize,
+  /// Instructions found by a linear sweep of the code placed by `Vm::load`,
+  /// or of the whole region if nothing was loaded.
+  pub instructions: usize,
+}
+
+impl Coverage {
+  /// Executed share of the swept instructions, from 0 to 1.
+  pub fn ratio(&self) -> f64 {
+    if self.instructions == 0 {
+      return 0.0;
+    }
+    self.executed.min(self.instructions) as f64 / self.instructions as f64
+  }
+}
+
+/// Everything about a finished run worth keeping, see `Vm::run_report`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunReport {
+  pub state: State,
+  pub ip: usize,
+  /// Instructions executed by the run.
+  pub steps: usize,
+  /// Cycles the timing model charged the run.
+  pub cycles: u64,
+  /// Energy the run consumed, when the vm has an energy table.
+  pub energy: Option<u64>,
+  pub registers: Vec<(Register, Block)>,
+  pub flags: Flags,
+  /// Faults raised in the order they happened, the last one ended the run
+  /// unless the vm halted.
+  pub faults: Vec<Fault>,
+  pub coverage: Coverage,
+  pub duration: Duration,
+}
+
+impl RunReport {
+  pub fn halted(&self) -> bool {
+    self.state == State::Halted
+  }
+
+  /// Renders the report as a pretty printed json document.
+  pub fn to_json(&self) -> String {
+    let status = match self.state {
+      State::Active => "active",
+      State::Halted => "halted",
+    };
+    let registers = self
+      .registers
+      .iter()
+      .map(|&(reg, value)| (reg.name(), Json::from(value)));
+    let flags = [
+      ("zf", Json::from(self.flags.zf)),
+      ("sf", Json::from(self.flags.sf)),
+      ("of", Json::from(self.flags.of)),
+    ];
+    let faults = self
+      .faults
+      .iter()
+      .map(|fault| {
+        Json::object([
+          ("address", Json::from(fault.address)),
+          ("message", Json::from(fault.message.as_str())),
+        ])
+      })
+      .collect();
+    let coverage = [
+      ("executed", Json::from(self.coverage.executed)),
+      ("instructions", Json::from(self.coverage.instructions)),
+    ];
+    let mut fields = vec![
+      ("status", Json::from(status)),
+      ("ip", Json::from(self.ip)),
+      ("steps", Json::from(self.steps)),
+      ("cycles", Json::from(self.cycles as usize)),
+    ];
+    if let Some(energy) = self.energy {
+      fields.push(("energy", Json::from(energy as usize)));
+    }
+    fields.extend([
+      ("registers", Json::object(registers)),
+      ("flags", Json::object(flags)),
+      ("faults", Json::Array(faults)),
+      ("coverage", Json::object(coverage)),
+      (
+        "duration_us",
+        Json::from(self.duration.as_micros() as usize),
+      ),
+    ]);
+    format!("{:#}", Json::object(fields))
+  }
+}
+
+pub(crate) fn run<R>(vm: &mut Vm, region: &R) -> RunReport
+where
+  R: Region,
+{
+  let filter = EventFilter::only(&[EventKind::InstructionRetired, EventKind::FaultRaised]);
+  let (id, events) = vm.subscribe_channel(filter);
+  let (start_steps, start_cycles) = (vm.steps(), vm.timing().cycles());
+  let start_energy = vm.timing().energy().map(|energy| energy.total());
+  let start = Instant::now();
+  // the error is also raised as an event, which is where faults come from
+  let _ = vm.run(region);
+  let duration = start.elapsed();
+  vm.unsubscribe(id);
+
+  let mut executed = HashSet::new();
+  let mut faults = Vec::new();
+  for event in events.try_iter() {
+    match event {
+      Event::InstructionRetired { address, .. } => {
+        executed.insert(address);
+      }
+      Event::FaultRaised { address, message } => faults.push(Fault { address, message }),
+      _ => {}
+    }
+  }
+  RunReport {
+    state: vm.state(),
+    ip: vm.ip(),
+    steps: vm.steps() - start_steps,
+    cycles: vm.timing().cycles() - start_cycles,
+    energy: vm
+      .timing()
+      .energy()
+      .zip(start_energy)
+      .map(|(energy, start)| energy.total() - start),
+    registers: vm.registers().collect(),
+    flags: vm.flags(),
+    faults,
+    coverage: Coverage {
+      executed: executed.len(),
+      instructions: instructions(vm, region),
+    },
+    duration,
+  }
+}
+
+fn instructions<R>(vm: &Vm, region: &R) -> usize
+where
+  R: Region,
+{
+  let bytes = region.instructions();
+  // programs run without `Vm::load` execute straight from the region
+  let code = match vm.code_range() {
+    code if code.is_empty() => 0..bytes.len(),
+    code => code,
+  };
+  let mut count = 0;
+  let mut address = code.start;
+  while address < code.end {
+    count += 1;
+    address +=
+      disasm::disassemble_at(bytes, address).map_or(1, |instruction| instruction.bytes().len());
+  }
+  count
+}
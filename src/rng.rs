@@ -0,0 +1,150 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::bus::DeviceId;
+use crate::snapshot::{self, Decoder, Encoder};
+
+/// Small seeded generator (splitmix64), deterministic across platforms so a
+/// seed reproduces a run exactly.
+#[derive(Debug, Clone)]
+struct Rng {
+  state: u64,
+}
+
+impl Rng {
+  fn new(seed: u64) -> Self {
+    Self { state: seed }
+  }
+
+  fn next_u64(&mut self) -> u64 {
+    self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = self.state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+  }
+}
+
+/// The single source of randomness of a run, see `VmBuilder::seed`. Each
+/// component draws from a stream of its own derived from the seed and the
+/// component's name, so a new consumer does not shift the values others see,
+/// and draws are counted for the audit.
+#[derive(Debug, Clone)]
+pub struct Entropy {
+  seed: u64,
+  // owned names only come back from `load`
+  streams: BTreeMap<Cow<'static, str>, (Rng, usize)>,
+}
+
+impl Entropy {
+  pub fn new(seed: u64) -> Self {
+    Self {
+      seed,
+      streams: BTreeMap::new(),
+    }
+  }
+
+  pub fn seed(&self) -> u64 {
+    self.seed
+  }
+
+  /// Next value of `component`'s stream.
+  pub fn next_u64(&mut self, component: &'static str) -> u64 {
+    let seed = self.seed;
+    let (rng, draws) = match self.streams.get_mut(component) {
+      Some(stream) => stream,
+      None => self
+        .streams
+        .entry(Cow::Borrowed(component))
+        .or_insert_with(|| (Rng::new(stream_seed(seed, component)), 0)),
+    };
+    *draws += 1;
+    rng.next_u64()
+  }
+
+  /// Uniform value in `0..bound` from `component`'s stream, `bound` must be
+  /// nonzero.
+  pub fn below(&mut self, component: &'static str, bound: usize) -> usize {
+    (self.next_u64(component) % bound as u64) as usize
+  }
+
+  /// Restarts every stream from the seed and forgets the draws.
+  pub fn reset(&mut self) {
+    self.streams.clear();
+  }
+
+  /// Components that drew since the last reset with their draw counts, in
+  /// name order.
+  pub fn audit(&self) -> impl Iterator<Item = (&str, usize)> + '_ {
+    self
+      .streams
+      .iter()
+      .map(|(component, &(_, draws))| (component.as_ref(), draws))
+  }
+
+  /// Encodes the position and draw count of every stream, the seed comes
+  /// from the builder.
+  pub(crate) fn save(&self) -> Vec<u8> {
+    let mut e = Encoder::new();
+    e.u64(self.streams.len() as u64);
+    for (component, (rng, draws)) in &self.streams {
+      e.bytes(component.as_bytes())
+        .u64(rng.state)
+        .u64(*draws as u64);
+    }
+    e.finish()
+  }
+
+  /// Restores what `save` produced, streams it does not mention start over.
+  pub(crate) fn load(&mut self, state: &[u8]) -> Result<(), snapshot::Error> {
+    let mut d = Decoder::new(state);
+    let mut streams = BTreeMap::new();
+    for _ in 0..d.u64()? {
+      let component =
+        String::from_utf8(d.bytes()?.to_vec()).map_err(|_| snapshot::Error::InvalidEntropy)?;
+      let rng = Rng::new(d.u64()?);
+      streams.insert(Cow::Owned(component), (rng, d.u64()? as usize));
+    }
+    self.streams = streams;
+    Ok(())
+  }
+}
+
+impl Default for Entropy {
+  fn default() -> Self {
+    Self::new(0)
+  }
+}
+
+// FNV-1a of the name mixed into the seed, one splitmix round keeps similar
+// names apart
+fn stream_seed(seed: u64, component: &str) -> u64 {
+  let hash = component
+    .bytes()
+    .fold(0xcbf2_9ce4_8422_2325, |hash: u64, byte| {
+      (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+  Rng::new(seed ^ hash).next_u64()
+}
+
+/// Something that made a run depend on more than its program and input, see
+/// `Vm::nondeterminism`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+  /// `component` drew `draws` values from the seeded entropy, so the seed
+  /// reproduces it.
+  Seeded { component: String, draws: usize },
+  /// The device reads host state, like a host clock, that no seed can
+  /// reproduce.
+  Host(DeviceId),
+}
+
+impl fmt::Display for Source {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Source::Seeded { component, draws } => write!(f, "{component}: {draws} seeded draws"),
+      Source::Host(id) => write!(f, "device {}: host state", id.0),
+    }
+  }
+}
@@ -0,0 +1,471 @@
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::Block;
+use crate::builder::VmBuilder;
+use crate::region::Chunk;
+use crate::register::Register;
+use crate::report::RunReport;
+use crate::syscall::{Console, Sandbox, Syscalls};
+use crate::vm::Vm;
+
+/// A program to run along with its initial and expected final state.
+#[derive(Debug, Clone)]
+pub struct Job {
+  name: String,
+  program: Chunk,
+  registers: Vec<(Register, Block)>,
+  memory: Vec<(usize, Block)>,
+  expected_registers: Vec<(Register, Block)>,
+  expected_memory: Vec<(usize, Block)>,
+  input: Option<Vec<u8>>,
+  expected_output: Option<Vec<u8>>,
+}
+
+impl Job {
+  pub fn new(name: impl Into<String>, program: impl Into<Chunk>) -> Self {
+    Self {
+      name: name.into(),
+      program: program.into(),
+      registers: Vec::new(),
+      memory: Vec::new(),
+      expected_registers: Vec::new(),
+      expected_memory: Vec::new(),
+      input: None,
+      expected_output: None,
+    }
+  }
+
+  /// Sets a register before the program starts.
+  pub fn with_register(mut self, reg: Register, value: Block) -> Self {
+    self.registers.push((reg, value));
+    self
+  }
+
+  /// Stores an aligned block before the program starts.
+  pub fn with_memory(mut self, address: usize, value: Block) -> Self {
+    self.memory.push((address, value));
+    self
+  }
+
+  pub fn expect_register(mut self, reg: Register, value: Block) -> Self {
+    self.expected_registers.push((reg, value));
+    self
+  }
+
+  pub fn expect_memory(mut self, address: usize, value: Block) -> Self {
+    self.expected_memory.push((address, value));
+    self
+  }
+
+  /// Runs the program with `Syscalls` reading `input` from descriptor 0.
+  pub fn with_input(mut self, input: impl Into<Vec<u8>>) -> Self {
+    self.input = Some(input.into());
+    self
+  }
+
+  /// Expects exactly `output` on descriptor 1, running the program with
+  /// `Syscalls` like `with_input` does.
+  pub fn expect_output(mut self, output: impl Into<Vec<u8>>) -> Self {
+    self.expected_output = Some(output.into());
+    self
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+}
+
+/// A difference between the expected and the final state of a job.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+  Register {
+    register: Register,
+    expected: Block,
+    actual: Block,
+  },
+  Memory {
+    address: usize,
+    expected: Block,
+    /// `None` when the address could not be read.
+    actual: Option<Block>,
+  },
+  Output {
+    expected: Vec<u8>,
+    actual: Vec<u8>,
+  },
+}
+
+/// Output mismatches are shown as the differing lines, expected ones
+/// prefixed with `-` and actual ones with `+`.
+impl fmt::Display for Mismatch {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Mismatch::Register {
+        register,
+        expected,
+        actual,
+      } => write!(f, "{register} expected {expected:#x}, got {actual:#x}"),
+      Mismatch::Memory {
+        address,
+        expected,
+        actual: Some(actual),
+      } => write!(
+        f,
+        "mem[{address:#x}] expected {expected:#x}, got {actual:#x}"
+      ),
+      Mismatch::Memory {
+        address, expected, ..
+      } => write!(f, "mem[{address:#x}] expected {expected:#x}, unreadable"),
+      Mismatch::Output { expected, actual } => {
+        let expected = String::from_utf8_lossy(expected);
+        let actual = String::from_utf8_lossy(actual);
+        let (expected, actual): (Vec<_>, Vec<_>) =
+          (expected.lines().collect(), actual.lines().collect());
+        write!(f, "output differs")?;
+        for line in 0..expected.len().max(actual.len()) {
+          let (old, new) = (expected.get(line), actual.get(line));
+          if old == new {
+            continue;
+          }
+          write!(f, "\n  line {}:", line + 1)?;
+          if let Some(old) = old {
+            write!(f, "\n  -{old}")?;
+          }
+          if let Some(new) = new {
+            write!(f, "\n  +{new}")?;
+          }
+        }
+        Ok(())
+      }
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+  /// Halted with every expectation met.
+  Passed,
+  /// Halted, but the final state differs from the expected one.
+  Failed(Vec<Mismatch>),
+  /// The vm raised an error before halting, or setup failed.
+  Faulted(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct JobOutcome {
+  pub name: String,
+  pub outcome: Outcome,
+  /// Instructions executed by the job.
+  pub steps: usize,
+  pub duration: Duration,
+  /// Full report of the run, `None` when setup failed before it started.
+  pub report: Option<RunReport>,
+}
+
+/// Outcomes of a batch, in the order the jobs were submitted.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+  pub outcomes: Vec<JobOutcome>,
+  pub elapsed: Duration,
+}
+
+impl Report {
+  fn count(&self, f: impl Fn(&Outcome) -> bool) -> usize {
+    self.outcomes.iter().filter(|job| f(&job.outcome)).count()
+  }
+
+  pub fn passed(&self) -> usize {
+    self.count(|outcome| *outcome == Outcome::Passed)
+  }
+
+  pub fn failed(&self) -> usize {
+    self.count(|outcome| matches!(outcome, Outcome::Failed(_)))
+  }
+
+  pub fn faulted(&self) -> usize {
+    self.count(|outcome| matches!(outcome, Outcome::Faulted(_)))
+  }
+
+  pub fn total_steps(&self) -> usize {
+    self.outcomes.iter().map(|job| job.steps).sum()
+  }
+
+  /// Jobs that did not pass.
+  pub fn failures(&self) -> impl Iterator<Item = &JobOutcome> + '_ {
+    self
+      .outcomes
+      .iter()
+      .filter(|job| job.outcome != Outcome::Passed)
+  }
+}
+
+/// Runs batches of jobs across a pool of worker threads, each reusing a
+/// single vm built from the template builder.
+#[derive(Debug, Clone)]
+pub struct BatchRunner {
+  builder: VmBuilder,
+  threads: usize,
+}
+
+impl BatchRunner {
+  /// One worker per available cpu, give the builder a `max_steps` so
+  /// programs that never halt cannot stall the batch.
+  pub fn new(builder: VmBuilder) -> Self {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    Self { builder, threads }
+  }
+
+  pub fn threads(mut self, threads: usize) -> Self {
+    self.threads = threads.max(1);
+    self
+  }
+
+  /// Runs every job, a job that panics is reported as faulted without
+  /// taking the rest of the batch down.
+  pub fn run(&self, jobs: &[Job]) -> Report {
+    let start = Instant::now();
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(jobs.len()));
+    thread::scope(|scope| {
+      for _ in 0..self.threads.min(jobs.len()) {
+        scope.spawn(|| {
+          let mut vm = self.builder.clone().build();
+          loop {
+            let index = next.fetch_add(1, Ordering::Relaxed);
+            let Some(job) = jobs.get(index) else {
+              break;
+            };
+            vm.reset();
+            let start = Instant::now();
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| run_job(&mut vm, job)))
+              .unwrap_or_else(|payload| {
+                // the vm may be left half way through a step
+                vm = self.builder.clone().build();
+                JobOutcome {
+                  name: job.name.clone(),
+                  outcome: Outcome::Faulted(format!("panicked - {}", panic_message(&*payload))),
+                  steps: 0,
+                  duration: start.elapsed(),
+                  report: None,
+                }
+              });
+            results
+              .lock()
+              .expect("results lock poisoned")
+              .push((index, outcome));
+          }
+        });
+      }
+    });
+    let mut results = results.into_inner().expect("results lock poisoned");
+    results.sort_unstable_by_key(|&(index, _)| index);
+    Report {
+      outcomes: results.into_iter().map(|(_, outcome)| outcome).collect(),
+      elapsed: start.elapsed(),
+    }
+  }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+  payload
+    .downcast_ref::<&str>()
+    .copied()
+    .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+    .unwrap_or("unknown panic")
+}
+
+fn run_job(vm: &mut Vm, job: &Job) -> JobOutcome {
+  let start = Instant::now();
+  let mut report = None;
+  let outcome = execute(vm, job, &mut report);
+  JobOutcome {
+    name: job.name.clone(),
+    outcome,
+    steps: vm.steps(),
+    duration: start.elapsed(),
+    report,
+  }
+}
+
+fn execute(vm: &mut Vm, job: &Job, report: &mut Option<RunReport>) -> Outcome {
+  // data the program assembles alongside its code lives in memory
+  if let Err(e) = vm.load(&job.program) {
+    return Outcome::Faulted(format!("setup failed - {e}"));
+  }
+  for &(reg, value) in &job.registers {
+    vm.set_register(reg, value);
+  }
+  // written from the host, so setup is not charged to the run or seen as a
+  // store by code write checks
+  for &(address, value) in &job.memory {
+    if let Err(e) = vm.write_bytes(address, &value.to_le_bytes()) {
+      return Outcome::Faulted(format!("setup failed - {e}"));
+    }
+  }
+  let console = (job.input.is_some() || job.expected_output.is_some()).then(|| {
+    let console = Console::new(job.input.clone().unwrap_or_default());
+    let sandbox = Sandbox::new().stdio(false);
+    vm.set_trap_handler(Syscalls::new(sandbox).with_console(console.clone()));
+    console
+  });
+  let run = report.insert(vm.run_report(&job.program));
+  if console.is_some() {
+    // the vm is reused by jobs without console io
+    vm.clear_trap_handler();
+  }
+  if !run.halted() {
+    let message = run
+      .faults
+      .last()
+      .map_or("did not halt", |fault| &fault.message);
+    return Outcome::Faulted(message.to_string());
+  }
+
+  let mut mismatches = Vec::new();
+  for &(reg, expected) in &job.expected_registers {
+    let actual = vm.register(reg);
+    if actual != expected {
+      mismatches.push(Mismatch::Register {
+        register: reg,
+        expected,
+        actual,
+      });
+    }
+  }
+  for &(address, expected) in &job.expected_memory {
+    let actual = vm.memory().read(address).ok();
+    if actual != Some(expected) {
+      mismatches.push(Mismatch::Memory {
+        address,
+        expected,
+        actual,
+      });
+    }
+  }
+  if let (Some(expected), Some(console)) = (&job.expected_output, &console) {
+    let actual = console.output();
+    if actual != *expected {
+      mismatches.push(Mismatch::Output {
+        expected: expected.clone(),
+        actual,
+      });
+    }
+  }
+  if mismatches.is_empty() {
+    Outcome::Passed
+  } else {
+    Outcome::Failed(mismatches)
+  }
+}
+
+// jobs and their vms cross thread boundaries
+const _: () = {
+  const fn assert_send<T: Send>() {}
+  assert_send::<Vm>();
+  assert_send::<Job>();
+};
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm;
+
+  fn program(source: &str) -> Vec<u8> {
+    asm::assemble(source).unwrap().bytes().to_vec()
+  }
+
+  fn runner() -> BatchRunner {
+    BatchRunner::new(VmBuilder::new().max_steps(1000)).threads(3)
+  }
+
+  #[test]
+  fn reports_outcomes_in_submission_order() {
+    let sum = program("addq %rdi, %rsi\nrmmovq %rsi, 0x100(%rbx)\nhalt");
+    let jobs = vec![
+      Job::new("pass", sum.clone())
+        .with_register(Register::Rdi, 2)
+        .with_register(Register::Rsi, 3)
+        .expect_register(Register::Rsi, 5)
+        .expect_memory(0x100, 5),
+      Job::new("fail", sum.clone())
+        .with_register(Register::Rdi, 2)
+        .expect_register(Register::Rsi, 5),
+      Job::new("spin", program("loop: jmp loop")),
+    ];
+    let report = runner().run(&jobs);
+    let names: Vec<_> = report
+      .outcomes
+      .iter()
+      .map(|job| job.name.as_str())
+      .collect();
+    assert_eq!(names, ["pass", "fail", "spin"]);
+    assert_eq!(report.outcomes[0].outcome, Outcome::Passed);
+    assert_eq!(
+      report.outcomes[1].outcome,
+      Outcome::Failed(vec![Mismatch::Register {
+        register: Register::Rsi,
+        expected: 5,
+        actual: 2,
+      }])
+    );
+    assert!(matches!(report.outcomes[2].outcome, Outcome::Faulted(_)));
+    assert_eq!(
+      (report.passed(), report.failed(), report.faulted()),
+      (1, 1, 1)
+    );
+    assert_eq!(report.failures().count(), 2);
+  }
+
+  #[test]
+  fn reused_vms_start_from_reset() {
+    let sum = program("addq %rdi, %rsi\nrmmovq %rsi, 0x100(%rbx)\nhalt");
+    let jobs = vec![
+      Job::new("first", sum.clone())
+        .with_register(Register::Rsi, 3)
+        .expect_memory(0x100, 3),
+      Job::new("second", sum)
+        .expect_register(Register::Rsi, 0)
+        .expect_memory(0x100, 0),
+    ];
+    // a single worker runs both jobs on the same vm
+    let report = runner().threads(1).run(&jobs);
+    assert_eq!(report.passed(), 2);
+  }
+
+  #[test]
+  fn compares_console_output() {
+    let hello = program(
+      "
+    irmovq $1, %rax
+    irmovq $1, %rdi
+    irmovq message, %rsi
+    irmovq $3, %rdx
+    .byte 0xc0
+    halt
+message:
+    .byte 0x6f
+    .byte 0x6b
+    .byte 0x0a
+",
+    );
+    let jobs = vec![
+      Job::new("same", hello.clone()).expect_output("ok\n"),
+      Job::new("differs", hello.clone()).expect_output("no\n"),
+    ];
+    let report = runner().run(&jobs);
+    assert_eq!(report.outcomes[0].outcome, Outcome::Passed);
+    let Outcome::Failed(mismatches) = &report.outcomes[1].outcome else {
+      panic!("expected a mismatch, got {:?}", report.outcomes[1].outcome);
+    };
+    assert_eq!(
+      mismatches[0].to_string(),
+      "output differs\n  line 1:\n  -no\n  +ok"
+    );
+  }
+}
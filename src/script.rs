@@ -0,0 +1,201 @@
+//! Scriptable breakpoint hooks, enabled by the `scripting` feature.
+//!
+//! Engines plug in through [`ScriptEngine`] so embedders can bring their own
+//! interpreter, the crate itself only ships the dependency free [`Builtin`]
+//! language.
+
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crate::Block;
+use crate::debugger::{Action, Hook};
+use crate::expr::{self, Expr};
+use crate::register::Register;
+use crate::vm::{self, Vm};
+
+/// A scripting language able to drive the machine from a breakpoint.
+pub trait ScriptEngine {
+  type Script;
+  type Error: std::error::Error + Send + Sync + 'static;
+
+  fn compile(&mut self, source: &str) -> Result<Self::Script, Self::Error>;
+
+  fn execute(&mut self, script: &Self::Script, vm: &mut Vm) -> Result<Action, Self::Error>;
+}
+
+/// Hook running a compiled script every time its breakpoint triggers.
+pub struct Scripted<E: ScriptEngine> {
+  engine: E,
+  script: E::Script,
+}
+
+impl<E: ScriptEngine> Scripted<E> {
+  pub fn new(mut engine: E, source: &str) -> Result<Self, E::Error> {
+    let script = engine.compile(source)?;
+    Ok(Self { engine, script })
+  }
+
+  pub fn engine(&self) -> &E {
+    &self.engine
+  }
+}
+
+impl<E: ScriptEngine> Hook for Scripted<E> {
+  fn call(&mut self, vm: &mut Vm) -> Result<Action, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(self.engine.execute(&self.script, vm)?)
+  }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("invalid statement {0:?}")]
+  InvalidStatement(String),
+
+  #[error("expression error - {0}")]
+  ExprError(#[from] expr::Error),
+
+  #[error("vm error - {0}")]
+  VmError(#[from] vm::Error),
+}
+
+#[derive(Debug, Clone)]
+enum Target {
+  Register(Register),
+  Ip,
+  Memory(Expr),
+}
+
+#[derive(Debug, Clone)]
+enum Statement {
+  Assign(Target, Expr),
+  Record(Expr),
+  Act(Action, Option<Expr>),
+}
+
+/// Finds the `=` of an assignment, skipping `==`, `!=`, `<=` and `>=`.
+fn assignment(source: &str) -> Option<usize> {
+  let bytes = source.as_bytes();
+  (0..bytes.len()).find(|&i| {
+    bytes[i] == b'='
+      && bytes.get(i + 1) != Some(&b'=')
+      && !matches!(
+        i.checked_sub(1).map(|j| bytes[j]),
+        Some(b'=' | b'!' | b'<' | b'>')
+      )
+  })
+}
+
+impl FromStr for Statement {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || Error::InvalidStatement(s.to_string());
+    for (keyword, action) in [("continue", Action::Continue), ("stop", Action::Stop)] {
+      if let Some(rest) = s.strip_prefix(keyword) {
+        let condition = match rest.trim() {
+          "" => None,
+          rest => Some(rest.strip_prefix("if").ok_or_else(invalid)?.parse()?),
+        };
+        return Ok(Statement::Act(action, condition));
+      }
+    }
+    if let Some(rest) = s.strip_prefix("record ") {
+      return Ok(Statement::Record(rest.parse()?));
+    }
+    let at = assignment(s).ok_or_else(invalid)?;
+    let (lhs, rhs) = (s[..at].trim(), s[at + 1..].parse()?);
+    let target = if lhs == "ip" {
+      Target::Ip
+    } else if lhs.starts_with('%') {
+      Target::Register(lhs.parse::<Register>().map_err(|_| invalid())?)
+    } else if let Some(address) = lhs.strip_prefix("mem[").and_then(|l| l.strip_suffix(']')) {
+      Target::Memory(address.parse()?)
+    } else {
+      return Err(invalid());
+    };
+    Ok(Statement::Assign(target, rhs))
+  }
+}
+
+/// Compiled form of a [`Builtin`] script.
+#[derive(Debug, Clone)]
+pub struct BuiltinScript(Vec<Statement>);
+
+/// A tiny statement language built on breakpoint condition expressions.
+///
+/// Statements are separated by `;` or newlines:
+///
+/// - `%rax = EXPR`, `ip = EXPR` and `mem[EXPR] = EXPR` modify the machine
+/// - `record EXPR` appends the value to [`Builtin::records`]
+/// - `continue [if EXPR]` and `stop [if EXPR]` end the script with that action
+///
+/// A script that runs off its end stops, like a plain breakpoint would.
+///
+/// Clones share their records, keep one around to read them back once the
+/// engine has been moved into a hook.
+#[derive(Debug, Clone, Default)]
+pub struct Builtin {
+  records: Arc<Mutex<Vec<Block>>>,
+}
+
+impl Builtin {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Values captured by `record` statements, in execution order.
+  pub fn records(&self) -> Vec<Block> {
+    self.records.lock().expect("records lock poisoned").clone()
+  }
+}
+
+impl ScriptEngine for Builtin {
+  type Script = BuiltinScript;
+  type Error = Error;
+
+  fn compile(&mut self, source: &str) -> Result<Self::Script, Self::Error> {
+    let statements = source
+      .split([';', '\n'])
+      .map(str::trim)
+      .filter(|statement| !statement.is_empty() && !statement.starts_with('#'))
+      .map(str::parse)
+      .collect::<Result<_, _>>()?;
+    Ok(BuiltinScript(statements))
+  }
+
+  fn execute(&mut self, script: &Self::Script, vm: &mut Vm) -> Result<Action, Self::Error> {
+    for statement in &script.0 {
+      match statement {
+        Statement::Assign(target, value) => {
+          let value = value.eval(vm)?;
+          match target {
+            Target::Register(reg) => vm.set_register(*reg, value),
+            Target::Ip => vm.set_ip(value as usize),
+            Target::Memory(address) => {
+              let address = address.eval(vm)? as usize;
+              vm.write_block(address, value)?;
+            }
+          }
+        }
+        Statement::Record(value) => {
+          let value = value.eval(vm)?;
+          self
+            .records
+            .lock()
+            .expect("records lock poisoned")
+            .push(value);
+        }
+        Statement::Act(action, condition) => {
+          let taken = match condition {
+            Some(condition) => condition.is_true(vm)?,
+            None => true,
+          };
+          if taken {
+            return Ok(*action);
+          }
+        }
+      }
+    }
+    Ok(Action::Stop)
+  }
+}
@@ -0,0 +1,77 @@
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("malformed session line {0}: {1:?}")]
+  MalformedLine(usize, String),
+}
+
+/// Debugger configuration worth keeping between runs of a program, see
+/// `Debugger::session` and `Debugger::restore`. Breakpoints and conditions
+/// are kept as text, with addresses written as labels where one matches, so
+/// they resolve again after the program is edited and reassembled.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Session {
+  pub breakpoints: Vec<String>,
+  pub watchpoints: Vec<String>,
+  pub displays: Vec<String>,
+  /// Commands entered, oldest first.
+  pub history: Vec<String>,
+}
+
+impl Session {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.breakpoints.is_empty()
+      && self.watchpoints.is_empty()
+      && self.displays.is_empty()
+      && self.history.is_empty()
+  }
+}
+
+/// One `KIND TEXT` line per entry, kinds being `break`, `watch`, `display`
+/// and `history`. Blank lines and lines starting with `#` are skipped.
+impl FromStr for Session {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut session = Session::new();
+    for (i, line) in s.lines().enumerate() {
+      if line.trim().is_empty() || line.trim_start().starts_with('#') {
+        continue;
+      }
+      let malformed = || Error::MalformedLine(i + 1, line.to_string());
+      let (kind, text) = line.split_once(' ').ok_or_else(malformed)?;
+      let list = match kind {
+        "break" => &mut session.breakpoints,
+        "watch" => &mut session.watchpoints,
+        "display" => &mut session.displays,
+        "history" => &mut session.history,
+        _ => return Err(malformed()),
+      };
+      list.push(text.to_string());
+    }
+    Ok(session)
+  }
+}
+
+impl fmt::Display for Session {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let entries = [
+      ("break", &self.breakpoints),
+      ("watch", &self.watchpoints),
+      ("display", &self.displays),
+      ("history", &self.history),
+    ];
+    for (kind, list) in entries {
+      for text in list {
+        writeln!(f, "{kind} {text}")?;
+      }
+    }
+    Ok(())
+  }
+}
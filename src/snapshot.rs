@@ -0,0 +1,251 @@
+use crate::Block;
+use crate::bus::DeviceId;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("not a snapshot image")]
+  BadMagic,
+
+  #[error("unsupported snapshot version {0}")]
+  UnsupportedVersion(u64),
+
+  #[error("snapshot ends unexpectedly")]
+  Truncated,
+
+  #[error("snapshot has state for device {0:?} which is not attached")]
+  MissingDevice(DeviceId),
+
+  #[error("malformed device state - {0}")]
+  InvalidDeviceState(String),
+
+  #[error("timing model configuration differs from the snapshot")]
+  TimingMismatch,
+
+  #[error("malformed entropy state")]
+  InvalidEntropy,
+
+  #[error("invalid register {0} in recording")]
+  InvalidRegister(u64),
+}
+
+/// Appends little endian fields, for device `save` hooks.
+#[derive(Debug, Default)]
+pub struct Encoder {
+  bytes: Vec<u8>,
+}
+
+impl Encoder {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn u64(&mut self, value: u64) -> &mut Self {
+    self.bytes.extend_from_slice(&value.to_le_bytes());
+    self
+  }
+
+  pub fn i64(&mut self, value: i64) -> &mut Self {
+    self.u64(value as u64)
+  }
+
+  pub fn bool(&mut self, value: bool) -> &mut Self {
+    self.u64(value as u64)
+  }
+
+  /// Length prefixed bytes.
+  pub fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+    self.u64(bytes.len() as u64);
+    self.bytes.extend_from_slice(bytes);
+    self
+  }
+
+  pub fn finish(&mut self) -> Vec<u8> {
+    std::mem::take(&mut self.bytes)
+  }
+}
+
+/// Reads back what an `Encoder` wrote, for device `load` hooks.
+#[derive(Debug)]
+pub struct Decoder<'b> {
+  bytes: &'b [u8],
+}
+
+impl<'b> Decoder<'b> {
+  pub fn new(bytes: &'b [u8]) -> Self {
+    Self { bytes }
+  }
+
+  fn take(&mut self, len: usize) -> Result<&'b [u8], Error> {
+    if self.bytes.len() < len {
+      return Err(Error::Truncated);
+    }
+    let (head, rest) = self.bytes.split_at(len);
+    self.bytes = rest;
+    Ok(head)
+  }
+
+  pub fn u64(&mut self) -> Result<u64, Error> {
+    let bytes = self.take(8)?;
+    Ok(u64::from_le_bytes(
+      bytes.try_into().expect("took eight bytes"),
+    ))
+  }
+
+  pub fn i64(&mut self) -> Result<i64, Error> {
+    Ok(self.u64()? as i64)
+  }
+
+  pub fn bool(&mut self) -> Result<bool, Error> {
+    Ok(self.u64()? != 0)
+  }
+
+  pub fn bytes(&mut self) -> Result<&'b [u8], Error> {
+    let len = self.u64()? as usize;
+    self.take(len)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.bytes.is_empty()
+  }
+}
+
+/// Complete machine state captured by `Vm::snapshot`, including the timing
+/// model, every attached device and the position of every entropy stream. Configuration, event subscribers, trap
+/// handlers and access traces are not part of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+  pub(crate) ip: usize,
+  pub(crate) registers: Vec<Block>,
+  pub(crate) flags: [bool; 3],
+  pub(crate) halted: bool,
+  pub(crate) steps: usize,
+  pub(crate) memory: Vec<u8>,
+  pub(crate) code: (usize, usize),
+  pub(crate) boundaries: Vec<usize>,
+  pub(crate) timing: Vec<u8>,
+  pub(crate) entropy: Vec<u8>,
+  pub(crate) devices: Vec<(DeviceId, Vec<u8>)>,
+}
+
+impl Snapshot {
+  const MAGIC: &'static [u8; 8] = b"y86snap\0";
+  const VERSION: u64 = 5;
+
+  pub fn ip(&self) -> usize {
+    self.ip
+  }
+
+  pub fn steps(&self) -> usize {
+    self.steps
+  }
+
+  /// Encodes the snapshot as a self contained image for writing to disk.
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut e = Encoder::new();
+    e.bytes.extend_from_slice(Self::MAGIC);
+    e.u64(Self::VERSION)
+      .u64(self.ip as u64)
+      .u64(self.registers.len() as u64);
+    for &value in &self.registers {
+      e.i64(value);
+    }
+    for flag in self.flags {
+      e.bool(flag);
+    }
+    e.bool(self.halted)
+      .u64(self.steps as u64)
+      .bytes(&self.memory)
+      .u64(self.code.0 as u64)
+      .u64(self.code.1 as u64)
+      .u64(self.boundaries.len() as u64);
+    for &boundary in &self.boundaries {
+      e.u64(boundary as u64);
+    }
+    e.bytes(&self.timing)
+      .bytes(&self.entropy)
+      .u64(self.devices.len() as u64);
+    for (id, state) in &self.devices {
+      e.u64(id.0 as u64).bytes(state);
+    }
+    e.finish()
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+    let mut d = Decoder::new(bytes);
+    if d.take(Self::MAGIC.len()).ok() != Some(Self::MAGIC.as_slice()) {
+      return Err(Error::BadMagic);
+    }
+    let version = d.u64()?;
+    if version != Self::VERSION {
+      return Err(Error::UnsupportedVersion(version));
+    }
+    let ip = d.u64()? as usize;
+    let registers = (0..d.u64()?).map(|_| d.i64()).collect::<Result<_, _>>()?;
+    let flags = [d.bool()?, d.bool()?, d.bool()?];
+    let halted = d.bool()?;
+    let steps = d.u64()? as usize;
+    let memory = d.bytes()?.to_vec();
+    let code = (d.u64()? as usize, d.u64()? as usize);
+    let boundaries = (0..d.u64()?)
+      .map(|_| d.u64().map(|b| b as usize))
+      .collect::<Result<_, _>>()?;
+    let timing = d.bytes()?.to_vec();
+    let entropy = d.bytes()?.to_vec();
+    let devices = (0..d.u64()?)
+      .map(|_| Ok((DeviceId(d.u64()? as usize), d.bytes()?.to_vec())))
+      .collect::<Result<_, Error>>()?;
+    Ok(Self {
+      ip,
+      registers,
+      flags,
+      halted,
+      steps,
+      memory,
+      code,
+      boundaries,
+      timing,
+      entropy,
+      devices,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm;
+  use crate::builder::VmBuilder;
+  use crate::region::Chunk;
+  use crate::register::Register;
+
+  #[test]
+  fn round_trips_through_bytes() {
+    let program = asm::assemble("irmovq $7, %rax\nrmmovq %rax, 0x101(%rax)\nhalt\n").unwrap();
+    let region = Chunk::from(program.bytes().to_vec());
+    let mut vm = VmBuilder::new().build();
+    vm.load(&region).unwrap();
+    vm.step(&region).unwrap();
+    vm.step(&region).unwrap();
+    let snapshot = vm.snapshot();
+    let decoded = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+    assert_eq!(decoded, snapshot);
+
+    vm.step(&region).unwrap();
+    vm.set_register(Register::Rax, 0);
+    vm.restore(&decoded).unwrap();
+    assert_eq!(vm.snapshot(), snapshot);
+  }
+
+  #[test]
+  fn rejects_foreign_and_truncated_bytes() {
+    assert!(matches!(
+      Snapshot::from_bytes(b"not a snapshot"),
+      Err(Error::BadMagic)
+    ));
+    let bytes = VmBuilder::new().build().snapshot().to_bytes();
+    assert!(matches!(
+      Snapshot::from_bytes(&bytes[..bytes.len() - 1]),
+      Err(Error::Truncated)
+    ));
+  }
+}
@@ -0,0 +1,245 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::Block;
+use crate::asm;
+use crate::opcode::Isa;
+use crate::register::Register;
+use crate::runner::Job;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("malformed manifest line {0}: {1:?}")]
+  MalformedLine(usize, String),
+
+  #[error("manifest line {0} comes before the first `case NAME` line")]
+  OutsideCase(usize),
+
+  #[error("case {0:?} has no program")]
+  MissingProgram(String),
+
+  #[error("failed to read {0:?} - {1}")]
+  ReadFailed(PathBuf, io::Error),
+
+  #[error("failed to assemble {0:?} - {1}")]
+  AsmError(PathBuf, asm::Error),
+}
+
+/// One test of a program, see `Suite` for the manifest entries setting each
+/// field.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Case {
+  pub name: String,
+  pub program: Option<PathBuf>,
+  pub registers: Vec<(Register, Block)>,
+  pub memory: Vec<(usize, Block)>,
+  pub input: Option<Vec<u8>>,
+  pub output: Option<Vec<u8>>,
+  pub expected_registers: Vec<(Register, Block)>,
+  pub expected_memory: Vec<(usize, Block)>,
+}
+
+/// Test cases read from a manifest, one entry per line:
+///
+/// - `case NAME` starts a case, the entries below belong to it
+/// - `program PATH` is the program, `.ys` sources are assembled
+/// - `set %REG VALUE` and `set ADDR VALUE` set up the initial state
+/// - `input TEXT` appends a line to stdin, `input-file PATH` a file
+/// - `output TEXT` appends a line to the expected stdout, `output-file PATH`
+///   a file
+/// - `expect %REG VALUE` and `expect ADDR VALUE` check the final state
+///
+/// Paths are relative to the manifest, numbers decimal or `0x` hex. Blank
+/// lines and lines starting with `#` are skipped. Cases with input or output
+/// run with `Syscalls` serving the standard streams from memory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Suite {
+  pub cases: Vec<Case>,
+}
+
+impl Suite {
+  /// Reads the manifest at `path` along with the files it names.
+  pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path).map_err(|e| Error::ReadFailed(path.to_path_buf(), e))?;
+    Self::parse(&text, path.parent().unwrap_or(Path::new("")))
+  }
+
+  /// Parses a manifest whose paths are relative to `base`.
+  pub fn parse(text: &str, base: &Path) -> Result<Self, Error> {
+    let mut cases: Vec<Case> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+      if line.trim().is_empty() || line.trim_start().starts_with('#') {
+        continue;
+      }
+      let malformed = || Error::MalformedLine(i + 1, line.to_string());
+      // a bare `input` or `output` is an empty line
+      let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+      if kind == "case" {
+        cases.push(Case {
+          name: rest.trim().to_string(),
+          ..Case::default()
+        });
+        continue;
+      }
+      let case = cases.last_mut().ok_or(Error::OutsideCase(i + 1))?;
+      let read = |path: &str| {
+        let path = base.join(path.trim());
+        fs::read(&path).map_err(|e| Error::ReadFailed(path, e))
+      };
+      match kind {
+        "program" => case.program = Some(base.join(rest.trim())),
+        "set" | "expect" => {
+          let (target, value) = rest.trim().split_once(' ').ok_or_else(malformed)?;
+          let value = parse_value(value.trim()).ok_or_else(malformed)?;
+          let (registers, memory) = match kind {
+            "set" => (&mut case.registers, &mut case.memory),
+            _ => (&mut case.expected_registers, &mut case.expected_memory),
+          };
+          if target.starts_with('%') {
+            registers.push((target.parse().map_err(|_| malformed())?, value));
+          } else {
+            let address = parse_value(target).and_then(|address| usize::try_from(address).ok());
+            memory.push((address.ok_or_else(malformed)?, value));
+          }
+        }
+        "input" => append_line(&mut case.input, rest),
+        "output" => append_line(&mut case.output, rest),
+        "input-file" => case.input.get_or_insert_default().extend(read(rest)?),
+        "output-file" => case.output.get_or_insert_default().extend(read(rest)?),
+        _ => return Err(malformed()),
+      }
+    }
+    Ok(Self { cases })
+  }
+
+  /// Loads every program, assembling sources for `isa`, in case order.
+  pub fn jobs(&self, isa: Isa) -> Result<Vec<Job>, Error> {
+    self.cases.iter().map(|case| case.job(isa)).collect()
+  }
+}
+
+impl Case {
+  fn job(&self, isa: Isa) -> Result<Job, Error> {
+    let path = self
+      .program
+      .as_ref()
+      .ok_or_else(|| Error::MissingProgram(self.name.clone()))?;
+    let bytes = if path.extension().is_some_and(|ext| ext == "ys") {
+      let source = fs::read_to_string(path).map_err(|e| Error::ReadFailed(path.clone(), e))?;
+      let assembled =
+        asm::assemble_isa(&source, isa).map_err(|e| Error::AsmError(path.clone(), e))?;
+      assembled.bytes().to_vec()
+    } else {
+      fs::read(path).map_err(|e| Error::ReadFailed(path.clone(), e))?
+    };
+    let mut job = Job::new(&self.name, bytes);
+    for &(reg, value) in &self.registers {
+      job = job.with_register(reg, value);
+    }
+    for &(address, value) in &self.memory {
+      job = job.with_memory(address, value);
+    }
+    for &(reg, value) in &self.expected_registers {
+      job = job.expect_register(reg, value);
+    }
+    for &(address, value) in &self.expected_memory {
+      job = job.expect_memory(address, value);
+    }
+    if let Some(input) = &self.input {
+      job = job.with_input(input.clone());
+    }
+    if let Some(output) = &self.output {
+      job = job.expect_output(output.clone());
+    }
+    Ok(job)
+  }
+}
+
+fn append_line(stream: &mut Option<Vec<u8>>, text: &str) {
+  let stream = stream.get_or_insert_default();
+  stream.extend(text.as_bytes());
+  stream.push(b'\n');
+}
+
+fn parse_value(s: &str) -> Option<Block> {
+  let (negative, digits) = match s.strip_prefix('-') {
+    Some(digits) => (true, digits),
+    None => (false, s),
+  };
+  let value = match digits.strip_prefix("0x") {
+    Some(hex) => u64::from_str_radix(hex, 16).ok()? as Block,
+    None => digits.parse().ok()?,
+  };
+  Some(if negative {
+    value.wrapping_neg()
+  } else {
+    value
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_manifests() {
+    let text = "
+# comment
+case sum
+program sum.ys
+set %rdi 0x10
+set 0x100 3
+input hello
+input
+expect %rax -1
+expect 0x108 0x2a
+case copy
+program copy.yo
+output done
+";
+    let suite = Suite::parse(text, Path::new("tests")).unwrap();
+    let [sum, copy] = &suite.cases[..] else {
+      panic!("expected two cases, got {:?}", suite.cases);
+    };
+    assert_eq!(sum.name, "sum");
+    assert_eq!(sum.program, Some(PathBuf::from("tests/sum.ys")));
+    assert_eq!(sum.registers, [(Register::Rdi, 0x10)]);
+    assert_eq!(sum.memory, [(0x100, 3)]);
+    assert_eq!(sum.input.as_deref(), Some(&b"hello\n\n"[..]));
+    assert_eq!(sum.expected_registers, [(Register::Rax, -1)]);
+    assert_eq!(sum.expected_memory, [(0x108, 0x2a)]);
+    assert_eq!(copy.output.as_deref(), Some(&b"done\n"[..]));
+    assert_eq!(copy.input, None);
+  }
+
+  #[test]
+  fn rejects_malformed_manifests() {
+    assert!(matches!(
+      Suite::parse("program a.ys", Path::new("")),
+      Err(Error::OutsideCase(1))
+    ));
+    assert!(matches!(
+      Suite::parse("case a\nset %rax", Path::new("")),
+      Err(Error::MalformedLine(2, _))
+    ));
+    assert!(matches!(
+      Suite::parse("case a\nset %nope 1", Path::new("")),
+      Err(Error::MalformedLine(2, _))
+    ));
+    assert!(matches!(
+      Suite::parse("case a\nfrobnicate", Path::new("")),
+      Err(Error::MalformedLine(2, _))
+    ));
+  }
+
+  #[test]
+  fn cases_need_a_program() {
+    let suite = Suite::parse("case a\nset %rax 1", Path::new("")).unwrap();
+    assert!(matches!(
+      suite.jobs(Isa::default()),
+      Err(Error::MissingProgram(name)) if name == "a"
+    ));
+  }
+}
@@ -0,0 +1,261 @@
+use crate::disasm::Instruction;
+use crate::opcode::Condition;
+use crate::region::Region;
+use crate::register::Register;
+use crate::vm::{self, ExecutedInstruction, Vm};
+
+/// Why an instruction could not issue alongside the one before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Hazards {
+  /// It read or wrote a register the first slot writes.
+  pub data: usize,
+  /// It tested the condition codes set by the first slot.
+  pub flags: usize,
+  /// Both slots wanted the single data memory port.
+  pub memory: usize,
+  /// The first slot was a jump, call, return or halt, or either was a trap.
+  pub control: usize,
+}
+
+// what the scheduler needs to know about one retired instruction
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+  reads: [Option<Register>; 3],
+  writes: [Option<Register>; 2],
+  loads: Option<Register>,
+  memory: bool,
+  sets_flags: bool,
+  reads_flags: bool,
+  control: bool,
+  // handled by a trap handler, which may touch any state
+  trap: bool,
+  // cycles after its group was fetched before the next group can be
+  redirect: usize,
+}
+
+impl Slot {
+  fn new(executed: &ExecutedInstruction) -> Self {
+    let Some(disassembled) = executed.instruction() else {
+      return Self::trap();
+    };
+    let instruction = disassembled.instruction();
+    let encoding = disassembled.encoding();
+    let fall_through = disassembled.address() + disassembled.bytes().len();
+    Self {
+      reads: instruction.reads(),
+      writes: instruction.writes(),
+      loads: instruction.loads(),
+      memory: encoding.reads_memory || encoding.writes_memory,
+      sets_flags: encoding.writes_flags,
+      reads_flags: encoding.reads_flags,
+      trap: false,
+      control: matches!(
+        instruction,
+        Instruction::Jxx(..) | Instruction::Call(_) | Instruction::Ret | Instruction::Halt
+      ),
+      redirect: match instruction {
+        Instruction::Jxx(cond, _)
+          if *cond != Condition::Always && executed.next_ip() == fall_through =>
+        {
+          3
+        }
+        Instruction::Ret => 4,
+        _ => 1,
+      },
+    }
+  }
+
+  // issues alone, neither joining the slot before it nor taking one after
+  fn trap() -> Self {
+    Self {
+      reads: [None; 3],
+      writes: [None; 2],
+      loads: None,
+      memory: false,
+      sets_flags: false,
+      reads_flags: false,
+      control: true,
+      trap: true,
+      redirect: 1,
+    }
+  }
+
+  fn depends_on(&self, first: &Slot) -> bool {
+    let written = |reg: &Option<Register>| reg.is_some() && first.writes.contains(reg);
+    self.reads.iter().any(written) || self.writes.iter().any(written)
+  }
+}
+
+/// Experimental in order dual issue model over the retired instruction
+/// stream, for comparison with seq and `Pipeline`.
+///
+/// Each cycle fetches a group of up to two instructions. The second joins
+/// the first unless it depends on it through a register or the condition
+/// codes, both need the data memory port, or the first transfers control.
+/// Otherwise the pipe rules apply: loads stall a dependent group behind them
+/// by a cycle, conditional jumps are predicted taken and `ret` waits for its
+/// return address.
+#[derive(Debug, Default)]
+pub struct DualIssue {
+  // fetch cycle of the latest group, 0 before the first
+  cycle: usize,
+  // first instruction of the latest group while the second slot is free
+  open: Option<Slot>,
+  // registers loaded by the latest group
+  loaded: Vec<Register>,
+  // earliest cycle the next group may be fetched in
+  resume: usize,
+  instructions: usize,
+  dual: usize,
+  hazards: Hazards,
+}
+
+impl DualIssue {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Runs `vm` until it halts or faults, scheduling every retired
+  /// instruction.
+  pub fn run<R>(vm: &mut Vm, region: &R) -> Result<Self, vm::Error>
+  where
+    R: Region,
+  {
+    let mut model = Self::new();
+    for executed in vm.iter(region) {
+      model.push(&executed?);
+    }
+    Ok(model)
+  }
+
+  /// Schedules the next retired instruction in program order.
+  pub fn push(&mut self, executed: &ExecutedInstruction) {
+    let slot = Slot::new(executed);
+    self.instructions += 1;
+    if let Some(first) = self.open.take() {
+      let hazard = if first.control || slot.trap {
+        Some(&mut self.hazards.control)
+      } else if slot.depends_on(&first) {
+        Some(&mut self.hazards.data)
+      } else if first.sets_flags && slot.reads_flags {
+        Some(&mut self.hazards.flags)
+      } else if first.memory && slot.memory {
+        Some(&mut self.hazards.memory)
+      } else {
+        None
+      };
+      match hazard {
+        Some(count) => *count += 1,
+        None => {
+          self.dual += 1;
+          self.loaded.extend(slot.loads);
+          self.resume = self.cycle + slot.redirect;
+          return;
+        }
+      }
+    }
+
+    let mut cycle = (self.cycle + 1).max(self.resume);
+    let load_use = slot
+      .reads
+      .iter()
+      .flatten()
+      .any(|reg| self.loaded.contains(reg));
+    if cycle == self.cycle + 1 && load_use {
+      cycle += 1;
+    }
+    self.cycle = cycle;
+    self.loaded = slot.loads.into_iter().collect();
+    self.resume = cycle + slot.redirect;
+    self.open = Some(slot);
+  }
+
+  pub fn instructions(&self) -> usize {
+    self.instructions
+  }
+
+  /// Cycles until the last group leaves write back.
+  pub fn cycles(&self) -> usize {
+    match self.cycle {
+      0 => 0,
+      cycle => cycle + 4,
+    }
+  }
+
+  /// Instructions retired per cycle, at most two.
+  pub fn ipc(&self) -> f64 {
+    match self.cycles() {
+      0 => 0.0,
+      cycles => self.instructions as f64 / cycles as f64,
+    }
+  }
+
+  /// Cycles that issued two instructions.
+  pub fn dual_issues(&self) -> usize {
+    self.dual
+  }
+
+  /// Times the second slot went unused, by cause.
+  pub fn hazards(&self) -> Hazards {
+    self.hazards
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm;
+  use crate::builder::VmBuilder;
+  use crate::region::Chunk;
+
+  fn schedule(source: &str) -> DualIssue {
+    let region = Chunk::from(asm::assemble(source).unwrap().bytes().to_vec());
+    let mut vm = VmBuilder::new().build();
+    vm.load(&region).unwrap();
+    DualIssue::run(&mut vm, &region).unwrap()
+  }
+
+  #[test]
+  fn independent_pairs_issue_together() {
+    let model =
+      schedule("irmovq $1, %rax\nirmovq $2, %rcx\nirmovq $3, %rdx\nirmovq $4, %rsi\nhalt");
+    assert_eq!(model.instructions(), 5);
+    assert_eq!(model.dual_issues(), 2);
+    assert_eq!(model.cycles(), 3 + 4);
+    assert_eq!(model.hazards(), Hazards::default());
+    assert!(model.ipc() > 0.7);
+  }
+
+  #[test]
+  fn hazards_split_groups_by_cause() {
+    let hazards = |source| schedule(source).hazards();
+    assert_eq!(
+      hazards("irmovq $1, %rax\naddq %rax, %rcx\nhalt"),
+      Hazards {
+        data: 1,
+        ..Hazards::default()
+      }
+    );
+    assert_eq!(
+      hazards("andq %rax, %rax\ncmove %rcx, %rdx\nhalt"),
+      Hazards {
+        flags: 1,
+        ..Hazards::default()
+      }
+    );
+    assert_eq!(
+      hazards("rmmovq %rax, 0x100(%rbx)\nmrmovq 0x108(%rbx), %rcx\nhalt"),
+      Hazards {
+        memory: 1,
+        ..Hazards::default()
+      }
+    );
+    assert_eq!(
+      hazards("jmp next\nnop\nnext: irmovq $1, %rax\nhalt"),
+      Hazards {
+        control: 1,
+        ..Hazards::default()
+      }
+    );
+  }
+}
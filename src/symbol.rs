@@ -0,0 +1,139 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("malformed symbol line {0}: {1:?}")]
+  MalformedLine(usize, String),
+}
+
+/// Bidirectional mapping between addresses and names, an address may have
+/// several names.
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+  // names in insertion order
+  by_address: BTreeMap<usize, Vec<String>>,
+  by_name: HashMap<String, usize>,
+}
+
+impl Symbols {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn insert(&mut self, name: impl Into<String>, address: usize) {
+    let name = name.into();
+    if let Some(old) = self.by_name.insert(name.clone(), address)
+      && let Some(names) = self.by_address.get_mut(&old)
+    {
+      names.retain(|other| *other != name);
+      if names.is_empty() {
+        self.by_address.remove(&old);
+      }
+    }
+    self.by_address.entry(address).or_default().push(name);
+  }
+
+  /// The first name given to `address`.
+  pub fn name_of(&self, address: usize) -> Option<&str> {
+    self.names_of(address).next()
+  }
+
+  /// Every name of `address`, in the order they were inserted.
+  pub fn names_of(&self, address: usize) -> impl Iterator<Item = &str> + '_ {
+    self
+      .by_address
+      .get(&address)
+      .into_iter()
+      .flatten()
+      .map(String::as_str)
+  }
+
+  pub fn address_of(&self, name: &str) -> Option<usize> {
+    self.by_name.get(name).copied()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.by_address.is_empty()
+  }
+
+  /// Symbols in ascending address order, names of the same address in the
+  /// order they were inserted.
+  pub fn iter(&self) -> impl Iterator<Item = (usize, &str)> + '_ {
+    self
+      .by_address
+      .iter()
+      .flat_map(|(&address, names)| names.iter().map(move |name| (address, name.as_str())))
+  }
+}
+
+/// Parses `nm` style listings, one `address name` pair per line. Addresses
+/// are hexadecimal with an optional `0x` prefix, `#` starts a comment.
+impl FromStr for Symbols {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut symbols = Symbols::new();
+    for (i, line) in s.lines().enumerate() {
+      let line = line.split('#').next().unwrap_or("").trim();
+      if line.is_empty() {
+        continue;
+      }
+      let malformed = || Error::MalformedLine(i + 1, line.to_string());
+      let mut parts = line.split_whitespace();
+      let (Some(address), Some(name), None) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(malformed());
+      };
+      let address = address.strip_prefix("0x").unwrap_or(address);
+      let address = usize::from_str_radix(address, 16).map_err(|_| malformed())?;
+      symbols.insert(name, address);
+    }
+    Ok(symbols)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_nm_listings() {
+    let symbols: Symbols = "0x10 main\n# comment\n\n20 loop  # inner\n0x10 start\n"
+      .parse()
+      .unwrap();
+    assert_eq!(symbols.address_of("main"), Some(0x10));
+    assert_eq!(symbols.address_of("loop"), Some(0x20));
+    assert_eq!(symbols.name_of(0x10), Some("main"));
+    assert_eq!(
+      symbols.names_of(0x10).collect::<Vec<_>>(),
+      ["main", "start"]
+    );
+  }
+
+  #[test]
+  fn rejects_malformed_lines() {
+    assert!(matches!(
+      "0x10 main extra".parse::<Symbols>(),
+      Err(Error::MalformedLine(1, _))
+    ));
+    assert!(matches!(
+      "main\n".parse::<Symbols>(),
+      Err(Error::MalformedLine(1, _))
+    ));
+    assert!(matches!(
+      "0x10 main\nxyz loop".parse::<Symbols>(),
+      Err(Error::MalformedLine(2, _))
+    ));
+  }
+
+  #[test]
+  fn reinserting_moves_only_that_name() {
+    let mut symbols = Symbols::new();
+    symbols.insert("a", 0);
+    symbols.insert("b", 0);
+    symbols.insert("a", 8);
+    assert_eq!(symbols.names_of(0).collect::<Vec<_>>(), ["b"]);
+    assert_eq!(symbols.name_of(8), Some("a"));
+    assert_eq!(symbols.iter().count(), 2);
+  }
+}
@@ -0,0 +1,471 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::Block;
+use crate::memory::MEMORY_SIZE;
+use crate::register::Register;
+use crate::trap::{Trap, TrapHandler};
+use crate::vm::Vm;
+
+/// Errors returned to the guest in `%rax`, negated linux errno values.
+pub mod errno {
+  use crate::Block;
+
+  pub const ENOENT: Block = -2;
+  pub const EIO: Block = -5;
+  pub const EBADF: Block = -9;
+  pub const EACCES: Block = -13;
+  pub const EFAULT: Block = -14;
+  pub const EINVAL: Block = -22;
+  pub const ENOSYS: Block = -38;
+  pub const EDQUOT: Block = -122;
+}
+
+/// Flags accepted by `open`, with linux values.
+pub mod flags {
+  use crate::Block;
+
+  pub const O_RDONLY: Block = 0;
+  pub const O_WRONLY: Block = 1;
+  pub const O_RDWR: Block = 2;
+  pub const O_CREAT: Block = 0x40;
+  pub const O_TRUNC: Block = 0x200;
+  pub const O_APPEND: Block = 0x400;
+}
+
+/// What the guest may touch on the host.
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+  allowed: Vec<PathBuf>,
+  read_only: bool,
+  stdio: bool,
+}
+
+impl Sandbox {
+  /// No files are reachable, the standard streams are.
+  pub fn new() -> Self {
+    Self {
+      allowed: Vec::new(),
+      read_only: false,
+      stdio: true,
+    }
+  }
+
+  /// Allows opening `path` and, for a directory, anything beneath it.
+  pub fn allow(mut self, path: impl Into<PathBuf>) -> Self {
+    self.allowed.push(path.into());
+    self
+  }
+
+  /// Rejects opening files for writing.
+  pub fn read_only(mut self, read_only: bool) -> Self {
+    self.read_only = read_only;
+    self
+  }
+
+  /// Whether descriptors 0, 1 and 2 reach the host stdin, stdout and stderr.
+  pub fn stdio(mut self, stdio: bool) -> Self {
+    self.stdio = stdio;
+    self
+  }
+
+  fn permits(&self, path: &Path) -> bool {
+    // anything already there, symlinks included even when dangling, resolves
+    // in full so a link cannot lead out of an allowed directory. Only files
+    // about to be created resolve through their parent
+    let exists = fs::symlink_metadata(path).is_ok();
+    let resolved = match (path.parent(), path.file_name()) {
+      (Some(parent), Some(name)) if !exists => {
+        let parent = if parent.as_os_str().is_empty() {
+          Path::new(".")
+        } else {
+          parent
+        };
+        fs::canonicalize(parent).map(|parent| parent.join(name))
+      }
+      _ => fs::canonicalize(path),
+    };
+    let Ok(resolved) = resolved else {
+      return false;
+    };
+    self
+      .allowed
+      .iter()
+      .filter_map(|allowed| fs::canonicalize(allowed).ok())
+      .any(|allowed| resolved.starts_with(allowed))
+  }
+}
+
+impl Default for Sandbox {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Standard streams kept in memory, for feeding a program its input and
+/// collecting what it prints. Clones share the same streams, keep one to read
+/// the output after handing another to `Syscalls::with_console`.
+#[derive(Debug, Clone, Default)]
+pub struct Console {
+  streams: Arc<Mutex<Streams>>,
+}
+
+#[derive(Debug, Default)]
+struct Streams {
+  input: io::Cursor<Vec<u8>>,
+  output: Vec<u8>,
+  errors: Vec<u8>,
+}
+
+impl Console {
+  /// Reads from descriptor 0 return `input`, then end of file.
+  pub fn new(input: impl Into<Vec<u8>>) -> Self {
+    let streams = Streams {
+      input: io::Cursor::new(input.into()),
+      ..Streams::default()
+    };
+    Self {
+      streams: Arc::new(Mutex::new(streams)),
+    }
+  }
+
+  /// Everything written to descriptor 1 so far.
+  pub fn output(&self) -> Vec<u8> {
+    self.lock().output.clone()
+  }
+
+  /// Everything written to descriptor 2 so far.
+  pub fn errors(&self) -> Vec<u8> {
+    self.lock().errors.clone()
+  }
+
+  fn lock(&self) -> MutexGuard<'_, Streams> {
+    self.streams.lock().expect("console lock poisoned")
+  }
+}
+
+/// Host file access for guest programs, installed with
+/// `Vm::set_trap_handler`.
+///
+/// The otherwise invalid opcode `0xc0` is a `syscall` instruction taking the
+/// call number in `%rax` and arguments in `%rdi`, `%rsi` and `%rdx`, and
+/// returning its result in `%rax`, negative `errno` values on failure:
+///
+/// - `0` read(fd, buf, len), bytes read
+/// - `1` write(fd, buf, len), bytes written
+/// - `2` open(path, flags), a descriptor, `path` is nul terminated
+/// - `3` close(fd), zero
+///
+/// Every other invalid opcode still faults.
+#[derive(Debug)]
+pub struct Syscalls {
+  sandbox: Sandbox,
+  console: Option<Console>,
+  files: HashMap<Block, File>,
+  next_fd: Block,
+}
+
+impl Syscalls {
+  pub const OPCODE: u8 = 0xc0;
+
+  pub const READ: Block = 0;
+  pub const WRITE: Block = 1;
+  pub const OPEN: Block = 2;
+  pub const CLOSE: Block = 3;
+
+  const MAX_PATH: usize = 4096;
+
+  pub fn new(sandbox: Sandbox) -> Self {
+    Self {
+      sandbox,
+      console: None,
+      files: HashMap::new(),
+      next_fd: 3,
+    }
+  }
+
+  /// Serves descriptors 0, 1 and 2 from `console` rather than the host
+  /// streams, whatever `Sandbox::stdio` says.
+  pub fn with_console(mut self, console: Console) -> Self {
+    self.console = Some(console);
+    self
+  }
+
+  fn dispatch(&mut self, vm: &mut Vm) -> Block {
+    let args = [Register::Rdi, Register::Rsi, Register::Rdx].map(|reg| vm.register(reg));
+    let result = match vm.register(Register::Rax) {
+      Self::READ => self.read(vm, args[0], args[1], args[2]),
+      Self::WRITE => self.write(vm, args[0], args[1], args[2]),
+      Self::OPEN => self.open(vm, args[0], args[1]),
+      Self::CLOSE => self.close(args[0]),
+      _ => Err(errno::ENOSYS),
+    };
+    result.unwrap_or_else(|errno| errno)
+  }
+
+  fn read(&mut self, vm: &mut Vm, fd: Block, buf: Block, len: Block) -> Result<Block, Block> {
+    let len = usize::try_from(len).map_err(|_| errno::EINVAL)?;
+    // checked before allocating, the guest picks the length
+    if (buf as usize)
+      .checked_add(len)
+      .is_none_or(|end| end > MEMORY_SIZE)
+    {
+      return Err(errno::EFAULT);
+    }
+    let mut bytes = vec![0; len];
+    let n = match (fd, &self.console) {
+      (0, Some(console)) => console.lock().input.read(&mut bytes),
+      (0, None) if self.sandbox.stdio => io::stdin().read(&mut bytes),
+      (fd, _) => self
+        .files
+        .get_mut(&fd)
+        .ok_or(errno::EBADF)?
+        .read(&mut bytes),
+    }
+    .map_err(|_| errno::EIO)?;
+    vm.write_bytes(buf as usize, &bytes[..n])
+      .map_err(|_| errno::EFAULT)?;
+    Ok(n as Block)
+  }
+
+  fn write(&mut self, vm: &mut Vm, fd: Block, buf: Block, len: Block) -> Result<Block, Block> {
+    let len = usize::try_from(len).map_err(|_| errno::EINVAL)?;
+    let bytes = vm
+      .read_bytes(buf as usize, len)
+      .map_err(|_| errno::EFAULT)?;
+    if !vm.charge_output(bytes.len()) {
+      return Err(errno::EDQUOT);
+    }
+    let n = match (fd, &self.console) {
+      (1, Some(console)) => console.lock().output.write(&bytes),
+      (2, Some(console)) => console.lock().errors.write(&bytes),
+      (1, None) if self.sandbox.stdio => io::stdout().write(&bytes),
+      (2, None) if self.sandbox.stdio => io::stderr().write(&bytes),
+      (fd, _) => self.files.get_mut(&fd).ok_or(errno::EBADF)?.write(&bytes),
+    }
+    .map_err(|_| errno::EIO)?;
+    Ok(n as Block)
+  }
+
+  fn open(&mut self, vm: &mut Vm, path: Block, flags: Block) -> Result<Block, Block> {
+    let path = read_path(vm, path as usize)?;
+    if !self.sandbox.permits(&path) {
+      return Err(errno::EACCES);
+    }
+    let access = flags & 3;
+    let writes =
+      access != flags::O_RDONLY || flags & (flags::O_CREAT | flags::O_TRUNC | flags::O_APPEND) != 0;
+    if writes && self.sandbox.read_only {
+      return Err(errno::EACCES);
+    }
+    let file = File::options()
+      .read(access != flags::O_WRONLY)
+      .write(access != flags::O_RDONLY)
+      .create(flags & flags::O_CREAT != 0)
+      .truncate(flags & flags::O_TRUNC != 0)
+      .append(flags & flags::O_APPEND != 0)
+      .open(&path)
+      .map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => errno::ENOENT,
+        io::ErrorKind::PermissionDenied => errno::EACCES,
+        io::ErrorKind::InvalidInput => errno::EINVAL,
+        _ => errno::EIO,
+      })?;
+    let fd = self.next_fd;
+    self.next_fd += 1;
+    self.files.insert(fd, file);
+    Ok(fd)
+  }
+
+  fn close(&mut self, fd: Block) -> Result<Block, Block> {
+    self.files.remove(&fd).map(|_| 0).ok_or(errno::EBADF)
+  }
+}
+
+fn read_path(vm: &Vm, address: usize) -> Result<PathBuf, Block> {
+  let mut path = Vec::new();
+  for offset in 0..Syscalls::MAX_PATH {
+    let byte = vm
+      .read_bytes(address + offset, 1)
+      .map_err(|_| errno::EFAULT)?[0];
+    if byte == 0 {
+      let path = String::from_utf8(path).map_err(|_| errno::EINVAL)?;
+      return Ok(PathBuf::from(path));
+    }
+    path.push(byte);
+  }
+  Err(errno::EINVAL)
+}
+
+impl TrapHandler for Syscalls {
+  fn invalid_opcode(&mut self, vm: &mut Vm, byte: u8) -> Trap {
+    if byte != Self::OPCODE {
+      return Trap::Fault;
+    }
+    let result = self.dispatch(vm);
+    vm.set_register(Register::Rax, result);
+    Trap::Skip(1)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::builder::VmBuilder;
+  use crate::region::Chunk;
+
+  fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("y86-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  // a vm whose program is a lone syscall, rerun by `call`
+  fn guest(syscalls: Syscalls) -> (Vm, Chunk) {
+    let region = Chunk::from(vec![Syscalls::OPCODE, 0x00]);
+    let mut vm = VmBuilder::new().build();
+    vm.load(&region).unwrap();
+    vm.set_trap_handler(syscalls);
+    (vm, region)
+  }
+
+  fn call(vm: &mut Vm, region: &Chunk, args: [Block; 4]) -> Block {
+    let registers = [Register::Rax, Register::Rdi, Register::Rsi, Register::Rdx];
+    for (reg, value) in registers.into_iter().zip(args) {
+      vm.set_register(reg, value);
+    }
+    vm.set_ip(0);
+    vm.step(region).unwrap();
+    vm.register(Register::Rax)
+  }
+
+  fn path(vm: &mut Vm, address: usize, path: &Path) -> Block {
+    let mut bytes = path.to_str().unwrap().as_bytes().to_vec();
+    bytes.push(0);
+    vm.write_bytes(address, &bytes).unwrap();
+    address as Block
+  }
+
+  #[test]
+  fn sandbox_resolves_paths() {
+    let dir = scratch("sandbox");
+    fs::write(dir.join("input"), "data").unwrap();
+    let sandbox = Sandbox::new().allow(&dir);
+    assert!(sandbox.permits(&dir.join("input")));
+    assert!(sandbox.permits(&dir.join("created")));
+    assert!(!sandbox.permits(&dir.join("../outside")));
+    assert!(!sandbox.permits(&dir.join("missing/file")));
+    assert!(!Sandbox::new().permits(&dir.join("input")));
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn sandbox_rejects_symlinks_out() {
+    let dir = scratch("symlinks");
+    let outside = scratch("symlinks-outside");
+    fs::write(outside.join("secret"), "secret").unwrap();
+    fs::write(dir.join("inside"), "inside").unwrap();
+    std::os::unix::fs::symlink(outside.join("secret"), dir.join("link")).unwrap();
+    std::os::unix::fs::symlink(outside.join("new"), dir.join("dangling")).unwrap();
+    std::os::unix::fs::symlink(dir.join("inside"), dir.join("local")).unwrap();
+    let sandbox = Sandbox::new().allow(&dir);
+    assert!(!sandbox.permits(&dir.join("link")));
+    assert!(!sandbox.permits(&dir.join("dangling")));
+    assert!(sandbox.permits(&dir.join("local")));
+  }
+
+  #[test]
+  fn reads_and_writes_files() {
+    let dir = scratch("files");
+    fs::write(dir.join("input"), "hello").unwrap();
+    let (mut vm, region) = guest(Syscalls::new(Sandbox::new().allow(&dir)));
+
+    let input = path(&mut vm, 0x400, &dir.join("input"));
+    let fd = call(
+      &mut vm,
+      &region,
+      [Syscalls::OPEN, input, flags::O_RDONLY, 0],
+    );
+    assert_eq!(fd, 3);
+    assert_eq!(call(&mut vm, &region, [Syscalls::READ, fd, 0x800, 16]), 5);
+    assert_eq!(vm.read_bytes(0x800, 5).unwrap(), b"hello");
+    assert_eq!(call(&mut vm, &region, [Syscalls::READ, fd, 0x800, 16]), 0);
+    assert_eq!(call(&mut vm, &region, [Syscalls::CLOSE, fd, 0, 0]), 0);
+    assert_eq!(
+      call(&mut vm, &region, [Syscalls::CLOSE, fd, 0, 0]),
+      errno::EBADF
+    );
+
+    let output = path(&mut vm, 0x400, &dir.join("output"));
+    let flags = flags::O_WRONLY | flags::O_CREAT | flags::O_TRUNC;
+    let fd = call(&mut vm, &region, [Syscalls::OPEN, output, flags, 0]);
+    assert_eq!(call(&mut vm, &region, [Syscalls::WRITE, fd, 0x800, 5]), 5);
+    call(&mut vm, &region, [Syscalls::CLOSE, fd, 0, 0]);
+    assert_eq!(fs::read(dir.join("output")).unwrap(), b"hello");
+  }
+
+  #[test]
+  fn reports_errors_as_errno() {
+    let dir = scratch("errno");
+    fs::write(dir.join("input"), "data").unwrap();
+    let sandbox = Sandbox::new().allow(&dir).read_only(true);
+    let (mut vm, region) = guest(Syscalls::new(sandbox));
+
+    let input = path(&mut vm, 0x400, &dir.join("input"));
+    let write = flags::O_WRONLY;
+    assert_eq!(
+      call(&mut vm, &region, [Syscalls::OPEN, input, write, 0]),
+      errno::EACCES
+    );
+    let missing = path(&mut vm, 0x400, &dir.join("missing"));
+    let read = flags::O_RDONLY;
+    assert_eq!(
+      call(&mut vm, &region, [Syscalls::OPEN, missing, read, 0]),
+      errno::ENOENT
+    );
+    let outside = path(&mut vm, 0x400, Path::new("/"));
+    assert_eq!(
+      call(&mut vm, &region, [Syscalls::OPEN, outside, read, 0]),
+      errno::EACCES
+    );
+    let huge = Block::MAX;
+    assert_eq!(
+      call(&mut vm, &region, [Syscalls::READ, 0, 0x800, huge]),
+      errno::EFAULT
+    );
+    assert_eq!(
+      call(&mut vm, &region, [Syscalls::READ, 0, 0x800, -1]),
+      errno::EINVAL
+    );
+    assert_eq!(
+      call(&mut vm, &region, [Syscalls::READ, 9, 0x800, 1]),
+      errno::EBADF
+    );
+    assert_eq!(call(&mut vm, &region, [99, 0, 0, 0]), errno::ENOSYS);
+  }
+
+  #[test]
+  fn console_serves_standard_streams() {
+    let console = Console::new("in");
+    let syscalls = Syscalls::new(Sandbox::new().stdio(false)).with_console(console.clone());
+    let (mut vm, region) = guest(syscalls);
+    assert_eq!(call(&mut vm, &region, [Syscalls::READ, 0, 0x800, 8]), 2);
+    assert_eq!(call(&mut vm, &region, [Syscalls::WRITE, 1, 0x800, 2]), 2);
+    assert_eq!(call(&mut vm, &region, [Syscalls::WRITE, 2, 0x800, 1]), 1);
+    assert_eq!(console.output(), b"in");
+    assert_eq!(console.errors(), b"i");
+  }
+
+  #[test]
+  fn other_invalid_opcodes_still_fault() {
+    let region = Chunk::from(vec![0xf0]);
+    let mut vm = VmBuilder::new().build();
+    vm.load(&region).unwrap();
+    vm.set_trap_handler(Syscalls::new(Sandbox::new()));
+    assert!(vm.step(&region).is_err());
+  }
+}
@@ -0,0 +1,781 @@
+use std::str::FromStr;
+
+use crate::BLOCK_SIZE;
+
+use crate::opcode::{MAX_INSTRUCTION_LEN, OpFun, Opcode};
+use crate::snapshot::{self, Decoder, Encoder};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("unknown instruction class {0:?}")]
+  UnknownClass(String),
+
+  #[error("malformed cost line {0}: {1:?}")]
+  MalformedLine(usize, String),
+
+  #[error("malformed energy line {0}: {1:?}")]
+  MalformedEnergyLine(usize, String),
+}
+
+/// Instruction families the timing model charges separately. `opq` is split
+/// by function so slow multiplies and divides can be modelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Class {
+  Halt,
+  Nop,
+  Rrmovq,
+  Cmovxx,
+  Irmovq,
+  Rmmovq,
+  Mrmovq,
+  Alu,
+  Multiply,
+  Divide,
+  Jxx,
+  Call,
+  Ret,
+  Pushq,
+  Popq,
+  Casq,
+}
+
+impl Class {
+  const ALL: [Class; 16] = [
+    Class::Halt,
+    Class::Nop,
+    Class::Rrmovq,
+    Class::Cmovxx,
+    Class::Irmovq,
+    Class::Rmmovq,
+    Class::Mrmovq,
+    Class::Alu,
+    Class::Multiply,
+    Class::Divide,
+    Class::Jxx,
+    Class::Call,
+    Class::Ret,
+    Class::Pushq,
+    Class::Popq,
+    Class::Casq,
+  ];
+
+  pub fn iter() -> impl Iterator<Item = Class> {
+    Self::ALL.into_iter()
+  }
+
+  /// Name used in cost tables, the mnemonic or mnemonic family.
+  pub fn name(self) -> &'static str {
+    match self {
+      Class::Halt => "halt",
+      Class::Nop => "nop",
+      Class::Rrmovq => "rrmovq",
+      Class::Cmovxx => "cmovxx",
+      Class::Irmovq => "irmovq",
+      Class::Rmmovq => "rmmovq",
+      Class::Mrmovq => "mrmovq",
+      Class::Alu => "opq",
+      Class::Multiply => "mulq",
+      Class::Divide => "divq",
+      Class::Jxx => "jxx",
+      Class::Call => "call",
+      Class::Ret => "ret",
+      Class::Pushq => "pushq",
+      Class::Popq => "popq",
+      Class::Casq => "casq",
+    }
+  }
+
+  pub(crate) fn of(opcode: &Opcode) -> Self {
+    match opcode {
+      Opcode::Halt => Class::Halt,
+      Opcode::Nop => Class::Nop,
+      Opcode::Rrmovq => Class::Rrmovq,
+      Opcode::Cmovxx(_) => Class::Cmovxx,
+      Opcode::Irmovq => Class::Irmovq,
+      Opcode::Rmmovq => Class::Rmmovq,
+      Opcode::Mrmovq => Class::Mrmovq,
+      Opcode::Opq(OpFun::Mul) => Class::Multiply,
+      Opcode::Opq(OpFun::Div | OpFun::Mod) => Class::Divide,
+      Opcode::Opq(_) => Class::Alu,
+      Opcode::Jxx(_) => Class::Jxx,
+      Opcode::Call => Class::Call,
+      Opcode::Ret => Class::Ret,
+      Opcode::Pushq => Class::Pushq,
+      Opcode::Popq => Class::Popq,
+      Opcode::Casq => Class::Casq,
+    }
+  }
+}
+
+impl FromStr for Class {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Class::iter()
+      .find(|class| class.name() == s)
+      .ok_or_else(|| Error::UnknownClass(s.to_string()))
+  }
+}
+
+/// Cycles charged for every retired instruction, plus extra cycles for every
+/// data access depending on whether it hit the cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CostTable {
+  base: [u64; 16],
+  memory_hit: u64,
+  memory_miss: u64,
+}
+
+impl CostTable {
+  /// Seq like costs, every instruction takes a single cycle and memory that
+  /// hits is free. Misses only happen once a cache is configured.
+  pub fn seq() -> Self {
+    Self {
+      base: [1; 16],
+      memory_hit: 0,
+      memory_miss: 10,
+    }
+  }
+
+  pub fn cost(&self, class: Class) -> u64 {
+    self.base[class as usize]
+  }
+
+  pub fn set(&mut self, class: Class, cycles: u64) {
+    self.base[class as usize] = cycles;
+  }
+
+  pub fn memory_hit(&self) -> u64 {
+    self.memory_hit
+  }
+
+  pub fn memory_miss(&self) -> u64 {
+    self.memory_miss
+  }
+
+  /// Extra cycles for a data access that hits and misses the cache.
+  pub fn set_memory(&mut self, hit: u64, miss: u64) {
+    self.memory_hit = hit;
+    self.memory_miss = miss;
+  }
+}
+
+impl Default for CostTable {
+  fn default() -> Self {
+    Self::seq()
+  }
+}
+
+/// Parses `class cycles` pairs, one per line, on top of the seq defaults.
+/// Classes are named as by `Class::name`, with `hit` and `miss` naming the
+/// memory costs. `#` starts a comment.
+impl FromStr for CostTable {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut table = CostTable::seq();
+    for (i, line) in s.lines().enumerate() {
+      let line = line.split('#').next().unwrap_or("").trim();
+      if line.is_empty() {
+        continue;
+      }
+      let malformed = || Error::MalformedLine(i + 1, line.to_string());
+      let mut parts = line.split_whitespace();
+      let (Some(name), Some(cycles), None) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(malformed());
+      };
+      let cycles = cycles.parse().map_err(|_| malformed())?;
+      match name {
+        "hit" => table.memory_hit = cycles,
+        "miss" => table.memory_miss = cycles,
+        name => table.set(name.parse()?, cycles),
+      }
+    }
+    Ok(table)
+  }
+}
+
+/// Abstract energy charged per retired instruction of each class and per
+/// data access, plus the area of the unit each class needs. Units are up to
+/// whoever writes the table, the defaults only keep sensible proportions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnergyTable {
+  base: [u64; 16],
+  area: [u64; 16],
+  load: u64,
+  store: u64,
+  miss: u64,
+}
+
+impl EnergyTable {
+  /// One unit per instruction, more for multiplies and divides, memory
+  /// accesses costing several instructions and misses many more. Every
+  /// class needs one unit of area, multiplies and divides more.
+  pub fn new() -> Self {
+    let mut table = Self {
+      base: [1; 16],
+      area: [1; 16],
+      load: 4,
+      store: 5,
+      miss: 40,
+    };
+    table.set(Class::Multiply, 6);
+    table.set(Class::Divide, 20);
+    table.set_area(Class::Multiply, 8);
+    table.set_area(Class::Divide, 12);
+    table
+  }
+
+  pub fn energy(&self, class: Class) -> u64 {
+    self.base[class as usize]
+  }
+
+  pub fn set(&mut self, class: Class, energy: u64) {
+    self.base[class as usize] = energy;
+  }
+
+  pub fn area(&self, class: Class) -> u64 {
+    self.area[class as usize]
+  }
+
+  pub fn set_area(&mut self, class: Class, area: u64) {
+    self.area[class as usize] = area;
+  }
+
+  pub fn load(&self) -> u64 {
+    self.load
+  }
+
+  pub fn store(&self) -> u64 {
+    self.store
+  }
+
+  /// Extra energy of an access missing the cache.
+  pub fn miss(&self) -> u64 {
+    self.miss
+  }
+
+  pub fn set_memory(&mut self, load: u64, store: u64, miss: u64) {
+    self.load = load;
+    self.store = store;
+    self.miss = miss;
+  }
+}
+
+impl Default for EnergyTable {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Parses `class energy [area]` lines on top of the defaults, with `load`,
+/// `store` and `miss` naming the memory costs. `#` starts a comment.
+impl FromStr for EnergyTable {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut table = EnergyTable::new();
+    for (i, line) in s.lines().enumerate() {
+      let line = line.split('#').next().unwrap_or("").trim();
+      if line.is_empty() {
+        continue;
+      }
+      let malformed = || Error::MalformedEnergyLine(i + 1, line.to_string());
+      let parts: Vec<&str> = line.split_whitespace().collect();
+      let numbers = parts[1..]
+        .iter()
+        .map(|part| part.parse::<u64>().map_err(|_| malformed()))
+        .collect::<Result<Vec<_>, _>>()?;
+      match (parts[0], numbers.as_slice()) {
+        ("load", &[energy]) => table.load = energy,
+        ("store", &[energy]) => table.store = energy,
+        ("miss", &[energy]) => table.miss = energy,
+        ("load" | "store" | "miss", _) => return Err(malformed()),
+        (name, &[energy]) => table.set(name.parse()?, energy),
+        (name, &[energy, area]) => {
+          let class = name.parse()?;
+          table.set(class, energy);
+          table.set_area(class, area);
+        }
+        _ => return Err(malformed()),
+      }
+    }
+    Ok(table)
+  }
+}
+
+/// Energy a run consumed so far under an `EnergyTable`, see
+/// `Timing::energy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Energy {
+  table: EnergyTable,
+  by_class: [u64; 16],
+  memory: u64,
+  used: [bool; 16],
+}
+
+impl Energy {
+  fn new(table: EnergyTable) -> Self {
+    Self {
+      table,
+      by_class: [0; 16],
+      memory: 0,
+      used: [false; 16],
+    }
+  }
+
+  pub fn table(&self) -> &EnergyTable {
+    &self.table
+  }
+
+  pub fn total(&self) -> u64 {
+    self.by_class.iter().sum::<u64>() + self.memory
+  }
+
+  /// Energy of the instructions of `class` alone.
+  pub fn of(&self, class: Class) -> u64 {
+    self.by_class[class as usize]
+  }
+
+  /// Energy of data accesses.
+  pub fn memory(&self) -> u64 {
+    self.memory
+  }
+
+  /// Area of the units needed by the classes executed so far, each counted
+  /// once.
+  pub fn area(&self) -> u64 {
+    Class::iter()
+      .filter(|&class| self.used[class as usize])
+      .map(|class| self.table.area(class))
+      .sum()
+  }
+
+  fn retire(&mut self, class: Class) {
+    self.by_class[class as usize] += self.table.energy(class);
+    self.used[class as usize] = true;
+  }
+
+  fn access(&mut self, write: bool, hit: bool) {
+    self.memory += if write {
+      self.table.store
+    } else {
+      self.table.load
+    };
+    if !hit {
+      self.memory += self.table.miss;
+    }
+  }
+
+  fn clear(&mut self) {
+    *self = Self::new(self.table.clone());
+  }
+}
+
+/// Direct mapped cache, tracks only which lines are resident to decide
+/// whether a data access hits.
+#[derive(Debug, Clone)]
+pub struct Cache {
+  line_size: usize,
+  tags: Vec<Option<usize>>,
+  hits: usize,
+  misses: usize,
+}
+
+impl Cache {
+  /// A cache of `lines` lines holding `line_size` bytes each, both rounded up
+  /// to a power of two and `line_size` to at least one block.
+  pub fn direct_mapped(lines: usize, line_size: usize) -> Self {
+    Self {
+      line_size: line_size.max(BLOCK_SIZE).next_power_of_two(),
+      tags: vec![None; lines.max(1).next_power_of_two()],
+      hits: 0,
+      misses: 0,
+    }
+  }
+
+  pub fn hits(&self) -> usize {
+    self.hits
+  }
+
+  pub fn misses(&self) -> usize {
+    self.misses
+  }
+
+  /// Looks up `address`, filling its line on a miss. Returns whether it hit.
+  fn access(&mut self, address: usize) -> bool {
+    let line = address / self.line_size;
+    let index = line % self.tags.len();
+    let slot = &mut self.tags[index];
+    let hit = *slot == Some(line);
+    *slot = Some(line);
+    if hit {
+      self.hits += 1;
+    } else {
+      self.misses += 1;
+    }
+    hit
+  }
+
+  fn clear(&mut self) {
+    self.tags.fill(None);
+    self.hits = 0;
+    self.misses = 0;
+  }
+}
+
+/// Front end fetching at most `width` bytes per cycle into a prefetch buffer
+/// of `buffer` bytes. An instruction issues once all of its bytes are
+/// buffered, and the buffer keeps filling while earlier instructions execute.
+/// Taken branches, calls and returns discard the buffer, so the instruction
+/// at the target always waits on fetch.
+#[derive(Debug, Clone)]
+pub struct Fetch {
+  width: usize,
+  capacity: usize,
+  buffered: usize,
+  redirected: bool,
+  stalls: FetchStalls,
+}
+
+impl Fetch {
+  /// The longest instruction is ten bytes, smaller buffers are raised to it.
+  pub fn new(width: usize, buffer: usize) -> Self {
+    Self {
+      width: width.max(1),
+      capacity: buffer.max(MAX_INSTRUCTION_LEN),
+      buffered: 0,
+      redirected: true,
+      stalls: FetchStalls::default(),
+    }
+  }
+
+  pub fn width(&self) -> usize {
+    self.width
+  }
+
+  pub fn buffer(&self) -> usize {
+    self.capacity
+  }
+
+  pub fn stalls(&self) -> FetchStalls {
+    self.stalls
+  }
+
+  /// Cycles the instruction of `len` bytes waits before it can issue.
+  fn issue(&mut self, len: usize) -> u64 {
+    let missing = len.saturating_sub(self.buffered);
+    let stall = missing.div_ceil(self.width);
+    if self.redirected {
+      self.stalls.redirect += stall as u64;
+    } else {
+      self.stalls.length += stall as u64;
+    }
+    self.buffered = (self.buffered + stall * self.width).min(self.capacity) - len;
+    stall as u64
+  }
+
+  /// Keeps fetching for `cycles` while the issued instruction executes.
+  fn execute(&mut self, cycles: u64, redirected: bool) {
+    self.redirected = redirected;
+    self.buffered = if redirected {
+      0
+    } else {
+      let fetched = self.width.saturating_mul(cycles as usize);
+      self.buffered.saturating_add(fetched).min(self.capacity)
+    };
+  }
+
+  fn clear(&mut self) {
+    *self = Self::new(self.width, self.capacity);
+  }
+}
+
+/// Cycles spent waiting on instruction fetch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FetchStalls {
+  /// Instructions longer than what the buffer had ready.
+  pub length: u64,
+  /// Refilling the buffer after a taken branch, call or return, or at reset.
+  pub redirect: u64,
+}
+
+/// Cycle accounting for a vm, configured through the builder.
+#[derive(Debug, Clone, Default)]
+pub struct Timing {
+  costs: CostTable,
+  cache: Option<Cache>,
+  fetch: Option<Fetch>,
+  energy: Option<Energy>,
+  cycles: u64,
+  mispredictions: u64,
+}
+
+impl Timing {
+  pub(crate) fn new(
+    costs: CostTable,
+    cache: Option<Cache>,
+    fetch: Option<Fetch>,
+    energy: Option<EnergyTable>,
+  ) -> Self {
+    Self {
+      costs,
+      cache,
+      fetch,
+      energy: energy.map(Energy::new),
+      cycles: 0,
+      mispredictions: 0,
+    }
+  }
+
+  /// Cycles elapsed since the vm was built or reset.
+  pub fn cycles(&self) -> u64 {
+    self.cycles
+  }
+
+  /// Conditional jumps that fell through, against the always taken
+  /// prediction the pipeline model makes.
+  pub fn mispredictions(&self) -> u64 {
+    self.mispredictions
+  }
+
+  pub fn costs(&self) -> &CostTable {
+    &self.costs
+  }
+
+  pub fn cache(&self) -> Option<&Cache> {
+    self.cache.as_ref()
+  }
+
+  /// Front end model, instructions are fetched for free without one.
+  pub fn fetch(&self) -> Option<&Fetch> {
+    self.fetch.as_ref()
+  }
+
+  /// Energy spent since the vm was built or reset, if an energy table was
+  /// configured.
+  pub fn energy(&self) -> Option<&Energy> {
+    self.energy.as_ref()
+  }
+
+  /// Charges a retired instruction of `len` bytes, `redirected` when it left
+  /// ip somewhere other than the fall through address.
+  pub(crate) fn retire(&mut self, class: Class, len: usize, redirected: bool) {
+    let cost = self.costs.cost(class);
+    if let Some(energy) = &mut self.energy {
+      energy.retire(class);
+    }
+    if let Some(fetch) = &mut self.fetch {
+      self.cycles += fetch.issue(len);
+      fetch.execute(cost, redirected);
+    }
+    self.cycles += cost;
+  }
+
+  /// Counts a conditional jump, mispredicted when not `taken`.
+  pub(crate) fn branch(&mut self, taken: bool) {
+    if !taken {
+      self.mispredictions += 1;
+    }
+  }
+
+  /// Charges one data access, every access hits when there is no cache.
+  pub(crate) fn access(&mut self, address: usize, write: bool) {
+    let hit = self
+      .cache
+      .as_mut()
+      .is_none_or(|cache| cache.access(address));
+    if let Some(energy) = &mut self.energy {
+      energy.access(write, hit);
+    }
+    self.cycles += if hit {
+      self.costs.memory_hit
+    } else {
+      self.costs.memory_miss
+    };
+  }
+
+  /// Encodes the dynamic state, costs and geometry come from the builder.
+  pub(crate) fn save(&self) -> Vec<u8> {
+    let mut e = Encoder::new();
+    e.u64(self.cycles).u64(self.mispredictions);
+    e.bool(self.cache.is_some());
+    if let Some(cache) = &self.cache {
+      e.u64(cache.line_size as u64)
+        .u64(cache.tags.len() as u64)
+        .u64(cache.hits as u64)
+        .u64(cache.misses as u64);
+      for tag in &cache.tags {
+        e.u64(tag.map_or(u64::MAX, |tag| tag as u64));
+      }
+    }
+    e.bool(self.fetch.is_some());
+    if let Some(fetch) = &self.fetch {
+      e.u64(fetch.width as u64)
+        .u64(fetch.capacity as u64)
+        .u64(fetch.buffered as u64)
+        .bool(fetch.redirected)
+        .u64(fetch.stalls.length)
+        .u64(fetch.stalls.redirect);
+    }
+    e.bool(self.energy.is_some());
+    if let Some(energy) = &self.energy {
+      for (&spent, &used) in energy.by_class.iter().zip(&energy.used) {
+        e.u64(spent).bool(used);
+      }
+      e.u64(energy.memory);
+    }
+    e.finish()
+  }
+
+  pub(crate) fn load(&mut self, state: &[u8]) -> Result<(), snapshot::Error> {
+    let mut d = Decoder::new(state);
+    let (cycles, mispredictions) = (d.u64()?, d.u64()?);
+    if d.bool()? != self.cache.is_some() {
+      return Err(snapshot::Error::TimingMismatch);
+    }
+    if let Some(cache) = &mut self.cache {
+      let (line_size, lines) = (d.u64()? as usize, d.u64()? as usize);
+      if line_size != cache.line_size || lines != cache.tags.len() {
+        return Err(snapshot::Error::TimingMismatch);
+      }
+      cache.hits = d.u64()? as usize;
+      cache.misses = d.u64()? as usize;
+      for tag in &mut cache.tags {
+        *tag = Some(d.u64()?)
+          .filter(|&tag| tag != u64::MAX)
+          .map(|tag| tag as usize);
+      }
+    }
+    if d.bool()? != self.fetch.is_some() {
+      return Err(snapshot::Error::TimingMismatch);
+    }
+    if let Some(fetch) = &mut self.fetch {
+      let (width, capacity) = (d.u64()? as usize, d.u64()? as usize);
+      if width != fetch.width || capacity != fetch.capacity {
+        return Err(snapshot::Error::TimingMismatch);
+      }
+      fetch.buffered = d.u64()? as usize;
+      fetch.redirected = d.bool()?;
+      fetch.stalls.length = d.u64()?;
+      fetch.stalls.redirect = d.u64()?;
+    }
+    if d.bool()? != self.energy.is_some() {
+      return Err(snapshot::Error::TimingMismatch);
+    }
+    if let Some(energy) = &mut self.energy {
+      for (spent, used) in energy.by_class.iter_mut().zip(&mut energy.used) {
+        *spent = d.u64()?;
+        *used = d.bool()?;
+      }
+      energy.memory = d.u64()?;
+    }
+    self.cycles = cycles;
+    self.mispredictions = mispredictions;
+    Ok(())
+  }
+
+  pub(crate) fn reset(&mut self) {
+    self.cycles = 0;
+    self.mispredictions = 0;
+    if let Some(cache) = &mut self.cache {
+      cache.clear();
+    }
+    if let Some(fetch) = &mut self.fetch {
+      fetch.clear();
+    }
+    if let Some(energy) = &mut self.energy {
+      energy.clear();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm;
+  use crate::builder::VmBuilder;
+  use crate::region::Chunk;
+  use crate::vm::Vm;
+
+  fn run(builder: VmBuilder, source: &str) -> Vm {
+    let region = Chunk::from(asm::assemble(source).unwrap().bytes().to_vec());
+    let mut vm = builder.build();
+    vm.load(&region).unwrap();
+    vm.run(&region).unwrap();
+    vm
+  }
+
+  #[test]
+  fn parses_cost_tables_over_the_seq_defaults() {
+    let table: CostTable = "irmovq 2\nmulq 5 # slow\n\nhit 1\nmiss 20".parse().unwrap();
+    assert_eq!(table.cost(Class::Irmovq), 2);
+    assert_eq!(table.cost(Class::Multiply), 5);
+    assert_eq!(table.cost(Class::Alu), 1);
+    assert_eq!((table.memory_hit(), table.memory_miss()), (1, 20));
+    assert!(matches!(
+      "addq 1".parse::<CostTable>(),
+      Err(Error::UnknownClass(name)) if name == "addq"
+    ));
+    assert!(matches!(
+      "opq 1 2".parse::<CostTable>(),
+      Err(Error::MalformedLine(1, _))
+    ));
+  }
+
+  #[test]
+  fn charges_classes_and_cache_misses() {
+    let costs = "irmovq 2\nmulq 5\nhit 1\nmiss 10".parse().unwrap();
+    let builder = VmBuilder::new()
+      .costs(costs)
+      .cache(Cache::direct_mapped(4, 8));
+    let vm = run(
+      builder,
+      "
+    irmovq $3, %rax
+    mulq %rax, %rax
+    rmmovq %rax, 0x100(%rbx)
+    mrmovq 0x100(%rbx), %rcx
+    halt
+",
+    );
+    // 2 + 5 + (1 + miss) + (1 + hit) + 1
+    assert_eq!(vm.timing().cycles(), 21);
+    let cache = vm.timing().cache().unwrap();
+    assert_eq!((cache.hits(), cache.misses()), (1, 1));
+  }
+
+  #[test]
+  fn fetch_stalls_on_long_instructions_and_redirects() {
+    let builder = VmBuilder::new().fetch(Fetch::new(2, 16));
+    // the nop waits a cycle after reset, the irmovq four more for its bytes
+    let vm = run(builder.clone(), "nop\nirmovq $1, %rax\nhalt");
+    let fetch = vm.timing().fetch().unwrap();
+    assert_eq!(
+      fetch.stalls(),
+      FetchStalls {
+        length: 4,
+        redirect: 1
+      }
+    );
+    assert_eq!(vm.timing().cycles(), 3 + 5);
+
+    // the taken jump empties the buffer, so its target waits too
+    let vm = run(builder, "jmp next\nnop\nnext: halt");
+    let fetch = vm.timing().fetch().unwrap();
+    assert_eq!(
+      fetch.stalls(),
+      FetchStalls {
+        length: 0,
+        redirect: 6
+      }
+    );
+    assert_eq!(vm.timing().cycles(), 2 + 6);
+  }
+
+  #[test]
+  fn fetch_buffers_hold_at_least_one_instruction() {
+    let fetch = Fetch::new(0, 4);
+    assert_eq!((fetch.width(), fetch.buffer()), (1, MAX_INSTRUCTION_LEN));
+  }
+}
@@ -0,0 +1,65 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("invalid trace format {0:?}, expected lackey or dinero")]
+  InvalidFormat(String),
+}
+
+/// Text format of a memory access trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TraceFormat {
+  /// Valgrind lackey `--trace-mem` lines, ` L 00001ff8,8` and ` S ...`.
+  #[default]
+  Lackey,
+  /// Dinero iv `din` lines, `0 1ff8` for reads and `1 1ff8` for writes.
+  Dinero,
+}
+
+impl FromStr for TraceFormat {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "lackey" => Ok(TraceFormat::Lackey),
+      "dinero" => Ok(TraceFormat::Dinero),
+      _ => Err(Error::InvalidFormat(s.to_string())),
+    }
+  }
+}
+
+/// Sink receiving one record per data access.
+pub(crate) struct AccessTrace {
+  sink: Box<dyn Write + Send>,
+  format: TraceFormat,
+}
+
+impl AccessTrace {
+  pub(crate) fn new(sink: Box<dyn Write + Send>, format: TraceFormat) -> Self {
+    Self { sink, format }
+  }
+
+  pub(crate) fn record(&mut self, write: bool, address: usize, size: usize) -> io::Result<()> {
+    match self.format {
+      TraceFormat::Lackey => {
+        let kind = if write { 'S' } else { 'L' };
+        writeln!(self.sink, " {kind} {address:08x},{size}")
+      }
+      TraceFormat::Dinero => writeln!(self.sink, "{} {address:x}", write as u8),
+    }
+  }
+
+  pub(crate) fn flush(&mut self) -> io::Result<()> {
+    self.sink.flush()
+  }
+}
+
+impl fmt::Debug for AccessTrace {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("AccessTrace")
+      .field("format", &self.format)
+      .finish_non_exhaustive()
+  }
+}
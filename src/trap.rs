@@ -0,0 +1,175 @@
+use std::fmt;
+
+use crate::vm::Vm;
+
+/// How execution continues after a trap handler ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+  /// The handler emulated the instruction and moved ip past it itself.
+  Resume,
+  /// Treat the faulting instruction as a `len` byte no op.
+  Skip(usize),
+  /// Fail the step with the original `InvalidOpcode` error.
+  Fault,
+}
+
+/// Called when the vm fetches a byte that is not a valid opcode, with ip still
+/// pointing at it. A handled trap counts as a retired instruction.
+pub trait TrapHandler: Send {
+  fn invalid_opcode(&mut self, vm: &mut Vm, byte: u8) -> Trap;
+}
+
+impl<F> TrapHandler for F
+where
+  F: FnMut(&mut Vm, u8) -> Trap + Send,
+{
+  fn invalid_opcode(&mut self, vm: &mut Vm, byte: u8) -> Trap {
+    self(vm, byte)
+  }
+}
+
+impl fmt::Debug for dyn TrapHandler {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("TrapHandler")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::analysis::{self, Engine};
+  use crate::asm;
+  use crate::builder::VmBuilder;
+  use crate::debugger::{Debugger, Stop};
+  use crate::opcode;
+  use crate::pipeline::Pipeline;
+  use crate::region::Chunk;
+  use crate::register::Register;
+  use crate::superscalar::DualIssue;
+  use crate::syscall::{Console, Sandbox, Syscalls};
+  use crate::vm::{self, Executed, Until};
+
+  // writes "hi\n" to stdout from inside a function
+  const HELLO: &str = "
+    call hello
+    halt
+hello:
+    irmovq $1, %rax
+    irmovq $1, %rdi
+    irmovq message, %rsi
+    irmovq $3, %rdx
+    .byte 0xc0
+    ret
+message:
+    .byte 0x68
+    .byte 0x69
+    .byte 0x0a
+";
+
+  fn hello() -> (Vm, Chunk, Console) {
+    let region = Chunk::from(asm::assemble(HELLO).unwrap().bytes().to_vec());
+    let mut vm = VmBuilder::new().build();
+    vm.load(&region).unwrap();
+    let console = Console::new("");
+    vm.set_trap_handler(Syscalls::new(Sandbox::new()).with_console(console.clone()));
+    (vm, region, console)
+  }
+
+  #[test]
+  fn iter_yields_trapped_syscalls() {
+    let (mut vm, region, console) = hello();
+    let executed: Vec<_> = vm.iter(&region).collect::<Result<_, _>>().unwrap();
+    assert_eq!(executed.len(), 8);
+    let trapped = &executed[5];
+    assert!(matches!(
+      trapped.executed(),
+      Executed::Trapped {
+        byte: Syscalls::OPCODE,
+        trap: Trap::Skip(1),
+        ..
+      }
+    ));
+    assert!(trapped.instruction().is_none());
+    assert_eq!(trapped.next_ip(), trapped.address() + 1);
+    assert_eq!(console.output(), b"hi\n");
+    assert_eq!(vm.register(Register::Rax), 3);
+  }
+
+  #[test]
+  fn debugger_runs_through_syscalls() {
+    let (mut vm, region, console) = hello();
+    let mut debugger = Debugger::new();
+    assert_eq!(debugger.run(&mut vm, &region).unwrap(), Stop::Halted);
+    assert_eq!(console.output(), b"hi\n");
+  }
+
+  #[test]
+  fn run_until_ret_returns_over_syscalls() {
+    let (mut vm, region, console) = hello();
+    vm.step(&region).unwrap();
+    assert_eq!(
+      vm.run_until_ret(&region).unwrap(),
+      Until::Reached { steps: 6 }
+    );
+    assert_eq!(vm.ip(), 9);
+    assert_eq!(console.output(), b"hi\n");
+  }
+
+  #[test]
+  fn models_schedule_trapped_syscalls() {
+    let (mut vm, region, _) = hello();
+    let pipeline = Pipeline::run(&mut vm, &region).unwrap();
+    assert_eq!(pipeline.instructions(), 8);
+    let trapped = pipeline
+      .rows()
+      .iter()
+      .find(|row| row.instruction().is_none() && !row.is_bubble());
+    assert!(trapped.is_some());
+
+    let (mut vm, region, _) = hello();
+    assert_eq!(DualIssue::run(&mut vm, &region).unwrap().instructions(), 8);
+
+    let region = Chunk::from(asm::assemble(HELLO).unwrap().bytes().to_vec());
+    let comparison = analysis::analyze_with(&VmBuilder::new(), &region, |vm| {
+      vm.set_trap_handler(Syscalls::new(Sandbox::new()).with_console(Console::new("")));
+    })
+    .unwrap();
+    assert_eq!(comparison.get(Engine::Seq).unwrap().instructions, 8);
+  }
+
+  #[test]
+  fn handlers_emulate_skip_or_fault() {
+    let region = Chunk::from(vec![0xf0, 0xf1, 0x00]);
+    let mut vm = VmBuilder::new().build();
+    vm.load(&region).unwrap();
+    assert!(matches!(
+      vm.step(&region),
+      Err(vm::Error::OpcodeError(opcode::Error::InvalidOpcode(0xf0)))
+    ));
+
+    vm.set_trap_handler(|vm: &mut Vm, byte| match byte {
+      0xf0 => {
+        vm.set_register(Register::Rax, 7);
+        vm.set_ip(vm.ip() + 1);
+        Trap::Resume
+      }
+      0xf1 => Trap::Skip(1),
+      _ => Trap::Fault,
+    });
+    let executed = vm.step(&region).unwrap();
+    assert!(matches!(
+      executed,
+      Executed::Trapped {
+        address: 0,
+        byte: 0xf0,
+        trap: Trap::Resume
+      }
+    ));
+    assert_eq!(vm.register(Register::Rax), 7);
+    vm.step(&region).unwrap();
+    assert_eq!(vm.ip(), 2);
+    let halt = vm.step(&region).unwrap();
+    assert!(halt.instruction().is_some());
+    assert_eq!(vm.steps(), 3);
+  }
+}
@@ -1,15 +1,60 @@
-use crate::Block;
-use crate::memory::{self, MainMemory};
-use crate::opcode::{self, JCmovFun, OpFun, Opcode};
-use crate::region::Region;
-use crate::register::{self, Flag, Register, RegisterFile};
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::mem;
+use std::ops::Range;
+use std::sync::mpsc;
+
+use crate::bisect::ArchState;
+use crate::builder::{CodeWrites, Config, DivisionOverflow, UninitializedReads, VmBuilder};
+use crate::bus::{self, Bus, Counters, Device, DeviceId};
+use crate::disasm::{self, Disassembled, Instruction, Style};
+use crate::event::{Event, EventBus, EventFilter, EventKind, Location, Subscriber, SubscriptionId};
+use crate::exit::{Exit, ExitHook, Stats};
+use crate::expr::Expr;
+use crate::history::WriteHistory;
+use crate::image::Image;
+use crate::json::Json;
+use crate::layout::{Area, Kind, MemoryMap};
+use crate::memory::{self, MainMemory, MemoryBackend};
+use crate::opcode::{self, Condition, Isa, MAX_INSTRUCTION_LEN, OpFun, Opcode};
+use crate::profile::Profile;
+use crate::quota::{Meter, Quota, Quotas, Usage};
+use crate::region::{Chunk, Region};
+use crate::register::{self, Flag, Flags, Register, RegisterFile};
+use crate::report::{self, RunReport};
+use crate::rng::{Entropy, Source};
+use crate::snapshot::{self, Snapshot};
+use crate::timing::{Class, Timing};
+use crate::trace::{AccessTrace, TraceFormat};
+use crate::trap::{Trap, TrapHandler};
+use crate::{BLOCK_SIZE, Block};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
-enum State {
+pub enum State {
   Active,
   Halted,
 }
 
+/// Where `Vm::run_until` and `Vm::run_until_ret` stopped, along with the
+/// number of instructions they executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Until {
+  /// Stopped before the instruction at the requested address, or right after
+  /// the function returned.
+  Reached { steps: usize },
+  /// The machine halted first.
+  Halted { steps: usize },
+}
+
+/// Where `Vm::run_for` stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantum {
+  /// Every instruction of the budget executed, the machine can continue.
+  Expired,
+  /// The machine halted after `steps` instructions of the budget.
+  Halted { steps: usize },
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
   #[error("machine is halted")]
@@ -18,9 +63,33 @@ pub enum Error {
   #[error("reached the end of instructions at ip {0}")]
   EndOfInstructions(usize),
 
+  #[error("step limit of {0} instructions exceeded")]
+  StepLimitExceeded(usize),
+
+  #[error("{0} exceeded")]
+  QuotaExceeded(Quota),
+
+  #[error("store to {0:#x} overwrites loaded code")]
+  CodeOverwrite(usize),
+
+  #[error("store to {0:#x} targets the read only boot rom")]
+  RomWrite(usize),
+
+  #[error("stack overflow, access to {0:#x} hit the guard band below the stack")]
+  StackGuardHit(usize),
+
+  #[error("control transfer at {0:#x} lands at {1:#x}, inside the instruction at {2:#x}")]
+  MisalignedTarget(usize, usize, usize),
+
+  #[error("instruction at {1:#x} read uninitialized {0}")]
+  UninitializedRead(Location, usize),
+
   #[error("division by zero")]
   DivisionByZero,
 
+  #[error("{0} at {1:#x} overflowed")]
+  ArithmeticOverflow(&'static str, usize),
+
   #[error("opcode error - {0}")]
   OpcodeError(#[from] opcode::Error),
 
@@ -29,6 +98,59 @@ pub enum Error {
 
   #[error("register error - {0}")]
   RegisterError(#[from] register::Error),
+
+  #[error("access trace error - {0}")]
+  TraceError(#[from] io::Error),
+
+  #[error("snapshot error - {0}")]
+  SnapshotError(#[from] snapshot::Error),
+
+  #[error("bus error - {0}")]
+  BusError(#[from] bus::Error),
+
+  #[error("disassembly error - {0}")]
+  DisasmError(#[from] disasm::Error),
+}
+
+/// Per core architectural state, everything but memory.
+#[derive(Debug, Clone)]
+pub(crate) struct Context {
+  pub(crate) ip: usize,
+  pub(crate) reg_file: RegisterFile,
+  pub(crate) state: State,
+  pub(crate) steps: usize,
+}
+
+/// A suspended guest thread: ip, registers, flags, state and step count,
+/// everything but memory, which threads on one vm share. Created with
+/// `Vm::capture` or `Vm::spawn` and resumed with `Vm::switch_to`.
+#[derive(Debug, Clone)]
+pub struct Continuation {
+  context: Context,
+}
+
+impl Continuation {
+  pub fn ip(&self) -> usize {
+    self.context.ip
+  }
+
+  pub fn state(&self) -> State {
+    self.context.state
+  }
+
+  /// Instructions the thread has executed.
+  pub fn steps(&self) -> usize {
+    self.context.steps
+  }
+
+  pub fn register(&self, reg: Register) -> Block {
+    self.context.reg_file[reg]
+  }
+
+  /// Sets a register the thread sees once resumed, such as an argument.
+  pub fn set_register(&mut self, reg: Register, value: Block) {
+    self.context.reg_file[reg] = value;
+  }
 }
 
 #[derive(Debug)]
@@ -37,35 +159,1096 @@ pub struct Vm {
   memory: MainMemory,
   reg_file: RegisterFile,
   state: State,
+  steps: usize,
+  config: Config,
+  events: EventBus,
+  // range of memory holding the program placed by `load`
+  code: Range<usize>,
+  // address of the instruction being executed, for reporting
+  current: usize,
+  guard: Range<usize>,
+  // sorted instruction starts found by `load`, ending with the address past
+  // the last decoded instruction, empty unless targets are checked
+  boundaries: Vec<usize>,
+  timing: Timing,
+  trap: Option<Box<dyn TrapHandler>>,
+  exit_hooks: Vec<Box<dyn ExitHook>>,
+  access_trace: Option<AccessTrace>,
+  bus: Bus,
+  meter: Meter,
+  entropy: Entropy,
+  profile: Option<Profile>,
+  write_history: Option<WriteHistory>,
+  // watch expressions with their last value, removed ones left as `None`
+  watches: Vec<Option<(Expr, Option<Block>)>>,
 }
 
 impl Vm {
   pub fn new() -> Self {
-    Self {
-      ip: 0,
+    Self::with_config(Config::default())
+  }
+
+  pub fn builder() -> VmBuilder {
+    VmBuilder::new()
+  }
+
+  pub(crate) fn with_config(config: Config) -> Self {
+    let mut vm = Self {
+      ip: config.reset_ip(),
       memory: MainMemory::default(),
-      reg_file: RegisterFile::default(),
+      reg_file: RegisterFile::new(config.stack_pointer()),
       state: State::Active,
+      steps: 0,
+      events: EventBus::default(),
+      code: 0..0,
+      current: config.reset_ip(),
+      guard: config.stack_guard(),
+      boundaries: Vec::new(),
+      timing: Timing::new(
+        config.costs.clone(),
+        config.cache.clone(),
+        config.fetch.clone(),
+        config.energy.clone(),
+      ),
+      trap: None,
+      exit_hooks: Vec::new(),
+      access_trace: None,
+      bus: Bus::new(),
+      meter: Meter::new(config.quotas),
+      entropy: Entropy::new(config.seed),
+      profile: None,
+      write_history: None,
+      watches: Vec::new(),
+      config,
+    };
+    vm.map_rom();
+    vm
+  }
+
+  /// Copies the boot rom into memory so programs can also load from it, the
+  /// part past the end of memory is only fetchable.
+  fn map_rom(&mut self) {
+    if let Some(rom) = &self.config.rom {
+      let end = rom.range().end.min(MainMemory::MEMORY_SIZE);
+      if rom.base < end {
+        let len = end - rom.base;
+        // in bounds by construction
+        let _ = self.memory.write_bytes(rom.base, &rom.bytes[..len]);
+      }
+      self.memory.mark_clean();
+    }
+  }
+
+  fn overlaps_rom(&self, range: Range<usize>) -> bool {
+    self
+      .config
+      .rom
+      .as_ref()
+      .is_some_and(|rom| range.start < rom.range().end && rom.base < range.end)
+  }
+
+  pub fn state(&self) -> State {
+    self.state
+  }
+
+  /// Address of the next instruction to execute.
+  pub fn ip(&self) -> usize {
+    self.ip
+  }
+
+  pub(crate) fn context(&self) -> Context {
+    Context {
+      ip: self.ip,
+      reg_file: self.reg_file.clone(),
+      state: self.state,
+      steps: self.steps,
+    }
+  }
+
+  /// Copies the running thread, switching to the copy later continues from
+  /// this point.
+  pub fn capture(&self) -> Continuation {
+    Continuation {
+      context: self.context(),
+    }
+  }
+
+  /// A new thread starting at `entry` with zeroed registers and flags, apart
+  /// from `%rsp` which starts at `stack_pointer`.
+  pub fn spawn(&self, entry: usize, stack_pointer: Block) -> Continuation {
+    Continuation {
+      context: Context {
+        ip: entry,
+        reg_file: RegisterFile::new(stack_pointer),
+        state: State::Active,
+        steps: 0,
+      },
+    }
+  }
+
+  /// Context switch: suspends the running thread into `next` and resumes
+  /// the thread `next` held, so calling it again switches back.
+  pub fn switch_to(&mut self, next: &mut Continuation) {
+    self.swap_context(&mut next.context);
+  }
+
+  /// Exchanges the architectural state of the vm with `context`, leaving
+  /// memory untouched.
+  pub(crate) fn swap_context(&mut self, context: &mut Context) {
+    mem::swap(&mut self.ip, &mut context.ip);
+    mem::swap(&mut self.reg_file, &mut context.reg_file);
+    mem::swap(&mut self.state, &mut context.state);
+    mem::swap(&mut self.steps, &mut context.steps);
+  }
+
+  /// Current value of `%rax`, the conventional return value register.
+  pub fn return_value(&self) -> Block {
+    self.reg_file[Register::Rax]
+  }
+
+  pub fn register(&self, reg: Register) -> Block {
+    self.reg_file[reg]
+  }
+
+  pub fn set_register(&mut self, reg: Register, value: Block) {
+    self.reg_file[reg] = value;
+  }
+
+  /// Moves execution to `ip`, the next step fetches from there.
+  pub fn set_ip(&mut self, ip: usize) {
+    self.ip = ip;
+  }
+
+  pub fn flags(&self) -> Flags {
+    self.reg_file.flags()
+  }
+
+  pub(crate) fn flag(&self, flag: Flag) -> bool {
+    self.reg_file[flag]
+  }
+
+  pub(crate) fn memory(&self) -> &MainMemory {
+    &self.memory
+  }
+
+  /// Every register along with its current value, in encoding order.
+  pub fn registers(&self) -> impl Iterator<Item = (Register, Block)> + '_ {
+    Register::iter().map(|reg| (reg, self.reg_file[reg]))
+  }
+
+  /// Copies `bytes` into memory starting at `address`, which need not be
+  /// aligned, failing without writing anything if it would run off the end.
+  pub fn write_bytes(&mut self, address: usize, bytes: &[u8]) -> Result<(), Error> {
+    Ok(self.memory.write_bytes(address, bytes)?)
+  }
+
+  /// Reads `len` bytes starting at `address`, which need not be aligned.
+  pub fn read_bytes(&self, address: usize, len: usize) -> Result<Vec<u8>, Error> {
+    Ok(self.memory.read_bytes(address, len)?.into_owned())
+  }
+
+  /// Address ranges written since the vm was built or reset, or since the
+  /// last `mark_clean`, with adjacent blocks merged. Ranges are block aligned
+  /// and include host writes such as `write_bytes`.
+  pub fn dirty_regions(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+    self.memory.dirty_regions()
+  }
+
+  /// Every block inside the dirty regions along with its current value.
+  pub fn dirty_blocks(&self) -> impl Iterator<Item = (usize, Block)> + '_ {
+    self
+      .dirty_regions()
+      .flat_map(|range| range.step_by(BLOCK_SIZE))
+      .map(|address| {
+        (
+          address,
+          self.memory.read(address).expect("dirty block in bounds"),
+        )
+      })
+  }
+
+  /// Checkpoints memory, subsequent dirty queries only report later writes.
+  pub fn mark_clean(&mut self) {
+    self.memory.mark_clean();
+  }
+
+  /// Every aligned memory block along with its address.
+  pub fn memory_blocks(&self) -> impl Iterator<Item = (usize, Block)> + '_ {
+    self.memory.blocks()
+  }
+
+  /// Loads the segments of `image` laid out from address zero and moves ip
+  /// to its entry point, returning the chunk to run. `reset` goes back to
+  /// the entry point of the builder, which should be the image's.
+  pub fn load_image(&mut self, image: &Image) -> Result<Chunk, Error> {
+    let chunk = Chunk::from(image);
+    self.load(&chunk)?;
+    self.ip = image.entry();
+    Ok(chunk)
+  }
+
+  /// Copies the program into memory at address zero, making data embedded
+  /// alongside the code readable by loads, and starts treating stores into it
+  /// according to the configured `CodeWrites`. Memory is marked clean
+  /// afterwards, so dirty tracking reports what the program itself changed.
+  pub fn load<R>(&mut self, region: &R) -> Result<(), Error>
+  where
+    R: Region,
+  {
+    let code = region.instructions();
+    if self.overlaps_rom(0..code.len()) {
+      return Err(Error::RomWrite(0));
+    }
+    self.memory.write_bytes(0, code)?;
+    self.code = 0..code.len();
+    self.memory.mark_clean();
+    self.boundaries.clear();
+    if self.config.check_targets {
+      let mut end = 0;
+      for instruction in disasm::disassemble(code, 0).map_while(Result::ok) {
+        self.boundaries.push(instruction.address());
+        end = instruction.address() + instruction.bytes().len();
+      }
+      self.boundaries.push(end);
+    }
+    Ok(())
+  }
+
+  pub fn isa(&self) -> Isa {
+    self.config.isa
+  }
+
+  /// Address range occupied by the loaded program, empty before `load`.
+  pub fn code_range(&self) -> Range<usize> {
+    self.code.clone()
+  }
+
+  /// Where the rom, program, stack, guard band and devices currently live.
+  pub fn memory_map(&self) -> MemoryMap {
+    let mut areas = vec![
+      Area {
+        kind: Kind::Code,
+        range: self.code.clone(),
+      },
+      Area {
+        kind: Kind::Stack,
+        range: self.config.stack(),
+      },
+      Area {
+        kind: Kind::Guard,
+        range: self.guard.clone(),
+      },
+    ];
+    if let Some(rom) = &self.config.rom {
+      areas.push(Area {
+        kind: Kind::Rom,
+        range: rom.range(),
+      });
+    }
+    areas.extend(self.bus.devices().map(|(id, range, priority)| Area {
+      kind: Kind::Device { id, priority },
+      range,
+    }));
+    MemoryMap::new(MainMemory::MEMORY_SIZE, areas)
+  }
+
+  /// Restores the freshly built state, keeping the configuration and event
+  /// subscribers, so one vm can run many programs without reallocating.
+  pub fn reset(&mut self) {
+    self.ip = self.config.reset_ip();
+    self.memory.clear();
+    self.map_rom();
+    self.reg_file = RegisterFile::new(self.config.stack_pointer());
+    self.state = State::Active;
+    self.steps = 0;
+    self.code = 0..0;
+    self.boundaries.clear();
+    self.timing.reset();
+    self.bus.reset();
+    self.meter.reset();
+    self.entropy.reset();
+    if let Some(profile) = &mut self.profile {
+      profile.clear();
+    }
+    if let Some(history) = &mut self.write_history {
+      history.clear();
+    }
+    self.update_watches(false);
+  }
+
+  /// Replaces the quotas set with `VmBuilder::quotas` and starts a new run
+  /// against them.
+  pub fn set_quotas(&mut self, quotas: Quotas) {
+    self.meter = Meter::new(quotas);
+  }
+
+  /// Resources consumed since the vm was built, reset or given new quotas.
+  pub fn usage(&self) -> Usage {
+    self.meter.usage()
+  }
+
+  /// Attributes what every following instruction costs to the regions of
+  /// `profile` holding its address, replacing any previous profile.
+  /// `reset` clears the counts but keeps the regions.
+  pub fn set_profile(&mut self, profile: Profile) {
+    self.profile = Some(profile);
+  }
+
+  pub fn profile(&self) -> Option<&Profile> {
+    self.profile.as_ref()
+  }
+
+  pub fn take_profile(&mut self) -> Option<Profile> {
+    self.profile.take()
+  }
+
+  /// Keeps the last stores to the ranges `history` watches, replacing any
+  /// previous history. `reset` forgets the stores but keeps the ranges.
+  pub fn set_write_history(&mut self, history: WriteHistory) {
+    self.write_history = Some(history);
+  }
+
+  pub fn write_history(&self) -> Option<&WriteHistory> {
+    self.write_history.as_ref()
+  }
+
+  pub fn take_write_history(&mut self) -> Option<WriteHistory> {
+    self.write_history.take()
+  }
+
+  /// Evaluates `expr` after every instruction, emitting
+  /// `Event::ExprChanged` whenever its value differs from the one before.
+  /// Returns the id used to refer to it later.
+  pub fn watch_expr(&mut self, expr: Expr) -> usize {
+    let value = expr.eval(self).ok();
+    self.watches.push(Some((expr, value)));
+    self.watches.len() - 1
+  }
+
+  pub fn unwatch_expr(&mut self, id: usize) -> Option<Expr> {
+    self.watches.get_mut(id)?.take().map(|(expr, _)| expr)
+  }
+
+  /// Every watch expression with its value after the last instruction,
+  /// `None` when it failed to evaluate.
+  pub fn watched_exprs(&self) -> impl Iterator<Item = (usize, &Expr, Option<Block>)> + '_ {
+    self
+      .watches
+      .iter()
+      .enumerate()
+      .filter_map(|(id, watch)| watch.as_ref().map(|(expr, value)| (id, expr, *value)))
+  }
+
+  /// Re-evaluates the watch expressions, emitting changes when `notify` is
+  /// set.
+  fn update_watches(&mut self, notify: bool) {
+    if self.watches.is_empty() {
+      return;
+    }
+    let mut watches = mem::take(&mut self.watches);
+    // the instruction that just retired
+    let step = self.steps.saturating_sub(1);
+    for (id, (expr, value)) in watches
+      .iter_mut()
+      .enumerate()
+      .filter_map(|(id, watch)| Some((id, watch.as_mut()?)))
+    {
+      let new = expr.eval(self).ok();
+      if new != *value && notify {
+        let old = *value;
+        self
+          .events
+          .emit(EventKind::ExprChanged, || Event::ExprChanged {
+            id,
+            step,
+            old,
+            new,
+          });
+      }
+      *value = new;
     }
+    self.watches = watches;
   }
 
-  pub fn step<R>(&mut self, region: &R) -> Result<(), Error>
+  /// Randomness seeded by `VmBuilder::seed`, restarted by `reset`.
+  pub fn entropy(&self) -> &Entropy {
+    &self.entropy
+  }
+
+  /// For components that need random values, each should draw under a
+  /// name of its own so the audit can tell them apart.
+  pub fn entropy_mut(&mut self) -> &mut Entropy {
+    &mut self.entropy
+  }
+
+  /// Audit of what the current run depends on besides its program: every
+  /// component that drew from the seeded entropy, then every attached
+  /// device reading host state.
+  pub fn nondeterminism(&self) -> Vec<Source> {
+    self
+      .entropy
+      .audit()
+      .map(|(component, draws)| Source::Seeded {
+        component: component.to_string(),
+        draws,
+      })
+      .chain(self.bus.nondeterministic().map(Source::Host))
+      .collect()
+  }
+
+  /// Counts `bytes` the program sent out of the vm other than through
+  /// devices, such as a syscall, false once over the output quota. The step
+  /// doing so then fails with `Error::QuotaExceeded`.
+  pub(crate) fn charge_output(&mut self, bytes: usize) -> bool {
+    self.meter.output(bytes).is_ok()
+  }
+
+  /// Maps `device` at `range`, program loads and stores there reach the
+  /// device instead of memory. See `Bus` for overlapping ranges.
+  pub fn attach_device(
+    &mut self,
+    range: Range<usize>,
+    device: impl Device + 'static,
+  ) -> Result<DeviceId, Error> {
+    Ok(self.bus.attach(range, device)?)
+  }
+
+  pub fn bus(&self) -> &Bus {
+    &self.bus
+  }
+
+  pub fn bus_mut(&mut self) -> &mut Bus {
+    &mut self.bus
+  }
+
+  /// Registers, flags, ip, halt state and memory, what two engines running
+  /// the same program must agree on, see `bisect`.
+  pub fn arch_state(&self) -> ArchState {
+    ArchState {
+      ip: self.ip,
+      registers: self.registers().map(|(_, value)| value).collect(),
+      flags: self.flags(),
+      halted: self.state == State::Halted,
+      memory: self.memory.bytes().into_owned(),
+    }
+  }
+
+  /// Captures registers, memory, the timing model, device state and entropy
+  /// streams, so the machine can later be rewound with `restore`.
+  pub fn snapshot(&self) -> Snapshot {
+    let flags = self.flags();
+    Snapshot {
+      ip: self.ip,
+      registers: self.registers().map(|(_, value)| value).collect(),
+      flags: [flags.zf, flags.sf, flags.of],
+      halted: self.state == State::Halted,
+      steps: self.steps,
+      memory: self.memory.bytes().into_owned(),
+      code: (self.code.start, self.code.end),
+      boundaries: self.boundaries.clone(),
+      timing: self.timing.save(),
+      entropy: self.entropy.save(),
+      devices: self.bus.save(),
+    }
+  }
+
+  /// Rewinds to `snapshot`, which must come from a vm built with the same
+  /// timing model and with the snapshotted devices still attached.
+  pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), Error> {
+    if snapshot.registers.len() != Register::iter().count()
+      || snapshot.memory.len() != MainMemory::MEMORY_SIZE
+    {
+      return Err(snapshot::Error::Truncated.into());
+    }
+    self.timing.load(&snapshot.timing)?;
+    self.entropy.load(&snapshot.entropy)?;
+    self.bus.load(&snapshot.devices)?;
+    self.ip = snapshot.ip;
+    for (reg, &value) in Register::iter().zip(&snapshot.registers) {
+      self.reg_file[reg] = value;
+    }
+    let [zf, sf, of] = snapshot.flags;
+    self.reg_file[Flag::ZF] = zf;
+    self.reg_file[Flag::SF] = sf;
+    self.reg_file[Flag::OF] = of;
+    self.state = if snapshot.halted {
+      State::Halted
+    } else {
+      State::Active
+    };
+    self.steps = snapshot.steps;
+    self.memory.write_bytes(0, &snapshot.memory)?;
+    self.memory.mark_clean();
+    self.code = snapshot.code.0..snapshot.code.1;
+    self.boundaries = snapshot.boundaries.clone();
+    Ok(())
+  }
+
+  /// Cycle count and cache statistics of the timing model.
+  pub fn timing(&self) -> &Timing {
+    &self.timing
+  }
+
+  /// Number of instructions executed so far.
+  pub fn steps(&self) -> usize {
+    self.steps
+  }
+
+  /// Renders the registers, flags, ip and state as a json document,
+  /// optionally listing every nonzero memory block as well.
+  pub fn state_json(&self, include_memory: bool) -> String {
+    let status = match self.state {
+      State::Active => "active",
+      State::Halted => "halted",
+    };
+    let registers = self
+      .registers()
+      .map(|(reg, value)| (reg.name(), Json::from(value)));
+    let flags = [
+      ("zf", Json::from(self.reg_file[Flag::ZF])),
+      ("sf", Json::from(self.reg_file[Flag::SF])),
+      ("of", Json::from(self.reg_file[Flag::OF])),
+    ];
+    let mut fields = vec![
+      ("status", Json::from(status)),
+      ("ip", Json::from(self.ip)),
+      ("steps", Json::from(self.steps)),
+      ("cycles", Json::from(self.timing.cycles() as usize)),
+      ("registers", Json::object(registers)),
+      ("flags", Json::object(flags)),
+    ];
+    if let Some(energy) = self.timing.energy() {
+      let energy = [
+        ("total", Json::from(energy.total() as usize)),
+        ("memory", Json::from(energy.memory() as usize)),
+        ("area", Json::from(energy.area() as usize)),
+      ];
+      fields.insert(4, ("energy", Json::object(energy)));
+    }
+    if include_memory {
+      let blocks = self
+        .memory_blocks()
+        .filter(|&(_, value)| value != 0)
+        .map(|(addr, value)| {
+          Json::object([("address", Json::from(addr)), ("value", Json::from(value))])
+        })
+        .collect();
+      fields.push(("memory", Json::Array(blocks)));
+    }
+    format!("{:#}", Json::object(fields))
+  }
+
+  /// Executes the instruction at ip, reporting what ran.
+  pub fn step<R>(&mut self, region: &R) -> Result<Executed, Error>
   where
     R: Region,
   {
     if self.state == State::Halted {
       return Err(Error::MachineHalted);
     }
-    let mut task = Task::new(self, region);
-    task.run()
+    if let Some(max_steps) = self.config.max_steps
+      && self.steps >= max_steps
+    {
+      return Err(Error::StepLimitExceeded(max_steps));
+    }
+    let address = self.ip;
+    self.current = address;
+    let executed = match self.execute(region) {
+      Ok(executed) => executed,
+      Err(e) => {
+        // leave ip at the faulting instruction rather than partway through it
+        self.ip = address;
+        self
+          .events
+          .emit(EventKind::FaultRaised, || Event::FaultRaised {
+            address,
+            message: e.to_string(),
+          });
+        return Err(e);
+      }
+    };
+    let step = self.steps;
+    self.steps += 1;
+    self.bus.tick();
+    let counters = Counters {
+      instructions: self.steps as u64,
+      cycles: self.timing.cycles(),
+      cache_misses: self.misses() as u64,
+      mispredictions: self.timing.mispredictions(),
+    };
+    self.bus.sample(&counters);
+    self.events.emit(EventKind::InstructionRetired, || {
+      Event::InstructionRetired { step, address }
+    });
+    self.update_watches(true);
+    Ok(executed)
+  }
+
+  /// Runs the instruction at ip within the quotas.
+  fn execute<R>(&mut self, region: &R) -> Result<Executed, Error>
+  where
+    R: Region,
+  {
+    self.meter.begin().map_err(Error::QuotaExceeded)?;
+    let (address, cycles, misses) = (self.ip, self.timing.cycles(), self.misses());
+    let executed = Task::new(self, region).run()?;
+    if self.profile.is_some() {
+      let (cycles, misses) = (self.timing.cycles() - cycles, self.misses() - misses);
+      if let Some(profile) = &mut self.profile {
+        profile.retire(address, cycles, misses);
+      }
+    }
+    self.meter.retire().map_err(Error::QuotaExceeded)?;
+    Ok(executed)
+  }
+
+  fn misses(&self) -> usize {
+    self.timing.cache().map_or(0, |cache| cache.misses())
+  }
+
+  /// Replaces the storage behind memory with `backend`, carrying the current
+  /// contents over so a loaded program survives the switch.
+  pub fn set_memory(&mut self, backend: impl MemoryBackend + 'static) {
+    self.memory.set_backend(Box::new(backend));
+  }
+
+  /// Routes invalid opcodes to `handler` instead of failing the step,
+  /// replacing any previous handler.
+  pub fn set_trap_handler(&mut self, handler: impl TrapHandler + 'static) {
+    self.trap = Some(Box::new(handler));
   }
 
-  fn read_block(&self, address: usize) -> Result<Block, Error> {
+  pub fn clear_trap_handler(&mut self) {
+    self.trap = None;
+  }
+
+  /// Streams a record of every data access the program makes to `sink`, for
+  /// external cache simulators. Writes are unbuffered, wrap files in a
+  /// `BufWriter`. A failed write fails the step with `Error::TraceError`.
+  pub fn trace_accesses(&mut self, sink: impl Write + Send + 'static, format: TraceFormat) {
+    self.access_trace = Some(AccessTrace::new(Box::new(sink), format));
+  }
+
+  /// Flushes and drops the access trace sink.
+  pub fn stop_access_trace(&mut self) -> Result<(), Error> {
+    if let Some(mut trace) = self.access_trace.take() {
+      trace.flush()?;
+    }
+    Ok(())
+  }
+
+  /// Delivers every event matching `filter` to `subscriber` until it is
+  /// unsubscribed.
+  pub fn subscribe(
+    &mut self,
+    filter: EventFilter,
+    subscriber: impl Subscriber + 'static,
+  ) -> SubscriptionId {
+    self.events.subscribe(filter, Box::new(subscriber))
+  }
+
+  /// Subscribes a channel, handy for observers living on another thread.
+  pub fn subscribe_channel(
+    &mut self,
+    filter: EventFilter,
+  ) -> (SubscriptionId, mpsc::Receiver<Event>) {
+    let (tx, rx) = mpsc::channel();
+    (self.subscribe(filter, tx), rx)
+  }
+
+  /// Returns whether the subscription existed.
+  pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+    self.events.unsubscribe(id)
+  }
+
+  /// Iterator stepping the vm once per item, ending after the instruction
+  /// that halts the machine or after the first error.
+  pub fn iter<'vm, 'region, R>(&'vm mut self, region: &'region R) -> Iter<'vm, 'region, R>
+  where
+    R: Region,
+  {
+    Iter {
+      vm: self,
+      region,
+      done: false,
+    }
+  }
+
+  /// Steps the vm until it halts, returning the first error encountered.
+  pub fn run<R>(&mut self, region: &R) -> Result<(), Error>
+  where
+    R: Region,
+  {
+    let ran = self.state != State::Halted;
+    let mut run = || {
+      while self.state != State::Halted {
+        self.step(region)?;
+      }
+      Ok(())
+    };
+    let result = run();
+    self.exited(ran, result)
+  }
+
+  /// Runs like `run`, collecting the outcome, final state, faults and
+  /// coverage of the run into one report instead of returning an error.
+  pub fn run_report<R>(&mut self, region: &R) -> RunReport
+  where
+    R: Region,
+  {
+    report::run(self, region)
+  }
+
+  /// Executes at most `instructions` instructions, stopping early only if
+  /// the machine halts, so a scheduler can preempt at exact and reproducible
+  /// points. A fault leaves ip at the faulting instruction as usual.
+  pub fn run_for<R>(&mut self, region: &R, instructions: usize) -> Result<Quantum, Error>
+  where
+    R: Region,
+  {
+    let ran = self.state != State::Halted;
+    let mut run = || {
+      for steps in 0..instructions {
+        if self.state == State::Halted {
+          return Ok(Quantum::Halted { steps });
+        }
+        self.step(region)?;
+      }
+      if self.state == State::Halted {
+        return Ok(Quantum::Halted {
+          steps: instructions,
+        });
+      }
+      Ok(Quantum::Expired)
+    };
+    let result = run();
+    self.exited(ran, result)
+  }
+
+  /// Steps until the ip reaches `address`, always executing at least one
+  /// instruction so calling it again with a loop head goes around the loop.
+  /// Bounded by `VmBuilder::max_steps` like any other run.
+  pub fn run_until<R>(&mut self, region: &R, address: usize) -> Result<Until, Error>
+  where
+    R: Region,
+  {
+    let (start, ran) = (self.steps, self.state != State::Halted);
+    let mut run = || {
+      while self.state != State::Halted {
+        self.step(region)?;
+        if self.ip == address {
+          return Ok(Until::Reached {
+            steps: self.steps - start,
+          });
+        }
+      }
+      Ok(Until::Halted {
+        steps: self.steps - start,
+      })
+    };
+    let result = run();
+    self.exited(ran, result)
+  }
+
+  /// Steps until the current function returns, stopping at the instruction
+  /// after the `call` that entered it. Calls made along the way are run
+  /// through to their own `ret`.
+  pub fn run_until_ret<R>(&mut self, region: &R) -> Result<Until, Error>
+  where
+    R: Region,
+  {
+    let (start, ran) = (self.steps, self.state != State::Halted);
+    let mut run = || {
+      let mut depth = 0usize;
+      while let Some(executed) = self.iter(region).next().transpose()? {
+        match executed.instruction().map(Disassembled::instruction) {
+          Some(Instruction::Call(_)) => depth += 1,
+          Some(Instruction::Ret) if depth == 0 => {
+            return Ok(Until::Reached {
+              steps: self.steps - start,
+            });
+          }
+          Some(Instruction::Ret) => depth -= 1,
+          _ => {}
+        }
+      }
+      Ok(Until::Halted {
+        steps: self.steps - start,
+      })
+    };
+    let result = run();
+    self.exited(ran, result)
+  }
+
+  /// Calls `hook` whenever a run halts or faults, after the hooks added
+  /// before it. Stepping the vm by hand never calls it.
+  pub fn on_exit(&mut self, hook: impl ExitHook + 'static) {
+    self.exit_hooks.push(Box::new(hook));
+  }
+
+  pub fn clear_exit_hooks(&mut self) {
+    self.exit_hooks.clear();
+  }
+
+  /// Steps, cycles, energy and quota usage since the vm was built or reset.
+  pub fn stats(&self) -> Stats {
+    Stats {
+      steps: self.steps,
+      cycles: self.timing.cycles(),
+      energy: self.timing.energy().map(|energy| energy.total()),
+      usage: self.meter.usage(),
+    }
+  }
+
+  /// Hands the end of a run to the exit hooks, `ran` telling whether the vm
+  /// was still active when the run started.
+  fn exited<T>(&mut self, ran: bool, result: Result<T, Error>) -> Result<T, Error> {
+    let exit = match &result {
+      _ if self.exit_hooks.is_empty() => return result,
+      Err(e) => Exit::Faulted(e),
+      Ok(_) if ran && self.state == State::Halted => Exit::Halted,
+      Ok(_) => return result,
+    };
+    let stats = self.stats();
+    let mut hooks = mem::take(&mut self.exit_hooks);
+    for hook in &mut hooks {
+      hook.exited(self, exit, &stats);
+    }
+    self.exit_hooks = hooks;
+    result
+  }
+
+  /// Decodes up to `before` instructions leading to the current ip, the one
+  /// at the ip, and up to `after` following it, in address order. Earlier
+  /// instructions are found by walking back from the ip, preferring starts
+  /// known from decoding the program from its first byte (or from the
+  /// `check_targets` predecode) so data and misaligned jumps do not throw the
+  /// boundaries off. The window ends early at bytes that do not decode.
+  pub fn disassemble_window<R>(&self, region: &R, before: usize, after: usize) -> Vec<Disassembled>
+  where
+    R: Region,
+  {
+    let decode =
+      |address: usize| disasm::disassemble_at(&self.code_bytes(region, address), address).ok();
+    let known = if self.boundaries.is_empty() {
+      self.sweep(region)
+    } else {
+      self.boundaries.clone()
+    };
+
+    let mut window = Vec::new();
+    let mut end = self.ip;
+    while window.len() < before {
+      // an instruction at `start` leads to `end` if it is exactly that long
+      let leads = |start: usize| decode(start).filter(|i| start + i.bytes().len() == end);
+      let candidates = end.saturating_sub(MAX_INSTRUCTION_LEN)..end;
+      let previous = candidates
+        .clone()
+        .rev()
+        .filter(|start| known.binary_search(start).is_ok())
+        .find_map(leads)
+        .or_else(|| candidates.rev().find_map(leads));
+      let Some(instruction) = previous else {
+        break;
+      };
+      end = instruction.address();
+      window.push(instruction);
+    }
+    window.reverse();
+
+    let mut address = self.ip;
+    for _ in 0..=after {
+      let Some(instruction) = decode(address) else {
+        break;
+      };
+      address += instruction.bytes().len();
+      window.push(instruction);
+    }
+    window
+  }
+
+  /// Instruction starts found by decoding from the beginning of the code the
+  /// ip is in, skipping a byte whenever decoding fails.
+  fn sweep<R>(&self, region: &R) -> Vec<usize>
+  where
+    R: Region,
+  {
+    let start = match &self.config.rom {
+      Some(rom) if rom.range().contains(&self.ip) => rom.base,
+      _ => self.code.start,
+    };
+    let mut starts = Vec::new();
+    let mut address = start;
+    while address < self.ip {
+      starts.push(address);
+      address += disasm::disassemble_at(&self.code_bytes(region, address), address)
+        .map_or(1, |instruction| instruction.bytes().len());
+    }
+    starts
+  }
+
+  /// Bytes instructions at `address` are fetched from, matching `Task::eat`.
+  fn code_bytes<'a, R>(&'a self, region: &'a R, address: usize) -> Cow<'a, [u8]>
+  where
+    R: Region,
+  {
+    let self_modifying =
+      self.config.code_writes == CodeWrites::SelfModifying && self.code.contains(&address);
+    if self_modifying || self.overlaps_rom(address..address + 1) {
+      self.memory.bytes()
+    } else {
+      Cow::Borrowed(region.instructions())
+    }
+  }
+
+  fn check_guard(&self, address: usize) -> Result<(), Error> {
+    if address < self.guard.end && self.guard.start < address.saturating_add(BLOCK_SIZE) {
+      return Err(Error::StackGuardHit(address));
+    }
+    Ok(())
+  }
+
+  /// Rejects control transfers into the middle of a decoded instruction.
+  /// Targets beyond what the predecode sweep reached are let through.
+  fn check_target(&self, target: usize) -> Result<(), Error> {
+    let (Some(&first), Some(&last)) = (self.boundaries.first(), self.boundaries.last()) else {
+      return Ok(());
+    };
+    if target < first || target >= last {
+      return Ok(());
+    }
+    match self.boundaries.binary_search(&target) {
+      Ok(_) => Ok(()),
+      Err(i) => Err(Error::MisalignedTarget(
+        self.current,
+        target,
+        self.boundaries[i - 1],
+      )),
+    }
+  }
+
+  pub(crate) fn read_block(&mut self, address: usize) -> Result<Block, Error> {
+    self.check_guard(address)?;
+    if !self.bus.claims(address) {
+      self.meter.touch(address).map_err(Error::QuotaExceeded)?;
+    }
+    self.timing.access(address, false);
+    if let Some(profile) = &mut self.profile {
+      profile.access(false);
+    }
+    if let Some(trace) = &mut self.access_trace {
+      trace.record(false, address, BLOCK_SIZE)?;
+    }
+    if let Some(value) = self.bus.read(address) {
+      self.events.emit(EventKind::DeviceIo, || Event::DeviceIo {
+        address,
+        value,
+        write: false,
+      });
+      return Ok(value);
+    }
+    if !self.memory.is_written(address) {
+      self.uninitialized_read(Location::Memory(address))?;
+    }
     Ok(self.memory.read(address)?)
   }
 
-  fn write_block(&mut self, address: usize, value: Block) -> Result<(), Error> {
-    Ok(self.memory.write(address, value)?)
+  /// Reports a read of `location` according to `UninitializedReads`.
+  fn uninitialized_read(&mut self, location: Location) -> Result<(), Error> {
+    let ip = self.current;
+    match self.config.uninitialized_reads {
+      UninitializedReads::Allow => Ok(()),
+      UninitializedReads::Warn => {
+        self
+          .events
+          .emit(EventKind::UninitializedRead, || Event::UninitializedRead {
+            ip,
+            location,
+          });
+        Ok(())
+      }
+      UninitializedReads::Fault => Err(Error::UninitializedRead(location, ip)),
+    }
+  }
+
+  /// Checks the registers the instruction at `address` reads, if it decodes.
+  fn check_register_reads<R>(&mut self, region: &R, address: usize) -> Result<(), Error>
+  where
+    R: Region,
+  {
+    let Ok(instruction) = disasm::disassemble_at(&self.code_bytes(region, address), address) else {
+      return Ok(());
+    };
+    // `xorq %rax, %rax` and `subq %rax, %rax` zero the register whatever it held
+    if let Instruction::Opq(OpFun::Xor | OpFun::Sub, ra, rb) = *instruction.instruction()
+      && ra == rb
+    {
+      return Ok(());
+    }
+    for reg in instruction.instruction().reads().into_iter().flatten() {
+      if !self.reg_file.is_written(reg) {
+        self.uninitialized_read(Location::Register(reg))?;
+      }
+    }
+    Ok(())
+  }
+
+  pub(crate) fn write_block(&mut self, address: usize, value: Block) -> Result<(), Error> {
+    self.check_guard(address)?;
+    if self.overlaps_rom(address..address.saturating_add(BLOCK_SIZE)) {
+      return Err(Error::RomWrite(address));
+    }
+    if self.bus.claims(address) {
+      self
+        .meter
+        .output(BLOCK_SIZE)
+        .map_err(Error::QuotaExceeded)?;
+    } else {
+      self.meter.touch(address).map_err(Error::QuotaExceeded)?;
+    }
+    let overwrites_code =
+      address < self.code.end && self.code.start < address.saturating_add(BLOCK_SIZE);
+    if overwrites_code {
+      match self.config.code_writes {
+        CodeWrites::Allow | CodeWrites::SelfModifying => {}
+        CodeWrites::Warn => {
+          let ip = self.current;
+          self
+            .events
+            .emit(EventKind::CodeOverwritten, || Event::CodeOverwritten {
+              ip,
+              address,
+            });
+        }
+        CodeWrites::Fault => return Err(Error::CodeOverwrite(address)),
+      }
+    }
+    self.timing.access(address, true);
+    if let Some(profile) = &mut self.profile {
+      profile.access(true);
+    }
+    if let Some(trace) = &mut self.access_trace {
+      trace.record(true, address, BLOCK_SIZE)?;
+    }
+    if self.bus.write(address, value) {
+      self.events.emit(EventKind::DeviceIo, || Event::DeviceIo {
+        address,
+        value,
+        write: true,
+      });
+      return Ok(());
+    }
+    self.memory.write(address, value)?;
+    if let Some(history) = &mut self.write_history {
+      history.record(self.steps, self.current, address, value);
+    }
+    self
+      .events
+      .emit(EventKind::MemoryWritten, || Event::MemoryWritten {
+        address,
+        value,
+      });
+    Ok(())
   }
 }
 
@@ -75,9 +1258,123 @@ impl Default for Vm {
   }
 }
 
+/// What one `Vm::step` executed.
+#[derive(Debug)]
+pub enum Executed {
+  /// An instruction, decoded from the bytes the step fetched.
+  Instruction(Disassembled),
+  /// `byte` at `address` is no opcode, the trap handler took over and
+  /// continued with `trap`, either `Trap::Resume` or `Trap::Skip`.
+  Trapped {
+    address: usize,
+    byte: u8,
+    trap: Trap,
+  },
+}
+
+impl Executed {
+  pub fn address(&self) -> usize {
+    match self {
+      Executed::Instruction(instruction) => instruction.address(),
+      Executed::Trapped { address, .. } => *address,
+    }
+  }
+
+  /// The decoded instruction, `None` for a trap.
+  pub fn instruction(&self) -> Option<&Disassembled> {
+    match self {
+      Executed::Instruction(instruction) => Some(instruction),
+      Executed::Trapped { .. } => None,
+    }
+  }
+
+  /// Renders the instruction in assembly syntax, a trap as the trapping
+  /// byte.
+  pub fn text(&self, style: &Style<'_>) -> String {
+    match self {
+      Executed::Instruction(instruction) => instruction.text(style),
+      Executed::Trapped { byte, .. } => format!("trap {byte:#04x}"),
+    }
+  }
+}
+
+/// An instruction the vm retired, as yielded by `Vm::iter`.
+#[derive(Debug)]
+pub struct ExecutedInstruction {
+  step: usize,
+  next_ip: usize,
+  executed: Executed,
+}
+
+impl ExecutedInstruction {
+  /// Zero based index of the instruction in the execution.
+  pub fn step(&self) -> usize {
+    self.step
+  }
+
+  pub fn address(&self) -> usize {
+    self.executed.address()
+  }
+
+  /// Where execution continued afterwards, differs from the fall through
+  /// address for taken jumps, calls and returns.
+  pub fn next_ip(&self) -> usize {
+    self.next_ip
+  }
+
+  pub fn executed(&self) -> &Executed {
+    &self.executed
+  }
+
+  /// The decoded instruction, `None` when a trap handler dealt with an
+  /// invalid opcode.
+  pub fn instruction(&self) -> Option<&Disassembled> {
+    self.executed.instruction()
+  }
+
+  pub fn into_executed(self) -> Executed {
+    self.executed
+  }
+}
+
+pub struct Iter<'vm, 'region, R> {
+  vm: &'vm mut Vm,
+  region: &'region R,
+  done: bool,
+}
+
+impl<R> Iterator for Iter<'_, '_, R>
+where
+  R: Region,
+{
+  type Item = Result<ExecutedInstruction, Error>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.done || self.vm.state == State::Halted {
+      return None;
+    }
+    let step = self.vm.steps;
+    let result = self
+      .vm
+      .step(self.region)
+      .map(|executed| ExecutedInstruction {
+        step,
+        next_ip: self.vm.ip,
+        executed,
+      });
+    self.done = result.is_err();
+    Some(result)
+  }
+}
+
 struct Task<'vm, 'region, R> {
   vm: &'vm mut Vm,
   region: &'region R,
+  // address of the instruction being executed
+  start: usize,
+  // bytes of it fetched so far
+  len: usize,
+  fetched: [u8; MAX_INSTRUCTION_LEN],
 }
 
 impl<'vm, 'region, R> Task<'vm, 'region, R>
@@ -85,19 +1382,34 @@ where
   R: Region,
 {
   fn new(vm: &'vm mut Vm, region: &'region R) -> Self {
-    Self { vm, region }
+    let start = vm.ip;
+    Self {
+      vm,
+      region,
+      start,
+      len: 0,
+      fetched: [0; MAX_INSTRUCTION_LEN],
+    }
   }
 
   fn eat(&mut self) -> Result<u8, Error> {
-    self
-      .region
-      .instructions()
-      .get(self.vm.ip)
-      .map(|b| {
-        self.vm.ip += 1;
-        *b
-      })
-      .ok_or(Error::EndOfInstructions(self.vm.ip))
+    let ip = self.vm.ip;
+    let rom = self.vm.config.rom.as_ref();
+    let byte = if let Some(rom) = rom
+      && rom.range().contains(&ip)
+    {
+      rom.byte(ip)
+    } else if self.vm.config.code_writes == CodeWrites::SelfModifying && self.vm.code.contains(&ip)
+    {
+      self.vm.memory.read_bytes(ip, 1)?.first().copied()
+    } else {
+      self.region.instructions().get(ip).copied()
+    };
+    let byte = byte.ok_or(Error::EndOfInstructions(ip))?;
+    self.vm.ip += 1;
+    self.fetched[self.len] = byte;
+    self.len += 1;
+    Ok(byte)
   }
 
   fn eat_immediate(&mut self) -> Result<Block, Error> {
@@ -109,8 +1421,15 @@ where
     Ok(Block::from_le_bytes(bytes))
   }
 
-  fn run(&mut self) -> Result<(), Error> {
-    let opcode = Opcode::try_from(self.eat()?)?;
+  fn run(&mut self) -> Result<Executed, Error> {
+    if self.vm.config.uninitialized_reads != UninitializedReads::Allow {
+      self.vm.check_register_reads(self.region, self.start)?;
+    }
+    let byte = self.eat()?;
+    let opcode = match Opcode::decode(byte, self.vm.config.isa) {
+      Ok(opcode) => opcode,
+      Err(e) => return self.trap(byte, e),
+    };
     match opcode {
       Opcode::Halt => halt(self)?,
       Opcode::Nop => nop(self)?,
@@ -125,8 +1444,37 @@ where
       Opcode::Ret => ret(self)?,
       Opcode::Pushq => pushq(self)?,
       Opcode::Popq => popq(self)?,
+      Opcode::Casq => casq(self)?,
     }
-    Ok(())
+    let redirected = self.vm.ip != self.start + self.len;
+    self
+      .vm
+      .timing
+      .retire(Class::of(&opcode), self.len, redirected);
+    let instruction = disasm::disassemble_fetched(&self.fetched[..self.len], self.start)?;
+    Ok(Executed::Instruction(instruction))
+  }
+
+  fn trap(&mut self, byte: u8, error: opcode::Error) -> Result<Executed, Error> {
+    self.vm.ip = self.start;
+    let Some(mut handler) = self.vm.trap.take() else {
+      return Err(error.into());
+    };
+    let trap = handler.invalid_opcode(self.vm, byte);
+    // the handler may have installed a replacement for itself
+    if self.vm.trap.is_none() {
+      self.vm.trap = Some(handler);
+    }
+    match trap {
+      Trap::Resume => {}
+      Trap::Skip(len) => self.vm.ip = self.start + len,
+      Trap::Fault => return Err(error.into()),
+    }
+    Ok(Executed::Trapped {
+      address: self.start,
+      byte,
+      trap,
+    })
   }
 }
 
@@ -148,12 +1496,12 @@ fn rrmovq(task: &mut Task<'_, '_, impl Region>) -> Result<(), Error> {
   Ok(())
 }
 
-fn cmovxx(task: &mut Task<'_, '_, impl Region>, cond: JCmovFun) -> Result<(), Error> {
+fn cmovxx(task: &mut Task<'_, '_, impl Region>, cond: Condition) -> Result<(), Error> {
   let byte = task.eat()?;
   let ra = Register::try_from(byte >> 4)?; // src
   let rb = Register::try_from(byte & 0xf)?; // dest
   // only move if condition is met
-  if task.vm.reg_file.eval_condition(&cond) {
+  if task.vm.reg_file.eval_condition(cond) {
     let val_a = task.vm.reg_file[ra];
     task.vm.reg_file[rb] = val_a;
   }
@@ -175,7 +1523,7 @@ fn rmmovq(task: &mut Task<'_, '_, impl Region>) -> Result<(), Error> {
   let val_c = task.eat_immediate()?;
   let val_a = task.vm.reg_file[ra];
   let val_b = task.vm.reg_file[rb];
-  let addr = (val_b + val_c) as usize;
+  let addr = val_b.wrapping_add(val_c) as usize;
   task.vm.write_block(addr, val_a)?;
   Ok(())
 }
@@ -186,12 +1534,35 @@ fn mrmovq(task: &mut Task<'_, '_, impl Region>) -> Result<(), Error> {
   let rb = Register::try_from(byte & 0xf)?; // base
   let val_c = task.eat_immediate()?;
   let val_b = task.vm.reg_file[rb];
-  let addr = (val_b + val_c) as usize;
+  let addr = val_b.wrapping_add(val_c) as usize;
   let val_m = task.vm.read_block(addr)?;
   task.vm.reg_file[ra] = val_m;
   Ok(())
 }
 
+/// Stores `rA` at `D(rB)` if the block there equals `%rax`, otherwise loads
+/// it into `%rax`. ZF tells which happened, SF and OF are cleared.
+fn casq(task: &mut Task<'_, '_, impl Region>) -> Result<(), Error> {
+  let byte = task.eat()?;
+  let ra = Register::try_from(byte >> 4)?; // new value
+  let rb = Register::try_from(byte & 0xf)?; // base
+  let val_c = task.eat_immediate()?;
+  let val_a = task.vm.reg_file[ra];
+  let val_b = task.vm.reg_file[rb];
+  let addr = val_b.wrapping_add(val_c) as usize;
+  let val_m = task.vm.read_block(addr)?;
+  let swapped = val_m == task.vm.reg_file[Register::Rax];
+  if swapped {
+    task.vm.write_block(addr, val_a)?;
+  } else {
+    task.vm.reg_file[Register::Rax] = val_m;
+  }
+  task.vm.reg_file[Flag::ZF] = swapped;
+  task.vm.reg_file[Flag::SF] = false;
+  task.vm.reg_file[Flag::OF] = false;
+  Ok(())
+}
+
 fn opq(task: &mut Task<'_, '_, impl Region>, fun: OpFun) -> Result<(), Error> {
   let byte = task.eat()?;
   let ra = Register::try_from(byte >> 4)?; // src
@@ -205,19 +1576,20 @@ fn opq(task: &mut Task<'_, '_, impl Region>, fun: OpFun) -> Result<(), Error> {
     OpFun::And => (val_b & val_a, false),
     OpFun::Xor => (val_b ^ val_a, false),
     OpFun::Mul => val_b.overflowing_mul(val_a),
-    OpFun::Div => {
-      if val_a == 0 {
-        return Err(Error::DivisionByZero);
-      }
-      (val_b / val_a, false)
-    }
-    OpFun::Mod => {
-      if val_a == 0 {
-        return Err(Error::DivisionByZero);
-      }
-      (val_b % val_a, false)
-    }
+    OpFun::Div | OpFun::Mod if val_a == 0 => return Err(Error::DivisionByZero),
+    // only `MIN / -1` overflows, its quotient does not fit
+    OpFun::Div => val_b.overflowing_div(val_a),
+    OpFun::Mod => val_b.overflowing_rem(val_a),
   };
+  let division = matches!(fun, OpFun::Div | OpFun::Mod);
+  let config = &task.vm.config;
+  if of && (config.trap_overflow || division && config.division_overflow == DivisionOverflow::Trap)
+  {
+    return Err(Error::ArithmeticOverflow(
+      Opcode::Opq(fun).mnemonic(),
+      task.start,
+    ));
+  }
 
   task.vm.reg_file[rb] = result;
   task.vm.reg_file[Flag::ZF] = result == 0;
@@ -227,19 +1599,36 @@ fn opq(task: &mut Task<'_, '_, impl Region>, fun: OpFun) -> Result<(), Error> {
   Ok(())
 }
 
-fn jxx(task: &mut Task<'_, '_, impl Region>, cond: JCmovFun) -> Result<(), Error> {
+fn jxx(task: &mut Task<'_, '_, impl Region>, cond: Condition) -> Result<(), Error> {
+  let address = task.start;
   let dest = task.eat_immediate()? as usize;
-  if task.vm.reg_file.eval_condition(&cond) {
+  let taken = task.vm.reg_file.eval_condition(cond);
+  let conditional = cond != Condition::Always;
+  if conditional {
+    task.vm.timing.branch(taken);
+  }
+  if taken {
+    task.vm.check_target(dest)?;
     task.vm.ip = dest;
+    if conditional {
+      task
+        .vm
+        .events
+        .emit(EventKind::BranchTaken, || Event::BranchTaken {
+          address,
+          target: dest,
+        });
+    }
   }
   Ok(())
 }
 
 fn call(task: &mut Task<'_, '_, impl Region>) -> Result<(), Error> {
   let dest = task.eat_immediate()? as usize;
+  task.vm.check_target(dest)?;
   let val_p = task.vm.ip as Block;
   let val_rsp = task.vm.reg_file[Register::Rsp];
-  let new_rsp = val_rsp - 8;
+  let new_rsp = val_rsp.wrapping_sub(8);
   // push ret address onto stack
   task.vm.write_block(new_rsp as usize, val_p)?;
   task.vm.reg_file[Register::Rsp] = new_rsp;
@@ -250,7 +1639,8 @@ fn call(task: &mut Task<'_, '_, impl Region>) -> Result<(), Error> {
 fn ret(task: &mut Task<'_, '_, impl Region>) -> Result<(), Error> {
   let val_rsp = task.vm.reg_file[Register::Rsp];
   let ret_addr = task.vm.read_block(val_rsp as usize)? as usize;
-  let new_rsp = val_rsp + 8;
+  task.vm.check_target(ret_addr)?;
+  let new_rsp = val_rsp.wrapping_add(8);
   task.vm.reg_file[Register::Rsp] = new_rsp;
   task.vm.ip = ret_addr;
   Ok(())
@@ -261,7 +1651,7 @@ fn pushq(task: &mut Task<'_, '_, impl Region>) -> Result<(), Error> {
   let ra = Register::try_from(byte >> 4)?; // src
   let val_a = task.vm.reg_file[ra];
   let val_rsp = task.vm.reg_file[Register::Rsp];
-  let new_rsp = val_rsp - 8;
+  let new_rsp = val_rsp.wrapping_sub(8);
   task.vm.write_block(new_rsp as usize, val_a)?;
   task.vm.reg_file[Register::Rsp] = new_rsp;
   Ok(())
@@ -272,7 +1662,7 @@ fn popq(task: &mut Task<'_, '_, impl Region>) -> Result<(), Error> {
   let ra = Register::try_from(byte >> 4)?; // dest
   let val_rsp = task.vm.reg_file[Register::Rsp];
   let val_m = task.vm.read_block(val_rsp as usize)?;
-  let new_rsp = val_rsp + 8;
+  let new_rsp = val_rsp.wrapping_add(8);
   task.vm.reg_file[ra] = val_m;
   task.vm.reg_file[Register::Rsp] = new_rsp;
   Ok(())